@@ -1,15 +1,27 @@
 // Declare our modules
 pub mod mgba_ffi; // this is private to user API
+pub mod backend;
 mod sdl_window;
-mod observation;
+pub mod observation;
+pub mod environment;
+pub mod replay;
+pub mod policy;
+pub mod game_definition;
+pub mod episode;
+pub mod preprocessing;
+pub mod trajectory;
+pub mod dataset;
+pub mod npy;
+pub mod protocol;
+pub mod frame_ring;
+pub mod frame_encoding;
+pub mod server;
 
 // pub mod agent_stuff { // redundant here
 // use super::sdl_window::{
 use sdl_window::SdlWindow;
 
 use sdl2::pixels::PixelFormatEnum;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpStream};
 use std::option::Option;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -24,9 +36,8 @@ use std::path::Path;
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)] // in order to do != operations!
 pub enum AgentControl {
     Human,  // this is already how we're IO'ing it
-    Replay, // this is going to be implemented now
-    // replay output keycode iterable
-    Intelligent, // this will be implemented later
+    Replay(replay::ReplayConfig), // replay output keycodes from a recorded input log
+    Intelligent(policy::PolicyConfig), // a Policy picks the keycode from the observation
 }
 
 use std::fmt;
@@ -34,8 +45,8 @@ impl fmt::Display for AgentControl {
     fn fmt(&self, f: &mut fmt::Formatter<'_>)-> fmt::Result {
         match self {
             Self::Human => write!(f, "AgentControl::Human"),
-            Self::Replay => write!(f, "AgentControl::Replay"),
-            Self::Intelligent => write!(f, "AgentControl::Intelligent"),
+            Self::Replay(replay_config) => write!(f, "AgentControl::Replay({})", replay_config.replay_path),
+            Self::Intelligent(policy_config) => write!(f, "AgentControl::Intelligent({})", policy_config.name),
         }
     }
 }
@@ -43,13 +54,102 @@ impl fmt::Display for AgentControl {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum AgentDriver {
     Sockets(u16), // port
+    UnixSocket(String), // socket path of a server on this machine (Serve --socket)
+    SharedMemory(String), // same socket path, but frames come back through a frame_ring::FrameRing
+    Remote(RemoteConfig), // server on another machine or container
     Native,
+    Synthetic(backend::synthetic::SyntheticConfig), // fake emulator, no libmgba or ROM needed
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    pub address: String, // host:port of a Serve --host 0.0.0.0 server, the host can be a name
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    // how long any response may take, unset waits forever
+    #[serde(default)]
+    pub read_timeout_ms: Option<u64>,
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    // wait after the first failed attempt, doubles up to max_backoff_ms after that
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_connect_attempts() -> u32 {
+    10
+}
+
+fn default_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_backoff_ms() -> u64 {
+    5000
+}
+
+impl RemoteConfig {
+    fn connect_options(&self) -> protocol::ConnectOptions {
+        protocol::ConnectOptions {
+            connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+            read_timeout: self.read_timeout_ms.map(Duration::from_millis),
+            connect_attempts: self.connect_attempts,
+            initial_backoff: Duration::from_millis(self.backoff_ms),
+            max_backoff: Duration::from_millis(self.max_backoff_ms),
+        }
+    }
+}
+
+// slots in a SharedMemory agent's frame ring, it waits on every frame so two is plenty
+const FRAME_RING_SLOTS: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GameConfigData {
     pub rom_path: String, // Path? ffi::CString?
     pub save_state_path: Option<String>, // Path? ffi::CString?
+    // mgba_ffi::savestate_flags used when loading save_state_path
+    #[serde(default = "default_save_state_flags")]
+    pub save_state_flags: u32,
+    // seeding step that runs after the save state is loaded, for reproducible episodes
+    #[serde(default)]
+    pub rng_seed: Option<RngSeed>,
+    // every AgentDriver but Native: send the ROM and save state contents along instead of just their paths,
+    // for a server that doesn't share our filesystem
+    #[serde(default)]
+    pub upload_files: bool,
+    // libmgba to load for Native (and a server's own config), unset tries $MGBA_LIB_PATH and then a search list
+    #[serde(default)]
+    pub libmgba_path: Option<String>,
+    // which of mgba's log messages reach the log crate (RUST_LOG=mgba=... on our side), default is errors only
+    #[serde(default)]
+    pub mgba_log: mgba_ffi::LogConfig,
+}
+
+fn default_save_state_flags() -> u32 {
+    mgba_ffi::savestate_flags::RTC
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RngSeed {
+    pub seed: u32,
+    // RAM location of the game's RNG state, the seed gets written here
+    #[serde(default)]
+    pub rng_address: Option<game_definition::ConfigNumber>,
+    #[serde(default = "default_rng_width")]
+    pub rng_width: u32, // bytes: 1, 2 or 4
+    // pin the real time clock (ms since unix epoch), otherwise RTC games depend on wall time
+    #[serde(default)]
+    pub rtc_time_ms: Option<i64>,
+}
+
+fn default_rng_width() -> u32 {
+    4
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClockRate {
@@ -73,9 +173,39 @@ pub struct AgentConfiguration {
     pub agent_control: AgentControl,
     pub render_condition: bool,
     pub store_observations: bool,
+    // ring buffer size for store_observations, older frames get overwritten
+    #[serde(default = "default_observation_capacity")]
+    pub observation_capacity: usize,
+    // store_observations albums go here, album_dir/episode_0000/ and up, each one written when its episode ends
+    #[serde(default = "default_album_dir")]
+    pub album_dir: String,
     pub emu_clock_mgr: Option<EmuClockMgr>,
     pub agent_driver: AgentDriver,
     pub game_config_data: GameConfigData,
+    // json file with the game's memory map, reward terms and termination conditions
+    #[serde(default)]
+    pub game_definition_path: Option<String>,
+    // episode length/count, default is one episode that runs until stopped
+    #[serde(default)]
+    pub episode_config: episode::EpisodeConfig,
+    // stages turning frame_data into processed_data, default is plain grayscale
+    #[serde(default = "preprocessing::default_preprocessing")]
+    pub preprocessing: Vec<preprocessing::PreprocessStage>,
+    // stream every IO cycle's observation/keycode/reward/done to a binary trajectory file
+    #[serde(default)]
+    pub trajectory: Option<trajectory::TrajectoryConfig>,
+    // how a socket driver wants its frames sent, see frame_encoding.rs (Native ignores it)
+    #[serde(default)]
+    pub frame_encoding: frame_encoding::FrameEncoding,
+}
+
+fn default_observation_capacity() -> usize {
+    // 10 seconds at 60 FPS
+    10 * 60
+}
+
+fn default_album_dir() -> String {
+    "imageData/".to_string()
 }
 
 pub fn read_configuration_file<'a>(file_path: &'a Path) -> AgentConfiguration {
@@ -86,6 +216,32 @@ pub fn read_configuration_file<'a>(file_path: &'a Path) -> AgentConfiguration {
         .expect(format!("Trouble parsing data from configuration file: {}",file_path.display()).as_str())
 }
 
+/// Why Agent::new couldn't put an Agent together
+#[derive(Debug)]
+pub enum AgentError {
+    Config(String), // agent_config.json asks for something that can't work
+    Upload { path: String, error: std::io::Error }, // a file to send along with upload_files
+    Connect { endpoint: protocol::Endpoint, error: protocol::ConnectError }, // never got a session
+    Session(protocol::ProtocolError), // got a session, setting it up failed
+    Core(mgba_ffi::CoreError), // AgentDriver::Native couldn't get libmgba to run the game
+    Trajectory { path: String, error: trajectory::TrajectoryError },
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(reason) => write!(f, "Invalid agent configuration: {}", reason),
+            Self::Upload { path, error } => write!(f, "Failed to read {} to upload: {}", path, error),
+            Self::Connect { endpoint, error } => write!(f, "Failed to connect to the mgba server at {}: {}", endpoint, error),
+            Self::Session(error) => write!(f, "Failed to set up the mgba server session: {}", error),
+            Self::Core(error) => write!(f, "Failed to start the mgba core: {}", error),
+            Self::Trajectory { path, error } => write!(f, "Failed to create trajectory {}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {}
+
 pub enum AgentIO {
     DirectIO(observation::ObservationData),
     SdlIO(SdlWindow),
@@ -106,19 +262,24 @@ use std::sync::Arc;
 pub struct Agent {
     agent_config: AgentConfiguration,
     agent_io: AgentIO,
-    tcp_stream: Option<TcpStream>,
-    mgba_core: Option<mgba_ffi::CoreData>,
+    backend: Box<dyn backend::EmulatorBackend>, // mgba_ffi::Core, protocol::Client or a SyntheticBackend
+    input_log: Option<replay::InputLog>, // only for AgentControl::Replay
+    policy: Option<Box<dyn policy::Policy>>, // only for AgentControl::Intelligent
+    game_tracker: Option<game_definition::GameTracker>, // rewards/termination from game memory
+    preprocess_pipeline: preprocessing::PreprocessPipeline,
+    trajectory_writer: Option<trajectory::TrajectoryWriter>,
+    episode_recorder: Option<episode::EpisodeRecorder>, // only with store_observations
     agent_stop_flag: Arc<AtomicBool>,
     stop_flag_polling_period: u32,
     cycle_duration: Option<Duration>,
 }
 
 /// This is the central struct of the library
-/// init_core -> run the mgba emulator core inside of this struct
+/// Core::new -> run the mgba emulator core inside of this struct (or any other EmulatorBackend)
 /// AgentConfiguration, run_client, and stop_flag are the only ways
 /// the user interacts with this Agent right now.
 impl Agent {
-    pub fn new(agent_config: AgentConfiguration, agent_stop_flag: Option<Arc<AtomicBool>>) -> Agent {
+    pub fn new(agent_config: AgentConfiguration, agent_stop_flag: Option<Arc<AtomicBool>>) -> Result<Agent, AgentError> {
 
         // if agent_config.emu_clock_mgr.is_some()
 
@@ -127,21 +288,127 @@ impl Agent {
             _=>5000_u32,
         };
         
-        let tcp_stream: Option<TcpStream>;
+        let backend: Box<dyn backend::EmulatorBackend> = match &agent_config.agent_driver {
+            AgentDriver::Native => Box::new(
+                mgba_ffi::Core::new(&agent_config.game_config_data).map_err(AgentError::Core)?
+            ),
+            AgentDriver::Synthetic(synthetic_config) => {
+                let mut synthetic_backend = backend::synthetic::SyntheticBackend::new(synthetic_config)
+                    .map_err(|e| AgentError::Config(format!("agent_driver: {}", e)))?;
+                // seeded from game_config_data.rng_seed like a native core would be
+                backend::EmulatorBackend::load_game(&mut synthetic_backend, &agent_config.game_config_data)
+                    .map_err(|e| AgentError::Config(format!("game_config_data: {}", e)))?;
+                Box::new(synthetic_backend)
+            },
+            AgentDriver::Sockets(mgba_port) => Box::new(Self::init_connection(
+                &agent_config.game_config_data,
+                agent_config.frame_encoding,
+                &protocol::Endpoint::localhost(*mgba_port),
+                &protocol::ConnectOptions::default(),
+                false,
+            )?),
+            AgentDriver::UnixSocket(socket_path) => Box::new(Self::init_connection(
+                &agent_config.game_config_data,
+                agent_config.frame_encoding,
+                &protocol::Endpoint::Unix(socket_path.into()),
+                &protocol::ConnectOptions::default(),
+                false,
+            )?),
+            AgentDriver::SharedMemory(socket_path) => Box::new(Self::init_connection(
+                &agent_config.game_config_data,
+                agent_config.frame_encoding,
+                &protocol::Endpoint::Unix(socket_path.into()),
+                &protocol::ConnectOptions::default(),
+                true,
+            )?),
+            AgentDriver::Remote(remote_config) => Box::new(Self::init_connection(
+                &agent_config.game_config_data,
+                agent_config.frame_encoding,
+                &protocol::Endpoint::Tcp(remote_config.address.clone()),
+                &remote_config.connect_options(),
+                false,
+            )?),
+        };
+        let mut agent_io = Self::init_agent_io(agent_config.render_condition, backend.as_ref());
+
+        let preprocess_pipeline = {
+            let observation_data = match &mut agent_io {
+                AgentIO::DirectIO(observation_data) => observation_data,
+                AgentIO::SdlIO(sdl_window) => &mut sdl_window.observation_data,
+            };
+            let frame_buffer = &mut observation_data.frame_buffer;
+            let preprocess_pipeline = preprocessing::PreprocessPipeline::new(
+                &agent_config.preprocessing,
+                frame_buffer.width,
+                frame_buffer.height,
+                frame_buffer.pixel_format,
+            ).map_err(|e| AgentError::Config(format!("preprocessing: {}", e)))?;
+            frame_buffer.set_processed_shape(preprocess_pipeline.output_shape());
+            preprocess_pipeline
+        };
+
+        let trajectory_writer = agent_config.trajectory.as_ref().map(|trajectory_config| {
+            let observation_data = match &agent_io {
+                AgentIO::DirectIO(observation_data) => observation_data,
+                AgentIO::SdlIO(sdl_window) => &sdl_window.observation_data,
+            };
+            trajectory::TrajectoryWriter::create(
+                Path::new(&trajectory_config.path),
+                trajectory::TrajectoryHeader::from_observation(observation_data, trajectory_config.include_frame_data),
+                trajectory_config.chunk_frames,
+            ).map_err(|error| AgentError::Trajectory { path: trajectory_config.path.clone(), error })
+        }).transpose()?;
 
-        let mgba_core: Option<mgba_ffi::CoreData>;
-        let agent_io: AgentIO;
+        if agent_config.store_observations && agent_config.observation_capacity == 0 {
+            return Err(AgentError::Config("observation_capacity has to be at least 1 with store_observations".to_string()));
+        }
+        // every slot is allocated here, once, for every episode run_client will ever record
+        let episode_recorder = agent_config.store_observations.then(|| {
+            episode::EpisodeRecorder::new(
+                Path::new(&agent_config.album_dir),
+                match &agent_io {
+                    AgentIO::DirectIO(observation_data) => observation_data,
+                    AgentIO::SdlIO(sdl_window) => &sdl_window.observation_data,
+                },
+                agent_config.observation_capacity,
+            )
+        });
 
-        (agent_io, mgba_core, tcp_stream) = match agent_config.agent_driver {
-            AgentDriver::Native => Self::init_core(
-                agent_config.render_condition,
-                &agent_config.game_config_data
+        let input_log = match &agent_config.agent_control {
+            AgentControl::Replay(replay_config) => Some(
+                replay::InputLog::from_config(replay_config).map_err(|e| AgentError::Config(format!("agent_control: {}", e)))?
             ),
-            AgentDriver::Sockets(mgba_port) => Self::init_connection(
-                agent_config.render_condition,
-                &agent_config.game_config_data,
-                mgba_port
+            _ => None,
+        };
+
+        let policy = match &agent_config.agent_control {
+            AgentControl::Intelligent(policy_config) => Some(
+                policy::build_policy(policy_config).map_err(|e| AgentError::Config(format!("agent_control: {}", e)))?
             ),
+            _ => None,
+        };
+
+        // socket drivers read variables through ReadMemory, block locations with the server's ListMemoryBlocks
+        let game_tracker = match &agent_config.game_definition_path {
+            Some(game_definition_path) => {
+                let mut game_definition =
+                    game_definition::read_game_definition_file(Path::new(game_definition_path))
+                        .map_err(AgentError::Config)?;
+                if let Some(termination_condition) = &agent_config.episode_config.termination_condition {
+                    game_definition
+                        .add_termination("episode_config.termination_condition", termination_condition)
+                        .map_err(|e| AgentError::Config(format!("episode_config: {}", e)))?;
+                }
+                Some(game_definition::GameTracker::new(game_definition))
+            },
+            None => {
+                if agent_config.episode_config.termination_condition.is_some() {
+                    return Err(AgentError::Config(
+                        "episode_config.termination_condition needs a game_definition_path to read variables from".to_string(),
+                    ));
+                }
+                None
+            },
         };
 
         let cycle_duration = match &agent_config.emu_clock_mgr {
@@ -149,95 +416,199 @@ impl Agent {
             _=>None,
         };
 
-        Agent {
+        Ok(Agent {
             agent_config: agent_config,
             agent_io: agent_io, // observation data and optional sdl rendering environment
-            tcp_stream: tcp_stream,
+            backend,
             // sdl_window: sdl_window, // moved into agent_io
-            mgba_core: mgba_core,
+            input_log,
+            policy,
+            game_tracker,
+            preprocess_pipeline,
+            trajectory_writer,
+            episode_recorder,
             // observation: observation, // moved into agent_io
             agent_stop_flag: agent_stop_flag
                 .unwrap_or_else(|| Arc::new(AtomicBool::new(false))),
             stop_flag_polling_period: stop_flag_polling_period,
             cycle_duration: cycle_duration,
-        }
+        })
     }
 
     // only called in the new function
     fn init_connection(
-        render_condition: bool,
         // passing of game ROM data to server
-        _game_config_data: &GameConfigData,
-        mgba_port: u16
-    ) -> (AgentIO, Option<mgba_ffi::CoreData>, Option<TcpStream>) {
-        println!("Client connecting to port {}", mgba_port);
-        let mgba_addr = SocketAddr::from(([127, 0, 0, 1], mgba_port)); // hard coded port -- DEFAULT port in mgba
-        thread::sleep(Duration::from_millis(5));
-        let mut tcp_stream = TcpStream::connect_timeout(&mgba_addr, Duration::from_millis(1000))
-            .expect("Ruh roh shaggy");
-
-        // assert we read 4 bytes each
-        println!("Reading width");
-        let mut buf = [0_u8; 4];
-        tcp_stream.read_exact(&mut buf).expect("Initial reading from tcp stream server failed: 0");
-        let width = u32::from_be_bytes(buf);
-        println!("Reading height");
-        tcp_stream.read_exact(&mut buf).expect("Initial reading from tcp stream server failed: 1");
-        let height = u32::from_be_bytes(buf);
-        println!("Reading bpp");
-        tcp_stream.read_exact(&mut buf).expect("Initial reading from tcp stream server failed: 2");
-        let bpp = u32::from_be_bytes(buf);
-
-        // Didn't have to use 'ne' for network order, it comes properly through stream read from_ne_bytes
-        println!("Agent.init_connection: width={}", width);
-        println!("Agent.init_connection: height={}", height);
-        println!("Agent.init_connection: bpp={}", bpp);
-
-        // (*frame_data) = vec![0_u8; (width*height*bpp) as usize];
-
-        let pixel_format: PixelFormatEnum = match bpp {
-            2 => PixelFormatEnum::RGB565,
-            4 => PixelFormatEnum::ABGR8888,
-            _ => panic!("This is not documented"),
-        };
+        game_config_data: &GameConfigData,
+        frame_encoding: frame_encoding::FrameEncoding,
+        endpoint: &protocol::Endpoint,
+        connect_options: &protocol::ConnectOptions,
+        shared_frames: bool,
+    ) -> Result<protocol::Client, AgentError> {
+        let mut connection = Self::connect_to_server(endpoint, connect_options, game_config_data, frame_encoding)?;
+        if shared_frames {
+            connection.map_frames(FRAME_RING_SLOTS).map_err(AgentError::Session)?;
+        }
+        Ok(connection)
+    }
 
-        let observation_data = observation::ObservationData {
-            // This will be overwritten
-            frame_buffer: observation::FrameBuffer::new(width,height,bpp,pixel_format),
+    // ObservationData shaped like the backend's frames, starting out with whatever frame it has now
+    fn init_agent_io(render_condition: bool, backend: &dyn backend::EmulatorBackend) -> AgentIO {
+        let (width, height, bpp) = backend.video_size();
+        let pixel_format: PixelFormatEnum = observation::pixel_format_for_bpp(bpp);
+        let mut observation_data = observation::ObservationData {
+            frame_buffer: observation::FrameBuffer::new(width, height, bpp, pixel_format),
             keycode_data: 0_u16,
         };
-        
-        let agent_io: AgentIO = match render_condition {
+        observation_data.frame_buffer.write_buffer(backend.framebuffer());
+        match render_condition {
             // create a barebones observation_data format
             false => AgentIO::DirectIO(observation_data),
+            // create an SdlWindow with the desired observation_data format
             true => AgentIO::SdlIO(SdlWindow::new("Newly organized window",observation_data)),
+        }
+    }
+
+    // Connects to an mgba server and asks it to run our game, the handshake tells us what the framebuffer looks like
+    // A server that's still starting up (or full) gets retried, see protocol::Client::connect_with_retry
+    fn connect_to_server(
+        endpoint: &protocol::Endpoint,
+        connect_options: &protocol::ConnectOptions,
+        game_config_data: &GameConfigData,
+        frame_encoding: frame_encoding::FrameEncoding,
+    ) -> Result<protocol::Client, AgentError> {
+        println!("Client connecting to {}", endpoint);
+        let read_upload = |file_path: &str| {
+            std::fs::read(file_path).map_err(|error| AgentError::Upload { path: file_path.to_string(), error })
         };
+        let session_request = protocol::SessionRequest {
+            game_config_data: Some(game_config_data.clone()),
+            rom_data: game_config_data.upload_files.then(|| read_upload(&game_config_data.rom_path)).transpose()?,
+            save_state_data: game_config_data.save_state_path.as_deref()
+                .filter(|_| game_config_data.upload_files)
+                .map(read_upload)
+                .transpose()?,
+            frame_encoding,
+        };
+        let connection = protocol::Client::connect_with_retry(endpoint, &session_request, connect_options)
+            .map_err(|error| AgentError::Connect { endpoint: endpoint.clone(), error })?;
+        let server_info = *connection.server_info();
 
-        (
-            agent_io,
-            None,
-            Some(tcp_stream),
-        )
-    }
-
-    fn init_core(
-        render_condition: bool,
-        game_config_data: &GameConfigData
-    ) -> (AgentIO, Option<mgba_ffi::CoreData>, Option<TcpStream>) {
-        let observation_data: observation::ObservationData;
-        let core_data: mgba_ffi::CoreData;
-        (observation_data,core_data) = mgba_ffi::init_core(game_config_data);
-        let agent_io: AgentIO = match render_condition {
-            // create a barebones observation_data format
-            false => AgentIO::DirectIO(observation_data),
-            // create an SdlWindow with the desired observation_data format
-            true => AgentIO::SdlIO(SdlWindow::new("Newly organized window",observation_data)),
+        println!("Agent.init_connection: width={}", server_info.width);
+        println!("Agent.init_connection: height={}", server_info.height);
+        println!("Agent.init_connection: bpp={}", server_info.bpp);
+        if server_info.frame_encoding != frame_encoding {
+            println!("Agent.init_connection: asked for {:?} frames, server sends {:?}", frame_encoding, server_info.frame_encoding);
+        }
+
+        Ok(connection)
+    }
+
+    // Both AgentIO variants own the ObservationData, just in different places
+    #[inline(always)]
+    fn observation_data(&self) -> &observation::ObservationData {
+        match &self.agent_io {
+            AgentIO::DirectIO(observation_data) => observation_data,
+            AgentIO::SdlIO(sdl_window) => &sdl_window.observation_data,
+        }
+    }
+
+    #[inline(always)]
+    fn observation_data_mut(&mut self) -> &mut observation::ObservationData {
+        match &mut self.agent_io {
+            AgentIO::DirectIO(observation_data) => observation_data,
+            AgentIO::SdlIO(sdl_window) => &mut sdl_window.observation_data,
+        }
+    }
+
+    /// FATAL/ERROR messages the emulator logged since the last call
+    fn take_emulator_errors(&mut self) -> Vec<String> {
+        self.backend.take_errors()
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.agent_stop_flag.load(Ordering::Relaxed)
+    }
+
+    /// Brings the emulator back to the state described by game_config_data
+    /// Native: restore the in memory snapshot the core took right after it started
+    /// Sockets/UnixSocket/SharedMemory/Remote: the server restores its own snapshot and sends the fresh frame back
+    fn reset_emulator(&mut self) -> Result<(), backend::BackendError> {
+        self.backend.restart()?;
+        let backend = &self.backend;
+        match &mut self.agent_io {
+            AgentIO::DirectIO(observation_data) => observation_data,
+            AgentIO::SdlIO(sdl_window) => &mut sdl_window.observation_data,
+        }.frame_buffer.write_buffer(backend.framebuffer());
+        // stacked frames from the last episode shouldn't leak into the next
+        self.preprocess_pipeline.reset();
+        Ok(())
+    }
+
+    /// Play game_config_data's game from now on, on the backend we already have (no new core or connection)
+    /// The next run_client starts with its first frame. Only the game changes, the rest of agent_config stays
+    pub fn load_game(&mut self, game_config_data: GameConfigData) -> Result<(), backend::BackendError> {
+        let video_size = self.backend.video_size();
+        self.backend.load_game(&game_config_data)?;
+        // observation buffers (and the window) were sized for the old game
+        if self.backend.video_size() != video_size {
+            return Err(backend::BackendError::Unsupported("switch to a game with another frame size"));
+        }
+        self.agent_config.game_config_data = game_config_data;
+        self.reset_emulator()?;
+        self.reset_game_tracker()
+
+    }
+
+    /// Re-run the seeding step with a different seed (Native only, the core is ours)
+    /// A backend that can't reseed at all only gets a message, any other failure is an Err
+    fn seed_emulator(&mut self, seed: u32) -> Result<(), backend::BackendError> {
+        let rng_seed = match &self.agent_config.game_config_data.rng_seed {
+            Some(rng_seed) => rng_seed,
+            None => {
+                println!("No rng_seed in game_config_data, don't know where to write seed {}", seed);
+                return Ok(());
+            },
         };
-        (
-            agent_io,
-            Some(core_data),
-            None,
-        )
+        match self.backend.seed_rng(rng_seed, seed) {
+            Err(e @ backend::BackendError::Unsupported(_)) => {
+                println!("Not seeding with {}: {}", seed, e);
+                Ok(())
+            },
+            result => result,
+        }
+    }
+
+    /// Whole emulator state, wherever the core lives
+    fn snapshot_emulator(&mut self) -> Result<Vec<u8>, backend::BackendError> {
+        self.backend.snapshot()
+    }
+
+    /// len bytes of game memory, a range that isn't mapped is an Err rather than a panic
+    fn read_emulator_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, String> {
+        self.backend.read_memory(address, len).map_err(|e| e.to_string())
+    }
+
+    /// Sockets/UnixSocket/SharedMemory/Remote: ask the server to stop listening once we're done with it, Native has nothing to stop
+    pub fn shutdown_server(&mut self) {
+        if let Err(e) = self.backend.shutdown() {
+            println!("mgba server didn't shut down cleanly: {}", e);
+        }
+    }
+
+    // Start of an episode for the game definition (if any), prev values = current values
+    fn reset_game_tracker(&mut self) -> Result<(), backend::BackendError> {
+        match &mut self.game_tracker {
+            Some(game_tracker) => game_tracker.reset(self.backend.as_mut()),
+            None => Ok(()),
+        }
+    }
+
+    // Reward and termination for the frame that just ran, nothing to report without a game definition
+    fn update_game_tracker(&mut self) -> Result<game_definition::GameStep, backend::BackendError> {
+        match &mut self.game_tracker {
+            Some(game_tracker) => game_tracker.update(self.backend.as_mut()),
+            None => Ok(game_definition::GameStep::default()),
+        }
     }
 
     #[inline(always)]
@@ -248,65 +619,75 @@ impl Agent {
             AgentIO::DirectIO(observation_data) => observation_data, // this compiles without & too
             AgentIO::SdlIO(sdl_window) => &mut (sdl_window.observation_data),
         };//.frame_buffer.post_process_data();
-        observation_data.frame_buffer.post_process_data();
+        observation_data.frame_buffer.post_process_data(&mut self.preprocess_pipeline);
         // return the observation
         // observation_data.clone()
     }
     
-    fn execute_cycle(&mut self) {
-        let output_keycode = match &mut self.agent_io {
-            AgentIO::DirectIO(ref mut observation_data) => observation_data.keycode_data,
-            AgentIO::SdlIO(ref mut sdl_window) => sdl_window.observation_data.keycode_data,
+    // Current observation goes into the trajectory file, if we're recording one
+    fn append_trajectory(&mut self) -> Result<(), trajectory::TrajectoryError> {
+        match self.trajectory_writer.as_mut() {
+            Some(trajectory_writer) => {
+                let observation_data = match &self.agent_io {
+                    AgentIO::DirectIO(observation_data) => observation_data,
+                    AgentIO::SdlIO(sdl_window) => &sdl_window.observation_data,
+                };
+                trajectory_writer.append_observation(observation_data)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn execute_cycle(&mut self) -> Result<(), backend::BackendError> {
+        let observation_data = match &mut self.agent_io {
+            AgentIO::DirectIO(ref mut observation_data) => observation_data,
+            AgentIO::SdlIO(ref mut sdl_window) => &mut sdl_window.observation_data,
         };
         // Execute an emulator cycle, write to new input
-        match &mut self.agent_config.agent_driver {
-            AgentDriver::Sockets(_) => {
-                // Only way to make reference mutable is.. clone?
-                // let mut stream = tcp_stream.try_clone().expect("");
-                let mut tcp_stream = self.tcp_stream.as_ref().unwrap();
-                tcp_stream.write(&output_keycode.to_be_bytes()).expect("Writing keycode to tcp stream failure");
-
-                // read in frame_buffer from TCP stream into correct location in memory:
-                match &mut self.agent_io {
-                    AgentIO::DirectIO(ref mut observation_data)=>{
-                        tcp_stream.read_exact(
-                            &mut observation_data.frame_buffer.frame_data[..]
-                        ).expect("Reading frame buffer from tcp stream failure");
-                    },
-                    AgentIO::SdlIO(ref mut sdl_window)=>{
-                        tcp_stream.read_exact(
-                            &mut sdl_window.observation_data.frame_buffer.frame_data[..]
-                        ).expect("Reading frame buffer from tcp stream failure");
-                    }
-                }
-            },
-            AgentDriver::Native => unsafe {
-                mgba_ffi::execute_core_cycle(
-                    self.mgba_core.as_ref().expect(""),
-                    output_keycode,
-                );
+        self.backend.set_keys(observation_data.keycode_data)?;
+        self.backend.run_frame()?;
+        // wherever the backend keeps its frame, the observation gets a copy
+        observation_data.frame_buffer.write_buffer(self.backend.framebuffer());
+        Ok(())
+    }
+
+    // store_observations: a fresh album for the episode that's about to run
+    fn start_episode_recording(&mut self) {
+        if let Some(episode_recorder) = self.episode_recorder.as_mut() {
+            episode_recorder
+                .start_episode()
+                .unwrap_or_else(|e| panic!("Failed to start an episode album: {}", e));
+        }
+    }
+
+    // The episode's album and how many frames went into it, (None, 0) without store_observations
+    fn finish_episode_recording(&mut self) -> (Option<std::path::PathBuf>, usize) {
+        match self.episode_recorder.as_mut() {
+            Some(episode_recorder) => {
+                let (album, observations_stored) = episode_recorder
+                    .finish_episode()
+                    .unwrap_or_else(|e| panic!("Failed to write an episode album: {}", e));
+                (Some(album), observations_stored)
             },
+            None => (None, 0),
         }
     }
 
-    pub fn run_client(&mut self) -> Option<observation::ObservationSet> {
-        let mut ret_val: Option<observation::ObservationSet> 
-            = match self.agent_config.store_observations {
-                false => None,
-                true => {
-                    let observation_data = match &self.agent_io {
-                        AgentIO::DirectIO(observation_data)=>&observation_data,
-                        AgentIO::SdlIO(sdl_window)=>&sdl_window.observation_data,
-                    };
-                    Some(observation::ObservationSet::new(
-                        None,
-                        observation_data.frame_buffer.width,
-                        observation_data.frame_buffer.height,
-                        observation_data.frame_buffer.pixel_format,
-                    ))
-                },
-        };
-        
+    /// Runs episodes until episode_config.num_episodes is reached or we're told to stop
+    /// Returns a result per episode, the last one may have been cut short (EpisodeEnd::Stopped),
+    /// or ended by the emulator or the trajectory failing (EpisodeEnd::Failed), which ends the run too
+    pub fn run_client(&mut self) -> Vec<episode::EpisodeResult> {
+        let mut episode_results: Vec<episode::EpisodeResult> = vec![];
+        self.start_episode_recording();
+        let mut episode_frames = 0_u32;
+        let mut episode_reward = 0_f32;
+        let mut episode_errors: Vec<String> = vec![];
+        // why the run ended early, goes into the last episode's result
+        let mut failure: Option<String> = self
+            .reset_game_tracker()
+            .err()
+            .map(|e| format!("Failed to read game variables: {}", e));
+
         let mut cycle_counter = 0_u32;
         let mut _frame_counter = 0_u32;
         let emu_loop_time = Instant::now();
@@ -318,7 +699,7 @@ impl Agent {
         // just going with 100 for the in between, 7.5
         // 166 uses like 7% too. So not that big of a deal
         let cycle_sleep_dur = Duration::from_micros(100);
-        'agent_loop_cycle: loop {
+        'agent_loop_cycle: while failure.is_none() {
             let mut io_control_flow = true;
             // This is our hot loop
             match (0 == (cycle_counter % self.stop_flag_polling_period))
@@ -377,35 +758,156 @@ impl Agent {
                                     },
                                 }
                             }
-                            AgentControl::Replay => {
-                                static FRAME_COUNTER: u32 = 0_u32;
-                                let tmp = format!("imgData/{:0>4}", FRAME_COUNTER);
-                                let replay_path = Path::new(&tmp);
-                                // replay_path = replay_path.join(format!("{:0>4}/",FRAME_COUNTER));
-                                println!("{:?}", replay_path);
-                                // println!("{}",format!("{:?}{:0>4}",replay_path,FRAME_COUNTER));
-                                break 'agent_loop_cycle;
-                                // step through to next output frame
-                                // is probably better to read this from somewhere in self, which was already preprocessed
-                                FRAME_COUNTER += 1;
-                                // 0_u16
+                            AgentControl::Replay(_) => {
+                                // step through to the next recorded keycode, stop when the log runs out
+                                let keycode = match self.input_log.as_mut().unwrap().next_keycode() {
+                                    Some(keycode) => keycode,
+                                    None => break 'agent_loop_cycle,
+                                };
+                                match &mut self.agent_io {
+                                    AgentIO::DirectIO(ref mut observation_data)=>{
+                                        observation_data.keycode_data = keycode;
+                                    },
+                                    AgentIO::SdlIO(ref mut sdl_window)=>{
+                                        // still pump events so the window can be closed, ignore the keys
+                                        if sdl_window.matt_events().is_none() {
+                                            break 'agent_loop_cycle;
+                                        }
+                                        sdl_window.observation_data.keycode_data = keycode;
+                                    },
+                                }
                             }
                             // Use our previous frame's observation (self.observation.frame_data) to base keycode
-                            AgentControl::Intelligent => panic!("dead code!!!"),
+                            AgentControl::Intelligent(_) => {
+                                self.get_observation(); // policy gets to see processed_data too
+                                let policy = self.policy.as_mut().unwrap();
+                                match &mut self.agent_io {
+                                    AgentIO::DirectIO(ref mut observation_data)=>{
+                                        observation_data.keycode_data = policy.act(observation_data);
+                                    },
+                                    AgentIO::SdlIO(ref mut sdl_window)=>{
+                                        // still pump events so the window can be closed, ignore the keys
+                                        if sdl_window.matt_events().is_none() {
+                                            break 'agent_loop_cycle;
+                                        }
+                                        sdl_window.observation_data.keycode_data =
+                                            policy.act(&sdl_window.observation_data);
+                                    },
+                                }
+                            }
                         };
                         
-                        if self.agent_config.store_observations {
-                            let _ = self.get_observation();
-                            // ret_val.as_mut().unwrap().push(self.get_observation());
+                        if self.episode_recorder.is_some() || self.trajectory_writer.is_some() {
+                            // Intelligent already processed this frame for the policy
+                            if !matches!(self.agent_config.agent_control, AgentControl::Intelligent(_)) {
+                                self.get_observation();
+                            }
+                            if let Some(episode_recorder) = self.episode_recorder.as_mut() {
+                                let observation_data = match &self.agent_io {
+                                    AgentIO::DirectIO(observation_data) => observation_data,
+                                    AgentIO::SdlIO(sdl_window) => &sdl_window.observation_data,
+                                };
+                                episode_recorder
+                                    .push(observation_data)
+                                    .unwrap_or_else(|e| panic!("Failed to write the input log: {}", e));
+                            }
+                            if let Err(e) = self.append_trajectory() {
+                                failure = Some(format!("Failed to write trajectory: {}", e));
+                                break 'agent_loop_cycle;
+                            }
                         }
                         _frame_counter+=1;
                     } // Observationdata aka Frame Encounter
-                    self.execute_cycle();
+                    if let Err(e) = self.execute_cycle() {
+                        failure = Some(format!("Failed to run an emulator frame: {}", e));
+                        break 'agent_loop_cycle;
+                    }
                     cycle_counter += 1;
+                    episode_errors.extend(self.take_emulator_errors());
+
+                    // Episode bookkeeping, the game definition (if any) decides reward/termination
+                    episode_frames += 1;
+                    let game_step = match self.update_game_tracker() {
+                        Ok(game_step) => game_step,
+                        Err(e) => {
+                            failure = Some(format!("Failed to read game variables: {}", e));
+                            break 'agent_loop_cycle;
+                        },
+                    };
+                    episode_reward += game_step.reward;
+                    let episode_end = match game_step.terminated_by {
+                        Some(condition_name) => Some(episode::EpisodeEnd::Terminated(condition_name)),
+                        None => self.agent_config.episode_config.max_frames
+                            .filter(|max_frames| episode_frames >= *max_frames)
+                            .map(|_| episode::EpisodeEnd::Truncated),
+                    };
+                    if let Some(trajectory_writer) = self.trajectory_writer.as_mut() {
+                        trajectory_writer.add_step_outcome(game_step.reward, episode_end.is_some());
+                    }
+                    if let Some(episode_end) = episode_end {
+                        let (album, observations_stored) = self.finish_episode_recording();
+                        episode_results.push(episode::EpisodeResult {
+                            episode_idx: episode_results.len() as u32,
+                            frames: episode_frames,
+                            total_reward: episode_reward,
+                            episode_end,
+                            album,
+                            observations_stored,
+                            emulator_errors: std::mem::take(&mut episode_errors),
+                        });
+                        episode_frames = 0;
+                        episode_reward = 0.0;
+                        // a finished episode is worth getting onto the disk
+                        if let Some(Err(e)) = self.trajectory_writer.as_mut().map(|trajectory_writer| trajectory_writer.flush()) {
+                            failure = Some(format!("Failed to write trajectory: {}", e));
+                            break 'agent_loop_cycle;
+                        }
+                        if self.agent_config.episode_config.num_episodes
+                            .is_some_and(|num_episodes| episode_results.len() as u32 >= num_episodes)
+                        {
+                            break 'agent_loop_cycle;
+                        }
+                        // the next episode's album first, so a failed reset has an album of its own to end in
+                        self.start_episode_recording();
+                        // back to the configured save state for the next episode
+                        if let Err(e) = self.reset_emulator() {
+                            failure = Some(format!("Failed to reset the emulator: {}", e));
+                            break 'agent_loop_cycle;
+                        }
+                        if let Err(e) = self.reset_game_tracker() {
+                            failure = Some(format!("Failed to read game variables: {}", e));
+                            break 'agent_loop_cycle;
+                        }
+                    }
                 }
                 Some(true) => break 'agent_loop_cycle,
             }
         }
+        // whatever was running when we got stopped still counts, and a failure always gets reported
+        if episode_frames > 0 || episode_results.is_empty() || failure.is_some() {
+            let (album, observations_stored) = self.finish_episode_recording();
+            episode_results.push(episode::EpisodeResult {
+                episode_idx: episode_results.len() as u32,
+                frames: episode_frames,
+                total_reward: episode_reward,
+                episode_end: failure.map_or(episode::EpisodeEnd::Stopped, episode::EpisodeEnd::Failed),
+                album,
+                observations_stored,
+                emulator_errors: episode_errors,
+            });
+        } else if let Some(episode_recorder) = self.episode_recorder.as_mut() {
+            // stopped right after an episode ended, the next one never got a frame
+            episode_recorder
+                .discard_episode()
+                .unwrap_or_else(|e| panic!("Failed to remove an empty episode album: {}", e));
+        }
+        if let Some(trajectory_writer) = self.trajectory_writer.as_mut() {
+            // the episode results are worth more than the trajectory's last chunk, don't throw them away over it
+            match trajectory_writer.flush() {
+                Ok(()) => println!("Wrote {} frames to the trajectory", trajectory_writer.len()),
+                Err(e) => println!("Failed to write trajectory: {}", e),
+            }
+        }
         println!(
             "mGBA runtime: {:?}, mGBA average FPS: {:?}",
             emu_loop_time.elapsed(),
@@ -413,7 +915,214 @@ impl Agent {
         );
         // calculate average FPS based on cycle_counter
 
-        // return ObservationData
-        ret_val
+        episode_results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Agent config running the synthetic backend headless, with a game definition over its fake WRAM
+    /// Albums go to a fresh temp directory, see album_frames
+    pub fn synthetic_agent_config(name: &str, agent_control: serde_json::Value) -> AgentConfiguration {
+        let game_definition_path = std::env::temp_dir()
+            .join(format!("synthetic_game_definition_{}_{}.json", name, std::process::id()));
+        let album_dir = std::env::temp_dir().join(format!("synthetic_albums_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&album_dir);
+        let game_definition = serde_json::json!({
+            "name":"Synthetic",
+            "variables":{
+                "x":{"block":"wram","offset":"0x8","width":4},
+                "a_presses":{"block":"wram","offset":"0x10","width":4}
+            },
+            "reward_terms":[{"name":"a_pressed","expression":"delta(a_presses)"}],
+            "termination":[{"name":"right_edge","condition":"x >= 121"}]
+        });
+        std::fs::write(&game_definition_path, game_definition.to_string()).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "agent_control":agent_control,
+            "render_condition":false,
+            "store_observations":true,
+            "album_dir":album_dir,
+            "emu_clock_mgr":null,
+            "agent_driver":{"Synthetic":{}},
+            "game_config_data":{"rom_path":"synthetic","save_state_path":null,"rng_seed":{"seed":5}},
+            "game_definition_path":game_definition_path,
+            "episode_config":{"num_episodes":2}
+        }))
+        .unwrap()
+    }
+
+    /// Removes a test's album dir when it goes out of scope, so a failed assert doesn't leave the albums behind
+    pub struct AlbumDirCleanup(pub String);
+
+    impl Drop for AlbumDirCleanup {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Frames run_client saved for an episode, oldest first
+    pub fn album_frames(episode_result: &episode::EpisodeResult) -> Vec<observation::ObservationData> {
+        let (observation_set, corrupt_frames) =
+            observation::ObservationSet::load(episode_result.album.as_ref().unwrap()).unwrap();
+        assert!(corrupt_frames.is_empty());
+        observation_set.iter().cloned().collect()
+    }
+
+    #[test]
+    fn synthetic_agent_runs_whole_episodes() {
+        // A then Right, the square starts at x = 116 so it takes 5 of each to hit x >= 121
+        let agent_control = serde_json::json!({"Intelligent":{
+            "name":"FixedMacro",
+            "params":{"steps":[{"keycode":1,"frames":1},{"keycode":16,"frames":1}],"idle_keycode":0}
+        }});
+        let agent_config = synthetic_agent_config("episodes", agent_control);
+        let _album_dir_cleanup = AlbumDirCleanup(agent_config.album_dir.clone());
+        let mut agent = Agent::new(agent_config, None).unwrap();
+        let episode_results = agent.run_client();
+
+        assert_eq!(episode_results.len(), 2);
+        for episode_result in &episode_results {
+            assert_eq!(episode_result.frames, 10);
+            assert_eq!(episode_result.total_reward, 5.0);
+            assert_eq!(episode_result.episode_end, episode::EpisodeEnd::Terminated("right_edge".to_string()));
+            assert!(episode_result.is_valid());
+        }
+        // the second episode starts over from the same first frame
+        let first_frames: Vec<u32> = episode_results
+            .iter()
+            .map(|episode_result| album_frames(episode_result)[0].frame_buffer.checksum())
+            .collect();
+        assert_eq!(first_frames[0], first_frames[1]);
+        assert_eq!(episode_results[0].observations_stored, 10);
+        // every episode got its own album and complete input log, both from the one ring buffer
+        let album = episode_results[1].album.as_ref().unwrap();
+        assert!(album.ends_with("episode_0001"));
+        assert_eq!(replay::read_input_log(&album.join(replay::INPUT_LOG_FILE_NAME)).unwrap(), [1, 16].repeat(5));
+    }
+
+    #[test]
+    fn albums_keep_the_tail_and_the_whole_input_log() {
+        let agent_control = serde_json::json!({"Intelligent":{
+            "name":"FixedMacro",
+            "params":{"steps":[{"keycode":1,"frames":1},{"keycode":16,"frames":1}],"idle_keycode":0}
+        }});
+        let mut agent_config = synthetic_agent_config("album_tail", agent_control);
+        agent_config.observation_capacity = 4;
+        agent_config.episode_config.num_episodes = Some(3);
+        let _album_dir_cleanup = AlbumDirCleanup(agent_config.album_dir.clone());
+        let mut agent = Agent::new(agent_config, None).unwrap();
+        let episode_results = agent.run_client();
+        // albums carry on numbering in the next run_client
+        let next_results = agent.run_client();
+
+        assert_eq!(episode_results.len(), 3);
+        for episode_result in &episode_results {
+            assert_eq!(episode_result.observations_stored, 4);
+            let keycodes: Vec<u16> = album_frames(episode_result).iter().map(|observation_data| observation_data.keycode_data).collect();
+            assert_eq!(keycodes, [1, 16, 1, 16]);
+            let input_log = replay::read_input_log(&episode_result.album.as_ref().unwrap().join(replay::INPUT_LOG_FILE_NAME)).unwrap();
+            assert_eq!(input_log.len(), 10);
+        }
+        assert!(next_results[0].album.as_ref().unwrap().ends_with("episode_0003"));
+    }
+
+    #[test]
+    fn config_errors_come_back_from_agent_new() {
+        let config_error = |agent_config: AgentConfiguration| match Agent::new(agent_config, None) {
+            Err(AgentError::Config(reason)) => reason,
+            Err(e) => panic!("expected a config error, got {}", e),
+            Ok(_) => panic!("expected a config error"),
+        };
+        let bad_policy = synthetic_agent_config("bad_policy", serde_json::json!({"Intelligent":{"name":"Smart"}}));
+        assert!(config_error(bad_policy).contains("No policy named Smart"));
+
+        let bad_replay = synthetic_agent_config(
+            "bad_replay",
+            serde_json::json!({"Replay":{"replay_path":"/nonexistent/input_log.keys","loop_replay":false}}),
+        );
+        assert!(config_error(bad_replay).contains("Failed to read input log"));
+
+        let mut bad_game_definition = synthetic_agent_config("bad_game_definition", serde_json::json!("Human"));
+        bad_game_definition.game_definition_path = Some("/nonexistent/game_definition.json".to_string());
+        assert!(config_error(bad_game_definition).contains("Error reading game definition file"));
+
+        let mut bad_termination = synthetic_agent_config("bad_termination", serde_json::json!("Human"));
+        bad_termination.episode_config.termination_condition = Some("lives == 0".to_string());
+        assert!(config_error(bad_termination).contains("Unknown variable lives"));
+    }
+
+    #[test]
+    fn replaying_an_album_repeats_the_episode() {
+        let agent_control = serde_json::json!({"Intelligent":{"name":"Random","params":{"seed":3,"hold_frames":2}}});
+        let mut agent_config = synthetic_agent_config("record", agent_control);
+        agent_config.episode_config = serde_json::from_value(serde_json::json!({"num_episodes":1,"max_frames":40})).unwrap();
+        // the last few frames are plenty to compare, and albums of full size frames are slow to write
+        agent_config.observation_capacity = 8;
+        let _album_dir_cleanup = AlbumDirCleanup(agent_config.album_dir.clone());
+        let mut recording_agent = Agent::new(agent_config.clone(), None).unwrap();
+        let recorded = recording_agent.run_client();
+        let recorded_album = recorded[0].album.clone().unwrap();
+
+        let mut replay_config = synthetic_agent_config(
+            "replay",
+            serde_json::json!({"Replay":{"replay_path":recorded_album,"loop_replay":false}}),
+        );
+        replay_config.episode_config = agent_config.episode_config.clone();
+        replay_config.observation_capacity = agent_config.observation_capacity;
+        let _replay_album_dir_cleanup = AlbumDirCleanup(replay_config.album_dir.clone());
+        let mut replay_agent = Agent::new(replay_config, None).unwrap();
+        let replayed = replay_agent.run_client();
+
+        // same keycodes into the same game, so the same frames, reward and ending
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].frames, recorded[0].frames);
+        assert_eq!(replayed[0].total_reward, recorded[0].total_reward);
+        assert_eq!(replayed[0].episode_end, recorded[0].episode_end);
+        let frames = |episode_result: &episode::EpisodeResult| -> Vec<(u16, Vec<u8>)> {
+            album_frames(episode_result)
+                .into_iter()
+                .map(|observation_data| (observation_data.keycode_data, observation_data.frame_buffer.frame_data))
+                .collect()
+        };
+        let recorded_frames = frames(&recorded[0]);
+        assert!(recorded_frames.iter().any(|(keycode, _)| *keycode != 0));
+        assert_eq!(frames(&replayed[0]), recorded_frames);
+    }
+
+    #[test]
+    fn agent_switches_games_without_a_new_backend() {
+        let agent_control = serde_json::json!({"Intelligent":{
+            "name":"FixedMacro",
+            "params":{"steps":[{"keycode":16,"frames":1}],"idle_keycode":0}
+        }});
+        let agent_config = synthetic_agent_config("load_game", agent_control);
+        let _album_dir_cleanup = AlbumDirCleanup(agent_config.album_dir.clone());
+        let mut agent = Agent::new(agent_config.clone(), None).unwrap();
+        let first_frame = |episode_results: &[episode::EpisodeResult]| {
+            album_frames(&episode_results[0])[0].frame_buffer.frame_data.clone()
+        };
+        let first_run = first_frame(&agent.run_client());
+
+        // a different seed is a different game as far as the frames go
+        let mut game_config_data = agent_config.game_config_data.clone();
+        game_config_data.rng_seed.as_mut().unwrap().seed = 6;
+        agent.load_game(game_config_data).unwrap();
+        let episode_results = agent.run_client();
+        assert_eq!(episode_results.len(), 2);
+        assert_eq!(episode_results[0].frames, 5);
+        assert_ne!(first_frame(&episode_results), first_run);
+
+        // the game_config_data the agent started with (seed 5) plays the same game again, from the same first frame
+        agent.load_game(agent_config.game_config_data.clone()).unwrap();
+        assert_eq!(first_frame(&agent.run_client()), first_run);
+
+        // without an rng_seed the synthetic backend falls back to SyntheticConfig::seed, which isn't 5
+        game_config_data = agent_config.game_config_data.clone();
+        game_config_data.rng_seed = None;
+        agent.load_game(game_config_data).unwrap();
+        assert_ne!(first_frame(&agent.run_client()), first_run);
     }
 }
@@ -0,0 +1,129 @@
+// What the Agent needs from an emulator, no matter where it runs
+// mgba_ffi::Core runs it in this process, protocol::Client asks a server to, and
+// synthetic::SyntheticBackend fakes one so everything above it can be tested without libmgba or a ROM
+
+pub mod synthetic;
+
+use std::fmt;
+
+use super::mgba_ffi::{self, Core, CoreError, MemoryBlock, MemoryError};
+use super::protocol::ProtocolError;
+use super::{GameConfigData, RngSeed};
+
+#[derive(Debug)]
+pub enum BackendError {
+    Core(CoreError),
+    Memory(MemoryError),
+    Session(ProtocolError),
+    Unsupported(&'static str), // this backend can't do that at all
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Core(error) => write!(f, "{}", error),
+            Self::Memory(error) => write!(f, "{}", error),
+            Self::Session(error) => write!(f, "{}", error),
+            Self::Unsupported(what) => write!(f, "This emulator backend can't {}", what),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+pub trait EmulatorBackend {
+    /// width, height and bytes per pixel of framebuffer()
+    fn video_size(&self) -> (u32, u32, u32);
+
+    /// Buttons held down from the next run_frame on, same bits as ObservationData::keycode_data
+    fn set_keys(&mut self, keycode: u16) -> Result<(), BackendError>;
+
+    fn run_frame(&mut self) -> Result<(), BackendError>;
+
+    /// The frame the last run_frame (or restart) produced
+    fn framebuffer(&self) -> &[u8];
+
+    /// Back to how the backend started out, framebuffer() is the first frame again
+    fn restart(&mut self) -> Result<(), BackendError>;
+
+    /// Whole emulator state, opaque bytes only this kind of backend understands
+    fn snapshot(&mut self) -> Result<Vec<u8>, BackendError>;
+
+    fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, BackendError>;
+
+    /// Named memory blocks, for {"block":..,"offset":..} game variables. Empty if the backend doesn't know them
+    fn memory_blocks(&self) -> &[MemoryBlock] {
+        &[]
+    }
+
+    /// Write seed into the game's RNG, see GameConfigData::rng_seed
+    fn seed_rng(&mut self, _rng_seed: &RngSeed, _seed: u32) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("reseed"))
+    }
+
+    /// Swap in (or reload) the game without building a new backend, restart() comes back to its first frame after this
+    fn load_game(&mut self, _game_config_data: &GameConfigData) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("load another game"))
+    }
+
+    /// FATAL/ERROR messages the emulator logged since the last call, empty if it can't tell us
+    fn take_errors(&mut self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Done with the emulator, a server gets told to stop listening
+    fn shutdown(&mut self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn block_address(&self, block_name: &str, offset: u32, width: u32) -> Result<u32, BackendError> {
+        mgba_ffi::block_address(self.memory_blocks(), block_name, offset, width).map_err(BackendError::Memory)
+    }
+}
+
+impl EmulatorBackend for Core {
+    fn video_size(&self) -> (u32, u32, u32) {
+        let (width, height) = self.frame_size();
+        (width, height, mgba_ffi::mgba_bindings::BYTES_PER_PIXEL)
+    }
+
+    fn set_keys(&mut self, keycode: u16) -> Result<(), BackendError> {
+        Core::set_keys(self, keycode).map_err(BackendError::Core)
+    }
+
+    fn run_frame(&mut self) -> Result<(), BackendError> {
+        Core::run_frame(self).map_err(BackendError::Core)
+    }
+
+    fn framebuffer(&self) -> &[u8] {
+        Core::framebuffer(self)
+    }
+
+    fn restart(&mut self) -> Result<(), BackendError> {
+        Core::restart(self).map_err(BackendError::Core)
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<u8>, BackendError> {
+        Core::snapshot(self).map_err(BackendError::Core)
+    }
+
+    fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, BackendError> {
+        self.read_bytes(address, len).map_err(BackendError::Memory)
+    }
+
+    fn memory_blocks(&self) -> &[MemoryBlock] {
+        Core::memory_blocks(self)
+    }
+
+    fn seed_rng(&mut self, rng_seed: &RngSeed, seed: u32) -> Result<(), BackendError> {
+        Core::seed_rng(self, rng_seed, seed).map_err(|reason| BackendError::Core(CoreError::Seed(reason)))
+    }
+
+    fn load_game(&mut self, game_config_data: &GameConfigData) -> Result<(), BackendError> {
+        Core::load_game(self, game_config_data).map_err(BackendError::Core)
+    }
+
+    fn take_errors(&mut self) -> Vec<String> {
+        Core::take_errors(self)
+    }
+}
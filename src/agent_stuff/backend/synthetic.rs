@@ -0,0 +1,330 @@
+// A fake emulator for tests and for trying out a pipeline without a ROM (AgentDriver::Synthetic)
+// Everything is a function of the inputs since the last restart, so the same keycodes always give the same frames:
+// the d-pad moves an 8x8 white square around, A presses get counted, and a small RNG mixes in every keycode.
+// The state sits in a fake "WRAM" block so game definitions have something to read:
+//   0x02000000  frame_counter u32    0x02000008  x u32    0x02000010  a_presses u32
+//   0x02000004  keys u16             0x0200000C  y u32    0x02000014  rng u32
+
+use serde::{Deserialize, Serialize};
+
+use super::{BackendError, EmulatorBackend};
+use crate::agent_stuff::mgba_ffi::mgba_bindings::{
+    mCoreMemoryBlockFlags_mCORE_MEMORY_READ,
+    mCoreMemoryBlockFlags_mCORE_MEMORY_WRITE,
+};
+use crate::agent_stuff::mgba_ffi::{MemoryBlock, MemoryError};
+use crate::agent_stuff::{GameConfigData, RngSeed};
+
+pub const WRAM_START: u32 = 0x0200_0000;
+pub const WRAM_SIZE: u32 = 0x40;
+pub const SQUARE_SIZE: u32 = 8;
+// always ABGR8888, like libmgba's frames
+pub const SYNTHETIC_BPP: u32 = 4;
+
+const STATE_LEN: usize = 24;
+
+// GBA keycode bits
+const KEY_A: u16 = 1 << 0;
+const KEY_RIGHT: u16 = 1 << 4;
+const KEY_LEFT: u16 = 1 << 5;
+const KEY_UP: u16 = 1 << 6;
+const KEY_DOWN: u16 = 1 << 7;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyntheticConfig {
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_height")]
+    pub height: u32,
+    // start value of the RNG, different seeds give different frames for the same inputs
+    #[serde(default)]
+    pub seed: u32,
+}
+
+fn default_width() -> u32 {
+    240
+}
+
+fn default_height() -> u32 {
+    160
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        Self { width: default_width(), height: default_height(), seed: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SyntheticState {
+    frame_counter: u32,
+    keys: u16,
+    x: u32,
+    y: u32,
+    a_presses: u32,
+    rng: u32,
+}
+
+impl SyntheticState {
+    // laid out like the WRAM block, so a snapshot is a WRAM dump
+    fn to_bytes(self) -> [u8; STATE_LEN] {
+        let mut bytes = [0_u8; STATE_LEN];
+        bytes[0..4].copy_from_slice(&self.frame_counter.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.keys.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.x.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.y.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.a_presses.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.rng.to_le_bytes());
+        bytes
+    }
+}
+
+// xorshift32, never stuck at 0 since the seed gets mixed first
+fn next_rng(rng: u32) -> u32 {
+    let mut rng = rng;
+    rng ^= rng << 13;
+    rng ^= rng >> 17;
+    rng ^= rng << 5;
+    rng
+}
+
+fn seed_rng_state(seed: u32) -> u32 {
+    match seed.wrapping_mul(0x9E37_79B9) ^ 0x2545_F491 {
+        0 => 1,
+        rng => rng,
+    }
+}
+
+pub struct SyntheticBackend {
+    width: u32,
+    height: u32,
+    seed: u32, // SyntheticConfig::seed, what load_game goes back to without an rng_seed
+    state: SyntheticState,
+    start_state: SyntheticState,
+    held_keys: u16,
+    frame_data: Vec<u8>,
+    memory_blocks: Vec<MemoryBlock>,
+}
+
+impl SyntheticBackend {
+    pub fn new(synthetic_config: &SyntheticConfig) -> Result<Self, String> {
+        let (width, height) = (synthetic_config.width, synthetic_config.height);
+        if width < SQUARE_SIZE || height < SQUARE_SIZE {
+            return Err(format!("{}x{} frames, need at least {}x{}", width, height, SQUARE_SIZE, SQUARE_SIZE));
+        }
+        let start_state = Self::start_state(width, height, synthetic_config.seed);
+        let mut backend = Self {
+            width,
+            height,
+            seed: synthetic_config.seed,
+            state: start_state,
+            start_state,
+            held_keys: 0,
+            frame_data: vec![0_u8; (width * height * SYNTHETIC_BPP) as usize],
+            memory_blocks: vec![MemoryBlock {
+                id: 0,
+                internal_name: "wram".to_string(),
+                short_name: "WRAM".to_string(),
+                start: WRAM_START,
+                end: WRAM_START + WRAM_SIZE,
+                size: WRAM_SIZE,
+                flags: mCoreMemoryBlockFlags_mCORE_MEMORY_READ | mCoreMemoryBlockFlags_mCORE_MEMORY_WRITE,
+            }],
+        };
+        backend.draw();
+        Ok(backend)
+    }
+
+    // square in the middle, nothing pressed yet
+    fn start_state(width: u32, height: u32, seed: u32) -> SyntheticState {
+        SyntheticState {
+            x: (width - SQUARE_SIZE) / 2,
+            y: (height - SQUARE_SIZE) / 2,
+            rng: seed_rng_state(seed),
+            ..SyntheticState::default()
+        }
+    }
+
+    fn draw(&mut self) {
+        let state = self.state;
+        let tint = (state.rng >> 24) as u8;
+        for (pixel_idx, pixel) in self.frame_data.chunks_exact_mut(SYNTHETIC_BPP as usize).enumerate() {
+            let (px, py) = (pixel_idx as u32 % self.width, pixel_idx as u32 / self.width);
+            let in_square = (state.x..state.x + SQUARE_SIZE).contains(&px) && (state.y..state.y + SQUARE_SIZE).contains(&py);
+            let rgb = match in_square {
+                true => [0xFF, 0xFF, 0xFF],
+                false => [(px + state.frame_counter) as u8, py as u8 ^ tint, state.keys as u8],
+            };
+            pixel.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 0xFF]);
+        }
+    }
+}
+
+impl EmulatorBackend for SyntheticBackend {
+    fn video_size(&self) -> (u32, u32, u32) {
+        (self.width, self.height, SYNTHETIC_BPP)
+    }
+
+    fn set_keys(&mut self, keycode: u16) -> Result<(), BackendError> {
+        self.held_keys = keycode;
+        Ok(())
+    }
+
+    fn run_frame(&mut self) -> Result<(), BackendError> {
+        let keys = self.held_keys;
+        let state = &mut self.state;
+        let (max_x, max_y) = (self.width - SQUARE_SIZE, self.height - SQUARE_SIZE);
+        if keys & KEY_RIGHT != 0 && state.x < max_x {
+            state.x += 1;
+        }
+        if keys & KEY_LEFT != 0 && state.x > 0 {
+            state.x -= 1;
+        }
+        if keys & KEY_DOWN != 0 && state.y < max_y {
+            state.y += 1;
+        }
+        if keys & KEY_UP != 0 && state.y > 0 {
+            state.y -= 1;
+        }
+        // a press, not a hold
+        if keys & KEY_A != 0 && state.keys & KEY_A == 0 {
+            state.a_presses += 1;
+        }
+        state.keys = keys;
+        state.rng = next_rng(state.rng ^ keys as u32);
+        state.frame_counter += 1;
+        self.draw();
+        Ok(())
+    }
+
+    fn framebuffer(&self) -> &[u8] {
+        &self.frame_data
+    }
+
+    fn restart(&mut self) -> Result<(), BackendError> {
+        self.state = self.start_state;
+        self.draw();
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<u8>, BackendError> {
+        Ok(self.state.to_bytes().to_vec())
+    }
+
+    fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, BackendError> {
+        let block = &self.memory_blocks[0];
+        if !block.contains(address, len) {
+            return Err(BackendError::Memory(MemoryError::UnmappedAddress { address, width: len }));
+        }
+        let mut wram = self.state.to_bytes().to_vec();
+        wram.resize(WRAM_SIZE as usize, 0);
+        let offset = (address - block.start) as usize;
+        Ok(wram[offset..offset + len as usize].to_vec())
+    }
+
+    fn memory_blocks(&self) -> &[MemoryBlock] {
+        &self.memory_blocks
+    }
+
+    // there's only the one game, loading it again starts it over (seeded, if game_config_data says so)
+    fn load_game(&mut self, game_config_data: &GameConfigData) -> Result<(), BackendError> {
+        let seed = game_config_data.rng_seed.as_ref().map_or(self.seed, |rng_seed| rng_seed.seed);
+        self.start_state = Self::start_state(self.width, self.height, seed);
+        self.state = self.start_state;
+        self.draw();
+        Ok(())
+    }
+
+    // rng_address doesn't mean anything here, the seed always goes into the RNG
+    fn seed_rng(&mut self, _rng_seed: &RngSeed, seed: u32) -> Result<(), BackendError> {
+        self.state.rng = seed_rng_state(seed);
+        self.draw();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(backend: &mut SyntheticBackend, keycodes: &[u16]) -> Vec<Vec<u8>> {
+        keycodes
+            .iter()
+            .map(|keycode| {
+                backend.set_keys(*keycode).unwrap();
+                backend.run_frame().unwrap();
+                backend.framebuffer().to_vec()
+            })
+            .collect()
+    }
+
+    fn read_u32(backend: &mut SyntheticBackend, offset: u32) -> u32 {
+        u32::from_le_bytes(backend.read_memory(WRAM_START + offset, 4).unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn same_inputs_give_the_same_frames() {
+        let keycodes = [0, KEY_RIGHT, KEY_RIGHT | KEY_A, KEY_DOWN, 0, KEY_A];
+        let mut first = SyntheticBackend::new(&SyntheticConfig::default()).unwrap();
+        let mut second = SyntheticBackend::new(&SyntheticConfig::default()).unwrap();
+        assert_eq!(first.video_size(), (240, 160, 4));
+        assert_eq!(first.framebuffer().len(), 240 * 160 * 4);
+        assert_eq!(run(&mut first, &keycodes), run(&mut second, &keycodes));
+
+        // any difference in input or seed shows up in the frame
+        let mut other_input = SyntheticBackend::new(&SyntheticConfig::default()).unwrap();
+        let mut other_seed = SyntheticBackend::new(&SyntheticConfig { seed: 1, ..SyntheticConfig::default() }).unwrap();
+        let frames = run(&mut first, &[KEY_UP]);
+        assert_ne!(frames, run(&mut other_input, &[KEY_LEFT]));
+        assert_ne!(run(&mut second, &[0]), run(&mut other_seed, &[0]));
+    }
+
+    #[test]
+    fn game_state_is_readable_as_memory() {
+        let mut backend = SyntheticBackend::new(&SyntheticConfig { width: 16, height: 16, seed: 3 }).unwrap();
+        assert_eq!((read_u32(&mut backend, 8), read_u32(&mut backend, 12)), (4, 4));
+        run(&mut backend, &[KEY_RIGHT; 10]);
+        // the square stops at the edge
+        assert_eq!(read_u32(&mut backend, 8), 8);
+        run(&mut backend, &[KEY_A, KEY_A, 0, KEY_A | KEY_UP]);
+        assert_eq!(read_u32(&mut backend, 0), 14);
+        assert_eq!(read_u32(&mut backend, 12), 3);
+        assert_eq!(read_u32(&mut backend, 16), 2);
+        assert_eq!(backend.read_memory(WRAM_START + 4, 2).unwrap(), (KEY_A | KEY_UP).to_le_bytes());
+        assert_eq!(backend.block_address("wram", 0x10, 4).unwrap(), WRAM_START + 0x10);
+
+        assert!(matches!(
+            backend.read_memory(WRAM_START + WRAM_SIZE - 2, 4),
+            Err(BackendError::Memory(MemoryError::UnmappedAddress { .. }))
+        ));
+        assert!(backend.block_address("vram", 0, 1).is_err());
+    }
+
+    #[test]
+    fn restart_and_snapshots_go_back_in_time() {
+        let mut backend = SyntheticBackend::new(&SyntheticConfig::default()).unwrap();
+        let first_frame = backend.framebuffer().to_vec();
+        let start_state = backend.snapshot().unwrap();
+        let frames = run(&mut backend, &[KEY_DOWN, KEY_A, KEY_LEFT]);
+        assert_ne!(backend.snapshot().unwrap(), start_state);
+
+        backend.restart().unwrap();
+        assert_eq!(backend.framebuffer(), first_frame);
+        assert_eq!(backend.snapshot().unwrap(), start_state);
+        assert_eq!(run(&mut backend, &[KEY_DOWN, KEY_A, KEY_LEFT]), frames);
+
+        // a reseed after restart changes what comes next, the same seed gives the same frames again
+        backend.restart().unwrap();
+        backend.seed_rng(&RngSeed { seed: 9, rng_address: None, rng_width: 4, rtc_time_ms: None }, 9).unwrap();
+        let reseeded = run(&mut backend, &[0, 0]);
+        backend.restart().unwrap();
+        backend.seed_rng(&RngSeed { seed: 9, rng_address: None, rng_width: 4, rtc_time_ms: None }, 9).unwrap();
+        assert_eq!(run(&mut backend, &[0, 0]), reseeded);
+        assert_eq!(read_u32(&mut backend, 0), 2);
+    }
+
+    #[test]
+    fn tiny_frames_are_rejected() {
+        assert!(SyntheticBackend::new(&SyntheticConfig { width: 4, height: 160, seed: 0 }).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+// Reading recorded observations back in, for offline training and replays
+// Two sources, both iterated lazily one frame at a time:
+// 1) An album directory written by ObservationSet::save_album ({:0>4}/img.data json files)
+// 2) A trajectory file written by trajectory::TrajectoryWriter
+// Frames that fail their checksum (or don't parse) come out as CorruptFrame instead of stopping the iteration
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::observation::{pixel_format_for_bpp, FrameBuffer, ObservationData};
+use super::preprocessing::{ElementType, Shape};
+use super::trajectory::{TrajectoryError, TrajectoryFrame, TrajectoryHeader, TrajectoryReader};
+
+#[derive(Debug)]
+pub enum DatasetError {
+    Io { path: PathBuf, error: std::io::Error },
+    Trajectory(TrajectoryError),
+    Empty(PathBuf), // nothing usable in it
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "Failed to read {}: {}", path.display(), error),
+            Self::Trajectory(e) => write!(f, "{}", e),
+            Self::Empty(path) => write!(f, "No valid frames in {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+impl From<TrajectoryError> for DatasetError {
+    fn from(e: TrajectoryError) -> Self {
+        Self::Trajectory(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CorruptFrame {
+    pub frame_idx: usize,
+    pub location: String, // img.data path or trajectory chunk
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame {} ({}): {}", self.frame_idx, self.location, self.reason)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DatasetFrame {
+    pub frame_idx: usize,
+    pub observation_data: ObservationData,
+    // only trajectories record these
+    pub reward: Option<f32>,
+    pub done: Option<bool>,
+}
+
+// ################################################################################
+// Albums
+// ################################################################################
+
+// What save_album writes into every img.data
+#[derive(Deserialize)]
+struct AlbumFrameData {
+    observation_data: ObservationData,
+    pixel_checksum: u32,
+    // missing in albums written before the checksum changed, see observation::ALBUM_CHECKSUM_VERSION
+    #[serde(default)]
+    checksum_version: Option<u32>,
+}
+
+/// Frame directories of an album in frame order
+pub fn album_frame_dirs(album_dir: &Path) -> Result<Vec<PathBuf>, DatasetError> {
+    // frame directories are named by frame index, padded to 4 digits but wider past frame 9999,
+    // so they're sorted by the number, not the name
+    let mut frame_dirs: Vec<(u64, PathBuf)> = fs::read_dir(album_dir)
+        .map_err(|error| DatasetError::Io { path: album_dir.to_path_buf(), error })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("img.data").is_file())
+        .filter_map(|path| Some((path.file_name()?.to_str()?.parse().ok()?, path)))
+        .collect();
+    frame_dirs.sort();
+    Ok(frame_dirs.into_iter().map(|(_, path)| path).collect())
+}
+
+fn read_album_frame(frame_idx: usize, frame_dir: &Path) -> Result<DatasetFrame, CorruptFrame> {
+    let image_data = frame_dir.join("img.data");
+    let corrupt = |reason: String| CorruptFrame {
+        frame_idx,
+        location: image_data.display().to_string(),
+        reason,
+    };
+    let file_read = fs::read_to_string(&image_data).map_err(|e| corrupt(e.to_string()))?;
+    let album_frame: AlbumFrameData = serde_json::from_str(&file_read).map_err(|e| corrupt(e.to_string()))?;
+    let mut observation_data = album_frame.observation_data;
+    let frame_buffer = &mut observation_data.frame_buffer;
+    // albums from before the preprocessing pipeline only have grayscale processed_data
+    if frame_buffer.processed_shape == Shape::default() {
+        frame_buffer.processed_shape = Shape {
+            frames: 1,
+            height: frame_buffer.height,
+            width: frame_buffer.width,
+            channels: 1,
+            element_type: ElementType::U8,
+        };
+    }
+    check_frame_buffer(frame_buffer).map_err(corrupt)?;
+    let checksum = frame_buffer
+        .album_checksum(album_frame.checksum_version)
+        .ok_or_else(|| corrupt(format!("unknown checksum_version {:?}", album_frame.checksum_version)))?;
+    if checksum != album_frame.pixel_checksum {
+        return Err(corrupt(format!(
+            "pixel_checksum is {:#010x} but the frame checksums to {:#010x}",
+            album_frame.pixel_checksum, checksum
+        )));
+    }
+    Ok(DatasetFrame {
+        frame_idx,
+        observation_data,
+        reward: None,
+        done: None,
+    })
+}
+
+// Buffer sizes have to agree with what the frame claims to be before anything indexes into them
+fn check_frame_buffer(frame_buffer: &FrameBuffer) -> Result<(), String> {
+    if ![2, 4].contains(&frame_buffer.bpp) || frame_buffer.pixel_format != pixel_format_for_bpp(frame_buffer.bpp) {
+        return Err(format!("{} bpp {:?} isn't a format we write", frame_buffer.bpp, frame_buffer.pixel_format));
+    }
+    if frame_buffer.frame_data.len() != frame_buffer.get_size() {
+        return Err(format!("frame_data is {} bytes, expected {}", frame_buffer.frame_data.len(), frame_buffer.get_size()));
+    }
+    if Some(frame_buffer.processed_data.len()) != frame_buffer.processed_shape.checked_num_bytes() {
+        return Err(format!(
+            "processed_data is {} bytes, which doesn't match {:?}",
+            frame_buffer.processed_data.len(),
+            frame_buffer.processed_shape
+        ));
+    }
+    Ok(())
+}
+
+// ################################################################################
+// Trajectories
+// ################################################################################
+
+fn trajectory_observation(header: &TrajectoryHeader, frame: TrajectoryFrame) -> ObservationData {
+    let mut frame_buffer = FrameBuffer::new(header.width, header.height, header.bpp, pixel_format_for_bpp(header.bpp));
+    frame_buffer.processed_shape = header.processed_shape;
+    frame_buffer.processed_data = frame.processed_data;
+    // without FLAG_FRAME_DATA the raw frame stays black
+    if let Some(frame_data) = frame.frame_data {
+        frame_buffer.frame_data = frame_data;
+    }
+    ObservationData {
+        frame_buffer,
+        keycode_data: frame.keycode,
+    }
+}
+
+// ################################################################################
+// Either one
+// ################################################################################
+
+enum DatasetSource {
+    Album { frame_dirs: Vec<PathBuf> },
+    Trajectory { reader: TrajectoryReader, chunk_idx: usize, chunk_frames: std::vec::IntoIter<DatasetFrame> },
+}
+
+/// Lazy iterator over a dataset, yields frames in order
+pub struct DatasetFrames {
+    path: PathBuf,
+    source: DatasetSource,
+    next_frame: usize,
+    corrupt: Vec<CorruptFrame>, // queued up when a whole trajectory chunk goes bad
+}
+
+impl DatasetFrames {
+    /// A directory is an album, a file is a trajectory
+    pub fn open(path: &Path) -> Result<Self, DatasetError> {
+        let source = if path.is_dir() {
+            DatasetSource::Album { frame_dirs: album_frame_dirs(path)? }
+        } else {
+            DatasetSource::Trajectory {
+                reader: TrajectoryReader::open(path)?,
+                chunk_idx: 0,
+                chunk_frames: vec![].into_iter(),
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            source,
+            next_frame: 0,
+            corrupt: vec![],
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Total frames (valid or not)
+    pub fn len(&self) -> usize {
+        match &self.source {
+            DatasetSource::Album { frame_dirs } => frame_dirs.len(),
+            DatasetSource::Trajectory { reader, .. } => reader.len(),
+        }
+    }
+}
+
+impl Iterator for DatasetFrames {
+    type Item = Result<DatasetFrame, CorruptFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(corrupt_frame) = self.corrupt.pop() {
+            return Some(Err(corrupt_frame));
+        }
+        let frame_idx = self.next_frame;
+        match &mut self.source {
+            DatasetSource::Album { frame_dirs } => {
+                let frame_dir = frame_dirs.get(frame_idx)?;
+                self.next_frame += 1;
+                Some(read_album_frame(frame_idx, frame_dir))
+            },
+            DatasetSource::Trajectory { reader, chunk_idx, chunk_frames } => {
+                if let Some(frame) = chunk_frames.next() {
+                    self.next_frame += 1;
+                    return Some(Ok(frame));
+                }
+                if *chunk_idx >= reader.chunks().len() {
+                    return None;
+                }
+                let header = *reader.header();
+                let chunk = reader.chunks()[*chunk_idx];
+                let chunk_result = reader.read_chunk(*chunk_idx);
+                *chunk_idx += 1;
+                match chunk_result {
+                    Ok(frames) => {
+                        *chunk_frames = frames
+                            .into_iter()
+                            .enumerate()
+                            .map(|(idx, frame)| DatasetFrame {
+                                frame_idx: chunk.first_frame + idx,
+                                reward: Some(frame.reward),
+                                done: Some(frame.done),
+                                observation_data: trajectory_observation(&header, frame),
+                            })
+                            .collect::<Vec<_>>()
+                            .into_iter();
+                    },
+                    Err(e) => {
+                        // the checksum covers the whole chunk, every frame in it is suspect
+                        let location = format!("{} chunk {}", self.path.display(), *chunk_idx - 1);
+                        self.corrupt = (chunk.first_frame..chunk.first_frame + chunk.num_frames)
+                            .rev()
+                            .map(|frame_idx| CorruptFrame {
+                                frame_idx,
+                                location: location.clone(),
+                                reason: e.to_string(),
+                            })
+                            .collect();
+                        self.next_frame += chunk.num_frames;
+                    },
+                }
+                self.next()
+            },
+        }
+    }
+}
+
+/// Frames that loaded fine, plus everything that didn't
+pub struct LoadedDataset {
+    pub frames: Vec<DatasetFrame>,
+    pub corrupt_frames: Vec<CorruptFrame>,
+}
+
+/// Read a whole dataset into memory, frames shaped differently from the first valid one count as corrupt
+pub fn load_dataset(path: &Path) -> Result<LoadedDataset, DatasetError> {
+    let mut frames: Vec<DatasetFrame> = vec![];
+    let mut corrupt_frames = vec![];
+    for frame_result in DatasetFrames::open(path)? {
+        match frame_result {
+            Ok(frame) => {
+                if let Some(first_frame) = frames.first() {
+                    let first = &first_frame.observation_data.frame_buffer;
+                    let this = &frame.observation_data.frame_buffer;
+                    if (this.width, this.height, this.bpp, this.processed_shape)
+                        != (first.width, first.height, first.bpp, first.processed_shape)
+                    {
+                        corrupt_frames.push(CorruptFrame {
+                            frame_idx: frame.frame_idx,
+                            location: path.display().to_string(),
+                            reason: "shape differs from the rest of the dataset".to_string(),
+                        });
+                        continue;
+                    }
+                }
+                frames.push(frame);
+            },
+            Err(corrupt_frame) => corrupt_frames.push(corrupt_frame),
+        }
+    }
+    if frames.is_empty() {
+        return Err(DatasetError::Empty(path.to_path_buf()));
+    }
+    Ok(LoadedDataset { frames, corrupt_frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::observation::ObservationSet;
+    use crate::agent_stuff::trajectory::TrajectoryWriter;
+
+    fn observation(keycode: u16) -> ObservationData {
+        let mut frame_buffer = FrameBuffer::new(4, 2, 2, pixel_format_for_bpp(2));
+        frame_buffer.frame_data.fill(keycode as u8);
+        frame_buffer.processed_data.fill(keycode as u8 + 1);
+        ObservationData {
+            frame_buffer,
+            keycode_data: keycode,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dataset_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn album_reports_bad_checksum() {
+        let album_dir = temp_path("album");
+        let mut observation_set = ObservationSet::new(&observation(0), 4);
+        for keycode in 0..4 {
+            observation_set.push(&observation(keycode));
+        }
+        observation_set.save_album(&album_dir);
+
+        // flip a pixel in frame 2 without fixing up its pixel_checksum
+        let image_data = album_dir.join("0002/img.data");
+        let tampered = fs::read_to_string(&image_data).unwrap().replacen("\"frame_data\":[2,", "\"frame_data\":[9,", 1);
+        fs::write(&image_data, tampered).unwrap();
+
+        let (loaded_set, corrupt_frames) = ObservationSet::load(&album_dir).unwrap();
+        assert_eq!(loaded_set.len(), 3);
+        assert_eq!(corrupt_frames.len(), 1);
+        assert_eq!(corrupt_frames[0].frame_idx, 2);
+        let keycodes: Vec<u16> = loaded_set.iter().map(|observation_data| observation_data.keycode_data).collect();
+        assert_eq!(keycodes, vec![0, 1, 3]);
+        fs::remove_dir_all(&album_dir).unwrap();
+    }
+
+    // img.data the way the baseline save_album wrote it: RGB565, no processed_shape or checksum_version,
+    // pixel_checksum is the two's complement of the raw byte sum
+    fn baseline_img_data(frame_data: [u8; 4], keycode: u16) -> String {
+        let raw_sum: u32 = frame_data.iter().map(|&byte| byte as u32).sum();
+        format!(
+            r#"{{"observation_data":{{"frame_buffer":{{"width":2,"height":1,"bpp":2,"pixel_format":"RGB565",
+            "frame_data":{:?},"processed_data":[7,7]}},"keycode_data":{}}},
+            "width":2,"height":1,"pixel_type":"RGB565","pixel_checksum":{}}}"#,
+            frame_data, keycode, 1_u32.overflowing_add(raw_sum ^ u32::MAX).0
+        )
+    }
+
+    #[test]
+    fn baseline_albums_still_load() {
+        let album_dir = temp_path("baseline_album");
+        for (frame_idx, frame_data) in [[0x1F, 0xF8, 0xE0, 0x07], [0xFF, 0xFF, 0x00, 0x00], [1, 2, 3, 4]].iter().enumerate() {
+            let frame_dir = album_dir.join(format!("{:0>4}", frame_idx));
+            fs::create_dir_all(&frame_dir).unwrap();
+            fs::write(frame_dir.join("img.data"), baseline_img_data(*frame_data, frame_idx as u16)).unwrap();
+        }
+        // a legacy frame that doesn't match its legacy checksum is still corrupt
+        let tampered = baseline_img_data([1, 2, 3, 4], 2).replace("[1, 2, 3, 4]", "[1, 2, 3, 5]");
+        fs::write(album_dir.join("0002/img.data"), tampered).unwrap();
+
+        let loaded_dataset = load_dataset(&album_dir).unwrap();
+        assert_eq!(loaded_dataset.frames.len(), 2);
+        assert_eq!(loaded_dataset.corrupt_frames.len(), 1);
+        assert_eq!(loaded_dataset.corrupt_frames[0].frame_idx, 2);
+        let frame_buffer = &loaded_dataset.frames[0].observation_data.frame_buffer;
+        assert_eq!(frame_buffer.frame_data, vec![0x1F, 0xF8, 0xE0, 0x07]);
+        assert_eq!(frame_buffer.processed_shape.num_bytes(), 2);
+
+        // saved again it gets the current checksum, and still loads
+        let (observation_set, _) = ObservationSet::load(&album_dir).unwrap();
+        let resaved_dir = temp_path("baseline_album_resaved");
+        observation_set.save_album(&resaved_dir);
+        let (resaved_set, corrupt_frames) = ObservationSet::load(&resaved_dir).unwrap();
+        assert!(corrupt_frames.is_empty());
+        assert_eq!(resaved_set.len(), 2);
+        fs::remove_dir_all(&album_dir).unwrap();
+        fs::remove_dir_all(&resaved_dir).unwrap();
+    }
+
+    #[test]
+    fn album_frames_past_9999_stay_in_order() {
+        let album_dir = temp_path("long_album");
+        for frame_idx in [10000, 9999, 10001, 2] {
+            let frame_dir = album_dir.join(format!("{:0>4}", frame_idx));
+            fs::create_dir_all(&frame_dir).unwrap();
+            fs::write(frame_dir.join("img.data"), baseline_img_data([1, 2, 3, 4], 0)).unwrap();
+        }
+        let frame_dirs = album_frame_dirs(&album_dir).unwrap();
+        let frame_names: Vec<&str> = frame_dirs.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(frame_names, vec!["0002", "9999", "10000", "10001"]);
+        fs::remove_dir_all(&album_dir).unwrap();
+    }
+
+    #[test]
+    fn trajectory_reports_every_frame_of_a_bad_chunk() {
+        let file_path = temp_path("trajectory.traj");
+        let header = TrajectoryHeader::from_observation(&observation(0), true);
+        {
+            let mut writer = TrajectoryWriter::create(&file_path, header, 2).unwrap();
+            for keycode in 0..6 {
+                writer.append_observation(&observation(keycode)).unwrap();
+                writer.add_step_outcome(1.0, keycode == 5);
+            }
+        }
+        // last byte of the middle chunk (frames 2 and 3)
+        let record_len = header.record_len();
+        let middle_chunk_end = 44 + 2 * (12 + 2 * record_len);
+        let mut raw_data = fs::read(&file_path).unwrap();
+        raw_data[middle_chunk_end - 1] ^= 0xFF;
+        fs::write(&file_path, raw_data).unwrap();
+
+        let frame_results: Vec<_> = DatasetFrames::open(&file_path).unwrap().collect();
+        assert_eq!(frame_results.len(), 6);
+        let corrupt_idx: Vec<usize> = frame_results
+            .iter()
+            .filter_map(|frame_result| frame_result.as_ref().err().map(|corrupt_frame| corrupt_frame.frame_idx))
+            .collect();
+        assert_eq!(corrupt_idx, vec![2, 3]);
+        let last_frame = frame_results[5].as_ref().unwrap();
+        assert_eq!(last_frame.observation_data.keycode_data, 5);
+        assert_eq!(last_frame.observation_data.frame_buffer.frame_data, observation(5).frame_buffer.frame_data);
+        assert_eq!((last_frame.reward, last_frame.done), (Some(1.0), Some(true)));
+        fs::remove_file(&file_path).unwrap();
+    }
+}
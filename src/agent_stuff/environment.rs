@@ -0,0 +1,205 @@
+// Gym style wrapper around the Agent
+// run_client owns the whole loop, this lets something else (a trainer) drive the emulator frame by frame
+// reset() -> Observation
+// step(action) -> (Observation, reward, done, info)
+
+use super::{
+    Agent, AgentConfiguration, AgentError, AgentIO,
+    backend::BackendError,
+    observation::ObservationData,
+};
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+// keycodes are u16, same bits as ObservationData::keycode_data
+pub type Action = u16;
+
+// Extra data about a step that isn't part of the observation
+#[derive(Debug, Clone, Default)]
+pub struct StepInfo {
+    pub frame_counter: u32,    // frames since the last reset
+    pub frame_checksum: u32,   // FrameBuffer::checksum of the new frame
+    pub stop_requested: bool,  // agent_stop_flag (Ctrl-C) was raised
+    pub window_closed: bool,   // the sdl window was closed/escaped
+    pub terminated_by: Option<String>, // game definition termination condition that ended the episode
+    pub truncated: bool,       // hit episode_config.max_frames
+    pub emulator_errors: Vec<String>, // FATAL/ERROR messages the emulator logged during the step
+}
+
+pub struct StepResult {
+    pub observation: ObservationData,
+    pub reward: f32,
+    pub done: bool,
+    pub info: StepInfo,
+}
+
+pub struct Environment {
+    agent: Agent,
+    frame_counter: u32,
+    // the first reset doesn't need to reinitialize the freshly created Agent
+    needs_emulator_reset: bool,
+}
+
+impl Environment {
+    pub fn new(agent_config: AgentConfiguration, agent_stop_flag: Option<Arc<AtomicBool>>) -> Result<Self, AgentError> {
+        Ok(Self::from_agent(Agent::new(agent_config, agent_stop_flag)?))
+    }
+
+    pub fn from_agent(agent: Agent) -> Self {
+        Self {
+            agent,
+            frame_counter: 0,
+            needs_emulator_reset: false,
+        }
+    }
+
+    /// Start a new episode, returns the first observation
+    /// Every call below is an Err when the emulator fails (a dropped connection, a bad memory read),
+    /// the Environment can still be reset and tried again after that
+    pub fn reset(&mut self) -> Result<ObservationData, BackendError> {
+        if self.needs_emulator_reset {
+            self.agent.reset_emulator()?;
+        }
+        self.needs_emulator_reset = true;
+        self.frame_counter = 0;
+        self.agent.reset_game_tracker()?;
+
+        self.agent.observation_data_mut().keycode_data = 0_u16;
+        self.agent.get_observation();
+        self.render();
+        Ok(self.agent.observation_data().clone())
+    }
+
+    /// reset(), then write a new seed into the game's RNG (see GameConfigData::rng_seed)
+    pub fn reset_with_seed(&mut self, seed: u32) -> Result<ObservationData, BackendError> {
+        if self.needs_emulator_reset {
+            self.agent.reset_emulator()?;
+            self.needs_emulator_reset = false;
+        }
+        self.agent.seed_emulator(seed)?;
+        self.reset()
+    }
+
+    /// Run a single emulator frame with the given keycode
+    pub fn step(&mut self, action: Action) -> Result<StepResult, BackendError> {
+        self.agent.observation_data_mut().keycode_data = action;
+        self.agent.execute_cycle()?;
+        self.agent.get_observation();
+        self.frame_counter += 1;
+
+        // reward/termination come from the game definition, 0/false without one
+        let game_step = self.agent.update_game_tracker()?;
+        let window_closed = !self.render();
+        let stop_requested = self.agent.stop_requested();
+        let truncated = self.agent.agent_config.episode_config.max_frames
+            .is_some_and(|max_frames| self.frame_counter >= max_frames);
+
+        Ok(StepResult {
+            observation: self.agent.observation_data().clone(),
+            reward: game_step.reward,
+            done: game_step.done || truncated || stop_requested || window_closed,
+            info: StepInfo {
+                frame_counter: self.frame_counter,
+                frame_checksum: self.agent.observation_data().frame_buffer.checksum(),
+                stop_requested,
+                window_closed,
+                terminated_by: game_step.terminated_by,
+                truncated,
+                emulator_errors: self.agent.take_emulator_errors(),
+            },
+        })
+    }
+
+    /// Whole emulator state (mCore::saveState), Native or over the socket protocol
+    pub fn snapshot(&mut self) -> Result<Vec<u8>, BackendError> {
+        self.agent.snapshot_emulator()
+    }
+
+    /// Raw game memory, e.g. to check what the seeding step wrote
+    pub fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, String> {
+        self.agent.read_emulator_memory(address, len)
+    }
+
+    // Draw the current frame if we have a window, returns false if the window wants to close
+    fn render(&mut self) -> bool {
+        match &mut self.agent.agent_io {
+            AgentIO::DirectIO(_) => true,
+            AgentIO::SdlIO(ref mut sdl_window) => {
+                sdl_window.update();
+                // still need to pump events so the window stays alive, keycodes are ignored here
+                sdl_window.matt_events().is_some()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::episode::EpisodeEnd;
+    use crate::agent_stuff::tests::{synthetic_agent_config, AlbumDirCleanup};
+
+    fn checksums(environment: &mut Environment, actions: &[Action]) -> Vec<u32> {
+        actions.iter().map(|action| environment.step(*action).unwrap().info.frame_checksum).collect()
+    }
+
+    #[test]
+    fn synthetic_environment_is_deterministic() {
+        let agent_config = synthetic_agent_config("environment", serde_json::json!("Human"));
+        let mut environment = Environment::new(agent_config, None).unwrap();
+        let actions = [0x10, 0x01, 0x10, 0x40, 0x20, 0x00, 0x01];
+
+        let first_observation = environment.reset().unwrap();
+        let first_run = checksums(&mut environment, &actions);
+        assert_eq!(environment.reset().unwrap().frame_buffer.checksum(), first_observation.frame_buffer.checksum());
+        assert_eq!(checksums(&mut environment, &actions), first_run);
+
+        // the game definition's termination condition shows up in the step
+        environment.reset().unwrap();
+        let steps: Vec<StepResult> = (0..5).map(|_| environment.step(0x10).unwrap()).collect();
+        assert!(steps[..4].iter().all(|step| !step.done));
+        assert!(steps[4].done);
+        assert_eq!(steps[4].info.terminated_by.as_deref(), Some("right_edge"));
+        assert!(steps.iter().all(|step| step.info.emulator_errors.is_empty()));
+
+        // same seed, same episode, a different one changes the frames
+        environment.reset_with_seed(7).unwrap();
+        let seeded_run = checksums(&mut environment, &actions);
+        environment.reset_with_seed(7).unwrap();
+        assert_eq!(checksums(&mut environment, &actions), seeded_run);
+        environment.reset_with_seed(8).unwrap();
+        assert_ne!(checksums(&mut environment, &actions), seeded_run);
+    }
+
+    #[test]
+    fn emulator_failures_are_errors_not_panics() {
+        // a variable outside of every memory block, the first read of it fails
+        let game_definition_path =
+            std::env::temp_dir().join(format!("unmapped_game_definition_{}.json", std::process::id()));
+        let game_definition = serde_json::json!({
+            "name":"Unmapped",
+            "variables":{"nowhere":{"address":"0x0","width":4}},
+            "termination":[{"name":"never","condition":"nowhere == 1"}]
+        });
+        std::fs::write(&game_definition_path, game_definition.to_string()).unwrap();
+        let mut agent_config = synthetic_agent_config("unmapped", serde_json::json!("Human"));
+        agent_config.game_definition_path = Some(game_definition_path.to_string_lossy().into_owned());
+        let _album_dir_cleanup = AlbumDirCleanup(agent_config.album_dir.clone());
+
+        let mut environment = Environment::new(agent_config.clone(), None).unwrap();
+        assert!(matches!(environment.reset(), Err(BackendError::Memory(_))));
+        // still usable afterwards, it's the game definition that's wrong and not the emulator
+        assert!(matches!(environment.reset(), Err(BackendError::Memory(_))));
+        assert!(matches!(environment.step(0), Err(BackendError::Memory(_))));
+        assert!(environment.snapshot().is_ok());
+
+        // run_client ends the run with the error instead of taking the process down
+        let mut agent = Agent::new(agent_config, None).unwrap();
+        let episode_results = agent.run_client();
+        assert_eq!(episode_results.len(), 1);
+        assert!(matches!(&episode_results[0].episode_end, EpisodeEnd::Failed(reason) if reason.contains("game variables")));
+        assert!(!episode_results[0].is_valid());
+        std::fs::remove_file(&game_definition_path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+// Episode boundaries for run_client (and the Environment)
+// An episode ends when the game definition says so, when it hits max_frames, or when we're told to stop
+// After an episode the emulator goes back to the configured save state and the next one starts
+
+use std::fs::{create_dir, create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::observation::{ObservationData, ObservationSet};
+use super::replay::{InputLogWriter, INPUT_LOG_FILE_NAME};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EpisodeConfig {
+    // truncate episodes after this many emulator frames
+    #[serde(default)]
+    pub max_frames: Option<u32>,
+    // run_client returns after this many episodes, None = run until stopped (Ctrl-C)
+    #[serde(default)]
+    pub num_episodes: Option<u32>,
+    // extra termination expression over the game definition's variables, e.g. "player_hp < 10"
+    #[serde(default)]
+    pub termination_condition: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EpisodeEnd {
+    Terminated(String), // name of the termination condition that fired
+    Truncated,          // hit max_frames
+    Stopped,            // stop flag, window closed or replay ran out
+    Failed(String),     // the emulator (or the trajectory file) stopped working, the run ends here too
+}
+
+#[derive(Clone, Debug)]
+pub struct EpisodeResult {
+    pub episode_idx: u32,
+    pub frames: u32,
+    pub total_reward: f32,
+    pub episode_end: EpisodeEnd,
+    pub album: Option<PathBuf>, // only with store_observations, album_dir/episode_NNNN/
+    pub observations_stored: usize, // frames in the album, at most observation_capacity
+    // FATAL/ERROR messages the emulator logged during the episode
+    pub emulator_errors: Vec<String>,
+}
+
+impl EpisodeResult {
+    /// An episode the emulator logged errors in (or that it failed in) may not have been emulated properly, don't train on it
+    pub fn is_valid(&self) -> bool {
+        self.emulator_errors.is_empty() && !matches!(self.episode_end, EpisodeEnd::Failed(_))
+    }
+}
+
+/// store_observations for run_client: one album per episode (album_dir/episode_0000/, album_dir/episode_0001/, ...)
+/// The same ring buffer holds every episode's frames, and the input log goes straight to the album as the episode runs,
+/// so nothing grows with the number or length of episodes. Albums keep counting up across run_client calls
+pub struct EpisodeRecorder {
+    album_dir: PathBuf,
+    observation_set: ObservationSet,
+    input_log_writer: Option<InputLogWriter>, // the running episode's, None between episodes
+    albums_started: u32,
+}
+
+impl EpisodeRecorder {
+    pub fn new(album_dir: &Path, template: &ObservationData, capacity: usize) -> Self {
+        Self {
+            album_dir: album_dir.to_path_buf(),
+            observation_set: ObservationSet::new(template, capacity),
+            input_log_writer: None,
+            albums_started: 0,
+        }
+    }
+
+    fn episode_album(&self) -> PathBuf {
+        self.album_dir.join(format!("episode_{:0>4}/", self.albums_started))
+    }
+
+    /// Empty ring buffer and a new album directory for the input log, an album that's already there is an error
+    pub fn start_episode(&mut self) -> std::io::Result<()> {
+        create_dir_all(&self.album_dir)?;
+        let episode_album = self.episode_album();
+        create_dir(&episode_album)?;
+        self.input_log_writer = Some(InputLogWriter::create(&episode_album.join(INPUT_LOG_FILE_NAME))?);
+        self.observation_set.clear();
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn push(&mut self, observation_data: &ObservationData) -> std::io::Result<()> {
+        self.observation_set.push(observation_data);
+        match self.input_log_writer.as_mut() {
+            Some(input_log_writer) => input_log_writer.push(observation_data.keycode_data),
+            None => Ok(()),
+        }
+    }
+
+    /// Save the running episode's frames next to its input log, returns the album and how many frames it got
+    pub fn finish_episode(&mut self) -> std::io::Result<(PathBuf, usize)> {
+        let episode_album = self.episode_album();
+        if let Some(mut input_log_writer) = self.input_log_writer.take() {
+            input_log_writer.flush()?;
+        }
+        self.observation_set.save_album(&episode_album);
+        self.albums_started += 1;
+        Ok((episode_album, self.observation_set.len()))
+    }
+
+    /// An episode that got started but never ran a frame, nothing worth keeping
+    pub fn discard_episode(&mut self) -> std::io::Result<()> {
+        if self.input_log_writer.take().is_some() {
+            remove_dir_all(self.episode_album())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn print_episode_summary(episode_results: &[EpisodeResult]) {
+    for episode_result in episode_results {
+        println!(
+            "Episode #{}: {} frames, total reward {}, ended by {:?}, {} observations stored",
+            episode_result.episode_idx,
+            episode_result.frames,
+            episode_result.total_reward,
+            episode_result.episode_end,
+            episode_result.observations_stored,
+        );
+        if let Some(album) = &episode_result.album {
+            println!("    album: {}", album.display());
+        }
+        if !episode_result.is_valid() {
+            println!("    invalid:");
+            if let EpisodeEnd::Failed(reason) = &episode_result.episode_end {
+                println!("        the run failed: {}", reason);
+            }
+            for error in &episode_result.emulator_errors {
+                println!("        the emulator logged: {}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,463 @@
+// How frames travel over the socket protocol, the client asks for one in its SessionRequest
+// Raw        frame_data as the core drew it
+// DeltaRle   XOR against the previous frame, run-length encoded, most of a frame doesn't change between steps
+//            payload: [KEYFRAME][whole frame] or [DELTA] then (skip, len, len XORed bytes) runs,
+//            skip/len are LEB128 varints, anything after the last run is unchanged
+// Grayscale  1 luma byte per pixel
+// Downscale  RGB of factor x factor box averaged blocks, 3 bytes per pixel
+// Raw and DeltaRle give the client the core's frame bit for bit. Grayscale and Downscale decode into ABGR8888,
+// so the client's FrameBuffer is exactly what the server computed, just not what the core drew
+
+use serde::{Deserialize, Serialize};
+
+use super::observation::rgb565_to_rgb888;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FrameEncoding {
+    #[default]
+    Raw,
+    DeltaRle,
+    Grayscale,
+    Downscale { factor: u32 },
+}
+
+// first byte of a DeltaRle payload
+const KEYFRAME: u8 = 0;
+const DELTA: u8 = 1;
+// unchanged bytes it takes to end a run, fewer are cheaper to send along than a new run header
+const MIN_SKIP: usize = 8;
+// what Grayscale and Downscale decode into
+const DECODED_BPP: u32 = 4;
+
+impl FrameEncoding {
+    /// Encoding id and its parameter, how the handshake carries it
+    pub fn to_wire(self) -> (u8, u32) {
+        match self {
+            Self::Raw => (0, 0),
+            Self::DeltaRle => (1, 0),
+            Self::Grayscale => (2, 0),
+            Self::Downscale { factor } => (3, factor),
+        }
+    }
+
+    pub fn from_wire(id: u8, parameter: u32) -> Option<Self> {
+        match id {
+            0 => Some(Self::Raw),
+            1 => Some(Self::DeltaRle),
+            2 => Some(Self::Grayscale),
+            3 => Some(Self::Downscale { factor: parameter }),
+            _ => None,
+        }
+    }
+
+    /// (width, height, bpp) of the frames the client decodes, given the core's, or why this encoding can't do them
+    pub fn decoded_format(self, width: u32, height: u32, bpp: u32) -> Result<(u32, u32, u32), String> {
+        if self != Self::Raw && bpp != 2 && bpp != 4 {
+            return Err(format!("{:?} doesn't know {} bytes per pixel", self, bpp));
+        }
+        match self {
+            Self::Raw | Self::DeltaRle => Ok((width, height, bpp)),
+            Self::Grayscale => Ok((width, height, DECODED_BPP)),
+            Self::Downscale { factor } => {
+                if factor == 0 || !width.is_multiple_of(factor) || !height.is_multiple_of(factor) {
+                    return Err(format!("Downscale factor {} has to evenly divide {}x{}", factor, width, height));
+                }
+                Ok((width / factor, height / factor, DECODED_BPP))
+            },
+        }
+    }
+}
+
+// Same pixel layouts as FrameBuffer::for_each_rgb
+fn rgb_pixels(frame_data: &[u8], bpp: u32, rgb: &mut Vec<[u8; 3]>) {
+    rgb.clear();
+    match bpp {
+        4 => rgb.extend(frame_data.chunks_exact(4).map(|pixel| [pixel[0], pixel[1], pixel[2]])),
+        _ => rgb.extend(
+            frame_data
+                .chunks_exact(2)
+                .map(|pixel| rgb565_to_rgb888(u16::from_le_bytes([pixel[0], pixel[1]]))),
+        ),
+    }
+}
+
+// Integer weights, so both ends of the wire (and every machine) agree on every byte
+fn luma([r, g, b]: [u8; 3]) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32 + 128) >> 8) as u8
+}
+
+fn put_varint(payload: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        payload.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    payload.push(value as u8);
+}
+
+fn take_varint(payload: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut value = 0_usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = *payload.get(*pos).ok_or("delta ends in the middle of a varint")?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is too long".to_string())
+}
+
+/// Server side, one per session: turns the core's frames into payloads
+pub struct FrameEncoder {
+    encoding: FrameEncoding,
+    width: u32,
+    bpp: u32,
+    previous: Vec<u8>, // DeltaRle: the last frame sent, empty until the first keyframe
+    rgb: Vec<[u8; 3]>, // Grayscale/Downscale: the frame being encoded as RGB
+    payload: Vec<u8>,
+}
+
+impl FrameEncoder {
+    pub fn new(encoding: FrameEncoding, width: u32, height: u32, bpp: u32) -> Result<Self, String> {
+        encoding.decoded_format(width, height, bpp)?;
+        Ok(Self { encoding, width, bpp, previous: vec![], rgb: vec![], payload: vec![] })
+    }
+
+    pub fn encoding(&self) -> FrameEncoding {
+        self.encoding
+    }
+
+    /// The payload to send for frame_data, Raw hands frame_data straight back
+    pub fn encode<'a>(&'a mut self, frame_data: &'a [u8]) -> &'a [u8] {
+        self.payload.clear();
+        match self.encoding {
+            FrameEncoding::Raw => return frame_data,
+            FrameEncoding::DeltaRle => self.encode_delta(frame_data),
+            FrameEncoding::Grayscale => {
+                rgb_pixels(frame_data, self.bpp, &mut self.rgb);
+                self.payload.extend(self.rgb.iter().map(|pixel| luma(*pixel)));
+            },
+            FrameEncoding::Downscale { factor } => {
+                rgb_pixels(frame_data, self.bpp, &mut self.rgb);
+                let (factor, width) = (factor as usize, self.width as usize);
+                let block_pixels = (factor * factor) as u32;
+                for block_y in 0..self.rgb.len() / width / factor {
+                    for block_x in 0..width / factor {
+                        let mut sum = [0_u32; 3];
+                        for y in block_y * factor..(block_y + 1) * factor {
+                            for pixel in &self.rgb[y * width + block_x * factor..y * width + (block_x + 1) * factor] {
+                                sum.iter_mut().zip(pixel).for_each(|(sum, channel)| *sum += *channel as u32);
+                            }
+                        }
+                        // rounded, not truncated, so a flat block keeps its colour
+                        self.payload.extend(sum.map(|sum| ((sum + block_pixels / 2) / block_pixels) as u8));
+                    }
+                }
+            },
+        }
+        &self.payload
+    }
+
+    fn encode_delta(&mut self, frame_data: &[u8]) {
+        if self.previous.len() != frame_data.len() {
+            self.payload.push(KEYFRAME);
+            self.payload.extend_from_slice(frame_data);
+            self.previous = frame_data.to_vec();
+            return;
+        }
+        self.payload.push(DELTA);
+        let previous = &self.previous;
+        let mut pos = 0;
+        let mut run_end = 0; // where the last run stopped, skips count from here
+        while pos < frame_data.len() {
+            if frame_data[pos] == previous[pos] {
+                pos += 1;
+                continue;
+            }
+            let run_start = pos;
+            // the run goes on until MIN_SKIP unchanged bytes in a row (or the end of the frame)
+            let mut last_changed = pos;
+            while pos < frame_data.len() && pos - last_changed <= MIN_SKIP {
+                if frame_data[pos] != previous[pos] {
+                    last_changed = pos;
+                }
+                pos += 1;
+            }
+            let run_len = last_changed + 1 - run_start;
+            put_varint(&mut self.payload, run_start - run_end);
+            put_varint(&mut self.payload, run_len);
+            self.payload.extend(
+                frame_data[run_start..run_start + run_len]
+                    .iter()
+                    .zip(&previous[run_start..run_start + run_len])
+                    .map(|(byte, previous_byte)| byte ^ previous_byte),
+            );
+            run_end = run_start + run_len;
+            pos = run_end;
+        }
+        // a delta bigger than the frame isn't worth it
+        if self.payload.len() > frame_data.len() {
+            self.payload.clear();
+            self.payload.push(KEYFRAME);
+            self.payload.extend_from_slice(frame_data);
+        }
+        self.previous.copy_from_slice(frame_data);
+    }
+}
+
+/// Client side: turns payloads back into frames of the format FrameEncoding::decoded_format gives
+pub struct FrameDecoder {
+    encoding: FrameEncoding,
+    frame_len: usize,
+    previous: Vec<u8>, // DeltaRle: the last frame decoded, what the next delta applies to
+    has_keyframe: bool,
+}
+
+impl FrameDecoder {
+    /// width/height/bpp are the decoded frame's, what the server's Welcome says
+    /// They come off the network, so a frame size that overflows or a bpp no encoding has is an Err
+    pub fn new(encoding: FrameEncoding, width: u32, height: u32, bpp: u32) -> Result<Self, String> {
+        let decoded_bpp_ok = match encoding {
+            FrameEncoding::Raw | FrameEncoding::DeltaRle => (1..=4).contains(&bpp),
+            FrameEncoding::Grayscale | FrameEncoding::Downscale { .. } => bpp == DECODED_BPP,
+        };
+        if !decoded_bpp_ok {
+            return Err(format!("{:?} frames can't decode to {} bytes per pixel", encoding, bpp));
+        }
+        let frame_len = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(bpp))
+            .ok_or_else(|| format!("{}x{} frames at {} bytes per pixel are too big", width, height, bpp))?
+            as usize;
+        Ok(Self {
+            encoding,
+            frame_len,
+            previous: match encoding {
+                FrameEncoding::DeltaRle => vec![0; frame_len],
+                _ => vec![],
+            },
+            has_keyframe: false,
+        })
+    }
+
+    pub fn encoding(&self) -> FrameEncoding {
+        self.encoding
+    }
+
+    /// Decode payload into frame_data
+    /// A DeltaRle payload still counts when frame_data is the wrong size, so the next delta applies to the right frame
+    pub fn decode(&mut self, payload: &[u8], frame_data: &mut [u8]) -> Result<(), String> {
+        if self.encoding == FrameEncoding::DeltaRle {
+            self.decode_delta(payload)?;
+        }
+        if frame_data.len() != self.frame_len {
+            return Err(format!("frame is {} bytes, expected {}", frame_data.len(), self.frame_len));
+        }
+        let pixels = self.frame_len / DECODED_BPP as usize;
+        match self.encoding {
+            FrameEncoding::Raw if payload.len() != self.frame_len => {
+                return Err(format!("frame is {} bytes, expected {}", payload.len(), self.frame_len));
+            },
+            FrameEncoding::Raw => frame_data.copy_from_slice(payload),
+            FrameEncoding::DeltaRle => frame_data.copy_from_slice(&self.previous),
+            FrameEncoding::Grayscale if payload.len() != pixels => {
+                return Err(format!("grayscale frame is {} bytes, expected {}", payload.len(), pixels));
+            },
+            FrameEncoding::Grayscale => frame_data
+                .chunks_exact_mut(4)
+                .zip(payload)
+                .for_each(|(pixel, luma)| pixel.copy_from_slice(&[*luma, *luma, *luma, 0xFF])),
+            FrameEncoding::Downscale { .. } if payload.len() != pixels * 3 => {
+                return Err(format!("downscaled frame is {} bytes, expected {}", payload.len(), pixels * 3));
+            },
+            FrameEncoding::Downscale { .. } => frame_data
+                .chunks_exact_mut(4)
+                .zip(payload.chunks_exact(3))
+                .for_each(|(pixel, rgb)| pixel.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 0xFF])),
+        }
+        Ok(())
+    }
+
+    fn decode_delta(&mut self, payload: &[u8]) -> Result<(), String> {
+        match payload.first() {
+            Some(&KEYFRAME) if payload.len() - 1 == self.frame_len => {
+                self.previous.copy_from_slice(&payload[1..]);
+                self.has_keyframe = true;
+                Ok(())
+            },
+            Some(&KEYFRAME) => Err(format!("keyframe is {} bytes, expected {}", payload.len() - 1, self.frame_len)),
+            Some(&DELTA) if !self.has_keyframe => Err("delta before any keyframe".to_string()),
+            Some(&DELTA) => {
+                // check every run fits before touching previous, a bad delta leaves it as it was
+                let mut runs = vec![];
+                let (mut pos, mut frame_pos) = (1, 0_usize);
+                while pos < payload.len() {
+                    let skip = take_varint(payload, &mut pos)?;
+                    let run_len = take_varint(payload, &mut pos)?;
+                    // the varints come off the network, a huge one mustn't wrap around into a valid looking range
+                    let run_start = frame_pos.checked_add(skip);
+                    let run_end = run_start.and_then(|run_start| run_start.checked_add(run_len));
+                    let (run_start, run_end) = match (run_start, run_end) {
+                        (Some(run_start), Some(run_end)) if run_end <= self.frame_len && run_len <= payload.len() - pos => {
+                            (run_start, run_end)
+                        },
+                        _ => {
+                            return Err(format!("delta run of {} bytes, {} bytes after {}, is past the end", run_len, skip, frame_pos));
+                        },
+                    };
+                    runs.push((run_start, &payload[pos..pos + run_len]));
+                    pos += run_len;
+                    frame_pos = run_end;
+                }
+                for (run_start, xor_bytes) in runs {
+                    self.previous[run_start..run_start + xor_bytes.len()]
+                        .iter_mut()
+                        .zip(xor_bytes)
+                        .for_each(|(byte, xor_byte)| *byte ^= xor_byte);
+                }
+                Ok(())
+            },
+            Some(kind) => Err(format!("unknown delta payload kind {}", kind)),
+            None => Err("empty delta payload".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 4;
+
+    // xorshift, enough to scribble over frames reproducibly
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    // A run of frames like a game makes: some identical, some with a few changed pixels, some all new
+    fn frame_sequence(bpp: u32) -> Vec<Vec<u8>> {
+        let mut state = 0x1234_5678;
+        let frame_len = (WIDTH * HEIGHT * bpp) as usize;
+        let mut frame = vec![0_u8; frame_len];
+        let mut frames = vec![frame.clone()];
+        for frame_idx in 0..40 {
+            match frame_idx % 4 {
+                0 => (),
+                1 | 2 => {
+                    for _ in 0..1 + frame_idx % 5 {
+                        let pos = next_random(&mut state) as usize % frame_len;
+                        frame[pos] = next_random(&mut state) as u8;
+                    }
+                },
+                _ => frame.iter_mut().for_each(|byte| *byte = next_random(&mut state) as u8),
+            }
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn lossless_encodings_round_trip_bit_exact() {
+        for bpp in [2, 4] {
+            for encoding in [FrameEncoding::Raw, FrameEncoding::DeltaRle] {
+                let mut encoder = FrameEncoder::new(encoding, WIDTH, HEIGHT, bpp).unwrap();
+                let (width, height, decoded_bpp) = encoding.decoded_format(WIDTH, HEIGHT, bpp).unwrap();
+                let mut decoder = FrameDecoder::new(encoding, width, height, decoded_bpp).unwrap();
+                let mut decoded = vec![0_u8; (width * height * decoded_bpp) as usize];
+                for (frame_idx, frame) in frame_sequence(bpp).iter().enumerate() {
+                    decoder.decode(encoder.encode(frame), &mut decoded).unwrap();
+                    assert_eq!(&decoded, frame, "{:?} at {} bpp, frame {}", encoding, bpp, frame_idx);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deltas_only_send_what_changed() {
+        let frame_len = (WIDTH * HEIGHT * 4) as usize;
+        let mut encoder = FrameEncoder::new(FrameEncoding::DeltaRle, WIDTH, HEIGHT, 4).unwrap();
+        let mut frame = vec![7_u8; frame_len];
+        assert_eq!(encoder.encode(&frame).len(), frame_len + 1);
+        // nothing changed: just the kind byte
+        assert_eq!(encoder.encode(&frame), &[DELTA]);
+        // two bytes close together are one run: skip, len, the XORs
+        frame[10] = 8;
+        frame[12] = 9;
+        assert_eq!(encoder.encode(&frame), &[DELTA, 10, 3, 7 ^ 8, 0, 7 ^ 9]);
+        // far apart they're two runs, the second skip counts from the end of the first
+        frame[0] = 0;
+        frame[100] = 0;
+        assert_eq!(encoder.encode(&frame), &[DELTA, 0, 1, 7, 99, 1, 7]);
+    }
+
+    #[test]
+    fn lossy_encodings_decode_to_what_the_server_computed() {
+        // 2x2 blocks of one colour each, so Downscale has nothing to round
+        let colours = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [10, 20, 30], [255, 255, 255], [0, 0, 0], [1, 2, 3], [200, 100, 50]];
+        let mut frame = vec![];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let [r, g, b] = colours[((y / 2) * (WIDTH / 2) + x / 2) as usize];
+                frame.extend_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+
+        let mut encoder = FrameEncoder::new(FrameEncoding::Grayscale, WIDTH, HEIGHT, 4).unwrap();
+        let mut decoder = FrameDecoder::new(FrameEncoding::Grayscale, WIDTH, HEIGHT, 4).unwrap();
+        let mut decoded = vec![0_u8; frame.len()];
+        decoder.decode(encoder.encode(&frame), &mut decoded).unwrap();
+        for (pixel, decoded_pixel) in frame.chunks_exact(4).zip(decoded.chunks_exact(4)) {
+            let luma = luma([pixel[0], pixel[1], pixel[2]]);
+            assert_eq!(decoded_pixel, [luma, luma, luma, 0xFF]);
+        }
+        assert_eq!(luma([255, 255, 255]), 255);
+        assert_eq!(luma([0, 0, 0]), 0);
+
+        let encoding = FrameEncoding::Downscale { factor: 2 };
+        let mut encoder = FrameEncoder::new(encoding, WIDTH, HEIGHT, 4).unwrap();
+        let (width, height, bpp) = encoding.decoded_format(WIDTH, HEIGHT, 4).unwrap();
+        assert_eq!((width, height, bpp), (WIDTH / 2, HEIGHT / 2, 4));
+        let mut decoder = FrameDecoder::new(encoding, width, height, bpp).unwrap();
+        let mut decoded = vec![0_u8; (width * height * bpp) as usize];
+        decoder.decode(encoder.encode(&frame), &mut decoded).unwrap();
+        let expected: Vec<u8> = colours.iter().flat_map(|[r, g, b]| [*r, *g, *b, 0xFF]).collect();
+        assert_eq!(decoded, expected);
+
+        assert!(FrameEncoder::new(FrameEncoding::Downscale { factor: 3 }, WIDTH, HEIGHT, 4).is_err());
+        assert!(FrameEncoder::new(FrameEncoding::Downscale { factor: 0 }, WIDTH, HEIGHT, 4).is_err());
+    }
+
+    #[test]
+    fn bad_payloads_are_errors() {
+        let frame_len = (WIDTH * HEIGHT * 4) as usize;
+        let mut decoder = FrameDecoder::new(FrameEncoding::DeltaRle, WIDTH, HEIGHT, 4).unwrap();
+        let mut decoded = vec![0_u8; frame_len];
+        assert!(decoder.decode(&[DELTA], &mut decoded).is_err());
+        assert!(decoder.decode(&[KEYFRAME, 1, 2], &mut decoded).is_err());
+        let keyframe = [&[KEYFRAME][..], &vec![5; frame_len]].concat();
+        decoder.decode(&keyframe, &mut decoded).unwrap();
+        // runs past the end of the frame, or of the payload
+        assert!(decoder.decode(&[DELTA, 120, 20, 1], &mut decoded).is_err());
+        assert!(decoder.decode(&[DELTA, 0, 4, 1], &mut decoded).is_err());
+        assert!(decoder.decode(&[DELTA, 0x80], &mut decoded).is_err());
+        // lengths that would overflow the bounds checks if they were added up unchecked
+        let mut huge_run = vec![DELTA];
+        put_varint(&mut huge_run, 2);
+        put_varint(&mut huge_run, usize::MAX);
+        assert!(decoder.decode(&huge_run, &mut decoded).is_err());
+        let mut wrapping_skip = vec![DELTA, 4, 1, 7];
+        put_varint(&mut wrapping_skip, usize::MAX - 2);
+        put_varint(&mut wrapping_skip, 1);
+        wrapping_skip.push(7);
+        assert!(decoder.decode(&wrapping_skip, &mut decoded).is_err());
+        // none of those touched the frame
+        decoder.decode(&[DELTA], &mut decoded).unwrap();
+        assert!(decoded.iter().all(|byte| *byte == 5));
+        assert!(FrameDecoder::new(FrameEncoding::Grayscale, WIDTH, HEIGHT, 4).unwrap().decode(&[0; 3], &mut decoded).is_err());
+        assert!(FrameDecoder::new(FrameEncoding::Raw, WIDTH, HEIGHT, 4).unwrap().decode(&[0; 3], &mut decoded).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+// Shared memory ring of frames for AgentDriver::SharedMemory
+// The server's session writes every frame into the next slot of a file both processes map (on /dev/shm when there is one)
+// and only tells the client which slot it used, so the framebuffer never gets copied through the kernel
+//
+// layout, native endian (both ends are on the same machine):
+//   [0, 64)            header: magic "MGBARING", version u32, slot_count u32, frame_len u32
+//   [64, 64+8*slots)   one AtomicU64 sequence number per slot
+//   then               slot_count frames, each starting on a 64 byte boundary
+// A slot's sequence number is 0 while the server writes into it and the frame's number once it's done (a seqlock),
+// the reader checks it before and after copying, so it never hands out a half written or already replaced frame
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+pub const FRAME_RING_MAGIC: [u8; 8] = *b"MGBARING";
+pub const FRAME_RING_VERSION: u32 = 1;
+// more than this is only wasted memory, the client waits on every frame anyway
+pub const MAX_FRAME_SLOTS: u32 = 64;
+
+const HEADER_LEN: usize = 64;
+const SLOT_ALIGN: usize = 64;
+
+fn align_up(offset: usize) -> usize {
+    offset.div_ceil(SLOT_ALIGN) * SLOT_ALIGN
+}
+
+// Where the frames start and how big the whole file is
+fn layout(slot_count: u32, frame_len: usize) -> (usize, usize) {
+    let frames_offset = align_up(HEADER_LEN + 8 * slot_count as usize);
+    let frame_stride = align_up(frame_len);
+    (frames_offset, frames_offset + frame_stride * slot_count as usize)
+}
+
+/// Where rings go by default: tmpfs if the system has it, so the file never touches a disk
+pub fn default_dir() -> PathBuf {
+    let shm_dir = Path::new("/dev/shm");
+    match shm_dir.is_dir() {
+        true => shm_dir.to_path_buf(),
+        false => std::env::temp_dir(),
+    }
+}
+
+pub struct FrameRing {
+    map: *mut u8,
+    map_len: usize,
+    slot_count: u32,
+    frame_len: usize,
+    frames_offset: usize,
+    // server side: number of the last frame published, the first one is 1 so 0 can mean "being written"
+    last_seq: u64,
+    path: PathBuf,
+    owner: bool, // created the file, so removes it again
+}
+
+// The mapping belongs to this FrameRing alone, moving it to another thread is no different from moving a Vec
+unsafe impl Send for FrameRing {}
+
+impl FrameRing {
+    /// Server side: create the file at path and map it read/write, fails if something already lives there
+    pub fn create(path: &Path, slot_count: u32, frame_len: usize) -> io::Result<Self> {
+        if slot_count == 0 || slot_count > MAX_FRAME_SLOTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} frame slots, must be 1 to {}", slot_count, MAX_FRAME_SLOTS),
+            ));
+        }
+        let (frames_offset, map_len) = layout(slot_count, frame_len);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+        let map = match file.set_len(map_len as u64).and_then(|_| map_file(&file, map_len, true)) {
+            Ok(map) => map,
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                return Err(e);
+            },
+        };
+        // set_len zero fills, so every slot already reads as "being written"
+        let mut header = [0_u8; HEADER_LEN];
+        header[0..8].copy_from_slice(&FRAME_RING_MAGIC);
+        header[8..12].copy_from_slice(&FRAME_RING_VERSION.to_ne_bytes());
+        header[12..16].copy_from_slice(&slot_count.to_ne_bytes());
+        header[16..20].copy_from_slice(&(frame_len as u32).to_ne_bytes());
+        unsafe { ptr::copy_nonoverlapping(header.as_ptr(), map, HEADER_LEN) };
+        Ok(Self { map, map_len, slot_count, frame_len, frames_offset, last_seq: 0, path: path.to_path_buf(), owner: true })
+    }
+
+    /// Client side: map a ring the server created, read only
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < HEADER_LEN {
+            return Err(invalid_data(format!("{} is too short for a frame ring", path.display())));
+        }
+        let mut header = [0_u8; HEADER_LEN];
+        let header_map = map_file(&file, HEADER_LEN, false)?;
+        unsafe {
+            ptr::copy_nonoverlapping(header_map, header.as_mut_ptr(), HEADER_LEN);
+            libc::munmap(header_map as *mut libc::c_void, HEADER_LEN);
+        }
+        if header[0..8] != FRAME_RING_MAGIC {
+            return Err(invalid_data(format!("{} isn't a frame ring", path.display())));
+        }
+        let version = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+        if version != FRAME_RING_VERSION {
+            return Err(invalid_data(format!("frame ring version {}, expected {}", version, FRAME_RING_VERSION)));
+        }
+        let slot_count = u32::from_ne_bytes(header[12..16].try_into().unwrap());
+        let frame_len = u32::from_ne_bytes(header[16..20].try_into().unwrap()) as usize;
+        if slot_count == 0 || slot_count > MAX_FRAME_SLOTS {
+            return Err(invalid_data(format!("frame ring has {} slots", slot_count)));
+        }
+        let (frames_offset, map_len) = layout(slot_count, frame_len);
+        if file_len < map_len {
+            return Err(invalid_data(format!("frame ring is {} bytes, its header needs {}", file_len, map_len)));
+        }
+        let map = map_file(&file, map_len, false)?;
+        Ok(Self { map, map_len, slot_count, frame_len, frames_offset, last_seq: 0, path: path.to_path_buf(), owner: false })
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.frame_len
+    }
+
+    fn slot_seq(&self, slot: u32) -> &AtomicU64 {
+        // 64 + 8 * slot is 8 byte aligned in a page aligned mapping
+        unsafe { &*(self.map.add(HEADER_LEN + 8 * slot as usize) as *const AtomicU64) }
+    }
+
+    fn slot_frame(&self, slot: u32) -> *mut u8 {
+        unsafe { self.map.add(self.frames_offset + align_up(self.frame_len) * slot as usize) }
+    }
+
+    /// Server side: write frame_data into the next slot, returns the slot and sequence number to send the client
+    pub fn publish(&mut self, frame_data: &[u8]) -> (u32, u64) {
+        assert!(self.owner, "Only the side that created a frame ring can publish to it");
+        assert_eq!(frame_data.len(), self.frame_len, "Frame doesn't fit the ring's slots");
+        self.last_seq += 1;
+        let seq = self.last_seq;
+        let slot = ((seq - 1) % self.slot_count as u64) as u32;
+        let slot_seq = self.slot_seq(slot);
+        slot_seq.store(0, Ordering::Relaxed);
+        // the 0 has to be visible before any of the new frame is
+        fence(Ordering::Release);
+        unsafe { ptr::copy_nonoverlapping(frame_data.as_ptr(), self.slot_frame(slot), self.frame_len) };
+        slot_seq.store(seq, Ordering::Release);
+        (slot, seq)
+    }
+
+    /// Client side: copy the frame published as (slot, seq) into frame_data
+    /// An Err means the slot holds something else by now, frame_data might be partly overwritten then
+    pub fn read(&self, slot: u32, seq: u64, frame_data: &mut [u8]) -> io::Result<()> {
+        if slot >= self.slot_count {
+            return Err(invalid_data(format!("slot {} of a {} slot frame ring", slot, self.slot_count)));
+        }
+        if frame_data.len() != self.frame_len {
+            return Err(invalid_data(format!("frame is {} bytes, expected {}", frame_data.len(), self.frame_len)));
+        }
+        let slot_seq = self.slot_seq(slot);
+        let found_seq = slot_seq.load(Ordering::Acquire);
+        if found_seq == seq {
+            unsafe { ptr::copy_nonoverlapping(self.slot_frame(slot), frame_data.as_mut_ptr(), self.frame_len) };
+            // the copy has to be done before we look at the sequence number again
+            fence(Ordering::Acquire);
+            if slot_seq.load(Ordering::Relaxed) == seq {
+                return Ok(());
+            }
+        }
+        Err(invalid_data(format!("frame {} in slot {} was already replaced", seq, slot)))
+    }
+}
+
+impl Drop for FrameRing {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.map_len) };
+        if self.owner {
+            if let Err(e) = fs::remove_file(&self.path) {
+                println!("Failed to remove frame ring {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+fn map_file(file: &File, len: usize, writable: bool) -> io::Result<*mut u8> {
+    let prot = match writable {
+        true => libc::PROT_READ | libc::PROT_WRITE,
+        false => libc::PROT_READ,
+    };
+    let map = unsafe { libc::mmap(ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if map == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(map as *mut u8)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mgba_frame_ring_test_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn frames_go_around_the_ring() {
+        let path = ring_path("round_trip");
+        let mut server_ring = FrameRing::create(&path, 2, 10).unwrap();
+        let client_ring = FrameRing::open(&path).unwrap();
+        assert_eq!(client_ring.frame_len(), 10);
+
+        let mut frame_data = [0_u8; 10];
+        let first = server_ring.publish(&[1; 10]);
+        client_ring.read(first.0, first.1, &mut frame_data).unwrap();
+        assert_eq!(frame_data, [1; 10]);
+        let second = server_ring.publish(&[2; 10]);
+        assert_ne!(first.0, second.0);
+        // the first frame is still there until the ring comes back around to its slot
+        client_ring.read(first.0, first.1, &mut frame_data).unwrap();
+        assert_eq!(frame_data, [1; 10]);
+        server_ring.publish(&[3; 10]);
+        assert!(client_ring.read(first.0, first.1, &mut frame_data).is_err());
+        client_ring.read(second.0, second.1, &mut frame_data).unwrap();
+        assert_eq!(frame_data, [2; 10]);
+
+        // the server's ring owns the file
+        drop(client_ring);
+        drop(server_ring);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bad_rings_are_errors() {
+        let path = ring_path("bad");
+        assert!(FrameRing::create(&path, 0, 10).is_err());
+        assert!(FrameRing::create(&path, MAX_FRAME_SLOTS + 1, 10).is_err());
+        fs::write(&path, [0_u8; HEADER_LEN]).unwrap();
+        assert_eq!(FrameRing::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        // something already lives there
+        assert!(FrameRing::create(&path, 1, 10).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
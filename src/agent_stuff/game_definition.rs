@@ -0,0 +1,367 @@
+// Per-game descriptions, loaded from a json file referenced by AgentConfiguration::game_definition_path
+// Instead of hard-coding PokemonRed::SeedRNG or BattleNetwork::{ReadScore,DeathScreen} in rust,
+// a game definition names the interesting RAM locations and builds rewards/termination out of them:
+// {
+//     "name":"ExampleGame",
+//     "variables":{
+//         "enemy_hp":{"address":"0x0203A9A0","width":2},
+//         "player_hp":{"block":"wram","offset":"0x36E40","width":2}
+//     },
+//     "reward_terms":[{"name":"damage_dealt","expression":"-delta(enemy_hp)","weight":1.0}],
+//     "termination":[{"name":"player_dead","condition":"player_hp == 0"}]
+// }
+
+mod expression;
+pub use expression::Expression;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::backend::{BackendError, EmulatorBackend};
+
+// json has no hex literals, and every RAM map out there is in hex
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigNumber {
+    Number(u32),
+    Text(String), // "0x0203A9A0" or "1234"
+}
+
+impl ConfigNumber {
+    pub fn value(&self) -> Result<u32, String> {
+        match self {
+            Self::Number(value) => Ok(*value),
+            Self::Text(text) => {
+                let text = text.trim();
+                match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                    Some(hex_digits) => u32::from_str_radix(hex_digits, 16),
+                    None => text.parse::<u32>(),
+                }
+                .map_err(|e| format!("Invalid number {}: {}", text, e))
+            },
+        }
+    }
+}
+
+// ################################################################################
+// What lives in the json file
+// ################################################################################
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VariableDefinition {
+    // either an absolute bus address, or a named memory block + offset
+    #[serde(default)]
+    pub address: Option<ConfigNumber>,
+    #[serde(default)]
+    pub block: Option<String>,
+    #[serde(default)]
+    pub offset: Option<ConfigNumber>,
+    pub width: u32, // bytes: 1, 2 or 4
+    #[serde(default)]
+    pub signed: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RewardTermDefinition {
+    pub name: String,
+    pub expression: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TerminationDefinition {
+    pub name: String,
+    pub condition: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GameDefinitionFile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // BTreeMap so the variable order (and indices) are stable between runs
+    pub variables: BTreeMap<String, VariableDefinition>,
+    #[serde(default)]
+    pub reward_terms: Vec<RewardTermDefinition>,
+    #[serde(default)]
+    pub termination: Vec<TerminationDefinition>,
+}
+
+// ################################################################################
+// Parsed/validated version used at runtime
+// ################################################################################
+
+#[derive(Clone, Debug)]
+pub enum VariableLocation {
+    Address(u32),
+    Block { block: String, offset: u32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct GameVariable {
+    pub name: String,
+    pub location: VariableLocation,
+    pub width: u32,
+    pub signed: bool,
+}
+
+impl GameVariable {
+    pub fn read(&self, backend: &mut dyn EmulatorBackend) -> Result<f64, BackendError> {
+        let address = match &self.location {
+            VariableLocation::Address(address) => *address,
+            VariableLocation::Block { block, offset } => backend.block_address(block, *offset, self.width)?,
+        };
+        // game memory is little endian, widths are validated when the definition is loaded
+        let bytes = backend.read_memory(address, self.width)?;
+        Ok(match (self.width, self.signed) {
+            (1, false) => bytes[0] as f64,
+            (1, true) => bytes[0] as i8 as f64,
+            (2, false) => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            (2, true) => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            (4, false) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RewardTerm {
+    pub expression: Expression,
+    pub weight: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TerminationCondition {
+    pub name: String,
+    pub condition: Expression,
+}
+
+#[derive(Clone, Debug)]
+pub struct GameDefinition {
+    pub name: String,
+    pub variables: Vec<GameVariable>,
+    pub reward_terms: Vec<RewardTerm>,
+    pub termination: Vec<TerminationCondition>,
+}
+
+impl GameDefinition {
+    pub fn from_definition_file(definition_file: GameDefinitionFile) -> Result<Self, String> {
+        let mut variables = vec![];
+        for (name, definition) in &definition_file.variables {
+            let location = match (&definition.address, &definition.block) {
+                (Some(address), None) => VariableLocation::Address(address.value()?),
+                (None, Some(block)) => VariableLocation::Block {
+                    block: block.clone(),
+                    offset: match &definition.offset {
+                        Some(offset) => offset.value()?,
+                        None => 0,
+                    },
+                },
+                _ => return Err(format!("Variable {} needs exactly one of address or block", name)),
+            };
+            if ![1, 2, 4].contains(&definition.width) {
+                return Err(format!("Variable {} has width {}, must be 1, 2 or 4", name, definition.width));
+            }
+            variables.push(GameVariable {
+                name: name.clone(),
+                location,
+                width: definition.width,
+                signed: definition.signed,
+            });
+        }
+
+        let variable_names: Vec<String> = variables.iter().map(|variable| variable.name.clone()).collect();
+        let parse = |kind: &str, name: &str, text: &str| {
+            Expression::parse(text, &variable_names)
+                .map_err(|e| format!("Bad {} expression {} \"{}\": {}", kind, name, text, e))
+        };
+
+        let mut reward_terms = vec![];
+        for term in &definition_file.reward_terms {
+            reward_terms.push(RewardTerm {
+                expression: parse("reward", &term.name, &term.expression)?,
+                weight: term.weight,
+            });
+        }
+
+        let mut termination = vec![];
+        for condition in &definition_file.termination {
+            termination.push(TerminationCondition {
+                name: condition.name.clone(),
+                condition: parse("termination", &condition.name, &condition.condition)?,
+            });
+        }
+
+        Ok(Self {
+            name: definition_file.name,
+            variables,
+            reward_terms,
+            termination,
+        })
+    }
+
+    /// Extra termination condition on top of the ones in the file (EpisodeConfig::termination_condition)
+    pub fn add_termination(&mut self, name: &str, condition: &str) -> Result<(), String> {
+        let variable_names: Vec<String> = self.variables.iter().map(|variable| variable.name.clone()).collect();
+        let condition = Expression::parse(condition, &variable_names)
+            .map_err(|e| format!("Bad termination expression {} \"{}\": {}", name, condition, e))?;
+        self.termination.push(TerminationCondition {
+            name: name.to_string(),
+            condition,
+        });
+        Ok(())
+    }
+
+    pub fn read_variables(&self, backend: &mut dyn EmulatorBackend, values: &mut [f64]) -> Result<(), BackendError> {
+        for (variable, value) in self.variables.iter().zip(values.iter_mut()) {
+            *value = variable.read(backend)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn read_game_definition_file(file_path: &Path) -> Result<GameDefinition, String> {
+    let file_read = fs::read_to_string(file_path)
+        .map_err(|e| format!("Error reading game definition file {}: {}", file_path.display(), e))?;
+    let definition_file = serde_json::from_str::<GameDefinitionFile>(&file_read)
+        .map_err(|e| format!("Trouble parsing game definition file {}: {}", file_path.display(), e))?;
+    let game_definition = GameDefinition::from_definition_file(definition_file)
+        .map_err(|e| format!("Invalid game definition {}: {}", file_path.display(), e))?;
+    println!(
+        "Loaded game definition {}: {} variables, {} reward terms, {} termination conditions",
+        game_definition.name,
+        game_definition.variables.len(),
+        game_definition.reward_terms.len(),
+        game_definition.termination.len(),
+    );
+    Ok(game_definition)
+}
+
+// ################################################################################
+// Per episode bookkeeping: variable values this frame and last frame
+// ################################################################################
+
+#[derive(Clone, Debug, Default)]
+pub struct GameStep {
+    pub reward: f32,
+    pub done: bool,
+    pub terminated_by: Option<String>, // name of the termination condition that fired
+}
+
+pub struct GameTracker {
+    definition: GameDefinition,
+    values: Vec<f64>,
+    prev_values: Vec<f64>,
+}
+
+impl GameTracker {
+    pub fn new(definition: GameDefinition) -> Self {
+        let num_variables = definition.variables.len();
+        Self {
+            definition,
+            values: vec![0.0; num_variables],
+            prev_values: vec![0.0; num_variables],
+        }
+    }
+
+    /// Start of an episode, prev == current so every delta() is 0 on the first step
+    pub fn reset(&mut self, backend: &mut dyn EmulatorBackend) -> Result<(), BackendError> {
+        self.definition.read_variables(backend, &mut self.values)?;
+        self.prev_values.copy_from_slice(&self.values);
+        Ok(())
+    }
+
+    /// Call after every emulator frame
+    pub fn update(&mut self, backend: &mut dyn EmulatorBackend) -> Result<GameStep, BackendError> {
+        std::mem::swap(&mut self.values, &mut self.prev_values);
+        self.definition.read_variables(backend, &mut self.values)?;
+        Ok(self.evaluate())
+    }
+
+    fn evaluate(&self) -> GameStep {
+        let reward = self
+            .definition
+            .reward_terms
+            .iter()
+            .map(|term| term.weight * term.expression.evaluate(&self.values, &self.prev_values) as f32)
+            .sum();
+        let terminated_by = self
+            .definition
+            .termination
+            .iter()
+            .find(|condition| condition.condition.evaluate(&self.values, &self.prev_values) != 0.0)
+            .map(|condition| condition.name.clone());
+        GameStep {
+            reward,
+            done: terminated_by.is_some(),
+            terminated_by,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition_file(variables: serde_json::Value) -> GameDefinitionFile {
+        serde_json::from_value(serde_json::json!({
+            "name":"Test",
+            "variables":variables,
+            "reward_terms":[{"name":"gain","expression":"delta(score)"}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn variables_need_a_valid_width_and_one_location() {
+        let game_definition = GameDefinition::from_definition_file(definition_file(serde_json::json!({
+            "score":{"address":"0x0203A9A0","width":2},
+            "lives":{"block":"wram","offset":"0x10","width":1,"signed":true},
+            "timer":{"block":"wram","width":4}
+        })))
+        .unwrap();
+        // BTreeMap order, not file order
+        let names: Vec<&str> = game_definition.variables.iter().map(|variable| variable.name.as_str()).collect();
+        assert_eq!(names, ["lives", "score", "timer"]);
+        assert!(matches!(game_definition.variables[1].location, VariableLocation::Address(0x0203A9A0)));
+        assert!(matches!(
+            &game_definition.variables[0].location,
+            VariableLocation::Block { block, offset: 0x10 } if block == "wram"
+        ));
+        assert!(matches!(game_definition.variables[2].location, VariableLocation::Block { offset: 0, .. }));
+
+        let error = |variables: serde_json::Value| {
+            GameDefinition::from_definition_file(definition_file(variables)).unwrap_err()
+        };
+        assert!(error(serde_json::json!({"score":{"address":"0x10","width":3}})).contains("width 3"));
+        assert!(error(serde_json::json!({"score":{"address":"0x10","block":"wram","width":2}})).contains("exactly one"));
+        assert!(error(serde_json::json!({"score":{"width":2}})).contains("exactly one"));
+        assert!(error(serde_json::json!({"score":{"address":"0xZZ","width":2}})).contains("Invalid number"));
+        assert!(error(serde_json::json!({"score":{"block":"wram","offset":"ten","width":2}})).contains("Invalid number"));
+        // the reward term's variable has to exist
+        assert!(error(serde_json::json!({"points":{"address":16,"width":2}})).contains("Bad reward expression gain"));
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        assert!(read_game_definition_file(Path::new("/nonexistent/game_definition.json"))
+            .unwrap_err()
+            .starts_with("Error reading"));
+        let file_path = std::env::temp_dir().join(format!("bad_game_definition_{}.json", std::process::id()));
+        fs::write(&file_path, r#"{"name":"Test","variables":{}}"#).unwrap();
+        assert_eq!(read_game_definition_file(&file_path).unwrap().variables.len(), 0);
+        fs::write(&file_path, r#"{"name":"Test"}"#).unwrap();
+        assert!(read_game_definition_file(&file_path).unwrap_err().starts_with("Trouble parsing"));
+        fs::write(&file_path, r#"{"name":"Test","variables":{"x":{"address":0,"width":8}}}"#).unwrap();
+        assert!(read_game_definition_file(&file_path).unwrap_err().starts_with("Invalid game definition"));
+        fs::remove_file(&file_path).unwrap();
+    }
+}
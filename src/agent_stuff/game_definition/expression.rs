@@ -0,0 +1,437 @@
+// Tiny expression language for reward terms and termination conditions
+// Everything evaluates to f64, comparisons/logic give 1.0 or 0.0
+//
+// Grammar, lowest precedence first:
+//   or      := and ( "||" and )*
+//   and     := cmp ( "&&" cmp )*
+//   cmp     := bitand ( ("==" | "!=" | "<" | "<=" | ">" | ">=") bitand )?
+//   bitand  := sum ( "&" sum )*
+//   sum     := product ( ("+" | "-") product )*
+//   product := unary ( ("*" | "/" | "%") unary )*
+//   unary   := ("-" | "!") unary | atom
+//   atom    := number | variable | "prev(" variable ")" | "delta(" variable ")" | "(" or ")"
+// Numbers can be decimal, float or hex (0x...). prev(x) is x on the previous frame, delta(x) = x - prev(x)
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpressionError {
+    pub message: String,
+    pub position: usize, // byte offset into the expression text
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or, And,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    BitAnd,
+    Add, Sub,
+    Mul, Div, Rem,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Variable(usize),
+    Previous(usize),
+    Delta(usize),
+    Negate(Box<Node>),
+    Not(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+/// Parsed expression, variable names are already resolved to indices into the game's variable list
+#[derive(Debug, Clone)]
+pub struct Expression {
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(text: &str, variable_names: &[String]) -> Result<Self, ExpressionError> {
+        let mut parser = Parser {
+            text,
+            position: 0,
+            variable_names,
+        };
+        let root = parser.parse_or()?;
+        parser.skip_whitespace();
+        if parser.position != text.len() {
+            return Err(parser.error("Unexpected trailing characters"));
+        }
+        Ok(Self { root })
+    }
+
+    pub fn evaluate(&self, values: &[f64], prev_values: &[f64]) -> f64 {
+        evaluate_node(&self.root, values, prev_values)
+    }
+}
+
+fn evaluate_node(node: &Node, values: &[f64], prev_values: &[f64]) -> f64 {
+    fn truth(value: f64) -> bool {
+        value != 0.0
+    }
+    fn from_bool(value: bool) -> f64 {
+        if value { 1.0 } else { 0.0 }
+    }
+    match node {
+        Node::Number(value) => *value,
+        Node::Variable(idx) => values[*idx],
+        Node::Previous(idx) => prev_values[*idx],
+        Node::Delta(idx) => values[*idx] - prev_values[*idx],
+        Node::Negate(inner) => -evaluate_node(inner, values, prev_values),
+        Node::Not(inner) => from_bool(!truth(evaluate_node(inner, values, prev_values))),
+        Node::Binary(op, lhs, rhs) => {
+            let lhs = evaluate_node(lhs, values, prev_values);
+            // short circuit the logic ops
+            match op {
+                BinaryOp::Or if truth(lhs) => return 1.0,
+                BinaryOp::And if !truth(lhs) => return 0.0,
+                _ => (),
+            }
+            let rhs = evaluate_node(rhs, values, prev_values);
+            match op {
+                BinaryOp::Or | BinaryOp::And => from_bool(truth(rhs)),
+                BinaryOp::Eq => from_bool(lhs == rhs),
+                BinaryOp::Ne => from_bool(lhs != rhs),
+                BinaryOp::Lt => from_bool(lhs < rhs),
+                BinaryOp::Le => from_bool(lhs <= rhs),
+                BinaryOp::Gt => from_bool(lhs > rhs),
+                BinaryOp::Ge => from_bool(lhs >= rhs),
+                // memory values are integers, masks only make sense on those
+                BinaryOp::BitAnd => ((lhs as i64) & (rhs as i64)) as f64,
+                BinaryOp::Add => lhs + rhs,
+                BinaryOp::Sub => lhs - rhs,
+                BinaryOp::Mul => lhs * rhs,
+                // a 0 divisor shouldn't blow up a whole episode's reward
+                BinaryOp::Div => if rhs == 0.0 { 0.0 } else { lhs / rhs },
+                BinaryOp::Rem => if rhs == 0.0 { 0.0 } else { lhs % rhs },
+            }
+        },
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+    variable_names: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> ExpressionError {
+        ExpressionError {
+            message: message.to_string(),
+            position: self.position,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.position = self.text.len() - trimmed.len();
+    }
+
+    // consume token if it's next (ignoring whitespace)
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    // like eat, but "&" must not match the start of "&&" etc.
+    fn eat_single(&mut self, token: &str, not_followed_by: char) -> bool {
+        self.skip_whitespace();
+        let rest = self.rest();
+        if rest.starts_with(token) && !rest[token.len()..].starts_with(not_followed_by) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ExpressionError> {
+        let mut lhs = self.parse_and()?;
+        while self.eat("||") {
+            let rhs = self.parse_and()?;
+            lhs = Node::Binary(BinaryOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ExpressionError> {
+        let mut lhs = self.parse_cmp()?;
+        while self.eat("&&") {
+            let rhs = self.parse_cmp()?;
+            lhs = Node::Binary(BinaryOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_cmp(&mut self) -> Result<Node, ExpressionError> {
+        let lhs = self.parse_bitand()?;
+        // two character operators first so "<=" doesn't get eaten as "<"
+        let op = if self.eat("==") {
+            BinaryOp::Eq
+        } else if self.eat("!=") {
+            BinaryOp::Ne
+        } else if self.eat("<=") {
+            BinaryOp::Le
+        } else if self.eat(">=") {
+            BinaryOp::Ge
+        } else if self.eat("<") {
+            BinaryOp::Lt
+        } else if self.eat(">") {
+            BinaryOp::Gt
+        } else {
+            return Ok(lhs);
+        };
+        let rhs = self.parse_bitand()?;
+        Ok(Node::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn parse_bitand(&mut self) -> Result<Node, ExpressionError> {
+        let mut lhs = self.parse_sum()?;
+        while self.eat_single("&", '&') {
+            let rhs = self.parse_sum()?;
+            lhs = Node::Binary(BinaryOp::BitAnd, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_sum(&mut self) -> Result<Node, ExpressionError> {
+        let mut lhs = self.parse_product()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_product()?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Node, ExpressionError> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Mul
+            } else if self.eat("/") {
+                BinaryOp::Div
+            } else if self.eat("%") {
+                BinaryOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.parse_unary()?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.eat("-") {
+            Ok(Node::Negate(Box::new(self.parse_unary()?)))
+        } else if self.eat_single("!", '=') {
+            Ok(Node::Not(Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, ExpressionError> {
+        if self.eat("(") {
+            let inner = self.parse_or()?;
+            if !self.eat(")") {
+                return Err(self.error("Expected )"));
+            }
+            return Ok(inner);
+        }
+        self.skip_whitespace();
+        let rest = self.rest();
+        match rest.chars().next() {
+            Some(c) if c.is_ascii_digit() || c == '.' => self.parse_number(),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.parse_identifier();
+                // prev/delta are only functions when called, otherwise they're plain variable names
+                let is_call = self.rest().trim_start().starts_with('(');
+                match name {
+                    "prev" | "delta" if is_call => {
+                        if !self.eat("(") {
+                            return Err(self.error("Expected ( after prev/delta"));
+                        }
+                        self.skip_whitespace();
+                        let variable_name = self.parse_identifier();
+                        let idx = self.variable_index(variable_name)?;
+                        if !self.eat(")") {
+                            return Err(self.error("Expected )"));
+                        }
+                        Ok(if name == "prev" { Node::Previous(idx) } else { Node::Delta(idx) })
+                    },
+                    _ => Ok(Node::Variable(self.variable_index(name)?)),
+                }
+            },
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of expression")),
+        }
+    }
+
+    fn parse_identifier(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        self.position += len;
+        &rest[..len]
+    }
+
+    fn variable_index(&self, name: &str) -> Result<usize, ExpressionError> {
+        if name.is_empty() {
+            return Err(self.error("Expected a variable name"));
+        }
+        self.variable_names
+            .iter()
+            .position(|variable_name| variable_name == name)
+            .ok_or_else(|| ExpressionError {
+                message: format!("Unknown variable {}", name),
+                position: self.position - name.len(),
+            })
+    }
+
+    fn parse_number(&mut self) -> Result<Node, ExpressionError> {
+        let rest = self.rest();
+        let start = self.position;
+        if let Some(hex_digits) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+            let len = hex_digits
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(hex_digits.len());
+            self.position += 2 + len;
+            return u64::from_str_radix(&hex_digits[..len], 16)
+                .map(|value| Node::Number(value as f64))
+                .map_err(|_| ExpressionError {
+                    message: "Invalid hex number".to_string(),
+                    position: start,
+                });
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        self.position += len;
+        rest[..len]
+            .parse::<f64>()
+            .map(Node::Number)
+            .map_err(|_| ExpressionError {
+                message: "Invalid number".to_string(),
+                position: start,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "delta" is a variable too, it's only the function when it's called
+    const VARIABLE_NAMES: [&str; 4] = ["hp", "score", "flags", "delta"];
+    const VALUES: [f64; 4] = [10.0, 250.0, 0b0110 as f64, 7.0];
+    const PREV_VALUES: [f64; 4] = [4.0, 300.0, 0.0, 7.0];
+
+    fn parse(text: &str) -> Result<Expression, ExpressionError> {
+        let variable_names: Vec<String> = VARIABLE_NAMES.iter().map(|name| name.to_string()).collect();
+        Expression::parse(text, &variable_names)
+    }
+
+    fn evaluate(text: &str) -> f64 {
+        parse(text)
+            .unwrap_or_else(|e| panic!("{}: {}", text, e))
+            .evaluate(&VALUES, &PREV_VALUES)
+    }
+
+    #[test]
+    fn operators_bind_in_grammar_order() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("10 - 4 - 3"), 3.0);
+        assert_eq!(evaluate("20 / 2 / 5"), 2.0);
+        assert_eq!(evaluate("7 % 4 * 2"), 6.0);
+        assert_eq!(evaluate("1 + 2 & 2"), 2.0);
+        assert_eq!(evaluate("flags & 0x4 == 4"), 1.0);
+        assert_eq!(evaluate("hp + 1 > 10 && score < 300"), 1.0);
+        assert_eq!(evaluate("0 || 1 && 0"), 0.0);
+        assert_eq!(evaluate("1 || 0 && 0"), 1.0);
+        assert_eq!(evaluate("hp >= 10 || 1 / 0"), 1.0);
+        assert_eq!(evaluate("!0 + 1"), 2.0);
+        assert_eq!(evaluate("!(hp == 10)"), 0.0);
+        assert_eq!(evaluate("hp != 10"), 0.0);
+        assert_eq!(evaluate("hp <= 10 && hp < 11 && hp > 9"), 1.0);
+        // dividing by 0 gives 0 instead of inf/NaN
+        assert_eq!(evaluate("hp / 0 + hp % 0"), 0.0);
+    }
+
+    #[test]
+    fn unary_minus_binds_tighter_than_everything_else() {
+        assert_eq!(evaluate("-3 + 5"), 2.0);
+        assert_eq!(evaluate("- -3"), 3.0);
+        assert_eq!(evaluate("5--3"), 8.0);
+        assert_eq!(evaluate("2 * -hp"), -20.0);
+        assert_eq!(evaluate("-hp * 2"), -20.0);
+        assert_eq!(evaluate("-(1 + 2) * 2"), -6.0);
+        assert_eq!(evaluate("-delta(score)"), 50.0);
+    }
+
+    #[test]
+    fn prev_and_delta_look_at_the_previous_frame() {
+        assert_eq!(evaluate("prev(hp)"), 4.0);
+        assert_eq!(evaluate("delta(hp)"), 6.0);
+        assert_eq!(evaluate("delta( score ) * 2"), -100.0);
+        assert_eq!(evaluate("hp - prev(hp) == delta(hp)"), 1.0);
+        // not called, so it's the variable
+        assert_eq!(evaluate("delta + 1"), 8.0);
+        assert_eq!(parse("delta(").unwrap_err().message, "Expected a variable name");
+        assert_eq!(parse("prev(hp").unwrap_err().message, "Expected )");
+        assert_eq!(parse("prev(lives)").unwrap_err().message, "Unknown variable lives");
+    }
+
+    #[test]
+    fn numbers_can_be_hex_or_floats() {
+        assert_eq!(evaluate("0x10 + 0XfF"), 271.0);
+        assert_eq!(evaluate("0x0203A9A0"), 33794464.0);
+        assert_eq!(evaluate("1.5 * 2"), 3.0);
+        assert_eq!(evaluate(".5"), 0.5);
+        assert_eq!(parse("0x").unwrap_err(), ExpressionError { message: "Invalid hex number".to_string(), position: 0 });
+        assert_eq!(parse("1 + 1.2.3").unwrap_err(), ExpressionError { message: "Invalid number".to_string(), position: 4 });
+    }
+
+    #[test]
+    fn bad_expressions_say_where() {
+        assert_eq!(
+            parse("hp + lives").unwrap_err(),
+            ExpressionError { message: "Unknown variable lives".to_string(), position: 5 }
+        );
+        assert_eq!(
+            parse("hp 2").unwrap_err(),
+            ExpressionError { message: "Unexpected trailing characters".to_string(), position: 3 }
+        );
+        assert_eq!(parse("hp)").unwrap_err().message, "Unexpected trailing characters");
+        assert_eq!(parse("(hp + 1").unwrap_err().message, "Expected )");
+        assert_eq!(parse("hp +").unwrap_err().message, "Unexpected end of expression");
+        assert_eq!(parse("").unwrap_err().message, "Unexpected end of expression");
+        assert_eq!(parse("hp # 2").unwrap_err().message, "Unexpected trailing characters");
+        assert_eq!(parse("hp + #").unwrap_err(), ExpressionError { message: "Unexpected character".to_string(), position: 5 });
+        assert_eq!(parse("hp == == 2").unwrap_err().message, "Unexpected character");
+    }
+}
@@ -1,269 +1,466 @@
 // FFI for critical C API's -- look over ../../server.c
+// Core is the only thing that touches the raw mCore, everything outside of mgba_ffi goes through its safe methods
 
 #[allow(non_snake_case,improper_ctypes_definitions,non_upper_case_globals,non_camel_case_types,dead_code,unreachable_code)]
 pub mod mgba_bindings;
+
+// Pull an entry out of the core's vtable, a libmgba build that leaves it empty gives $error instead of a crash
+macro_rules! core_fn {
+    ($core:expr, $entry:ident, $error:path) => {{
+        let core: *mut $crate::agent_stuff::mgba_ffi::mgba_bindings::mCore = $core;
+        unsafe { (*core).$entry }.ok_or($error(stringify!($entry)))
+    }};
+}
+
+mod library;
+mod logging;
+mod memory;
+use library::Library;
+use logging::CoreLogger;
+pub use library::LibraryError;
+pub use logging::LogConfig;
+pub use memory::{block_address, MemoryBlock, MemoryError};
 use mgba_bindings::{
-    mgba, mCore, mLogger,
-    BYTES_PER_PIXEL, mLogLevel,
-    __va_list_tag, mCoreConfig,
-    VFile,
+    mgba, mCore,
+    mCoreConfig,
+    VFile, color_t,
 };
 
-use std::io::prelude::*;
-use std::net::TcpListener;
-
 // a couple of redefinitions? had to comment out
 // bindgen ./wrapper.h -- -I../../../../../include > ../src/mgba_ffi/mgba_bindings.rs
 use std::ffi;
+use std::fmt;
+use std::sync::Arc;
 
 
 use super::{
     GameConfigData,
-    observation::{
-        ObservationData,
-        FrameBuffer
-    }
+    RngSeed,
 };
-use sdl2::pixels::PixelFormatEnum;
-
-// pub fn spawn_server(port_listen: u16, rom_path: String) {
-pub fn spawn_server(port_listen: u16, game_config_data: &GameConfigData,) {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port_listen)).unwrap();
-    // spawn a tcp connection
-
-    // this loop stays alive forever? Whatever for now, only expect once
-    println!("Waitin for connection at {}", port_listen);
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        println!("Connection established! Stream = {:?}", stream);
-        // get the core/observation data thingy
-        let observation_data: ObservationData;
-        let core_data: CoreData;
-        (observation_data,core_data) = init_core(game_config_data);
-
-        // send over socket
-        stream.write(&observation_data.frame_buffer.width.to_be_bytes()).expect("Error writing to tcp socket stream 1");
-        stream.write(&observation_data.frame_buffer.height.to_be_bytes()).expect("Error writing to tcp socket stream 2");
-        stream.write(&observation_data.frame_buffer.bpp.to_be_bytes()).expect("Error writing to tcp socket stream 3");
-        
-        // ##########################################################################################
-        // mgba STEADY STATE phase
-        // ##########################################################################################
-        let mut client_keycode_message_buffer = [0_u8; 2];
-
-        // let server_stream_buffer = unsafe {
-        //     std::slice::from_raw_parts(
-        //         &mut observation_data.frame_buffer.frame_data[..] as *mut u8,
-        //         observation_data.frame_buffer.get_size().try_into().unwrap(),
-        //     )
-        // };
-        
-        loop {
-            // thread::sleep(Duration::from_millis(2000));
-            stream
-                .read_exact(&mut client_keycode_message_buffer)
-                .expect("Error reading client keycode from stream");
-            let client_keycode = u16::from_be_bytes(client_keycode_message_buffer);
-
-            unsafe { execute_core_cycle(&core_data,client_keycode) };
-
-            // stream.write(server_stream_buffer).expect("Error writing to tcp socket stream 0");
-            stream.write(&observation_data.frame_buffer.frame_data[..]).expect("Error writing to tcp socket stream 0");
+
+#[derive(Debug)]
+pub enum CoreError {
+    Library(LibraryError), // no libmgba, or not one the bindings fit
+    InvalidPath(String),
+    NoCore(String),     // mCoreFind doesn't know what the ROM is
+    OtherPlatform(String), // load_game with a ROM the core's platform can't run
+    InitFailed,
+    RomLoad { rom_path: String, errors: Vec<String> }, // errors: what mgba logged about it
+    MissingEntry(&'static str), // mCore vtable entry this libmgba doesn't fill in
+    FileOpen(String),
+    StateLoad { path: String, errors: Vec<String> },
+    StateSize { expected: usize, found: usize },
+    SnapshotFailed,
+    StateRejected,
+    Seed(String),
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Library(error) => write!(f, "{}", error),
+            Self::InvalidPath(path) => write!(f, "{} can't be passed to mgba (contains a nul byte)", path),
+            Self::NoCore(rom_path) => write!(f, "No mgba core can run {}", rom_path),
+            Self::OtherPlatform(rom_path) => write!(f, "{} is for another platform than this core, it needs a new one", rom_path),
+            Self::InitFailed => write!(f, "mCore::init failed"),
+            Self::RomLoad { rom_path, errors } => {
+                write!(f, "mgba failed to load {}", rom_path)?;
+                write_log_errors(f, errors)
+            },
+            Self::MissingEntry(entry) => write!(f, "This libmgba's mCore has no {}", entry),
+            Self::FileOpen(path) => write!(f, "mgba failed to open {}", path),
+            Self::StateLoad { path, errors } => {
+                write!(f, "mgba failed to load the save state {}", path)?;
+                write_log_errors(f, errors)
+            },
+            Self::StateSize { expected, found } => {
+                write!(f, "State is {} bytes, core expects {}", found, expected)
+            },
+            Self::SnapshotFailed => write!(f, "mCore::saveState failed to snapshot the core"),
+            Self::StateRejected => write!(f, "mCore::loadState rejected the state"),
+            Self::Seed(reason) => write!(f, "Failed to seed the emulator: {}", reason),
         }
     }
 }
 
-pub fn init_core(
-    game_config_data: &GameConfigData,
-) -> (ObservationData, CoreData) {
-    
-    let libmgba_so_path = ffi::OsStr::new("libmgba.so.0.11");
-    // load in dynamic library!
-    let loaded_mgba_lib = unsafe {
-        mgba::new(libmgba_so_path).expect("Error loading in dynamic libmgba.so lib")
-    };
-
-    let rom_path = ffi::CString::new(game_config_data.rom_path.as_str()).expect("Could not convert ROM path");
-    let mut sav_path = game_config_data.rom_path.clone();
-    let truncate_len = if sav_path.find(".gba").is_some() {3} else {2};
-    sav_path.truncate(sav_path.len()-truncate_len);
-    sav_path+="sav";
-    // println!("sav_path: {}",sav_path);
-    let sav_path = ffi::CString::new(sav_path.as_str()).expect("naur");
-        // .truncate(game_config_data.rom_path.len()-3)+"sav";
-    // save path
-
-    let idle_optimization = ffi::CString::new("idleOptimization").expect("CString::new failed");
-    let detect = ffi::CString::new("detect").expect("CString::new failed");
-    let sgb_borders = ffi::CString::new("sgb.borders").expect("");
-    // let log_level = ffi::CString::new("logLevel").expect("");
-
-    let mut allocated_width: ffi::c_uint = 0; // = 0_u32;
-    let mut allocated_height: ffi::c_uint = 0; // = 0_u32;
-    
-    let mut observation_data: ObservationData;
-    // begin unsafe C API section
-    let core_data: CoreData = unsafe {
-        // struct mLogger logger = { .log = _log };
-        // let logger: Box<mgba_bindings::mLogger> =
-        //     Box::new_zeroed();
-        let mut logger: Box<mgba_bindings::mLogger> =
-            Box::new(mgba_bindings::mLogger {
-                log:Some(log_handler),
-                filter:std::ptr::null::<*mut mgba_bindings::mLogFilter>() as *mut mgba_bindings::mLogFilter,
-            });
-        
-        loaded_mgba_lib.mLogSetThreadLogger(&mut *logger as *mut mgba_bindings::mLogger);
-        
-        let core: *mut mgba_bindings::mCore = loaded_mgba_lib.mCoreFind(rom_path.as_ptr());
-        if core.is_null() {
-            panic!("We have no core, abort mission!!");
+impl std::error::Error for CoreError {}
+
+fn write_log_errors(f: &mut fmt::Formatter<'_>, errors: &[String]) -> fmt::Result {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => write!(f, " ({})", errors.join(", ")),
+    }
+}
+
+// game.gba, game.gb, game.gbc (or just game) all keep their battery save next to them as game.sav
+fn save_path(rom_path: &str) -> std::path::PathBuf {
+    std::path::Path::new(rom_path).with_extension("sav")
+}
+
+fn c_string(path: &str) -> Result<ffi::CString, CoreError> {
+    ffi::CString::new(path).map_err(|_| CoreError::InvalidPath(path.to_string()))
+}
+
+// A VFile we opened ourselves, closed again when it goes out of scope
+struct VFileHandle(*mut VFile);
+
+impl VFileHandle {
+    fn open(mgba_lib: &mgba, path: &str, flags: u32) -> Result<Self, CoreError> {
+        let c_path = c_string(path)?;
+        let vf = unsafe { mgba_lib.VFileOpen(c_path.as_ptr(), flags as i32) };
+        match vf.is_null() {
+            true => Err(CoreError::FileOpen(path.to_string())),
+            false => Ok(Self(vf)),
         }
-        
-        (*core).init.unwrap()(core);
-
-        loaded_mgba_lib.mCoreLoadFile(core, rom_path.as_ptr());
-        
-        loaded_mgba_lib.mCoreConfigInit(&mut (*core).config, std::ptr::null());
-
-        loaded_mgba_lib.mCoreConfigLoad(&mut (*core).config);
-
-        // apply arguments, mArgumentsApply,mCoreConfigSetDefaultValue,
-        
-        loaded_mgba_lib.mCoreConfigSetDefaultValue(
-            &mut (*core).config,
-            idle_optimization.as_ptr(),
-            detect.as_ptr(),
-        );
-        // mCoreConfigSetDefaultIntValue(&m_config, "sgb.borders", 1);
-        
-        loaded_mgba_lib.mCoreConfigSetDefaultIntValue(
-            &mut (*core).config,
-            sgb_borders.as_ptr(),
-            0,
-        );
-        
-        loaded_mgba_lib.mCoreLoadConfig(core);
-
-        (*core).currentVideoSize.unwrap()(core, &mut allocated_width, &mut allocated_height); // idk how to get a mutable raw pointer reference?
-        
-        // need to supply a frame buffer
-
-        let pixel_format: PixelFormatEnum = match BYTES_PER_PIXEL {
-            2 => PixelFormatEnum::RGB565,
-            4 => PixelFormatEnum::ABGR8888,
-            _ => panic!("This is not documented"),
-        };
+    }
+
+    // hand the file over to someone that closes it themselves
+    fn into_raw(self) -> *mut VFile {
+        let vf = self.0;
+        std::mem::forget(self);
+        vf
+    }
+}
+
+impl Drop for VFileHandle {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(close) = (*self.0).close {
+                close(self.0);
+            }
+        }
+    }
+}
+
+// would need to make bindings for serialize.h at least in addition
+// but for now just defining here
+#[allow(dead_code)]
+pub mod savestate_flags {
+    pub const SCREENSHOT: u32 = 1;
+    pub const SAVEDATA: u32 = 2;
+    pub const CHEATS: u32 = 4;
+    pub const RTC: u32 = 8;
+    pub const METADATA: u32 = 16;
+    pub const ALL: u32 = 31;
+}
+
+// Everything that lives as long as the mCore does: the library it came from, the logger mgba points at
+// and the video buffer it draws into. The save file belongs to the core itself once loadSave has it
+// The game on it can change (load_game), the library and mCore stay until the Core is dropped
+pub struct Core {
+    core: *mut mCore,
+    mgba_lib: Arc<Library>,
+    // mgba's log messages go through this into the log crate, see logging.rs
+    logger: Box<CoreLogger>,
+    // mgba draws every frame in here, only replaced (and handed to mgba again) if a game changes the frame size
+    video_buffer: Vec<color_t>,
+    width: u32,
+    height: u32,
+    rom_loaded: bool,
+    // state and frame right after the last load_game, restart() goes back to these
+    start_state: Vec<u8>,
+    start_frame: Vec<color_t>,
+    // listMemoryBlocks of the loaded ROM, every memory access is checked against these
+    memory_blocks: Vec<MemoryBlock>,
+}
+
+impl Core {
+    /// Get libmgba (loaded once per process, see library.rs for where from), find a core for the ROM,
+    /// configure it and load_game the game
+    pub fn new(game_config_data: &GameConfigData) -> Result<Self, CoreError> {
+        let mgba_lib = library::shared_library(game_config_data.libmgba_path.as_deref()).map_err(CoreError::Library)?;
 
-        observation_data = ObservationData {
-            // This will be overwritten
-            frame_buffer: FrameBuffer::new(
-                allocated_width,
-                allocated_height,
-                BYTES_PER_PIXEL,pixel_format
-            ),
-            keycode_data: 0_u16,
+        let rom_path = c_string(&game_config_data.rom_path)?;
+        let idle_optimization = ffi::CString::new("idleOptimization").expect("CString::new failed");
+        let detect = ffi::CString::new("detect").expect("CString::new failed");
+        let sgb_borders = ffi::CString::new("sgb.borders").expect("");
+
+        // logs from mCoreFind on already go through our logger and its filter
+        let logger = CoreLogger::new(Arc::clone(&mgba_lib), &game_config_data.mgba_log)?;
+        let core = unsafe {
+            mgba_lib.mLogSetThreadLogger(logger.mlogger());
+            mgba_lib.mCoreFind(rom_path.as_ptr())
+        };
+        let init_error = match core.is_null() {
+            true => Some(CoreError::NoCore(game_config_data.rom_path.clone())),
+            // a core we can't trust the layout of gets leaked rather than deinit'ed
+            false => match library::check_vtable(&mgba_lib, core) {
+                Err(e) => Some(CoreError::Library(e)),
+                Ok(()) => match core_fn!(core, init, CoreError::MissingEntry) {
+                    Ok(init) => (!unsafe { init(core) }).then_some(CoreError::InitFailed),
+                    Err(e) => Some(e),
+                },
+            },
         };
-        
-        (*core).setVideoBuffer.unwrap()(
+        if let Some(e) = init_error {
+            // nothing to deinit yet, but mgba mustn't keep a logger we're about to free
+            unsafe { mgba_lib.mLogSetThreadLogger(std::ptr::null_mut()) };
+            return Err(e);
+        }
+        // from here on Drop cleans up after us, config included
+        unsafe { mgba_lib.mCoreConfigInit(&mut (*core).config, std::ptr::null()) };
+        let mut core = Self {
             core,
-            observation_data.frame_buffer.frame_data.as_mut_ptr() as *mut u32,
-            allocated_width.try_into().unwrap(),
-        );
-
-        // So, not even keeping track of this
-        // Seems as if the core deinit'ing will close the file
-        let save_file = Box::new(loaded_mgba_lib.VFileOpen(
-            sav_path.as_ptr(),
-            mgba_bindings::O_RDWR as i32,
-        ));
-        (*core).loadSave.unwrap()(core,&mut *(*save_file) as *mut VFile);
+            mgba_lib,
+            logger,
+            video_buffer: vec![],
+            width: 0,
+            height: 0,
+            rom_loaded: false,
+            start_state: vec![],
+            start_frame: vec![],
+            memory_blocks: vec![],
+        };
+
+        unsafe {
+            let config = &mut (*core.core).config;
+            core.mgba_lib.mCoreConfigLoad(config);
+            // apply arguments, mArgumentsApply,mCoreConfigSetDefaultValue,
+            core.mgba_lib.mCoreConfigSetDefaultValue(config, idle_optimization.as_ptr(), detect.as_ptr());
+            // mCoreConfigSetDefaultIntValue(&m_config, "sgb.borders", 1);
+            core.mgba_lib.mCoreConfigSetDefaultIntValue(config, sgb_borders.as_ptr(), 0);
+            core.mgba_lib.mCoreLoadConfig(core.core);
+        }
+        core.load_game(game_config_data)?;
+        Ok(core)
+    }
+
+    /// Swap in game_config_data's ROM (or load the same one again) and get it ready to run: save file, reset,
+    /// then save state and RNG seed if game_config_data has them. restart() comes back to where this leaves off
+    /// Much cheaper than a new Core, but the ROM has to be for the same platform (GBA or GB) as the core
+    pub fn load_game(&mut self, game_config_data: &GameConfigData) -> Result<(), CoreError> {
+        let rom_path = c_string(&game_config_data.rom_path)?;
+        let sav_path = save_path(&game_config_data.rom_path);
+
+        // mCoreFind picked the core by platform, a ROM for the other one would need a whole other core
+        let platform = core_fn!(self.core, platform, CoreError::MissingEntry)?;
+        let rom_file = VFileHandle::open(&self.mgba_lib, &game_config_data.rom_path, mgba_bindings::O_RDONLY)?;
+        if unsafe { self.mgba_lib.mCoreIsCompatible(rom_file.0) != platform(self.core) } {
+            return Err(CoreError::OtherPlatform(game_config_data.rom_path.clone()));
+        }
+        drop(rom_file);
 
+        if self.rom_loaded {
+            // closes the ROM and the save file loadSave handed over
+            let unload_rom = core_fn!(self.core, unloadROM, CoreError::MissingEntry)?;
+            unsafe { unload_rom(self.core) };
+            self.rom_loaded = false;
+        }
+        if !unsafe { self.mgba_lib.mCoreLoadFile(self.core, rom_path.as_ptr()) } {
+            let errors = self.logger.take_errors();
+            return Err(CoreError::RomLoad { rom_path: game_config_data.rom_path.clone(), errors });
+        }
+        self.rom_loaded = true;
+
+        let (width, height) = self.current_video_size()?;
+        if (width, height) != (self.width, self.height) {
+            (self.width, self.height) = (width, height);
+            self.video_buffer = vec![0; (width * height) as usize];
+            let set_video_buffer = core_fn!(self.core, setVideoBuffer, CoreError::MissingEntry)?;
+            unsafe { set_video_buffer(self.core, self.video_buffer.as_mut_ptr(), width as usize) };
+        }
+
+        // no save file yet is fine, the game starts from scratch then
+        if sav_path.exists() {
+            self.load_save(&sav_path.to_string_lossy())?;
+        }
+        // the previous game's rtc_time_ms mustn't stick to this one, seed_rng pins the clock again if this game has one
+        self.set_rtc_override(None);
         // Reset the core. This is needed before it can run.
-        (*core).reset.unwrap()(core);
+        self.reset()?;
 
         // loading a state file if we want to, otherwise load the save file
-        match &game_config_data.save_state_path {
-            Some(path) => {
-                // let x: () = path;
-                let save_state = ffi::CString::new(path.as_str()).expect("Could not convert save_state path");
-                let vf = loaded_mgba_lib.VFileOpen(
-                    save_state.as_ptr(),
-                    mgba_bindings::O_RDONLY as i32,
-                );
-                // would need to make bindings for serialize.h at least in addition
-                // but for now just defining here
-                const _SAVESTATE_SCREENSHOT: u8 = 1;
-                const _SAVESTATE_SAVEDATA: u8 = 2;
-                const _SAVESTATE_CHEATS: u8 = 4;
-                const _SAVESTATE_RTC: u8 = 8;
-                const _SAVESTATE_METADATA: u8 = 16;
-                const _SAVESTATE_ALL: u8 = 31;
-                loaded_mgba_lib.mCoreLoadStateNamed(core,vf,_SAVESTATE_RTC.into());
-                (*vf).close.unwrap()(vf);
-            },
-            None => (),
+        if let Some(save_state_path) = &game_config_data.save_state_path {
+            let save_state = VFileHandle::open(&self.mgba_lib, save_state_path, mgba_bindings::O_RDONLY)?;
+            let loaded = unsafe {
+                self.mgba_lib.mCoreLoadStateNamed(self.core, save_state.0, game_config_data.save_state_flags as i32)
+            };
+            if !loaded {
+                let errors = self.logger.take_errors();
+                return Err(CoreError::StateLoad { path: save_state_path.clone(), errors });
+            }
         }
-        
-        // return the core
-        CoreData::new(core,loaded_mgba_lib,logger,)
-    };
 
-    (observation_data,core_data)
-}
+        self.memory_blocks = memory::list_memory_blocks(self.core);
+
+        // Seed after the save state, otherwise the state would overwrite it
+        if let Some(rng_seed) = &game_config_data.rng_seed {
+            self.seed_rng(rng_seed, rng_seed.seed).map_err(CoreError::Seed)?;
+        }
+        self.start_state = self.snapshot()?;
+        self.start_frame = self.video_buffer.clone();
+        Ok(())
+    }
+
+    /// Size of framebuffer() as the last load_game set it up
+    pub fn frame_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn current_video_size(&self) -> Result<(u32, u32), CoreError> {
+        let current_video_size = core_fn!(self.core, currentVideoSize, CoreError::MissingEntry)?;
+        let (mut width, mut height): (ffi::c_uint, ffi::c_uint) = (0, 0);
+        unsafe { current_video_size(self.core, &mut width, &mut height) };
+        Ok((width, height))
+    }
+
+    /// The last frame mgba drew, BYTES_PER_PIXEL bytes per pixel
+    pub fn framebuffer(&self) -> &[u8] {
+        let len = self.video_buffer.len() * std::mem::size_of::<color_t>();
+        unsafe { std::slice::from_raw_parts(self.video_buffer.as_ptr() as *const u8, len) }
+    }
+
+    pub fn set_keys(&mut self, keycode_data: u16) -> Result<(), CoreError> {
+        let set_keys = core_fn!(self.core, setKeys, CoreError::MissingEntry)?;
+        unsafe { set_keys(self.core, keycode_data.into()) };
+        Ok(())
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), CoreError> {
+        let run_frame = core_fn!(self.core, runFrame, CoreError::MissingEntry)?;
+        unsafe { run_frame(self.core) };
+        Ok(())
+    }
 
-#[inline(always)]
-pub unsafe fn execute_core_cycle(core_data: &CoreData, keycode_data: u16) {
-    // Assumes the core is already setup at this point, that's why it's unsafe
-    (*(core_data.core)).setKeys.unwrap()(core_data.core, keycode_data.into());
-    (*(core_data.core)).runFrame.unwrap()(core_data.core);
+    pub fn reset(&mut self) -> Result<(), CoreError> {
+        let reset = core_fn!(self.core, reset, CoreError::MissingEntry)?;
+        unsafe { reset(self.core) };
+        Ok(())
+    }
+
+    /// Open the save file at path and give it to the core, which closes it when it's done with the ROM
+    pub fn load_save(&mut self, path: &str) -> Result<(), CoreError> {
+        let load_save = core_fn!(self.core, loadSave, CoreError::MissingEntry)?;
+        let save_file = VFileHandle::open(&self.mgba_lib, path, mgba_bindings::O_RDWR)?;
+        if !unsafe { load_save(self.core, save_file.0) } {
+            return Err(CoreError::FileOpen(path.to_string()));
+        }
+        save_file.into_raw();
+        Ok(())
+    }
+
+    /// Back to the state the last load_game left the core in, framebuffer() shows the frame from back then too
+    /// Cheap compared to tearing down and re-initializing the core, so episodes reset with this
+    pub fn restart(&mut self) -> Result<(), CoreError> {
+        // restore needs the core mutably, so the state is moved out for the call and put back whatever happens
+        let start_state = std::mem::take(&mut self.start_state);
+        let restored = self.restore(&start_state);
+        self.start_state = start_state;
+        restored?;
+        // the core only draws into its buffer on the next frame
+        self.video_buffer.copy_from_slice(&self.start_frame);
+        Ok(())
+    }
+
+    /// FATAL/ERROR messages mgba logged since the last call, the core may not be emulating properly after one
+    pub fn take_errors(&mut self) -> Vec<String> {
+        self.logger.take_errors()
+    }
+
+    pub fn frame_counter(&self) -> Result<u32, CoreError> {
+        let frame_counter = core_fn!(self.core, frameCounter, CoreError::MissingEntry)?;
+        Ok(unsafe { frame_counter(self.core) })
+    }
 }
 
-// all of these lifetimes need to correspond with agent
-pub struct CoreData {
-    // Can create a drop for mCore that would call its deinit things.
-    // Would also need to be packaged with the bindings themselves,
-    // as it requires a library call to deinig a core->config
-    // Not going to worry about organizing this better right now, @TODO!
-    pub core: *mut mCore,
-    // These 2 only needed as pub for constructor
-    mgba_lib: mgba,
-    // Data about the logger, needed such that stdout doesn't get flooded with mgba logs
-    _logger: Box<mLogger>,
+impl Core {
+    /// Serialize the whole emulator state into memory (mCore::saveState)
+    pub fn snapshot(&self) -> Result<Vec<u8>, CoreError> {
+        let state_size = core_fn!(self.core, stateSize, CoreError::MissingEntry)?;
+        let save_state = core_fn!(self.core, saveState, CoreError::MissingEntry)?;
+        let mut state = vec![0_u8; unsafe { state_size(self.core) }];
+        if !unsafe { save_state(self.core, state.as_mut_ptr() as *mut ffi::c_void) } {
+            return Err(CoreError::SnapshotFailed);
+        }
+        Ok(state)
+    }
+
+    /// Load a state produced by snapshot() back into the core (mCore::loadState)
+    /// Errors if the state doesn't fit this core or mgba rejects it
+    pub fn restore(&mut self, state: &[u8]) -> Result<(), CoreError> {
+        let state_size = core_fn!(self.core, stateSize, CoreError::MissingEntry)?;
+        let load_state = core_fn!(self.core, loadState, CoreError::MissingEntry)?;
+        // loadState reads stateSize bytes no matter what we hand it
+        let expected = unsafe { state_size(self.core) };
+        if state.len() != expected {
+            return Err(CoreError::StateSize { expected, found: state.len() });
+        }
+        match unsafe { load_state(self.core, state.as_ptr() as *const ffi::c_void) } {
+            true => Ok(()),
+            false => Err(CoreError::StateRejected),
+        }
+    }
 }
 
-impl CoreData {
-    pub fn new(
-        core: *mut mCore,
-        mgba_lib: mgba,
-        _logger: Box<mLogger>,
-    ) -> Self {
-        Self {core,mgba_lib,_logger,}
+impl Core {
+    /// Make the next frames reproducible: write seed into the game's RNG state and pin the RTC
+    /// Same seed + same inputs from the same save state gives the same frames
+    pub fn seed_rng(&mut self, rng_seed: &RngSeed, seed: u32) -> Result<(), String> {
+        if rng_seed.rtc_time_ms.is_some() {
+            self.set_rtc_override(rng_seed.rtc_time_ms);
+        }
+        if let Some(rng_address) = &rng_seed.rng_address {
+            let rng_address = rng_address.value()?;
+            match rng_seed.rng_width {
+                1 => self.write_u8(rng_address, seed as u8),
+                2 => self.write_u16(rng_address, seed as u16),
+                4 => self.write_u32(rng_address, seed),
+                width => return Err(format!("rng_width is {}, must be 1, 2 or 4", width)),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Pin the RTC to rtc_time_ms, None hands it back to the wall clock
+    fn set_rtc_override(&mut self, rtc_time_ms: Option<i64>) {
+        // core->rtc is the mRTCGenericSource the core reads time from, RTC_FIXED makes it return value/1000
+        let (override_, value) = match rtc_time_ms {
+            Some(rtc_time_ms) => (mgba_bindings::mRTCGenericType_RTC_FIXED, rtc_time_ms),
+            None => (mgba_bindings::mRTCGenericType_RTC_NO_OVERRIDE, 0),
+        };
+        unsafe {
+            (*(self.core)).rtc.override_ = override_;
+            (*(self.core)).rtc.value = value;
+        }
     }
 }
 
-impl Drop for CoreData {
+impl Drop for Core {
     fn drop(&mut self) {
         // printing just for debugging purposes, like did we actually drop our memory?
-        println!("Dropping some CoreData!");
+        match self.frame_counter() {
+            Ok(frames) => println!("Dropping a core after {} frames", frames),
+            Err(_) => println!("Dropping a core"),
+        }
         // run mGBA deinit process
         unsafe {
-            // Deinitialization associated with the core.
+            // Deinitialization associated with the core, closes the save file too
             self.mgba_lib.mCoreConfigDeinit(&mut (*(self.core)).config as *mut mCoreConfig);
-            (*(self.core)).deinit.unwrap()(self.core);
+            if let Some(deinit) = (*(self.core)).deinit {
+                deinit(self.core);
+            }
+            // mgba would keep logging to our logger after it's freed, unless another core took over this thread since
+            if std::ptr::eq(self.mgba_lib.mLogGetThreadLogger(), self.logger.mlogger()) {
+                self.mgba_lib.mLogSetThreadLogger(std::ptr::null_mut());
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
 
-#[no_mangle]
-pub extern "C" fn log_handler(
-    _arg1: *mut mLogger,
-    _category: ::std::os::raw::c_int,
-    _level: mLogLevel,
-    _format: *const ::std::os::raw::c_char,
-    _args: *mut __va_list_tag,
-) {
-    // println!("WAAAAH");
-    // Do nothing!
-}
\ No newline at end of file
+    #[test]
+    fn save_file_sits_next_to_the_rom() {
+        assert_eq!(save_path("roms/game.gba"), Path::new("roms/game.sav"));
+        assert_eq!(save_path("roms/game.gb"), Path::new("roms/game.sav"));
+        assert_eq!(save_path("roms/game.gbc"), Path::new("roms/game.sav"));
+        assert_eq!(save_path("roms.gba/game"), Path::new("roms.gba/game.sav"));
+        // short paths (anything a remote client sends) can't underflow
+        assert_eq!(save_path("a"), Path::new("a.sav"));
+        assert_eq!(save_path(""), Path::new(""));
+    }
+}
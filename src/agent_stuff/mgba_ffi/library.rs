@@ -0,0 +1,269 @@
+// Finding libmgba and making sure the bindings fit it
+// mgba_bindings.rs was generated from the 0.11 headers, mCore (vtable included) only has that layout in 0.11.x builds,
+// calling into any other build through it would be undefined behavior, so those get a LibraryError instead
+// The library comes from GameConfigData::libmgba_path, then $MGBA_LIB_PATH, then LIBMGBA_SEARCH_LIST
+// and is loaded once per process, every Core after the first one shares it
+
+use std::ffi::{self, CStr};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use super::mgba_bindings::{
+    mgba, mCore,
+    mPlatform_mPLATFORM_GB, mPlatform_mPLATFORM_GBA,
+};
+
+pub const LIBMGBA_ENV_VAR: &str = "MGBA_LIB_PATH";
+
+// major.minor of the headers mgba_bindings.rs came from
+pub const BINDINGS_VERSION: &str = "0.11";
+
+// bare names go through the dynamic loader's search (LD_LIBRARY_PATH, ld.so.cache), then the usual install spots
+const LIBMGBA_SEARCH_LIST: &[&str] = &[
+    "libmgba.so.0.11",
+    "libmgba.so",
+    "/usr/local/lib/libmgba.so.0.11",
+    "/usr/lib/libmgba.so.0.11",
+    "/usr/lib/x86_64-linux-gnu/libmgba.so.0.11",
+];
+
+#[derive(Debug)]
+pub enum LibraryError {
+    Load { path: String, reason: String },
+    NotFound(Vec<(String, String)>), // every search list entry and why it didn't load
+    Version { path: String, found: String },
+    MissingSymbols { path: String, symbols: Vec<&'static str> },
+    Vtable { entry: &'static str, reason: &'static str }, // the mCore mCoreFind handed back doesn't look like ours
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Load { path, reason } => write!(f, "Failed to load libmgba from {}: {}", path, reason),
+            Self::NotFound(tried) => {
+                write!(f, "No libmgba found, set libmgba_path in game_config_data or {}. Tried:", LIBMGBA_ENV_VAR)?;
+                for (path, reason) in tried {
+                    write!(f, "\n    {}: {}", path, reason)?;
+                }
+                Ok(())
+            },
+            Self::Version { path, found } => write!(
+                f,
+                "{} is libmgba {}, but the bindings are for {}.x (mCore's layout changes between versions)",
+                path, found, BINDINGS_VERSION
+            ),
+            Self::MissingSymbols { path, symbols } => {
+                write!(f, "{} doesn't export {}", path, symbols.join(", "))
+            },
+            Self::Vtable { entry, reason } => write!(
+                f,
+                "mCore::{} {}, this libmgba's mCore doesn't match the bindings' layout",
+                entry, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LibraryError {}
+
+/// Which libmgba got loaded, printed when a core starts
+#[derive(Clone, Debug)]
+pub struct LibraryInfo {
+    pub path: String,
+    pub version: String,
+    pub git_commit: String,
+}
+
+impl fmt::Display for LibraryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "libmgba {} ({}) from {}", self.version, self.git_commit, self.path)
+    }
+}
+
+/// A loaded libmgba, derefs to the generated bindings
+pub struct Library {
+    mgba_lib: mgba,
+    pub info: LibraryInfo,
+}
+
+impl Deref for Library {
+    type Target = mgba;
+
+    fn deref(&self) -> &mgba {
+        &self.mgba_lib
+    }
+}
+
+// Libraries loaded so far, by the explicit path they were asked for (None = the search list)
+// Never unloaded, mgba keeps pointers into them (thread loggers, static core tables) around anyway
+static LOADED_LIBRARIES: Mutex<Vec<(Option<String>, Arc<Library>)>> = Mutex::new(vec![]);
+
+/// The process wide libmgba for configured_path, loaded (and checked) on first use
+/// configured_path, then $MGBA_LIB_PATH, then the search list, see load_library
+pub fn shared_library(configured_path: Option<&str>) -> Result<Arc<Library>, LibraryError> {
+    let explicit_path = configured_path
+        .map(str::to_string)
+        .or_else(|| std::env::var(LIBMGBA_ENV_VAR).ok().filter(|path| !path.is_empty()));
+    // a failed load isn't remembered, the next core gets to try again
+    let mut loaded_libraries = LOADED_LIBRARIES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, library)) = loaded_libraries.iter().find(|(path, _)| *path == explicit_path) {
+        return Ok(Arc::clone(library));
+    }
+    let library = Arc::new(load_library(explicit_path.as_deref())?);
+    println!("Loaded {}", library.info);
+    loaded_libraries.push((explicit_path, Arc::clone(&library)));
+    Ok(library)
+}
+
+/// An explicit path is the only one tried, a search list entry that loads
+/// but doesn't fit the bindings is an error rather than a reason to keep looking
+fn load_library(explicit_path: Option<&str>) -> Result<Library, LibraryError> {
+    if let Some(path) = explicit_path {
+        return open_library(path);
+    }
+    let mut tried = vec![];
+    for path in LIBMGBA_SEARCH_LIST {
+        match open_library(path) {
+            Err(LibraryError::Load { path, reason }) => tried.push((path, reason)),
+            found => return found,
+        }
+    }
+    Err(LibraryError::NotFound(tried))
+}
+
+fn open_library(path: &str) -> Result<Library, LibraryError> {
+    let load_error = |e: libloading::Error| LibraryError::Load { path: path.to_string(), reason: e.to_string() };
+    let library = unsafe { libloading::Library::new(path) }.map_err(load_error)?;
+    let info = LibraryInfo {
+        path: path.to_string(),
+        version: exported_string(&library, b"projectVersion\0").unwrap_or_else(|| "unknown".to_string()),
+        git_commit: exported_string(&library, b"gitCommitShort\0").unwrap_or_else(|| "unknown".to_string()),
+    };
+    if !version_matches(&info.version) {
+        return Err(LibraryError::Version { path: info.path, found: info.version });
+    }
+    let mgba_lib = unsafe { mgba::from_library(library) }.map_err(load_error)?;
+    let symbols = missing_symbols(&mgba_lib);
+    if !symbols.is_empty() {
+        return Err(LibraryError::MissingSymbols { path: info.path, symbols });
+    }
+    Ok(Library { mgba_lib, info })
+}
+
+// libmgba's version.c strings are `const char* const`, so the symbol is the address of the pointer
+fn exported_string(library: &libloading::Library, symbol: &[u8]) -> Option<String> {
+    unsafe {
+        let string_address = library.get::<*const *const ffi::c_char>(symbol).ok()?;
+        let string = **string_address;
+        (!string.is_null()).then(|| CStr::from_ptr(string).to_string_lossy().into_owned())
+    }
+}
+
+// "0.11.0", "0.11.1" and dev builds like "0.11-8123-abcdef0" all fit, "0.10.3" or "0.110" don't
+fn version_matches(version: &str) -> bool {
+    version
+        .strip_prefix(BINDINGS_VERSION)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '-']))
+}
+
+// Every library function Core calls, mgba's generated wrappers would only panic on the first call to a missing one
+fn missing_symbols(mgba_lib: &mgba) -> Vec<&'static str> {
+    macro_rules! missing {
+        ($($symbol:ident),* $(,)?) => {
+            [$((stringify!($symbol), mgba_lib.$symbol.is_ok())),*]
+                .into_iter()
+                .filter(|(_, found)| !found)
+                .map(|(symbol, _)| symbol)
+                .collect()
+        };
+    }
+    missing!(
+        VFileOpen,
+        mCoreConfigDeinit,
+        mCoreConfigInit,
+        mCoreConfigLoad,
+        mCoreConfigSetDefaultIntValue,
+        mCoreConfigSetDefaultValue,
+        mCoreFind,
+        mCoreLoadConfig,
+        mCoreLoadFile,
+        mCoreLoadStateNamed,
+        mLogGetThreadLogger,
+        mLogSetThreadLogger,
+        mCoreIsCompatible,
+        mLogCategoryName,
+        mLogFilterInit,
+        mLogFilterDeinit,
+        mLogFilterSet,
+        vsnprintf,
+    )
+}
+
+/// Call right after mCoreFind, before anything in the vtable runs
+/// A libmgba with a different mCore layout puts other fields where we look for these entries,
+/// so each one has to be filled in and point at code inside libmgba, and platform() has to make sense
+pub fn check_vtable(mgba_lib: &mgba, core: *mut mCore) -> Result<(), LibraryError> {
+    macro_rules! entries {
+        ($($entry:ident),* $(,)?) => {
+            [$((stringify!($entry), unsafe { (*core).$entry }.map(|entry| entry as *const ffi::c_void))),*]
+        };
+    }
+    let entries = entries!(
+        init, deinit, platform,
+        setVideoBuffer, currentVideoSize, setKeys, runFrame, reset, frameCounter,
+        loadSave, unloadROM, stateSize, saveState, loadState,
+        busRead8, busRead16, busRead32, busWrite8, busWrite16, busWrite32,
+        listMemoryBlocks, getMemoryBlock,
+    );
+    // mgba_lib only exists once mCoreFind loaded, so this one is always there
+    let library_base = shared_object_base(*mgba_lib.mCoreFind.as_ref().unwrap() as *const ffi::c_void);
+    for (entry, function) in entries {
+        let function = function.ok_or(LibraryError::Vtable { entry, reason: "is empty" })?;
+        if library_base.is_none() || shared_object_base(function) != library_base {
+            return Err(LibraryError::Vtable { entry, reason: "doesn't point into libmgba" });
+        }
+    }
+    let platform = unsafe { (*core).platform.unwrap()(core) };
+    if platform != mPlatform_mPLATFORM_GBA && platform != mPlatform_mPLATFORM_GB {
+        return Err(LibraryError::Vtable { entry: "platform", reason: "returned an unknown platform" });
+    }
+    Ok(())
+}
+
+// Load address of the shared object that address lies in, None if it isn't inside one
+fn shared_object_base(address: *const ffi::c_void) -> Option<usize> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    match unsafe { libc::dladdr(address, &mut info) } {
+        0 => None,
+        _ => Some(info.dli_fbase as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_bindings_version_builds_fit() {
+        assert!(version_matches("0.11"));
+        assert!(version_matches("0.11.0"));
+        assert!(version_matches("0.11-8123-abcdef0"));
+        assert!(!version_matches("0.10.3"));
+        assert!(!version_matches("0.110"));
+        assert!(!version_matches("unknown"));
+    }
+
+    #[test]
+    fn wrong_libraries_are_errors() {
+        match shared_library(Some("/nonexistent/libmgba.so.0.11")) {
+            Err(LibraryError::Load { path, .. }) => assert_eq!(path, "/nonexistent/libmgba.so.0.11"),
+            other => panic!("expected a load error, got {:?}", other.map(|library| library.info.clone())),
+        }
+        // loads fine, but it's not a libmgba
+        match shared_library(Some("libc.so.6")) {
+            Err(LibraryError::Version { found, .. }) => assert_eq!(found, "unknown"),
+            other => panic!("expected a version error, got {:?}", other.map(|library| library.info.clone())),
+        }
+    }
+}
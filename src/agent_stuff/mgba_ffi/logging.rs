@@ -0,0 +1,202 @@
+// mgba's log output, into the log crate instead of nowhere
+// Every Core makes a CoreLogger its thread logger: mgba checks the mLogFilter (per category levels from LogConfig)
+// and only then calls log_handler, which formats the message and hands it to log:: with the "mgba" target
+// FATAL/ERROR messages are kept as well, so the agent can tell an episode ran on a core that was in trouble
+
+use std::collections::BTreeMap;
+use std::ffi::{self, CStr};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use super::library::Library;
+use super::mgba_bindings::{
+    mLogFilter, mLogLevel, mLogger, __va_list_tag,
+    mLogLevel_mLOG_ALL, mLogLevel_mLOG_DEBUG, mLogLevel_mLOG_ERROR, mLogLevel_mLOG_FATAL,
+    mLogLevel_mLOG_GAME_ERROR, mLogLevel_mLOG_INFO, mLogLevel_mLOG_WARN,
+};
+use super::{c_string, CoreError};
+
+// longer messages get cut off, mgba's own loggers use even less
+const LOG_MESSAGE_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LogLevel {
+    Off, // mgba still lets FATAL through for a category with its own level
+    Fatal,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    All, // stubs (unimplemented hardware features) too
+}
+
+impl LogLevel {
+    // mLogFilter levels are a bitmask, each of ours lets everything at least that important through
+    fn mask(self) -> ffi::c_int {
+        let fatal = mLogLevel_mLOG_FATAL;
+        let error = fatal | mLogLevel_mLOG_ERROR;
+        let warn = error | mLogLevel_mLOG_WARN | mLogLevel_mLOG_GAME_ERROR;
+        let info = warn | mLogLevel_mLOG_INFO;
+        let debug = info | mLogLevel_mLOG_DEBUG;
+        let mask = match self {
+            Self::Off => 0,
+            Self::Fatal => fatal,
+            Self::Error => error,
+            Self::Warn => warn,
+            Self::Info => info,
+            Self::Debug => debug,
+            Self::All => mLogLevel_mLOG_ALL,
+        };
+        mask as ffi::c_int
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogConfig {
+    #[serde(default = "default_log_level")]
+    pub default_level: LogLevel,
+    // mgba category id ("gba", "gba.bios", "gba.memory", "gba.video", ...) -> level, instead of default_level
+    #[serde(default)]
+    pub categories: BTreeMap<String, LogLevel>,
+}
+
+// warnings are mostly games poking at odd addresses, often enough to flood the output
+fn default_log_level() -> LogLevel {
+    LogLevel::Error
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self { default_level: default_log_level(), categories: BTreeMap::new() }
+    }
+}
+
+// mgba hands log_handler the mLogger pointer we registered, which is the first field of one of these
+#[repr(C)]
+pub struct CoreLogger {
+    logger: mLogger,
+    filter: mLogFilter, // logger.filter points here, so a CoreLogger stays in its Box
+    mgba_lib: Arc<Library>,
+    // own allocation, log_handler only ever gets at it through a shared reference
+    errors: Arc<Mutex<Vec<String>>>,
+}
+
+impl CoreLogger {
+    pub fn new(mgba_lib: Arc<Library>, log_config: &LogConfig) -> Result<Box<Self>, CoreError> {
+        let category_ids = log_config
+            .categories
+            .iter()
+            .map(|(category_id, level)| Ok((c_string(category_id)?, level.mask())))
+            .collect::<Result<Vec<_>, CoreError>>()?;
+        let mut core_logger = Box::new(Self {
+            logger: mLogger { log: Some(log_handler), filter: std::ptr::null_mut() },
+            // plain tables and an int, mLogFilterInit sets them up
+            filter: unsafe { std::mem::zeroed() },
+            mgba_lib,
+            errors: Arc::new(Mutex::new(vec![])),
+        });
+        unsafe {
+            core_logger.mgba_lib.mLogFilterInit(&mut core_logger.filter);
+            core_logger.filter.defaultLevels = log_config.default_level.mask();
+            for (category_id, mask) in &category_ids {
+                core_logger.mgba_lib.mLogFilterSet(&mut core_logger.filter, category_id.as_ptr(), *mask);
+            }
+        }
+        core_logger.logger.filter = &mut core_logger.filter;
+        Ok(core_logger)
+    }
+
+    /// What to give mLogSetThreadLogger
+    pub fn mlogger(&self) -> *mut mLogger {
+        &self.logger as *const mLogger as *mut mLogger
+    }
+
+    /// FATAL/ERROR messages logged since the last call
+    pub fn take_errors(&self) -> Vec<String> {
+        std::mem::take(&mut *self.errors.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+impl Drop for CoreLogger {
+    fn drop(&mut self) {
+        unsafe { self.mgba_lib.mLogFilterDeinit(&mut self.filter) };
+    }
+}
+
+// mgba level, what we call it, and what the log crate gets. GAME_ERROR is the game doing something odd,
+// not the emulator failing, so it's only a warning
+const LEVELS: [(mLogLevel, &str, log::Level); 6] = [
+    (mLogLevel_mLOG_FATAL, "FATAL", log::Level::Error),
+    (mLogLevel_mLOG_ERROR, "ERROR", log::Level::Error),
+    (mLogLevel_mLOG_WARN, "WARN", log::Level::Warn),
+    (mLogLevel_mLOG_GAME_ERROR, "GAME_ERROR", log::Level::Warn),
+    (mLogLevel_mLOG_INFO, "INFO", log::Level::Info),
+    (mLogLevel_mLOG_DEBUG, "DEBUG", log::Level::Debug),
+];
+
+fn describe_level(level: mLogLevel) -> (&'static str, log::Level) {
+    LEVELS
+        .iter()
+        .find(|(mgba_level, _, _)| *mgba_level == level)
+        .map_or(("STUB", log::Level::Trace), |(_, name, log_level)| (*name, *log_level))
+}
+
+#[no_mangle]
+pub extern "C" fn log_handler(
+    logger: *mut mLogger,
+    category: ::std::os::raw::c_int,
+    level: mLogLevel,
+    format: *const ::std::os::raw::c_char,
+    args: *mut __va_list_tag,
+) {
+    // Core only ever registers the logger inside a CoreLogger
+    let core_logger = unsafe { &*(logger as *const CoreLogger) };
+    let mut buffer = [0 as ffi::c_char; LOG_MESSAGE_SIZE];
+    let (message, category_name) = unsafe {
+        core_logger.mgba_lib.vsnprintf(buffer.as_mut_ptr(), LOG_MESSAGE_SIZE as ffi::c_ulong, format, args);
+        let category_name = core_logger.mgba_lib.mLogCategoryName(category);
+        (
+            CStr::from_ptr(buffer.as_ptr()).to_string_lossy(),
+            match category_name.is_null() {
+                true => "Unknown".into(),
+                false => CStr::from_ptr(category_name).to_string_lossy(),
+            },
+        )
+    };
+    let (level_name, log_level) = describe_level(level);
+    log::log!(target: "mgba", log_level, "[{}] {}", category_name, message);
+    if level == mLogLevel_mLOG_FATAL || level == mLogLevel_mLOG_ERROR {
+        core_logger
+            .errors
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(format!("{} [{}] {}", level_name, category_name, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::mgba_ffi::mgba_bindings::mLogLevel_mLOG_STUB;
+
+    #[test]
+    fn levels_include_everything_more_important() {
+        let mask = |level: LogLevel| level.mask() as mLogLevel;
+        assert_eq!(mask(LogLevel::Off), 0);
+        assert_eq!(mask(LogLevel::Error), mLogLevel_mLOG_FATAL | mLogLevel_mLOG_ERROR);
+        assert_ne!(mask(LogLevel::Warn) & mLogLevel_mLOG_GAME_ERROR, 0);
+        assert_eq!(mask(LogLevel::Warn) & mLogLevel_mLOG_INFO, 0);
+        assert_eq!(mask(LogLevel::Debug) | mLogLevel_mLOG_STUB, mLogLevel_mLOG_ALL);
+    }
+
+    #[test]
+    fn log_config_parses_category_levels() {
+        let log_config: LogConfig =
+            serde_json::from_str(r#"{"categories":{"gba.bios":"Off","gba.memory":"Debug"}}"#).unwrap();
+        assert_eq!(log_config.default_level, LogLevel::Error);
+        assert_eq!(log_config.categories["gba.bios"], LogLevel::Off);
+        assert_eq!(log_config.categories["gba.memory"], LogLevel::Debug);
+        assert_eq!(serde_json::from_str::<LogConfig>("{}").unwrap(), LogConfig::default());
+    }
+}
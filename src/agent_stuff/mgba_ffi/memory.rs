@@ -0,0 +1,291 @@
+// Safe game memory access on top of the core's bus/memory block vtable entries
+// Reward and termination logic reads scores, HP, RNG state etc. through here
+// Every access is bounds checked against the core's listMemoryBlocks, so a bad address in a config
+// gives a MemoryError instead of poking at whatever mgba happens to map there
+
+use std::ffi::CStr;
+use std::fmt;
+
+use super::mgba_bindings::{
+    mCore,
+    mCoreMemoryBlock,
+    mCoreMemoryBlockFlags_mCORE_MEMORY_WRITE,
+};
+use super::Core;
+
+// Owned copy of mCoreMemoryBlock, the C strings are static inside libmgba but lets not depend on that
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryBlock {
+    pub id: usize,
+    pub internal_name: String, // "wram", "iwram", "vram", ...
+    pub short_name: String,    // "WRAM", "IWRAM", "VRAM", ...
+    pub start: u32,
+    pub end: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+impl MemoryBlock {
+    fn from_raw(raw_block: &mCoreMemoryBlock) -> Self {
+        fn to_string(raw_name: *const std::os::raw::c_char) -> String {
+            if raw_name.is_null() {
+                return String::new();
+            }
+            unsafe { CStr::from_ptr(raw_name) }.to_string_lossy().into_owned()
+        }
+        Self {
+            id: raw_block.id,
+            internal_name: to_string(raw_block.internalName),
+            short_name: to_string(raw_block.shortName),
+            start: raw_block.start,
+            end: raw_block.end,
+            size: raw_block.size,
+            flags: raw_block.flags,
+        }
+    }
+
+    // Either name works, case doesn't matter: "wram" == "WRAM"
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.internal_name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+
+    pub fn contains(&self, address: u32, width: u32) -> bool {
+        // end is exclusive in mgba's tables
+        address >= self.start && (address as u64 + width as u64) <= self.end as u64
+    }
+
+    pub fn is_writable(&self) -> bool {
+        (self.flags & mCoreMemoryBlockFlags_mCORE_MEMORY_WRITE) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryError {
+    UnmappedAddress { address: u32, width: u32 },
+    UnknownBlock(String),
+    OutOfBounds { block: String, offset: u32, width: u32, size: u32 },
+    ReadOnly { block: String, address: u32 },
+    MissingEntry(&'static str), // the core has no bus/memory block function for this
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnmappedAddress { address, width } => {
+                write!(f, "{} byte access at {:#010x} isn't inside any memory block", width, address)
+            },
+            Self::UnknownBlock(name) => write!(f, "No memory block named {}", name),
+            Self::OutOfBounds { block, offset, width, size } => write!(
+                f,
+                "{} byte access at offset {:#x} is outside of {} (size {:#x})",
+                width, offset, block, size
+            ),
+            Self::ReadOnly { block, address } => {
+                write!(f, "Can't write to {:#010x}, {} is read only", address, block)
+            },
+            Self::MissingEntry(entry) => write!(f, "This libmgba's mCore has no {}", entry),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// Absolute bus address of offset inside the block named block_name, checked against the block size
+/// Anything that has a list of memory blocks can resolve {"block":..,"offset":..} locations with this
+pub fn block_address(
+    memory_blocks: &[MemoryBlock],
+    block_name: &str,
+    offset: u32,
+    width: u32,
+) -> Result<u32, MemoryError> {
+    let block = memory_blocks
+        .iter()
+        .find(|block| block.matches_name(block_name))
+        .ok_or_else(|| MemoryError::UnknownBlock(block_name.to_string()))?;
+    if (offset as u64 + width as u64) > block.size as u64 {
+        return Err(MemoryError::OutOfBounds {
+            block: block.short_name.clone(),
+            offset,
+            width,
+            size: block.size,
+        });
+    }
+    Ok(block.start + offset)
+}
+
+// Core::load_game rebuilds the list every time it swaps in a ROM, which blocks there are depends on the game
+pub(super) fn list_memory_blocks(core: *mut mCore) -> Vec<MemoryBlock> {
+    let list_memory_blocks = match core_fn!(core, listMemoryBlocks, MemoryError::MissingEntry) {
+        Ok(list_memory_blocks) => list_memory_blocks,
+        Err(_) => return vec![],
+    };
+    unsafe {
+        let mut raw_blocks: *const mCoreMemoryBlock = std::ptr::null();
+        let num_blocks = list_memory_blocks(core, &mut raw_blocks);
+        if raw_blocks.is_null() {
+            return vec![];
+        }
+        std::slice::from_raw_parts(raw_blocks, num_blocks)
+            .iter()
+            .map(MemoryBlock::from_raw)
+            .collect()
+    }
+}
+
+impl Core {
+    pub fn memory_blocks(&self) -> &[MemoryBlock] {
+        &self.memory_blocks
+    }
+
+    #[allow(dead_code)]
+    pub fn memory_block(&self, block_name: &str) -> Result<&MemoryBlock, MemoryError> {
+        self.memory_blocks
+            .iter()
+            .find(|block| block.matches_name(block_name))
+            .ok_or_else(|| MemoryError::UnknownBlock(block_name.to_string()))
+    }
+
+    fn block_for_address(&self, address: u32, width: u32) -> Result<&MemoryBlock, MemoryError> {
+        self.memory_blocks
+            .iter()
+            .find(|block| block.contains(address, width))
+            .ok_or(MemoryError::UnmappedAddress { address, width })
+    }
+
+    fn writable_block_for_address(&self, address: u32, width: u32) -> Result<&MemoryBlock, MemoryError> {
+        let block = self.block_for_address(address, width)?;
+        if !block.is_writable() {
+            return Err(MemoryError::ReadOnly { block: block.short_name.clone(), address });
+        }
+        Ok(block)
+    }
+
+    /// Absolute bus address of offset inside a named block, checked against the block size
+    #[allow(dead_code)]
+    pub fn block_address(&self, block_name: &str, offset: u32, width: u32) -> Result<u32, MemoryError> {
+        block_address(&self.memory_blocks, block_name, offset, width)
+    }
+
+    // ################################################################################
+    // By address (bus reads, same view of memory the game has)
+    // ################################################################################
+
+    #[allow(dead_code)]
+    pub fn read_u8(&self, address: u32) -> Result<u8, MemoryError> {
+        self.block_for_address(address, 1)?;
+        let bus_read8 = core_fn!(self.core, busRead8, MemoryError::MissingEntry)?;
+        Ok(unsafe { bus_read8(self.core, address) } as u8)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_u16(&self, address: u32) -> Result<u16, MemoryError> {
+        self.block_for_address(address, 2)?;
+        let bus_read16 = core_fn!(self.core, busRead16, MemoryError::MissingEntry)?;
+        Ok(unsafe { bus_read16(self.core, address) } as u16)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_u32(&self, address: u32) -> Result<u32, MemoryError> {
+        self.block_for_address(address, 4)?;
+        let bus_read32 = core_fn!(self.core, busRead32, MemoryError::MissingEntry)?;
+        Ok(unsafe { bus_read32(self.core, address) })
+    }
+
+    pub fn write_u8(&mut self, address: u32, value: u8) -> Result<(), MemoryError> {
+        self.writable_block_for_address(address, 1)?;
+        let bus_write8 = core_fn!(self.core, busWrite8, MemoryError::MissingEntry)?;
+        unsafe { bus_write8(self.core, address, value) };
+        Ok(())
+    }
+
+    pub fn write_u16(&mut self, address: u32, value: u16) -> Result<(), MemoryError> {
+        self.writable_block_for_address(address, 2)?;
+        let bus_write16 = core_fn!(self.core, busWrite16, MemoryError::MissingEntry)?;
+        unsafe { bus_write16(self.core, address, value) };
+        Ok(())
+    }
+
+    pub fn write_u32(&mut self, address: u32, value: u32) -> Result<(), MemoryError> {
+        self.writable_block_for_address(address, 4)?;
+        let bus_write32 = core_fn!(self.core, busWrite32, MemoryError::MissingEntry)?;
+        unsafe { bus_write32(self.core, address, value) };
+        Ok(())
+    }
+
+    pub fn read_bytes(&self, address: u32, len: u32) -> Result<Vec<u8>, MemoryError> {
+        self.block_for_address(address, len)?;
+        let bus_read8 = core_fn!(self.core, busRead8, MemoryError::MissingEntry)?;
+        Ok((address..address + len)
+            .map(|byte_address| unsafe { bus_read8(self.core, byte_address) } as u8)
+            .collect())
+    }
+
+    #[allow(dead_code)]
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryError> {
+        self.writable_block_for_address(address, data.len() as u32)?;
+        let bus_write8 = core_fn!(self.core, busWrite8, MemoryError::MissingEntry)?;
+        for (byte_address, byte) in (address..).zip(data) {
+            unsafe { bus_write8(self.core, byte_address, *byte) };
+        }
+        Ok(())
+    }
+
+    // ################################################################################
+    // By named block + offset
+    // ################################################################################
+
+    #[allow(dead_code)]
+    pub fn read_block_u8(&self, block_name: &str, offset: u32) -> Result<u8, MemoryError> {
+        self.read_u8(self.block_address(block_name, offset, 1)?)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_block_u16(&self, block_name: &str, offset: u32) -> Result<u16, MemoryError> {
+        self.read_u16(self.block_address(block_name, offset, 2)?)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_block_u32(&self, block_name: &str, offset: u32) -> Result<u32, MemoryError> {
+        self.read_u32(self.block_address(block_name, offset, 4)?)
+    }
+
+    #[allow(dead_code)]
+    pub fn write_block_u8(&mut self, block_name: &str, offset: u32, value: u8) -> Result<(), MemoryError> {
+        self.write_u8(self.block_address(block_name, offset, 1)?, value)
+    }
+
+    #[allow(dead_code)]
+    pub fn write_block_u16(&mut self, block_name: &str, offset: u32, value: u16) -> Result<(), MemoryError> {
+        self.write_u16(self.block_address(block_name, offset, 2)?, value)
+    }
+
+    #[allow(dead_code)]
+    pub fn write_block_u32(&mut self, block_name: &str, offset: u32, value: u32) -> Result<(), MemoryError> {
+        self.write_u32(self.block_address(block_name, offset, 4)?, value)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_block_bytes(&self, block_name: &str, offset: u32, len: u32) -> Result<Vec<u8>, MemoryError> {
+        self.read_bytes(self.block_address(block_name, offset, len)?, len)
+    }
+
+    #[allow(dead_code)]
+    pub fn write_block_bytes(&mut self, block_name: &str, offset: u32, data: &[u8]) -> Result<(), MemoryError> {
+        self.write_bytes(self.block_address(block_name, offset, data.len() as u32)?, data)
+    }
+
+    /// Copy of a whole block straight out of mgba's backing memory (getMemoryBlock), no bus reads
+    #[allow(dead_code)]
+    pub fn dump_block(&self, block_name: &str) -> Result<Vec<u8>, MemoryError> {
+        let block = self.memory_block(block_name)?;
+        let get_memory_block = core_fn!(self.core, getMemoryBlock, MemoryError::MissingEntry)?;
+        unsafe {
+            let mut block_size: usize = 0;
+            let block_memory = get_memory_block(self.core, block.id, &mut block_size);
+            if block_memory.is_null() {
+                return Err(MemoryError::UnknownBlock(block_name.to_string()));
+            }
+            Ok(std::slice::from_raw_parts(block_memory as *const u8, block_size).to_vec())
+        }
+    }
+}
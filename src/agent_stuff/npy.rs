@@ -0,0 +1,375 @@
+// NumPy .npy/.npz export, so the training side can np.load() what the agent recorded
+// Small in-crate encoder: .npy version 1.0 and uncompressed (stored) zip archives for .npz
+//
+//   frames  N x H x W (x C), or N x F x H x W (x C) with a FrameStack, processed_data or the decoded raw frame
+//   actions N keycodes
+//   rewards N, dones N (only when every frame came with them, i.e. trajectories)
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::observation::ObservationData;
+use super::preprocessing::{ElementType, Shape};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameSource {
+    Processed, // processed_data as the preprocessing pipeline laid it out
+    Raw,       // frame_data decoded to RGB (u8, 3 channels), whatever the pixel format was
+}
+
+pub struct NpyArray {
+    descr: &'static str, // numpy dtype string, '<' is little endian, '|' doesn't care
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl NpyArray {
+    fn header(&self) -> Vec<u8> {
+        let shape = match self.shape.len() {
+            1 => format!("({},)", self.shape[0]),
+            _ => format!("({})", self.shape.iter().map(|dim| dim.to_string()).collect::<Vec<_>>().join(", ")),
+        };
+        let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", self.descr, shape);
+        // magic (6) + version (2) + header length (2) + header has to be a multiple of 64, ending in \n
+        let unpadded_len = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded_len % 64) % 64));
+        header.push('\n');
+
+        let mut npy_header = Vec::with_capacity(10 + header.len());
+        npy_header.extend_from_slice(b"\x93NUMPY");
+        npy_header.extend_from_slice(&[1, 0]);
+        npy_header.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        npy_header.extend_from_slice(header.as_bytes());
+        npy_header
+    }
+
+    pub fn to_npy_bytes(&self) -> Vec<u8> {
+        let mut npy_bytes = self.header();
+        npy_bytes.extend_from_slice(&self.data);
+        npy_bytes
+    }
+
+    pub fn write_npy(&self, file_path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(file_path)?);
+        file.write_all(&self.header())?;
+        file.write_all(&self.data)?;
+        file.flush()
+    }
+}
+
+// ################################################################################
+// .npz = zip of .npy files, stored without compression
+// ################################################################################
+
+fn crc32(data: &[u8]) -> u32 {
+    // bitwise IEEE crc32, the table version isn't worth it next to the disk writes
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// sizes and offsets are u32 without zip64, 0xFFFFFFFF itself means "look in the zip64 record"
+const ZIP_SIZE_LIMIT: u64 = u32::MAX as u64;
+
+pub fn write_npz(file_path: &Path, arrays: &[(&str, &NpyArray)]) -> io::Result<()> {
+    write_npz_with_limit(file_path, arrays, ZIP_SIZE_LIMIT)
+}
+
+// Every size and offset the archive will hold, checked against size_limit before the file is created
+// so a too big export fails without leaving half an archive behind
+fn check_npz_layout(arrays: &[(&str, &NpyArray)], size_limit: u64) -> io::Result<()> {
+    let too_big = |what: String| {
+        // would need zip64
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("npz export is limited to 4 GiB, {}", what)))
+    };
+    if arrays.len() > u16::MAX as usize {
+        return too_big(format!("{} arrays is over the {} entry limit", arrays.len(), u16::MAX));
+    }
+    let mut offset = 0_u64;
+    let mut central_directory_len = 0_u64;
+    for (name, array) in arrays {
+        let file_name_len = (name.len() + ".npy".len()) as u64;
+        let npy_len = (array.header().len() + array.data.len()) as u64;
+        if npy_len >= size_limit {
+            return too_big(format!("{}.npy is {} bytes", name, npy_len));
+        }
+        if offset >= size_limit {
+            return too_big(format!("{}.npy would start at byte {}", name, offset));
+        }
+        offset += 30 + file_name_len + npy_len;
+        central_directory_len += 46 + file_name_len;
+    }
+    if offset >= size_limit {
+        return too_big(format!("the central directory would start at byte {}", offset));
+    }
+    if central_directory_len >= size_limit {
+        return too_big(format!("the central directory would be {} bytes", central_directory_len));
+    }
+    Ok(())
+}
+
+fn write_npz_with_limit(file_path: &Path, arrays: &[(&str, &NpyArray)], size_limit: u64) -> io::Result<()> {
+    check_npz_layout(arrays, size_limit)?;
+    let mut file = BufWriter::new(File::create(file_path)?);
+    let mut central_directory = vec![];
+    let mut offset = 0_u64;
+    for (name, array) in arrays {
+        let file_name = format!("{}.npy", name);
+        let npy_bytes = array.to_npy_bytes();
+        let crc = crc32(&npy_bytes);
+        let size = npy_bytes.len() as u32;
+
+        // fields shared by the local header and the central directory entry:
+        // version needed, flags, method (0 = stored), mod time, mod date, crc, sizes, name length
+        let mut common = vec![];
+        common.extend_from_slice(&20_u16.to_le_bytes());
+        common.extend_from_slice(&0_u16.to_le_bytes());
+        common.extend_from_slice(&0_u16.to_le_bytes());
+        common.extend_from_slice(&0_u16.to_le_bytes());
+        common.extend_from_slice(&0x21_u16.to_le_bytes()); // 1980-01-01
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+
+        file.write_all(&0x0403_4B50_u32.to_le_bytes())?;
+        file.write_all(&common)?;
+        file.write_all(&0_u16.to_le_bytes())?; // extra field length
+        file.write_all(file_name.as_bytes())?;
+        file.write_all(&npy_bytes)?;
+
+        central_directory.extend_from_slice(&0x0201_4B50_u32.to_le_bytes());
+        central_directory.extend_from_slice(&20_u16.to_le_bytes()); // version made by
+        central_directory.extend_from_slice(&common);
+        central_directory.extend_from_slice(&[0_u8; 2 + 2 + 2 + 2 + 4]); // extra, comment, disk, attributes
+        central_directory.extend_from_slice(&(offset as u32).to_le_bytes());
+        central_directory.extend_from_slice(file_name.as_bytes());
+
+        offset += 30 + file_name.len() as u64 + npy_bytes.len() as u64;
+    }
+    file.write_all(&central_directory)?;
+    // end of central directory
+    file.write_all(&0x0605_4B50_u32.to_le_bytes())?;
+    file.write_all(&[0_u8; 4])?; // disk numbers
+    file.write_all(&(arrays.len() as u16).to_le_bytes())?;
+    file.write_all(&(arrays.len() as u16).to_le_bytes())?;
+    file.write_all(&(central_directory.len() as u32).to_le_bytes())?;
+    file.write_all(&(offset as u32).to_le_bytes())?;
+    file.write_all(&0_u16.to_le_bytes())?; // comment length
+    file.flush()
+}
+
+// ################################################################################
+// Observations -> arrays
+// ################################################################################
+
+/// Collects observations into the arrays, then writes them as one .npz or a set of .npy files
+pub struct NpyBatch {
+    frame_source: FrameSource,
+    frame_shape: Option<Vec<usize>>, // per frame, set by the first push
+    frame_descr: &'static str,
+    frames: Vec<u8>,
+    actions: Vec<u8>,
+    rewards: Vec<u8>,
+    dones: Vec<u8>,
+    has_outcomes: bool, // every frame so far came with reward and done
+    len: usize,
+}
+
+impl NpyBatch {
+    pub fn new(frame_source: FrameSource) -> Self {
+        Self {
+            frame_source,
+            frame_shape: None,
+            frame_descr: "|u1",
+            frames: vec![],
+            actions: vec![],
+            rewards: vec![],
+            dones: vec![],
+            has_outcomes: true,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // frames/channels of 1 get dropped, so plain grayscale comes out as N x H x W
+    fn processed_frame_shape(shape: Shape) -> Vec<usize> {
+        let mut frame_shape = vec![];
+        if shape.frames > 1 {
+            frame_shape.push(shape.frames as usize);
+        }
+        frame_shape.extend([shape.height as usize, shape.width as usize]);
+        if shape.channels > 1 {
+            frame_shape.push(shape.channels as usize);
+        }
+        frame_shape
+    }
+
+    pub fn push(&mut self, observation_data: &ObservationData, reward: Option<f32>, done: Option<bool>) -> Result<(), String> {
+        let frame_buffer = &observation_data.frame_buffer;
+        let (frame_shape, frame_descr) = match self.frame_source {
+            FrameSource::Processed => (
+                Self::processed_frame_shape(frame_buffer.processed_shape),
+                match frame_buffer.processed_shape.element_type {
+                    ElementType::U8 => "|u1",
+                    ElementType::F32 => "<f4",
+                },
+            ),
+            FrameSource::Raw => (vec![frame_buffer.height as usize, frame_buffer.width as usize, 3], "|u1"),
+        };
+        match &self.frame_shape {
+            Some(batch_shape) if *batch_shape != frame_shape || self.frame_descr != frame_descr => {
+                return Err(format!("Frame shape {:?} {} doesn't match the batch's {:?} {}", frame_shape, frame_descr, batch_shape, self.frame_descr));
+            },
+            Some(_) => (),
+            None => {
+                self.frame_shape = Some(frame_shape);
+                self.frame_descr = frame_descr;
+            },
+        }
+
+        match self.frame_source {
+            FrameSource::Processed => self.frames.extend_from_slice(&frame_buffer.processed_data),
+            FrameSource::Raw => frame_buffer.for_each_rgb(|pixel| self.frames.extend_from_slice(&pixel)),
+        }
+        self.actions.extend_from_slice(&observation_data.keycode_data.to_le_bytes());
+        match (reward, done) {
+            (Some(reward), Some(done)) => {
+                self.rewards.extend_from_slice(&reward.to_le_bytes());
+                self.dones.push(done as u8);
+            },
+            _ => self.has_outcomes = false,
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    fn arrays(self) -> Vec<(&'static str, NpyArray)> {
+        let mut frames_shape = vec![self.len];
+        frames_shape.extend(self.frame_shape.unwrap_or_default());
+        let mut arrays = vec![
+            ("frames", NpyArray { descr: self.frame_descr, shape: frames_shape, data: self.frames }),
+            ("actions", NpyArray { descr: "<u2", shape: vec![self.len], data: self.actions }),
+        ];
+        if self.has_outcomes && self.len > 0 {
+            arrays.push(("rewards", NpyArray { descr: "<f4", shape: vec![self.len], data: self.rewards }));
+            arrays.push(("dones", NpyArray { descr: "|b1", shape: vec![self.len], data: self.dones }));
+        }
+        arrays
+    }
+
+    /// out.npz gets every array inside it, out.npy gets the frames and out_actions.npy etc. next to it
+    /// Returns the names of the arrays that were written
+    pub fn write(self, out_path: &Path) -> io::Result<Vec<&'static str>> {
+        let arrays = self.arrays();
+        let names = arrays.iter().map(|(name, _)| *name).collect();
+        if out_path.extension().is_some_and(|extension| extension == "npy") {
+            let stem = out_path.file_stem().unwrap_or_default().to_string_lossy();
+            for (name, array) in &arrays {
+                let array_path = match *name {
+                    "frames" => out_path.to_path_buf(),
+                    _ => out_path.with_file_name(format!("{}_{}.npy", stem, name)),
+                };
+                array.write_npy(&array_path)?;
+            }
+        } else {
+            let named_arrays: Vec<(&str, &NpyArray)> = arrays.iter().map(|(name, array)| (*name, array)).collect();
+            write_npz(out_path, &named_arrays)?;
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::observation::{pixel_format_for_bpp, FrameBuffer};
+
+    fn observation(keycode: u16, fill: u8) -> ObservationData {
+        let mut frame_buffer = FrameBuffer::new(4, 2, 4, pixel_format_for_bpp(4));
+        frame_buffer.frame_data.fill(fill);
+        frame_buffer.processed_data.fill(fill);
+        ObservationData {
+            frame_buffer,
+            keycode_data: keycode,
+        }
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let array = NpyArray { descr: "<u2", shape: vec![3], data: vec![0; 6] };
+        let npy_bytes = array.to_npy_bytes();
+        let header_len = u16::from_le_bytes([npy_bytes[8], npy_bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(npy_bytes[10 + header_len - 1], b'\n');
+        let header = std::str::from_utf8(&npy_bytes[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<u2', 'fortran_order': False, 'shape': (3,), }"));
+        assert_eq!(npy_bytes.len(), 10 + header_len + 6);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn npz_holds_every_array() {
+        let file_path = std::env::temp_dir().join(format!("npy_export_{}.npz", std::process::id()));
+        let mut npy_batch = NpyBatch::new(FrameSource::Processed);
+        for frame_idx in 0..3_u8 {
+            npy_batch.push(&observation(frame_idx as u16, frame_idx), Some(1.0), Some(frame_idx == 2)).unwrap();
+        }
+        assert_eq!(npy_batch.write(&file_path).unwrap(), ["frames", "actions", "rewards", "dones"]);
+
+        let npz_bytes = std::fs::read(&file_path).unwrap();
+        assert_eq!(&npz_bytes[..4], b"PK\x03\x04");
+        let end_of_central_directory = &npz_bytes[npz_bytes.len() - 22..];
+        assert_eq!(&end_of_central_directory[..4], b"PK\x05\x06");
+        assert_eq!(u16::from_le_bytes([end_of_central_directory[10], end_of_central_directory[11]]), 4);
+        // stored, so the first entry is frames.npy as is: 3 grayscale 2x4 frames
+        let frames = NpyArray { descr: "|u1", shape: vec![3, 2, 4], data: [[0; 8], [1; 8], [2; 8]].concat() };
+        let entry_start = 30 + "frames.npy".len();
+        assert_eq!(&npz_bytes[entry_start..entry_start + frames.to_npy_bytes().len()], frames.to_npy_bytes());
+        std::fs::remove_file(&file_path).unwrap();
+
+        // no outcomes on one frame and rewards/dones are left out
+        let mut npy_batch = NpyBatch::new(FrameSource::Raw);
+        npy_batch.push(&observation(0, 0), Some(1.0), Some(false)).unwrap();
+        npy_batch.push(&observation(1, 1), None, None).unwrap();
+        let arrays = npy_batch.arrays();
+        assert_eq!(arrays.len(), 2);
+        assert_eq!(arrays[0].1.shape, [2, 2, 4, 3]);
+    }
+
+    #[test]
+    fn npz_past_the_zip_limits_is_an_error() {
+        let file_path = std::env::temp_dir().join(format!("npy_export_limit_{}.npz", std::process::id()));
+        let array = NpyArray { descr: "|u1", shape: vec![64], data: vec![0; 64] };
+        // a 30 byte local header, then the 1 byte name + 4 for .npy and the npy itself
+        let npy_len = array.to_npy_bytes().len() as u64;
+        let entry_len = 30 + 5 + npy_len;
+        let arrays = [("a", &array), ("b", &array)];
+
+        // exactly what the archive needs fits
+        write_npz_with_limit(&file_path, &arrays, 2 * entry_len + 1).unwrap();
+        let npz_bytes = std::fs::read(&file_path).unwrap();
+        assert_eq!(npz_bytes.len() as u64, 2 * entry_len + 2 * (46 + 5) + 22);
+        std::fs::remove_file(&file_path).unwrap();
+
+        // every entry fits, but the central directory would start past the limit
+        let error = write_npz_with_limit(&file_path, &arrays, 2 * entry_len).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("central directory"), "{}", error);
+        // second entry's local header would start past the limit
+        assert!(write_npz_with_limit(&file_path, &arrays, entry_len).is_err());
+        // one array over the limit on its own
+        assert!(write_npz_with_limit(&file_path, &arrays[..1], npy_len).is_err());
+        // nothing half written is left behind
+        assert!(!file_path.exists());
+    }
+}
@@ -1,5 +1,6 @@
 use std::fs::{
     create_dir, // will have an error if dir already exists :')
+    create_dir_all,
     // read_dir,
     File,
 };
@@ -8,6 +9,14 @@ use serde::{Deserialize, Serialize};
 use sdl2::pixels::{PixelFormatEnum,};
 use std::io::prelude::*;
 
+use super::preprocessing::{self, ElementType, PreprocessPipeline, Shape};
+use super::dataset::{self, CorruptFrame, DatasetError};
+use super::npy::{FrameSource, NpyBatch};
+
+// Which checksum save_album's pixel_checksum is, written next to it as checksum_version
+// Albums without a checksum_version are from before FrameBuffer::checksum and sum the raw bytes (raw_checksum)
+pub const ALBUM_CHECKSUM_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "PixelFormatEnum")]
 pub enum PixelFormatEnumDef {
@@ -58,9 +67,11 @@ pub struct FrameBuffer {
     pub height: u32,
     pub bpp: u32,
     #[serde(with = "PixelFormatEnumDef")]
-    pub pixel_format: PixelFormatEnum, // 2 bpp: RGB565,4 bpp: ABGR8888
+    pub pixel_format: PixelFormatEnum, // 2 bpp: RGB565,4 bpp: ABGR8888 (see pixel_format_for_bpp)
     pub frame_data: Vec<u8>, // vector of frame buffer bytes
-    pub processed_data: Vec<u8>,
+    pub processed_data: Vec<u8>, // output of the preprocessing pipeline, laid out as processed_shape
+    #[serde(default)]
+    pub processed_shape: Shape,
 }
 
 impl FrameBuffer {
@@ -75,8 +86,22 @@ impl FrameBuffer {
             // frame_data: Vec::with_capacity((width * height * bpp) as usize),
             processed_data: vec![0_u8; (width * height * 1) as usize], // grayscale
             // processed_data: vec![0_u8; (width * height * (bpp-1)) as usize], // RGB
+            // grayscale until the agent sets the configured pipeline's shape
+            processed_shape: Shape {
+                frames: 1,
+                height,
+                width,
+                channels: 1,
+                element_type: ElementType::U8,
+            },
         }
     }
+
+    /// Resize processed_data to whatever the preprocessing pipeline outputs
+    pub fn set_processed_shape(&mut self, processed_shape: Shape) {
+        self.processed_shape = processed_shape;
+        self.processed_data = vec![0_u8; processed_shape.num_bytes()];
+    }
     
     // pub fn from_data(width: u32, height: u32, bpp: u32, pixel_format: PixelFormatEnum, frame_data: Vec<u8>) -> Self {
     //     Self {width,height,bpp,pixel_format,frame_data}
@@ -96,26 +121,52 @@ impl FrameBuffer {
         self.frame_data.copy_from_slice(data);
     }
 
-    // function for removing alpha pixel data
-    // return processed frame data (inside a FrameBuffer?)
-    // pub fn post_process_data(&self) -> Vec<u8> {
+    /// Calls f with every pixel as [r, g, b], oldest row first, whatever the pixel format is
+    #[inline(always)]
+    pub fn for_each_rgb(&self, mut f: impl FnMut([u8; 3])) {
+        match self.pixel_format {
+            // byte order in memory is R G B A
+            PixelFormatEnum::ABGR8888 => self.frame_data
+                .chunks_exact(4)
+                .for_each(|pixel| f([pixel[0], pixel[1], pixel[2]])),
+            // native (little) endian u16s straight out of mgba's color_t
+            PixelFormatEnum::RGB565 => self.frame_data
+                .chunks_exact(2)
+                .for_each(|pixel| f(rgb565_to_rgb888(u16::from_le_bytes([pixel[0], pixel[1]])))),
+            _ => panic!("Pixel format {:?} isn't supported", self.pixel_format),
+        }
+    }
+
+    // Same checksum save_album writes, cheap way to compare frames between runs
+    // Only the colour channels count, so alpha doesn't matter and an RGB565 frame checksums
+    // the same as its ABGR8888 expansion
+    pub fn checksum(&self) -> u32 {
+        let mut sum = 0_u32;
+        self.for_each_rgb(|[r, g, b]| sum = sum.wrapping_add(r as u32 + g as u32 + b as u32));
+        // get 2's compliment of buffer self sum
+        sum.wrapping_neg()
+    }
+
+    // The checksum albums used to be written with, every byte of frame_data (alpha too) summed
+    pub fn raw_checksum(&self) -> u32 {
+        let sum = self.frame_data.iter().fold(0_u32, |sum, &byte| sum.wrapping_add(byte as u32));
+        sum.wrapping_neg()
+    }
+
+    /// What an album frame's pixel_checksum should be for its checksum_version (None = written before there was one)
+    /// None for versions we don't know
+    pub fn album_checksum(&self, checksum_version: Option<u32>) -> Option<u32> {
+        match checksum_version {
+            None => Some(self.raw_checksum()),
+            Some(ALBUM_CHECKSUM_VERSION) => Some(self.checksum()),
+            Some(_) => None,
+        }
+    }
+
+    // run frame_data through the configured stages (see preprocessing.rs) into processed_data
     #[inline(always)]
-    pub fn post_process_data(&mut self) {
-        let driver_buffer: &mut [u8] = &mut self.frame_data;
-        let chunked_driver_buf: &mut [[u8; 4]] = unsafe { core::slice::from_raw_parts_mut(driver_buffer.as_mut_ptr().cast(), driver_buffer.len() / 4) };
-        let processed_buffer: &mut [u8] = &mut self.processed_data;
-        // let chunked_processed_buf: &mut [[u8; 3]] = unsafe { core::slice::from_raw_parts_mut(processed_buffer.as_mut_ptr().cast(), processed_buffer.len() / 3) };
-        // let chunked_processed_buf: &mut [[u8; 1]] = unsafe { core::slice::from_raw_parts_mut(processed_buffer.as_mut_ptr().cast(), processed_buffer.len()) };
-        // Set up references to vectorize the post processing
-        chunked_driver_buf
-            .iter().copied()
-            // .iter() // removing .copied() doesn't change the benchmark
-            .zip(processed_buffer)
-            // return RGB
-            // .for_each(|([a,b,c,_],d)| *d = [*a,*b,*c]);
-            // scale and sum to make RGB-> Grayscale
-            .for_each(|([a,b,c,_],d)| *d = (( (0.2989*((a) as f32)) + (0.5870*((b) as f32)) + (0.114*((c) as f32))) as u8));
-            // .for_each(|([_r,_g,b,_a],e)| *e = b);
+    pub fn post_process_data(&mut self, pipeline: &mut PreprocessPipeline) {
+        pipeline.process(self);
     }
 }
 
@@ -148,7 +199,11 @@ impl ObservationData {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObservationSet {
+    // Fixed capacity ring buffer, every slot is allocated up front so push never allocates
+    // observations[head] is the oldest observation once the buffer has wrapped
     observations: Vec<ObservationData>,
+    head: usize,
+    len: usize,
     width: u32,
     height: u32,
     // serialize deserialize needs to be added
@@ -157,29 +212,38 @@ pub struct ObservationSet {
 }
 
 impl ObservationSet {
+    /// capacity slots shaped like template (same frame/processed buffer sizes)
     pub fn new(
-        data: Option<ObservationData>,
-        width: u32,
-        height: u32,
-        pixel_type: PixelFormatEnum,
+        template: &ObservationData,
+        capacity: usize,
     ) -> Self {
-        if data.is_some() {
-            Self {
-                observations: vec![data.unwrap()],
-                width,
-                height,
-                pixel_type,
-            }
-        } else {
-            Self {
-                observations: vec![],
-                width,
-                height,
-                pixel_type,
-            }
+        assert!(capacity > 0, "ObservationSet needs room for at least one observation");
+        Self {
+            observations: vec![template.clone(); capacity],
+            head: 0,
+            len: 0,
+            width: template.frame_buffer.width,
+            height: template.frame_buffer.height,
+            pixel_type: template.frame_buffer.pixel_format,
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.observations.len()
+    }
+
+    /// Empty again, the slots stay allocated
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = &ObservationData> + '_ {
+        let (wrapped, start) = self.observations.split_at(self.head);
+        start.iter().chain(wrapped.iter()).take(self.len)
+    }
+
     // pub fn to_file(&self, file_path: &Path) {
     //     // flooie
     //     // let mut file = File::create(file_path).expect("Cracka pls");
@@ -190,16 +254,12 @@ impl ObservationSet {
 
     pub fn save_album(&self, album_dir: &Path) {
         // create dir if doesn't exist
-        create_dir(album_dir).expect("Going to kms");
+        // the agent may have started the album already, with the episode's input log (see episode::EpisodeRecorder)
+        create_dir_all(album_dir).expect("Going to kms");
         // loop through and write
-        // only do the last 120 frames (2 seconds)
-        // subtract 120 from length and saturate to 0
-        const FPS: usize = 60;
-        const TIME_DURATION_BUFFER: usize = 10;
-        const MAX_FRAME_DATA_SAVE: usize = TIME_DURATION_BUFFER * FPS;
-        let start_idx = self.observations.len().saturating_sub(MAX_FRAME_DATA_SAVE);
-        println!("Observation length: {}, start index: {}",self.observations.len(),start_idx);
-        for (idx, observation_data) in self.observations[start_idx..].iter().enumerate() {
+        // the ring buffer only holds the last observation_capacity frames
+        println!("Observation length: {}, capacity: {}", self.len, self.capacity());
+        for (idx, observation_data) in self.iter().enumerate() {
             // convert observation_data.frame_buffer.frame_data[] into imagebuffer
             // let mut img = image::ImageBuffer::<P, Vec<u8>>::from_raw(self.width,self.height,observation_data.frame_buffer.frame_data);
 
@@ -218,14 +278,19 @@ impl ObservationSet {
             //     _ => panic!("This is not documented"),
             // };
             // instead of saving buffer, make a frame collection?
-            image::save_buffer(
-                image_path,
-                &observation_data.frame_buffer.processed_data[..],
-                self.width,
-                self.height,
-                // color_type,
-                image::ColorType::L8,
-            ).expect("Failed to save image");
+            // the png shows what the agent sees, newest frame of a stack, scaled back to 0..255
+            let processed_shape = observation_data.frame_buffer.processed_shape;
+            if let Some((pixels, color_type)) =
+                preprocessing::preview_image(processed_shape, &observation_data.frame_buffer.processed_data)
+            {
+                image::save_buffer(
+                    image_path,
+                    &pixels,
+                    processed_shape.width,
+                    processed_shape.height,
+                    color_type,
+                ).expect("Failed to save image");
+            }
             // write serialized data
             let mut file = File::create(image_data).expect("couasdpocuasdiopucapdiosu");
             // file.write_all(serde_json::to_string(&self).expect("Y me"));
@@ -238,17 +303,16 @@ impl ObservationSet {
                 #[serde(with = "PixelFormatEnumDef")]
                 pixel_type: PixelFormatEnum,
                 pixel_checksum: u32,
+                checksum_version: u32,
             }
-            // let pixel_checksum = get_pixel_buffer_checksum(&observation_data.frame_buffer.frame_data[..]);
-            let pixel_checksum = get_pixel_buffer_checksum(&observation_data.frame_buffer.frame_data);
-            // filter out alpha channel
-            // let pixel_checksum = get_pixel_buffer_checksum(&observation_data.frame_buffer.frame_data.iter().position(|x| ((*x)+1)%4).collect());
+            let pixel_checksum = observation_data.frame_buffer.checksum();
             let out_data = JsonStruct {
                 observation_data: observation_data,
                 width: self.width,
                 height: self.height,
                 pixel_type: self.pixel_type,
                 pixel_checksum: pixel_checksum,
+                checksum_version: ALBUM_CHECKSUM_VERSION,
             };
             write!(file, "{}", serde_json::to_string(&out_data).expect("Y me"))
                 .expect(format!("Failed to write to file {:?}",file).as_str());
@@ -258,25 +322,182 @@ impl ObservationSet {
         }
     }
 
-    pub fn push(&mut self, data: ObservationData) {
-        self.observations.push(data);
+    /// Copy data into the next slot, overwriting the oldest observation once full
+    #[inline(always)]
+    pub fn push(&mut self, data: &ObservationData) {
+        let tail = (self.head + self.len) % self.capacity();
+        let slot = &mut self.observations[tail];
+        slot.keycode_data = data.keycode_data;
+        slot.frame_buffer.frame_data.copy_from_slice(&data.frame_buffer.frame_data);
+        slot.frame_buffer.processed_data.copy_from_slice(&data.frame_buffer.processed_data);
+        if self.len < self.capacity() {
+            self.len += 1;
+        } else {
+            self.head = (self.head + 1) % self.capacity();
+        }
     }
 
-    // pub fn from_file(file_path: &Path) {
-    //     //
-    // }
+    /// Album directory or trajectory file back into an ObservationSet with room for every valid frame
+    /// Corrupt frames are left out and handed back so the caller can report them
+    pub fn load(path: &Path) -> Result<(Self, Vec<CorruptFrame>), DatasetError> {
+        let loaded_dataset = dataset::load_dataset(path)?;
+        let mut observation_set = Self::new(
+            &loaded_dataset.frames[0].observation_data,
+            loaded_dataset.frames.len(),
+        );
+        for frame in &loaded_dataset.frames {
+            observation_set.push(&frame.observation_data);
+        }
+        Ok((observation_set, loaded_dataset.corrupt_frames))
+    }
 
     pub fn len(&self) -> usize {
-        self.observations.len()
+        self.len
+    }
+
+    /// The frames still in the ring buffer as numpy arrays (.npz, or .npy files), see npy.rs
+    /// An ObservationSet has no rewards or dones, only frames and actions get written
+    pub fn export_npy(&self, out_path: &Path, frame_source: FrameSource) -> std::io::Result<Vec<&'static str>> {
+        let mut npy_batch = NpyBatch::new(frame_source);
+        for observation_data in self.iter() {
+            // every slot was cloned from the same template, the shapes can't disagree
+            npy_batch.push(observation_data, None, None).expect("ObservationSet frames differ in shape");
+        }
+        npy_batch.write(out_path)
     }
 }
 
-fn get_pixel_buffer_checksum(pixel_buffer: &Vec<u8>) -> u32 {
-    // shhh don't worry about why
-    let mut ret: u32 = 0;
-    for x in pixel_buffer {
-        ret += (*x) as u32;
+/// Pixel formats the emulator can hand us, by bytes per pixel
+pub fn pixel_format_for_bpp(bpp: u32) -> PixelFormatEnum {
+    match bpp {
+        2 => PixelFormatEnum::RGB565,
+        4 => PixelFormatEnum::ABGR8888,
+        _ => panic!("This is not documented: {} bytes per pixel", bpp),
+    }
+}
+
+/// 5/6 bit channels -> 8 bit, the top bits get repeated so 0x1F becomes 0xFF and not 0xF8
+#[inline(always)]
+pub fn rgb565_to_rgb888(pixel: u16) -> [u8; 3] {
+    let r = ((pixel >> 11) & 0x1F) as u8;
+    let g = ((pixel >> 5) & 0x3F) as u8;
+    let b = (pixel & 0x1F) as u8;
+    [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::preprocessing::PreprocessStage;
+
+    // every channel value here survives the 565 round trip (5/6 bit value with its top bits repeated)
+    const PIXELS: [[u8; 3]; 6] = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [255, 0, 0],
+        [0, 255, 0],
+        [0, 0, 255],
+        [132, 130, 66],
+    ];
+    const WIDTH: u32 = 3;
+    const HEIGHT: u32 = 2;
+
+    fn rgb888_to_rgb565([r, g, b]: [u8; 3]) -> u16 {
+        ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+    }
+
+    fn synthetic_frame(bpp: u32) -> FrameBuffer {
+        let mut frame_buffer = FrameBuffer::new(WIDTH, HEIGHT, bpp, pixel_format_for_bpp(bpp));
+        frame_buffer.frame_data = PIXELS
+            .iter()
+            .flat_map(|&pixel| match bpp {
+                // alpha is garbage on purpose, nothing should look at it
+                4 => vec![pixel[0], pixel[1], pixel[2], 0x5A],
+                _ => rgb888_to_rgb565(pixel).to_le_bytes().to_vec(),
+            })
+            .collect();
+        frame_buffer
+    }
+
+    fn processed(frame_buffer: &mut FrameBuffer, stages: &[PreprocessStage]) -> Vec<u8> {
+        let mut pipeline = PreprocessPipeline::new(stages, WIDTH, HEIGHT, frame_buffer.pixel_format).unwrap();
+        frame_buffer.set_processed_shape(pipeline.output_shape());
+        frame_buffer.post_process_data(&mut pipeline);
+        frame_buffer.processed_data.clone()
+    }
+
+    #[test]
+    fn rgb565_expands_to_full_range() {
+        assert_eq!(rgb565_to_rgb888(0x0000), [0, 0, 0]);
+        assert_eq!(rgb565_to_rgb888(0xFFFF), [255, 255, 255]);
+        assert_eq!(rgb565_to_rgb888(0xF800), [255, 0, 0]);
+        assert_eq!(rgb565_to_rgb888(0x07E0), [0, 255, 0]);
+        assert_eq!(rgb565_to_rgb888(0x001F), [0, 0, 255]);
+        for pixel in PIXELS {
+            assert_eq!(rgb565_to_rgb888(rgb888_to_rgb565(pixel)), pixel);
+        }
+    }
+
+    #[test]
+    fn both_formats_decode_to_the_same_pixels() {
+        for bpp in [2, 4] {
+            let frame_buffer = synthetic_frame(bpp);
+            let mut decoded = vec![];
+            frame_buffer.for_each_rgb(|pixel| decoded.push(pixel));
+            assert_eq!(decoded, PIXELS, "bpp {}", bpp);
+        }
+    }
+
+    #[test]
+    fn checksum_ignores_format_and_alpha() {
+        let rgb565 = synthetic_frame(2);
+        let mut abgr8888 = synthetic_frame(4);
+        assert_eq!(rgb565.checksum(), abgr8888.checksum());
+        abgr8888.frame_data[3] = 0xFF;
+        assert_eq!(rgb565.checksum(), abgr8888.checksum());
+        abgr8888.frame_data[0] = 1;
+        assert_ne!(rgb565.checksum(), abgr8888.checksum());
+    }
+
+    #[test]
+    fn baseline_album_checksums_still_verify() {
+        // img.data as the baseline save_album wrote it: no processed_shape, no checksum_version,
+        // pixel_checksum the two's complement of every raw byte (alpha included) summed
+        let baseline_img_data = r#"{"observation_data":{"frame_buffer":{"width":2,"height":1,"bpp":4,
+            "pixel_format":"ABGR8888","frame_data":[10,20,30,255,1,2,3,255],"processed_data":[20,2]},"keycode_data":16},
+            "width":2,"height":1,"pixel_type":"ABGR8888","pixel_checksum":4294966720}"#;
+        let album_frame: serde_json::Value = serde_json::from_str(baseline_img_data).unwrap();
+        let observation_data: ObservationData =
+            serde_json::from_value(album_frame["observation_data"].clone()).unwrap();
+        let frame_buffer = &observation_data.frame_buffer;
+        assert_eq!(frame_buffer.album_checksum(None), Some(4294966720));
+        // the current checksum leaves alpha out, so it can't be what the baseline wrote
+        assert_ne!(frame_buffer.checksum(), 4294966720);
+        assert_eq!(frame_buffer.album_checksum(Some(ALBUM_CHECKSUM_VERSION)), Some(frame_buffer.checksum()));
+        assert_eq!(frame_buffer.album_checksum(Some(ALBUM_CHECKSUM_VERSION + 1)), None);
+    }
+
+    #[test]
+    fn grayscale_matches_between_formats() {
+        let expected: Vec<u8> = PIXELS
+            .iter()
+            .map(|&[r, g, b]| ((0.2989 * r as f32) + (0.5870 * g as f32) + (0.114 * b as f32)) as u8)
+            .collect();
+        for bpp in [2, 4] {
+            let mut frame_buffer = synthetic_frame(bpp);
+            assert_eq!(processed(&mut frame_buffer, &[PreprocessStage::Grayscale]), expected, "bpp {}", bpp);
+        }
+    }
+
+    #[test]
+    fn png_preview_is_rgb_for_both_formats() {
+        let expected: Vec<u8> = PIXELS.iter().flatten().copied().collect();
+        for bpp in [2, 4] {
+            let mut frame_buffer = synthetic_frame(bpp);
+            let processed_data = processed(&mut frame_buffer, &[PreprocessStage::Rgb]);
+            let (pixels, color_type) = preprocessing::preview_image(frame_buffer.processed_shape, &processed_data).unwrap();
+            assert_eq!(color_type, image::ColorType::Rgb8);
+            assert_eq!(pixels, expected, "bpp {}", bpp);
+        }
     }
-    // get 2's compliment of buffer self sum
-    1_u32.overflowing_add(ret ^ u32::MAX).0
 }
@@ -0,0 +1,232 @@
+// Policies decide the keycode for AgentControl::Intelligent
+// Every IO cycle run_client hands the policy the current observation and writes back whatever keycode it returns
+// Policies are picked by name in the configuration:
+// "agent_control":{"Intelligent":{"name":"Random","params":{"seed":7,"hold_frames":4}}}
+
+use serde::{Deserialize, Serialize};
+
+use super::observation::ObservationData;
+
+pub trait Policy {
+    fn act(&mut self, obs: &ObservationData) -> u16;
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    pub name: String,
+    // each policy parses its own parameters, missing params means defaults
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+// All the GBA buttons: A B Select Start Right Left Up Down R L
+pub const ALL_BUTTONS_MASK: u16 = 0x3FF;
+
+/// Look up a policy implementation by name and build it with its parameters
+pub fn build_policy(policy_config: &PolicyConfig) -> Result<Box<dyn Policy>, String> {
+    fn parse_params<T: serde::de::DeserializeOwned>(policy_config: &PolicyConfig) -> Result<T, String> {
+        // null -> {} so that every param can fall back to its default
+        let params = match &policy_config.params {
+            serde_json::Value::Null => serde_json::json!({}),
+            params => params.clone(),
+        };
+        serde_json::from_value(params)
+            .map_err(|e| format!("Trouble parsing params for policy {}: {}", policy_config.name, e))
+    }
+    Ok(match policy_config.name.as_str() {
+        "Random" => Box::new(RandomPolicy::new(parse_params(policy_config)?)),
+        "FixedMacro" => Box::new(SequencePolicy::new(parse_params(policy_config)?, true)),
+        "Scripted" => Box::new(SequencePolicy::new(parse_params(policy_config)?, false)),
+        unknown => return Err(format!("No policy named {}, options are: Random, FixedMacro, Scripted", unknown)),
+    })
+}
+
+// ################################################################################
+// Random buttons
+// ################################################################################
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomPolicyParams {
+    pub seed: u64,
+    pub button_mask: u16,  // only these buttons are ever pressed
+    pub hold_frames: u32,  // keep a random keycode held for this many frames
+}
+
+impl Default for RandomPolicyParams {
+    fn default() -> Self {
+        Self {
+            seed: 0x2545_F491_4F6C_DD1D,
+            button_mask: ALL_BUTTONS_MASK,
+            hold_frames: 1,
+        }
+    }
+}
+
+pub struct RandomPolicy {
+    params: RandomPolicyParams,
+    rng_state: u64,
+    cur_keycode: u16,
+    frames_held: u32,
+}
+
+impl RandomPolicy {
+    pub fn new(params: RandomPolicyParams) -> Self {
+        Self {
+            // xorshift gets stuck at 0
+            rng_state: params.seed.max(1),
+            params,
+            cur_keycode: 0,
+            frames_held: 0,
+        }
+    }
+
+    // xorshift64, no need for a whole rand crate to mash buttons
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+}
+
+impl Policy for RandomPolicy {
+    fn act(&mut self, _obs: &ObservationData) -> u16 {
+        if self.frames_held == 0 {
+            self.cur_keycode = (self.next_random() as u16) & self.params.button_mask;
+        }
+        self.frames_held = (self.frames_held + 1) % self.params.hold_frames.max(1);
+        self.cur_keycode
+    }
+}
+
+// ################################################################################
+// Fixed macro (looping) and scripted sequence (played once)
+// ################################################################################
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SequenceStep {
+    pub keycode: u16,
+    pub frames: u32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SequencePolicyParams {
+    pub steps: Vec<SequenceStep>,
+    pub idle_keycode: u16, // what a script holds once it is finished
+}
+
+pub struct SequencePolicy {
+    params: SequencePolicyParams,
+    loop_sequence: bool,
+    step_idx: usize,
+    step_frame: u32,
+}
+
+impl SequencePolicy {
+    pub fn new(params: SequencePolicyParams, loop_sequence: bool) -> Self {
+        Self {
+            params,
+            loop_sequence,
+            step_idx: 0,
+            step_frame: 0,
+        }
+    }
+}
+
+impl Policy for SequencePolicy {
+    fn act(&mut self, _obs: &ObservationData) -> u16 {
+        // skip over finished (or zero length) steps
+        while self.step_idx < self.params.steps.len()
+            && self.step_frame >= self.params.steps[self.step_idx].frames
+        {
+            self.step_idx += 1;
+            self.step_frame = 0;
+            if self.step_idx == self.params.steps.len() && self.loop_sequence {
+                // an all zero length macro would spin here forever
+                if self.params.steps.iter().all(|step| step.frames == 0) {
+                    break;
+                }
+                self.step_idx = 0;
+            }
+        }
+        match self.params.steps.get(self.step_idx) {
+            Some(step) => {
+                self.step_frame += 1;
+                step.keycode
+            },
+            None => self.params.idle_keycode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::observation::{pixel_format_for_bpp, FrameBuffer};
+
+    fn keycodes(policy_config: serde_json::Value, frames: usize) -> Vec<u16> {
+        let policy_config: PolicyConfig = serde_json::from_value(policy_config).unwrap();
+        let mut policy = build_policy(&policy_config).unwrap();
+        // none of the built in policies look at the frame
+        let observation_data = ObservationData {
+            frame_buffer: FrameBuffer::new(1, 1, 4, pixel_format_for_bpp(4)),
+            keycode_data: 0,
+        };
+        (0..frames).map(|_| policy.act(&observation_data)).collect()
+    }
+
+    #[test]
+    fn random_is_deterministic_per_seed() {
+        let random = |params: serde_json::Value| keycodes(serde_json::json!({"name":"Random","params":params}), 64);
+        let first_run = random(serde_json::json!({"seed":7}));
+        assert_eq!(random(serde_json::json!({"seed":7})), first_run);
+        assert_ne!(random(serde_json::json!({"seed":8})), first_run);
+        assert!(first_run.iter().all(|keycode| keycode & !ALL_BUTTONS_MASK == 0));
+        // seed 0 would leave xorshift stuck at 0
+        assert!(random(serde_json::json!({"seed":0})).iter().any(|keycode| *keycode != 0));
+        // no params at all is the default seed
+        assert_eq!(keycodes(serde_json::json!({"name":"Random"}), 64), random(serde_json::json!({})));
+
+        let masked = random(serde_json::json!({"seed":7,"button_mask":0x30}));
+        assert!(masked.iter().all(|keycode| keycode & !0x30 == 0));
+        let held = random(serde_json::json!({"seed":7,"hold_frames":4}));
+        for hold in held.chunks(4) {
+            assert!(hold.iter().all(|keycode| *keycode == hold[0]));
+        }
+    }
+
+    #[test]
+    fn fixed_macro_wraps_around() {
+        let fixed_macro = serde_json::json!({"name":"FixedMacro","params":{
+            "steps":[{"keycode":1,"frames":2},{"keycode":16,"frames":1},{"keycode":32,"frames":0},{"keycode":2,"frames":1}]
+        }});
+        // the zero length step never shows up
+        assert_eq!(keycodes(fixed_macro, 9), [1, 1, 16, 2, 1, 1, 16, 2, 1]);
+        // nothing to loop over holds idle_keycode instead of spinning forever
+        let empty_macro = serde_json::json!({"name":"FixedMacro","params":{
+            "steps":[{"keycode":1,"frames":0}],"idle_keycode":8
+        }});
+        assert_eq!(keycodes(empty_macro, 3), [8, 8, 8]);
+    }
+
+    #[test]
+    fn scripted_plays_once_then_idles() {
+        let scripted = serde_json::json!({"name":"Scripted","params":{
+            "steps":[{"keycode":8,"frames":1},{"keycode":1,"frames":2}],"idle_keycode":64
+        }});
+        assert_eq!(keycodes(scripted, 6), [8, 1, 1, 64, 64, 64]);
+        assert_eq!(keycodes(serde_json::json!({"name":"Scripted"}), 2), [0, 0]);
+    }
+
+    #[test]
+    fn bad_policy_configs_are_errors() {
+        let build = |policy_config: serde_json::Value| {
+            build_policy(&serde_json::from_value(policy_config).unwrap()).err().unwrap()
+        };
+        assert!(build(serde_json::json!({"name":"Smart"})).starts_with("No policy named Smart"));
+        assert!(build(serde_json::json!({"name":"Random","params":{"seed":"seven"}})).starts_with("Trouble parsing params for policy Random"));
+        assert!(build(serde_json::json!({"name":"FixedMacro","params":{"steps":[{"keycode":1}]}})).contains("frames"));
+    }
+}
@@ -0,0 +1,472 @@
+// Observation preprocessing: turns the raw emulator frame_data into FrameBuffer::processed_data
+// The agent config picks the chain of stages, e.g.
+// "preprocessing":["Grayscale",{"Crop":{"x":0,"y":16,"width":240,"height":128}},{"Downscale":{"factor":2}},"Normalize",{"FrameStack":{"frames":4}}]
+// Each stage declares the Shape it outputs, so the chain is validated once when the Agent is created
+// Internally everything is f32, the last stage's element type decides how processed_data is encoded
+
+use serde::{Deserialize, Serialize};
+use sdl2::pixels::PixelFormatEnum;
+
+use super::observation::FrameBuffer;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PreprocessStage {
+    Grayscale,                                           // RGB -> luma, 1 channel
+    Rgb,                                                 // keep the 3 colour channels (alpha is always dropped)
+    Downscale { factor: u32 },                           // box filter, averages factor x factor blocks
+    Resize { width: u32, height: u32 },                  // nearest neighbour to any size
+    Crop { x: u32, y: u32, width: u32, height: u32 },    // keep a region of the frame
+    Normalize,                                           // 0..255 -> 0.0..1.0, output becomes f32
+    FrameStack { frames: u32 },                          // last N frames, oldest first
+}
+
+pub fn default_preprocessing() -> Vec<PreprocessStage> {
+    // what post_process_data always did
+    vec![PreprocessStage::Grayscale]
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ElementType {
+    #[default]
+    U8,  // one byte per element
+    F32, // 4 little-endian bytes per element
+}
+
+impl ElementType {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::F32 => 4,
+        }
+    }
+}
+
+// frames x height x width x channels, row-major, oldest frame first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Shape {
+    pub frames: u32,
+    pub height: u32,
+    pub width: u32,
+    pub channels: u32,
+    pub element_type: ElementType,
+}
+
+impl Shape {
+    /// Bytes in the whole shape, None if that doesn't fit in a usize
+    /// The dimensions come from configs and files, PreprocessPipeline::new rejects shapes this fails for
+    pub fn checked_num_bytes(&self) -> Option<usize> {
+        self.checked_frame_elements()?.checked_mul(self.frames as usize)?.checked_mul(self.element_type.size())
+    }
+
+    fn checked_frame_elements(&self) -> Option<usize> {
+        (self.height as usize).checked_mul(self.width as usize)?.checked_mul(self.channels as usize)
+    }
+
+    pub fn num_elements(&self) -> usize {
+        self.num_bytes() / self.element_type.size()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.checked_num_bytes().expect("shape was checked when the pipeline was built")
+    }
+
+    fn frame_elements(&self) -> usize {
+        self.checked_frame_elements().expect("shape was checked when the pipeline was built")
+    }
+}
+
+impl PreprocessStage {
+    /// Shape this stage produces from input, or why it can't take that input
+    pub fn output_shape(&self, input: Shape) -> Result<Shape, String> {
+        match self {
+            Self::Grayscale | Self::Rgb if input.channels != 3 => {
+                Err(format!("{:?} needs 3 colour channels, got {}", self, input.channels))
+            },
+            Self::Downscale { .. } | Self::Resize { .. } | Self::Crop { .. } if input.frames != 1 => {
+                Err(format!("{:?} has to come before FrameStack", self))
+            },
+            Self::Grayscale => Ok(Shape { channels: 1, ..input }),
+            Self::Rgb => Ok(input),
+            Self::Downscale { factor } => {
+                if *factor == 0 || !input.width.is_multiple_of(*factor) || !input.height.is_multiple_of(*factor) {
+                    return Err(format!(
+                        "Downscale factor {} has to evenly divide {}x{}",
+                        factor, input.width, input.height
+                    ));
+                }
+                Ok(Shape { width: input.width / factor, height: input.height / factor, ..input })
+            },
+            Self::Resize { width, height } => {
+                if *width == 0 || *height == 0 {
+                    return Err("Resize to an empty frame".to_string());
+                }
+                Ok(Shape { width: *width, height: *height, ..input })
+            },
+            Self::Crop { x, y, width, height } => {
+                let fits = |start: u32, len: u32, available: u32| start.checked_add(len).is_some_and(|end| end <= available);
+                if *width == 0 || *height == 0 || !fits(*x, *width, input.width) || !fits(*y, *height, input.height) {
+                    return Err(format!(
+                        "Crop {}x{} at ({},{}) doesn't fit in {}x{}",
+                        width, height, x, y, input.width, input.height
+                    ));
+                }
+                Ok(Shape { width: *width, height: *height, ..input })
+            },
+            Self::Normalize => match input.element_type {
+                ElementType::U8 => Ok(Shape { element_type: ElementType::F32, ..input }),
+                ElementType::F32 => Err("Normalize twice".to_string()),
+            },
+            Self::FrameStack { frames } => {
+                if *frames == 0 || input.frames != 1 {
+                    return Err("FrameStack needs at least 1 frame, and only once".to_string());
+                }
+                Ok(Shape { frames: *frames, ..input })
+            },
+        }
+    }
+}
+
+pub struct PreprocessPipeline {
+    stages: Vec<PreprocessStage>,
+    input_shape: Shape,    // decoded RGB frame
+    shapes: Vec<Shape>,    // output of each stage
+    // one preallocated buffer per stage (plus the decoded input), no allocating per frame
+    buffers: Vec<Vec<f32>>,
+    stack_filled: bool,    // FrameStack history is empty until the first frame after a reset
+}
+
+impl PreprocessPipeline {
+    pub fn new(stages: &[PreprocessStage], width: u32, height: u32, pixel_format: PixelFormatEnum) -> Result<Self, String> {
+        match pixel_format {
+            PixelFormatEnum::ABGR8888 | PixelFormatEnum::RGB565 => (),
+            _ => return Err(format!("Pixel format {:?} isn't supported by the preprocessing pipeline", pixel_format)),
+        }
+        let input_shape = Shape {
+            frames: 1,
+            height,
+            width,
+            channels: 3,
+            element_type: ElementType::U8,
+        };
+        let mut shapes = vec![];
+        let mut cur_shape = input_shape;
+        for stage in stages {
+            cur_shape = stage.output_shape(cur_shape)?;
+            if cur_shape.checked_num_bytes().is_none() {
+                return Err(format!("{:?} makes {:?} frames, too big to allocate", stage, cur_shape));
+            }
+            shapes.push(cur_shape);
+        }
+        let buffers = std::iter::once(input_shape)
+            .chain(shapes.iter().copied())
+            .map(|shape| vec![0_f32; shape.num_elements()])
+            .collect();
+        Ok(Self {
+            stages: stages.to_vec(),
+            input_shape,
+            shapes,
+            buffers,
+            stack_filled: false,
+        })
+    }
+
+    pub fn output_shape(&self) -> Shape {
+        *self.shapes.last().unwrap_or(&self.input_shape)
+    }
+
+    /// New episode, forget stacked frames
+    pub fn reset(&mut self) {
+        self.stack_filled = false;
+    }
+
+    /// frame_buffer.frame_data -> frame_buffer.processed_data
+    pub fn process(&mut self, frame_buffer: &mut FrameBuffer) {
+        decode_rgb(frame_buffer, &mut self.buffers[0]);
+        for (stage_idx, stage) in self.stages.iter().enumerate() {
+            let input_shape = if stage_idx == 0 { self.input_shape } else { self.shapes[stage_idx - 1] };
+            let output_shape = self.shapes[stage_idx];
+            // input is buffers[stage_idx], output is buffers[stage_idx + 1]
+            let (done, rest) = self.buffers.split_at_mut(stage_idx + 1);
+            let input = &done[stage_idx];
+            let output = &mut rest[0];
+            match stage {
+                PreprocessStage::Grayscale => {
+                    input
+                        .chunks_exact(3)
+                        .zip(output.iter_mut())
+                        .for_each(|(rgb, luma)| *luma = (0.2989 * rgb[0]) + (0.5870 * rgb[1]) + (0.114 * rgb[2]));
+                },
+                PreprocessStage::Rgb => output.copy_from_slice(input),
+                PreprocessStage::Normalize => {
+                    input
+                        .iter()
+                        .zip(output.iter_mut())
+                        .for_each(|(value, normalized)| *normalized = value / 255.0);
+                },
+                PreprocessStage::Downscale { factor } => downscale(input, input_shape, output, output_shape, *factor),
+                PreprocessStage::Resize { .. } => resize(input, input_shape, output, output_shape),
+                PreprocessStage::Crop { x, y, .. } => crop(input, input_shape, output, output_shape, *x, *y),
+                PreprocessStage::FrameStack { .. } => {
+                    let frame_elements = input_shape.frame_elements();
+                    if self.stack_filled {
+                        // drop the oldest frame, newest goes at the end
+                        output.copy_within(frame_elements.., 0);
+                        let newest_start = output.len() - frame_elements;
+                        output[newest_start..].copy_from_slice(input);
+                    } else {
+                        // first frame of an episode fills the whole history
+                        output.chunks_exact_mut(frame_elements).for_each(|frame| frame.copy_from_slice(input));
+                        self.stack_filled = true;
+                    }
+                },
+            }
+        }
+
+        let output_shape = self.output_shape();
+        let output = self.buffers.last().unwrap();
+        match output_shape.element_type {
+            ElementType::U8 => output
+                .iter()
+                .zip(frame_buffer.processed_data.iter_mut())
+                .for_each(|(value, byte)| *byte = *value as u8),
+            ElementType::F32 => output
+                .iter()
+                .zip(frame_buffer.processed_data.chunks_exact_mut(4))
+                .for_each(|(value, bytes)| bytes.copy_from_slice(&value.to_le_bytes())),
+        }
+    }
+}
+
+// Raw emulator pixels -> RGB f32 (0..255), alpha dropped
+fn decode_rgb(frame_buffer: &FrameBuffer, rgb: &mut [f32]) {
+    let mut out_pixels = rgb.chunks_exact_mut(3);
+    frame_buffer.for_each_rgb(|pixel| {
+        if let Some(out) = out_pixels.next() {
+            out[0] = pixel[0] as f32;
+            out[1] = pixel[1] as f32;
+            out[2] = pixel[2] as f32;
+        }
+    });
+}
+
+fn downscale(input: &[f32], input_shape: Shape, output: &mut [f32], output_shape: Shape, factor: u32) {
+    let channels = input_shape.channels as usize;
+    let scale = 1.0 / (factor * factor) as f32;
+    for out_y in 0..output_shape.height as usize {
+        for out_x in 0..output_shape.width as usize {
+            for channel in 0..channels {
+                let mut sum = 0_f32;
+                for dy in 0..factor as usize {
+                    let in_y = out_y * factor as usize + dy;
+                    let row_start = in_y * input_shape.width as usize;
+                    for dx in 0..factor as usize {
+                        let in_x = out_x * factor as usize + dx;
+                        sum += input[(row_start + in_x) * channels + channel];
+                    }
+                }
+                output[(out_y * output_shape.width as usize + out_x) * channels + channel] = sum * scale;
+            }
+        }
+    }
+}
+
+fn resize(input: &[f32], input_shape: Shape, output: &mut [f32], output_shape: Shape) {
+    let channels = input_shape.channels as usize;
+    for out_y in 0..output_shape.height as usize {
+        let in_y = out_y * input_shape.height as usize / output_shape.height as usize;
+        for out_x in 0..output_shape.width as usize {
+            let in_x = out_x * input_shape.width as usize / output_shape.width as usize;
+            let in_idx = (in_y * input_shape.width as usize + in_x) * channels;
+            let out_idx = (out_y * output_shape.width as usize + out_x) * channels;
+            output[out_idx..out_idx + channels].copy_from_slice(&input[in_idx..in_idx + channels]);
+        }
+    }
+}
+
+fn crop(input: &[f32], input_shape: Shape, output: &mut [f32], output_shape: Shape, x: u32, y: u32) {
+    let channels = input_shape.channels as usize;
+    let row_len = output_shape.width as usize * channels;
+    for (out_y, out_row) in output.chunks_exact_mut(row_len).enumerate() {
+        let in_start = ((y as usize + out_y) * input_shape.width as usize + x as usize) * channels;
+        out_row.copy_from_slice(&input[in_start..in_start + row_len]);
+    }
+}
+
+/// Something save_album can write as a png: the newest frame, f32 scaled back up to 0..255
+pub fn preview_image(shape: Shape, processed_data: &[u8]) -> Option<(Vec<u8>, image::ColorType)> {
+    let color_type = match shape.channels {
+        1 => image::ColorType::L8,
+        3 => image::ColorType::Rgb8,
+        _ => return None,
+    };
+    let frame_elements = shape.frame_elements();
+    let newest_start = (shape.frames as usize - 1) * frame_elements;
+    let pixels = match shape.element_type {
+        ElementType::U8 => processed_data[newest_start..newest_start + frame_elements].to_vec(),
+        ElementType::F32 => processed_data[newest_start * 4..(newest_start + frame_elements) * 4]
+            .chunks_exact(4)
+            .map(|bytes| (f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) * 255.0) as u8)
+            .collect(),
+    };
+    Some((pixels, color_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ABGR8888 frame with pixel(x, y) as its colour
+    fn frame_buffer(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 3]) -> FrameBuffer {
+        let mut frame_buffer = FrameBuffer::new(width, height, 4, PixelFormatEnum::ABGR8888);
+        frame_buffer.frame_data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let [r, g, b] = pixel(x, y);
+                [r, g, b, 0xFF]
+            })
+            .collect();
+        frame_buffer
+    }
+
+    fn pipeline(stages: &[PreprocessStage], frame_buffer: &mut FrameBuffer) -> PreprocessPipeline {
+        let pipeline = PreprocessPipeline::new(stages, frame_buffer.width, frame_buffer.height, frame_buffer.pixel_format).unwrap();
+        frame_buffer.set_processed_shape(pipeline.output_shape());
+        pipeline
+    }
+
+    fn processed(stages: &[PreprocessStage], frame_buffer: &mut FrameBuffer) -> (Shape, Vec<u8>) {
+        let mut pipeline = pipeline(stages, frame_buffer);
+        pipeline.process(frame_buffer);
+        (pipeline.output_shape(), frame_buffer.processed_data.clone())
+    }
+
+    fn shape(frames: u32, height: u32, width: u32, channels: u32, element_type: ElementType) -> Shape {
+        Shape { frames, height, width, channels, element_type }
+    }
+
+    #[test]
+    fn downscale_averages_blocks() {
+        // 4x2, every 2x2 block averages to a round number
+        let mut frame_buffer = frame_buffer(4, 2, |x, y| {
+            let value = (x * 10 + y * 20) as u8;
+            [value, 100, 255 - value]
+        });
+        let (output_shape, data) =
+            processed(&[PreprocessStage::Rgb, PreprocessStage::Downscale { factor: 2 }], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 1, 2, 3, ElementType::U8));
+        // left block 0,10,20,30 -> 15, right block 20,30,40,50 -> 35
+        assert_eq!(data, vec![15, 100, 240, 35, 100, 220]);
+
+        let (output_shape, data) =
+            processed(&[PreprocessStage::Grayscale, PreprocessStage::Downscale { factor: 1 }], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 2, 4, 1, ElementType::U8));
+        assert_eq!(data.len(), 8);
+
+        let input = shape(1, 160, 240, 3, ElementType::U8);
+        assert!(PreprocessStage::Downscale { factor: 0 }.output_shape(input).is_err());
+        assert!(PreprocessStage::Downscale { factor: 7 }.output_shape(input).is_err());
+        assert_eq!(
+            PreprocessStage::Downscale { factor: 4 }.output_shape(input).unwrap(),
+            shape(1, 40, 60, 3, ElementType::U8)
+        );
+    }
+
+    #[test]
+    fn resize_picks_the_nearest_pixel() {
+        let mut frame_buffer = frame_buffer(4, 2, |x, y| [x as u8, y as u8, 7]);
+        let (output_shape, data) =
+            processed(&[PreprocessStage::Rgb, PreprocessStage::Resize { width: 2, height: 1 }], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 1, 2, 3, ElementType::U8));
+        assert_eq!(data, vec![0, 0, 7, 2, 0, 7]);
+
+        // growing repeats pixels
+        let (output_shape, data) =
+            processed(&[PreprocessStage::Rgb, PreprocessStage::Resize { width: 8, height: 2 }], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 2, 8, 3, ElementType::U8));
+        let red: Vec<u8> = data.chunks_exact(3).map(|pixel| pixel[0]).collect();
+        assert_eq!(red, vec![0, 0, 1, 1, 2, 2, 3, 3, 0, 0, 1, 1, 2, 2, 3, 3]);
+        let green: Vec<u8> = data.chunks_exact(3).map(|pixel| pixel[1]).collect();
+        assert_eq!(green, [vec![0; 8], vec![1; 8]].concat());
+
+        let input = shape(1, 160, 240, 1, ElementType::U8);
+        assert!(PreprocessStage::Resize { width: 0, height: 10 }.output_shape(input).is_err());
+        assert!(PreprocessStage::Resize { width: 10, height: 0 }.output_shape(input).is_err());
+    }
+
+    #[test]
+    fn crop_keeps_the_region_inside_the_frame() {
+        let mut frame_buffer = frame_buffer(4, 3, |x, y| [(y * 4 + x) as u8, 0, 0]);
+        let crop = PreprocessStage::Crop { x: 1, y: 1, width: 2, height: 2 };
+        let (output_shape, data) = processed(&[PreprocessStage::Rgb, crop], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 2, 2, 3, ElementType::U8));
+        let red: Vec<u8> = data.chunks_exact(3).map(|pixel| pixel[0]).collect();
+        assert_eq!(red, vec![5, 6, 9, 10]);
+
+        // right up against the bottom right corner is fine
+        let corner = PreprocessStage::Crop { x: 2, y: 1, width: 2, height: 2 };
+        let (_, data) = processed(&[PreprocessStage::Rgb, corner], &mut frame_buffer);
+        let red: Vec<u8> = data.chunks_exact(3).map(|pixel| pixel[0]).collect();
+        assert_eq!(red, vec![6, 7, 10, 11]);
+
+        // one pixel past either edge, or nothing at all, isn't
+        let input = shape(1, 3, 4, 3, ElementType::U8);
+        for (x, y, width, height) in [(3, 0, 2, 1), (0, 2, 1, 2), (4, 0, 1, 1), (0, 0, 0, 1), (0, 0, 1, 0), (u32::MAX, 0, 1, 1)] {
+            let crop = PreprocessStage::Crop { x, y, width, height };
+            assert!(crop.output_shape(input).is_err(), "{:?}", crop);
+        }
+        assert!(PreprocessStage::Crop { x: 0, y: 0, width: 4, height: 3 }.output_shape(input).is_ok());
+    }
+
+    #[test]
+    fn oversized_shapes_are_rejected_when_the_pipeline_is_built() {
+        let huge = PreprocessStage::Resize { width: u32::MAX, height: u32::MAX };
+        assert!(PreprocessPipeline::new(&[huge], 240, 160, PixelFormatEnum::ABGR8888).is_err());
+        let stacked = [PreprocessStage::Resize { width: 0x10000, height: 0x10000 }, PreprocessStage::FrameStack { frames: u32::MAX }];
+        assert!(PreprocessPipeline::new(&stacked, 240, 160, PixelFormatEnum::ABGR8888).is_err());
+        assert_eq!(shape(u32::MAX, u32::MAX, u32::MAX, 3, ElementType::F32).checked_num_bytes(), None);
+        assert_eq!(shape(4, 80, 120, 1, ElementType::F32).checked_num_bytes(), Some(4 * 80 * 120 * 4));
+    }
+
+    #[test]
+    fn normalize_outputs_little_endian_f32() {
+        let mut frame_buffer = frame_buffer(2, 1, |x, _| [x as u8 * 255, 51, 0]);
+        let (output_shape, data) = processed(&[PreprocessStage::Rgb, PreprocessStage::Normalize], &mut frame_buffer);
+        assert_eq!(output_shape, shape(1, 1, 2, 3, ElementType::F32));
+        assert_eq!(data.len(), output_shape.num_bytes());
+        let values: Vec<f32> =
+            data.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect();
+        assert_eq!(values, vec![0.0, 0.2, 0.0, 1.0, 0.2, 0.0]);
+
+        let normalized = shape(1, 1, 2, 3, ElementType::F32);
+        assert!(PreprocessStage::Normalize.output_shape(normalized).is_err());
+    }
+
+    #[test]
+    fn frame_stack_warms_up_again_after_a_reset() {
+        let stages = [PreprocessStage::Rgb, PreprocessStage::Downscale { factor: 2 }, PreprocessStage::FrameStack { frames: 3 }];
+        let mut frame_buffer = frame_buffer(2, 2, |_, _| [0, 0, 0]);
+        let mut pipeline = pipeline(&stages, &mut frame_buffer);
+        assert_eq!(pipeline.output_shape(), shape(3, 1, 1, 3, ElementType::U8));
+
+        // every frame a single flat colour, so each stacked frame is one pixel
+        let mut step = |value: u8, pipeline: &mut PreprocessPipeline| {
+            frame_buffer.frame_data.chunks_exact_mut(4).for_each(|pixel| pixel[..3].fill(value));
+            pipeline.process(&mut frame_buffer);
+            frame_buffer.processed_data.chunks_exact(3).map(|pixel| pixel[0]).collect::<Vec<u8>>()
+        };
+        // the first frame fills the whole history, then the oldest drops off the front
+        assert_eq!(step(1, &mut pipeline), vec![1, 1, 1]);
+        assert_eq!(step(2, &mut pipeline), vec![1, 1, 2]);
+        assert_eq!(step(3, &mut pipeline), vec![1, 2, 3]);
+        assert_eq!(step(4, &mut pipeline), vec![2, 3, 4]);
+        // nothing from the last episode survives a reset
+        pipeline.reset();
+        assert_eq!(step(9, &mut pipeline), vec![9, 9, 9]);
+        assert_eq!(step(8, &mut pipeline), vec![9, 9, 8]);
+
+        let stacked = shape(3, 2, 2, 1, ElementType::U8);
+        assert!(PreprocessStage::FrameStack { frames: 2 }.output_shape(stacked).is_err());
+        assert!(PreprocessStage::Crop { x: 0, y: 0, width: 1, height: 1 }.output_shape(stacked).is_err());
+        assert!(PreprocessStage::FrameStack { frames: 0 }.output_shape(shape(1, 2, 2, 1, ElementType::U8)).is_err());
+    }
+}
@@ -0,0 +1,1380 @@
+// Socket protocol between an Agent (AgentDriver::Sockets/UnixSocket/SharedMemory) and server::spawn_server
+// Runs the same over TCP and unix domain sockets (Endpoint)
+// Every message is framed: [length u32][type u8][payload], length counts the type byte + payload
+// Everything is big-endian (network order), same as the keycodes in an input log
+//
+// client                              server
+//   Hello {magic, version, caps,  ->
+//          session request}
+//                                 <-  Welcome {version, caps, frame encoding, width, height, bpp}  (or Error, then hang up)
+//                                     width/height/bpp are the frames as the client decodes them (frame_encoding.rs)
+//                                     a server with every session taken answers ServerFull
+//   Step {keycode}                ->
+//                                 <-  Frame {frame_data, encoded as agreed in the handshake}
+//   Reset                         ->
+//                                 <-  Frame {frame_data of the fresh core}
+//   Snapshot                      ->
+//                                 <-  SnapshotData {mCore::saveState bytes}
+//   ReadMemory {address, len}     ->
+//                                 <-  MemoryData {bytes}  (or Error if the range isn't mapped)
+//   MapFrames {slots}             ->
+//                                 <-  FramesMapped {path of a frame_ring::FrameRing}
+//                                     from now on Step/Reset answer FrameReady {slot, seq} instead of Frame,
+//                                     the frame itself is in the ring (same machine only)
+//   Shutdown                      ->
+//                                 <-  Goodbye, and the server stops (every other session too)
+// Hanging up without a Shutdown only ends that session
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use super::backend::{BackendError, EmulatorBackend};
+use super::frame_encoding::{FrameDecoder, FrameEncoding};
+use super::frame_ring::FrameRing;
+use super::mgba_ffi::MemoryBlock;
+use super::GameConfigData;
+
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"MGBA";
+// 2: the Hello carries a SessionRequest
+// 3: frame encodings, requested in the SessionRequest and confirmed in the Welcome
+pub const PROTOCOL_VERSION: u16 = 3;
+// biggest payload we'll accept, a savestate or a whole memory block fits with room to spare
+pub const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+// Capability bits exchanged in the handshake, the server answers with what both sides have
+pub const CAP_SNAPSHOT: u32 = 1 << 0;
+pub const CAP_READ_MEMORY: u32 = 1 << 1;
+pub const CAP_SHARED_FRAMES: u32 = 1 << 2;
+pub const CAP_MEMORY_BLOCKS: u32 = 1 << 3;
+pub const ALL_CAPABILITIES: u32 = CAP_SNAPSHOT | CAP_READ_MEMORY | CAP_SHARED_FRAMES | CAP_MEMORY_BLOCKS;
+
+pub mod message_type {
+    // client -> server
+    pub const HELLO: u8 = 0x01;
+    pub const STEP: u8 = 0x02;
+    pub const RESET: u8 = 0x03;
+    pub const SNAPSHOT: u8 = 0x04;
+    pub const READ_MEMORY: u8 = 0x05;
+    pub const SHUTDOWN: u8 = 0x06;
+    pub const MAP_FRAMES: u8 = 0x07;
+    pub const LIST_MEMORY_BLOCKS: u8 = 0x08;
+    // server -> client
+    pub const WELCOME: u8 = 0x81;
+    pub const FRAME: u8 = 0x82;
+    pub const SNAPSHOT_DATA: u8 = 0x84;
+    pub const MEMORY_DATA: u8 = 0x85;
+    pub const GOODBYE: u8 = 0x86;
+    pub const FRAMES_MAPPED: u8 = 0x87;
+    pub const FRAME_READY: u8 = 0x88;
+    pub const MEMORY_BLOCKS: u8 = 0x89;
+    pub const ERROR: u8 = 0xFF;
+}
+
+// What went wrong on the other end, sent along with a message in Response::Error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnexpectedMessage,
+    Malformed,
+    Memory,
+    Emulator,
+    ServerFull,
+    GameConfig,
+    SharedMemory,
+}
+
+impl ErrorCode {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::UnsupportedVersion => 1,
+            Self::UnexpectedMessage => 2,
+            Self::Malformed => 3,
+            Self::Memory => 4,
+            Self::Emulator => 5,
+            Self::ServerFull => 6,
+            Self::GameConfig => 7,
+            Self::SharedMemory => 8,
+        }
+    }
+
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::UnsupportedVersion),
+            2 => Some(Self::UnexpectedMessage),
+            3 => Some(Self::Malformed),
+            4 => Some(Self::Memory),
+            5 => Some(Self::Emulator),
+            6 => Some(Self::ServerFull),
+            7 => Some(Self::GameConfig),
+            8 => Some(Self::SharedMemory),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    Closed, // the other side hung up between messages
+    TimedOut, // nothing came back within the read timeout (or the connect timeout)
+    MessageTooLarge { len: u32 },
+    UnknownMessageType(u8),
+    Malformed { message_type: u8, reason: String },
+    BadMagic([u8; 4]),
+    VersionMismatch { ours: u16, theirs: u16 },
+    Unexpected { expected: &'static str, message_type: u8 },
+    MissingCapability(u32),
+    Remote { code: ErrorCode, message: String }, // the server answered with Response::Error
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Socket IO failed: {}", e),
+            Self::Closed => write!(f, "Connection closed by the other side"),
+            Self::TimedOut => write!(f, "Timed out waiting on the other side"),
+            Self::MessageTooLarge { len } => write!(f, "Message of {} bytes is over the {} byte limit", len, MAX_MESSAGE_LEN),
+            Self::UnknownMessageType(message_type) => write!(f, "Unknown message type {:#04x}", message_type),
+            Self::Malformed { message_type, reason } => write!(f, "Malformed message {:#04x}: {}", message_type, reason),
+            Self::BadMagic(magic) => write!(f, "Bad handshake magic {:?}, not an mgba agent", magic),
+            Self::VersionMismatch { ours, theirs } => write!(f, "Protocol version {} doesn't match the other side's {}", ours, theirs),
+            Self::Unexpected { expected, message_type } => {
+                write!(f, "Expected {}, got message type {:#04x}", expected, message_type)
+            },
+            Self::MissingCapability(capability) => write!(f, "Server doesn't support capability {:#x}", capability),
+            Self::Remote { code, message } => write!(f, "Server error ({:?}): {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => Self::Closed,
+            // a read timeout shows up as WouldBlock on unix
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::TimedOut,
+            _ => Self::Io(error),
+        }
+    }
+}
+
+// ################################################################################
+// Transports
+// ################################################################################
+
+/// Where a server listens and a Client connects
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(String), // host:port, the host can be a name (another container) or 0.0.0.0 for a server
+    Unix(PathBuf), // unix domain socket path, skips the TCP stack for agents on the same machine
+}
+
+impl Endpoint {
+    pub fn localhost(port: u16) -> Self {
+        Self::Tcp(format!("127.0.0.1:{}", port))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(socket_path) => write!(f, "socket {}", socket_path.display()),
+        }
+    }
+}
+
+/// How long to wait on a server and how hard to try reaching it, see connect_with_retry
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectOptions {
+    pub connect_timeout: Duration, // per attempt and resolved address
+    pub read_timeout: Option<Duration>, // for every response after connecting, None waits forever
+    pub connect_attempts: u32,
+    pub initial_backoff: Duration, // wait after the first failed attempt, doubles after every one after that
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_millis(1000),
+            read_timeout: None,
+            connect_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(2000),
+        }
+    }
+}
+
+/// connect_with_retry gave up, error is what the last attempt ran into
+#[derive(Debug)]
+pub struct ConnectError {
+    pub attempts: u32,
+    pub error: ProtocolError,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (after {} attempts)", self.error, self.attempts)
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// A connected socket of either kind, the protocol only needs Read + Write
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(endpoint: &Endpoint, connect_timeout: Duration) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(address) => {
+                // resolved on every connect, a container's address can change while we back off
+                let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} didn't resolve to any address", address));
+                for server_addr in address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&server_addr, connect_timeout) {
+                        Ok(stream) => {
+                            // requests are tiny and we wait on every response, Nagle would only add latency
+                            stream.set_nodelay(true)?;
+                            return Ok(Self::Tcp(stream));
+                        },
+                        Err(e) => last_error = e,
+                    }
+                }
+                Err(last_error)
+            },
+            Endpoint::Unix(socket_path) => Ok(Self::Unix(UnixStream::connect(socket_path)?)),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => Ok(Self::Tcp(stream.try_clone()?)),
+            Self::Unix(stream) => Ok(Self::Unix(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_write_timeout(timeout),
+            Self::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// ################################################################################
+// Framing
+// ################################################################################
+
+/// One frame on the wire, header and payload in a single write so small messages go out in one packet
+pub fn write_message(stream: &mut impl Write, message_type: u8, payload: &[u8]) -> Result<(), ProtocolError> {
+    let len = payload.len() as u64 + 1;
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(ProtocolError::MessageTooLarge { len: len.min(u32::MAX as u64) as u32 });
+    }
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.extend_from_slice(&(len as u32).to_be_bytes());
+    message.push(message_type);
+    message.extend_from_slice(payload);
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+// Length and type, the payload is still in the stream
+fn read_message_header(stream: &mut impl Read) -> Result<(u8, usize), ProtocolError> {
+    let mut header = [0_u8; 5];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    if len == 0 {
+        return Err(ProtocolError::Malformed { message_type: 0, reason: "zero length message".to_string() });
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(ProtocolError::MessageTooLarge { len });
+    }
+    Ok((header[4], len as usize - 1))
+}
+
+pub fn read_message(stream: &mut impl Read) -> Result<(u8, Vec<u8>), ProtocolError> {
+    let (message_type, payload_len) = read_message_header(stream)?;
+    let mut payload = vec![0_u8; payload_len];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, payload))
+}
+
+// Pulls fixed size fields off the front of a payload
+struct PayloadReader<'a> {
+    message_type: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.payload.len() < len {
+            return Err(ProtocolError::Malformed {
+                message_type: self.message_type,
+                reason: format!("payload ends {} bytes early", len - self.payload.len()),
+            });
+        }
+        let (field, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let field = self.take(2)?;
+        Ok(u16::from_be_bytes([field[0], field[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let field = self.take(4)?;
+        Ok(u32::from_be_bytes([field[0], field[1], field[2], field[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // encoding id, then its parameter
+    fn frame_encoding(&mut self) -> Result<FrameEncoding, ProtocolError> {
+        let (id, parameter) = (self.u8()?, self.u32()?);
+        FrameEncoding::from_wire(id, parameter).ok_or_else(|| ProtocolError::Malformed {
+            message_type: self.message_type,
+            reason: format!("unknown frame encoding {}", id),
+        })
+    }
+
+    // presence byte, then a u32 length and that many bytes
+    fn optional_bytes(&mut self) -> Result<Option<&'a [u8]>, ProtocolError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => {
+                let len = self.u32()? as usize;
+                Ok(Some(self.take(len)?))
+            },
+        }
+    }
+
+    // u16 length, then that much utf-8
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ProtocolError::Malformed {
+            message_type: self.message_type,
+            reason: "string isn't utf-8".to_string(),
+        })
+    }
+
+    // everything else in the payload
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.payload)
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        match self.payload.len() {
+            0 => Ok(()),
+            extra => Err(ProtocolError::Malformed {
+                message_type: self.message_type,
+                reason: format!("{} trailing bytes", extra),
+            }),
+        }
+    }
+}
+
+fn put_frame_encoding(payload: &mut Vec<u8>, frame_encoding: FrameEncoding) {
+    let (id, parameter) = frame_encoding.to_wire();
+    payload.push(id);
+    payload.extend_from_slice(&parameter.to_be_bytes());
+}
+
+fn put_string(payload: &mut Vec<u8>, string: &str) {
+    payload.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    payload.extend_from_slice(string.as_bytes());
+}
+
+fn put_optional_bytes(payload: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        None => payload.push(0),
+        Some(bytes) => {
+            payload.push(1);
+            payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            payload.extend_from_slice(bytes);
+        },
+    }
+}
+
+// ################################################################################
+// Messages
+// ################################################################################
+
+/// What the client wants the server to run, sent along with the Hello
+/// Without a game_config_data the server uses the one it was started with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionRequest {
+    pub game_config_data: Option<GameConfigData>,
+    // file contents for a server that can't see our filesystem, the paths are then only used for their file names
+    pub rom_data: Option<Vec<u8>>,
+    pub save_state_data: Option<Vec<u8>>,
+    // what we'd like frames sent as, the server's Welcome says what it went with
+    pub frame_encoding: FrameEncoding,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Hello { version: u16, capabilities: u32, session_request: Box<SessionRequest> }, // boxed, it dwarfs every other request
+    Step { keycode: u16 },
+    Reset,
+    Snapshot,
+    ReadMemory { address: u32, len: u32 },
+    Shutdown,
+    MapFrames { slots: u32 },
+    ListMemoryBlocks,
+}
+
+impl Request {
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<(), ProtocolError> {
+        let mut payload = vec![];
+        let message_type = match self {
+            Self::Hello { version, capabilities, session_request } => {
+                payload.extend_from_slice(&PROTOCOL_MAGIC);
+                payload.extend_from_slice(&version.to_be_bytes());
+                payload.extend_from_slice(&capabilities.to_be_bytes());
+                // GameConfigData goes as json, same as it sits in agent_config.json
+                let game_config_json = session_request
+                    .game_config_data
+                    .as_ref()
+                    .map(|game_config_data| serde_json::to_vec(game_config_data).expect("GameConfigData always serializes"));
+                put_optional_bytes(&mut payload, game_config_json.as_deref());
+                put_optional_bytes(&mut payload, session_request.rom_data.as_deref());
+                put_optional_bytes(&mut payload, session_request.save_state_data.as_deref());
+                put_frame_encoding(&mut payload, session_request.frame_encoding);
+                message_type::HELLO
+            },
+            Self::Step { keycode } => {
+                payload.extend_from_slice(&keycode.to_be_bytes());
+                message_type::STEP
+            },
+            Self::Reset => message_type::RESET,
+            Self::Snapshot => message_type::SNAPSHOT,
+            Self::ReadMemory { address, len } => {
+                payload.extend_from_slice(&address.to_be_bytes());
+                payload.extend_from_slice(&len.to_be_bytes());
+                message_type::READ_MEMORY
+            },
+            Self::Shutdown => message_type::SHUTDOWN,
+            Self::MapFrames { slots } => {
+                payload.extend_from_slice(&slots.to_be_bytes());
+                message_type::MAP_FRAMES
+            },
+            Self::ListMemoryBlocks => message_type::LIST_MEMORY_BLOCKS,
+        };
+        write_message(stream, message_type, &payload)
+    }
+
+    pub fn read_from(stream: &mut impl Read) -> Result<Self, ProtocolError> {
+        let (message_type, payload) = read_message(stream)?;
+        let mut reader = PayloadReader { message_type, payload: &payload };
+        let request = match message_type {
+            message_type::HELLO => {
+                let magic = reader.take(4)?;
+                if magic != PROTOCOL_MAGIC {
+                    return Err(ProtocolError::BadMagic([magic[0], magic[1], magic[2], magic[3]]));
+                }
+                let version = reader.u16()?;
+                let capabilities = reader.u32()?;
+                // the rest of another version's Hello might look different, read_hello turns it away anyway
+                if version != PROTOCOL_VERSION {
+                    return Ok(Self::Hello { version, capabilities, session_request: Box::default() });
+                }
+                let game_config_data = match reader.optional_bytes()? {
+                    Some(game_config_json) => Some(serde_json::from_slice(game_config_json).map_err(|e| {
+                        ProtocolError::Malformed { message_type, reason: format!("bad GameConfigData: {}", e) }
+                    })?),
+                    None => None,
+                };
+                let session_request = SessionRequest {
+                    game_config_data,
+                    rom_data: reader.optional_bytes()?.map(<[u8]>::to_vec),
+                    save_state_data: reader.optional_bytes()?.map(<[u8]>::to_vec),
+                    frame_encoding: reader.frame_encoding()?,
+                };
+                Self::Hello { version, capabilities, session_request: Box::new(session_request) }
+            },
+            message_type::STEP => Self::Step { keycode: reader.u16()? },
+            message_type::RESET => Self::Reset,
+            message_type::SNAPSHOT => Self::Snapshot,
+            message_type::READ_MEMORY => Self::ReadMemory { address: reader.u32()?, len: reader.u32()? },
+            message_type::SHUTDOWN => Self::Shutdown,
+            message_type::MAP_FRAMES => Self::MapFrames { slots: reader.u32()? },
+            message_type::LIST_MEMORY_BLOCKS => Self::ListMemoryBlocks,
+            _ => return Err(ProtocolError::UnknownMessageType(message_type)),
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+/// What the server tells the client in its Welcome
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ServerInfo {
+    pub version: u16,
+    pub capabilities: u32,
+    pub frame_encoding: FrameEncoding,
+    // frames after decoding, not necessarily what the core draws
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u32,
+}
+
+impl ServerInfo {
+    /// Bytes in a decoded frame, or why the Welcome's frame format can't be right
+    /// The format comes from the other end of a socket, so it's checked before anything gets allocated for it:
+    /// a raw frame has to fit in one message, which keeps a bogus Welcome from asking for gigabytes
+    pub fn frame_len(&self) -> Result<usize, ProtocolError> {
+        let malformed = |reason: String| ProtocolError::Malformed { message_type: message_type::WELCOME, reason };
+        if !(1..=4).contains(&self.bpp) {
+            return Err(malformed(format!("{} bytes per pixel", self.bpp)));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(malformed(format!("empty {}x{} frames", self.width, self.height)));
+        }
+        match self.width.checked_mul(self.height).and_then(|pixels| pixels.checked_mul(self.bpp)) {
+            Some(frame_len) if frame_len < MAX_MESSAGE_LEN => Ok(frame_len as usize),
+            _ => Err(malformed(format!(
+                "{}x{} frames at {} bytes per pixel don't fit in a {} byte message",
+                self.width, self.height, self.bpp, MAX_MESSAGE_LEN
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Welcome(ServerInfo),
+    Frame(Vec<u8>), // the server writes frames straight from its buffer with write_message instead
+    SnapshotData(Vec<u8>),
+    MemoryData(Vec<u8>),
+    Goodbye,
+    FramesMapped { path: String },
+    FrameReady { slot: u32, seq: u64 }, // the frame is in the client's FrameRing
+    MemoryBlocks(Vec<MemoryBlock>), // the server core's memory map, for resolving {"block":..} locations
+    Error { code: ErrorCode, message: String },
+}
+
+impl Response {
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<(), ProtocolError> {
+        match self {
+            Self::Welcome(server_info) => {
+                let mut payload = vec![];
+                payload.extend_from_slice(&server_info.version.to_be_bytes());
+                payload.extend_from_slice(&server_info.capabilities.to_be_bytes());
+                put_frame_encoding(&mut payload, server_info.frame_encoding);
+                payload.extend_from_slice(&server_info.width.to_be_bytes());
+                payload.extend_from_slice(&server_info.height.to_be_bytes());
+                payload.extend_from_slice(&server_info.bpp.to_be_bytes());
+                write_message(stream, message_type::WELCOME, &payload)
+            },
+            Self::Frame(frame_data) => write_message(stream, message_type::FRAME, frame_data),
+            Self::SnapshotData(state) => write_message(stream, message_type::SNAPSHOT_DATA, state),
+            Self::MemoryData(data) => write_message(stream, message_type::MEMORY_DATA, data),
+            Self::Goodbye => write_message(stream, message_type::GOODBYE, &[]),
+            Self::FramesMapped { path } => write_message(stream, message_type::FRAMES_MAPPED, path.as_bytes()),
+            Self::FrameReady { slot, seq } => {
+                let mut payload = vec![];
+                payload.extend_from_slice(&slot.to_be_bytes());
+                payload.extend_from_slice(&seq.to_be_bytes());
+                write_message(stream, message_type::FRAME_READY, &payload)
+            },
+            Self::MemoryBlocks(memory_blocks) => {
+                let mut payload = vec![];
+                payload.extend_from_slice(&(memory_blocks.len() as u32).to_be_bytes());
+                for block in memory_blocks {
+                    payload.extend_from_slice(&(block.id as u32).to_be_bytes());
+                    put_string(&mut payload, &block.internal_name);
+                    put_string(&mut payload, &block.short_name);
+                    for field in [block.start, block.end, block.size, block.flags] {
+                        payload.extend_from_slice(&field.to_be_bytes());
+                    }
+                }
+                write_message(stream, message_type::MEMORY_BLOCKS, &payload)
+            },
+            Self::Error { code, message } => {
+                let mut payload = vec![code.to_u8()];
+                payload.extend_from_slice(message.as_bytes());
+                write_message(stream, message_type::ERROR, &payload)
+            },
+        }
+    }
+
+    pub fn read_from(stream: &mut impl Read) -> Result<Self, ProtocolError> {
+        let (message_type, payload) = read_message(stream)?;
+        Self::decode(message_type, &payload)
+    }
+
+    fn decode(message_type: u8, payload: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = PayloadReader { message_type, payload };
+        let response = match message_type {
+            message_type::WELCOME => Self::Welcome(ServerInfo {
+                version: reader.u16()?,
+                capabilities: reader.u32()?,
+                frame_encoding: reader.frame_encoding()?,
+                width: reader.u32()?,
+                height: reader.u32()?,
+                bpp: reader.u32()?,
+            }),
+            message_type::FRAME => Self::Frame(reader.rest().to_vec()),
+            message_type::SNAPSHOT_DATA => Self::SnapshotData(reader.rest().to_vec()),
+            message_type::MEMORY_DATA => Self::MemoryData(reader.rest().to_vec()),
+            message_type::GOODBYE => Self::Goodbye,
+            message_type::FRAMES_MAPPED => Self::FramesMapped {
+                path: String::from_utf8(reader.rest().to_vec()).map_err(|_| ProtocolError::Malformed {
+                    message_type,
+                    reason: "frame ring path isn't utf-8".to_string(),
+                })?,
+            },
+            message_type::FRAME_READY => Self::FrameReady { slot: reader.u32()?, seq: reader.u64()? },
+            message_type::MEMORY_BLOCKS => {
+                let num_blocks = reader.u32()?;
+                let mut memory_blocks = vec![];
+                for _ in 0..num_blocks {
+                    memory_blocks.push(MemoryBlock {
+                        id: reader.u32()? as usize,
+                        internal_name: reader.string()?,
+                        short_name: reader.string()?,
+                        start: reader.u32()?,
+                        end: reader.u32()?,
+                        size: reader.u32()?,
+                        flags: reader.u32()?,
+                    });
+                }
+                Self::MemoryBlocks(memory_blocks)
+            },
+            message_type::ERROR => {
+                let raw_code = reader.u8()?;
+                let code = ErrorCode::from_u8(raw_code).ok_or_else(|| ProtocolError::Malformed {
+                    message_type,
+                    reason: format!("unknown error code {}", raw_code),
+                })?;
+                Self::Error { code, message: String::from_utf8_lossy(reader.rest()).into_owned() }
+            },
+            _ => return Err(ProtocolError::UnknownMessageType(message_type)),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+
+    // Turns a server Error into ProtocolError::Remote, anything else unexpected into Unexpected
+    fn unexpected(self, expected: &'static str, message_type: u8) -> ProtocolError {
+        match self {
+            Self::Error { code, message } => ProtocolError::Remote { code, message },
+            _ => ProtocolError::Unexpected { expected, message_type },
+        }
+    }
+}
+
+// ################################################################################
+// Server side
+// ################################################################################
+
+/// First thing a server does with a new connection: wait for the Hello
+/// Returns the client's capabilities and what it wants to run, a bad Hello gets an Error response before the Err comes back
+pub fn read_hello(stream: &mut (impl Read + Write)) -> Result<(u32, SessionRequest), ProtocolError> {
+    let (code, error) = match Request::read_from(stream) {
+        Ok(Request::Hello { version, capabilities, session_request }) if version == PROTOCOL_VERSION => {
+            return Ok((capabilities, *session_request));
+        },
+        Ok(Request::Hello { version, .. }) => (
+            ErrorCode::UnsupportedVersion,
+            ProtocolError::VersionMismatch { ours: PROTOCOL_VERSION, theirs: version },
+        ),
+        Ok(request) => (
+            ErrorCode::UnexpectedMessage,
+            ProtocolError::Malformed { message_type: 0, reason: format!("expected Hello, got {:?}", request) },
+        ),
+        Err(error @ (ProtocolError::Io(_) | ProtocolError::Closed)) => return Err(error),
+        Err(error) => (ErrorCode::Malformed, error),
+    };
+    // best effort, the client might already be gone
+    let _ = Response::Error { code, message: error.to_string() }.write_to(stream);
+    Err(error)
+}
+
+// ################################################################################
+// Client side
+// ################################################################################
+
+/// The Agent's end of the connection, every call is one request and its response
+pub struct Client {
+    stream: Stream,
+    server_info: ServerInfo,
+    frame_decoder: FrameDecoder,
+    payload: Vec<u8>, // encoded frames land here before decoding
+    frame_ring: Option<FrameRing>, // after map_frames, frames come through here instead of the socket
+    memory_blocks: Vec<MemoryBlock>, // fetched right after the Welcome, empty without CAP_MEMORY_BLOCKS
+    // EmulatorBackend side: keys for the next step and the last frame the server sent
+    held_keys: u16,
+    frame_data: Vec<u8>,
+}
+
+impl Client {
+    /// One attempt, connect_options decides the timeouts
+    pub fn connect_with(
+        endpoint: &Endpoint,
+        session_request: SessionRequest,
+        connect_options: &ConnectOptions,
+    ) -> Result<Self, ProtocolError> {
+        let mut stream = Stream::connect(endpoint, connect_options.connect_timeout)?;
+        // covers the handshake too, a server that accepts and then says nothing shouldn't hang us
+        stream.set_read_timeout(connect_options.read_timeout)?;
+        stream.set_write_timeout(connect_options.read_timeout)?;
+
+        Request::Hello { version: PROTOCOL_VERSION, capabilities: ALL_CAPABILITIES, session_request: Box::new(session_request) }
+            .write_to(&mut stream)?;
+        let server_info = match Response::read_from(&mut stream)? {
+            Response::Welcome(server_info) if server_info.version == PROTOCOL_VERSION => server_info,
+            Response::Welcome(server_info) => {
+                return Err(ProtocolError::VersionMismatch { ours: PROTOCOL_VERSION, theirs: server_info.version });
+            },
+            response => return Err(response.unexpected("Welcome", message_type::WELCOME)),
+        };
+        let frame_len = server_info.frame_len()?;
+        let frame_decoder =
+            FrameDecoder::new(server_info.frame_encoding, server_info.width, server_info.height, server_info.bpp)
+                .map_err(|reason| ProtocolError::Malformed { message_type: message_type::WELCOME, reason })?;
+        let frame_data = vec![0_u8; frame_len];
+        let mut client = Self {
+            stream,
+            server_info,
+            frame_decoder,
+            payload: vec![],
+            frame_ring: None,
+            memory_blocks: vec![],
+            held_keys: 0,
+            frame_data,
+        };
+        // the map doesn't change during a session, one round trip here saves one on every block variable read
+        if client.require(CAP_MEMORY_BLOCKS).is_ok() {
+            client.memory_blocks = client.list_memory_blocks()?;
+        }
+        Ok(client)
+    }
+
+    /// Keep trying until the server takes us or connect_options.connect_attempts run out, backing off in between
+    /// Only retries what waiting can fix: nobody listening (yet), timeouts, a full server
+    pub fn connect_with_retry(
+        endpoint: &Endpoint,
+        session_request: &SessionRequest,
+        connect_options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        let mut backoff = connect_options.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match Self::connect_with(endpoint, session_request.clone(), connect_options) {
+                Ok(client) => return Ok(client),
+                Err(error) => error,
+            };
+            let retryable = matches!(
+                error,
+                ProtocolError::Io(_)
+                    | ProtocolError::Closed
+                    | ProtocolError::TimedOut
+                    | ProtocolError::Remote { code: ErrorCode::ServerFull, .. }
+            );
+            if !retryable || attempts >= connect_options.connect_attempts {
+                return Err(ConnectError { attempts, error });
+            }
+            println!("Connecting to {} failed ({}), retrying in {:?}", endpoint, error, backoff);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(connect_options.max_backoff);
+        }
+    }
+
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server_info
+    }
+
+    fn require(&self, capability: u32) -> Result<(), ProtocolError> {
+        match self.server_info.capabilities & capability {
+            0 => Err(ProtocolError::MissingCapability(capability)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Have the server put frames into a shared memory ring of slots, only works if it runs on this machine
+    pub fn map_frames(&mut self, slots: u32) -> Result<(), ProtocolError> {
+        self.require(CAP_SHARED_FRAMES)?;
+        Request::MapFrames { slots }.write_to(&mut self.stream)?;
+        let path = match Response::read_from(&mut self.stream)? {
+            Response::FramesMapped { path } => path,
+            response => return Err(response.unexpected("FramesMapped", message_type::FRAMES_MAPPED)),
+        };
+        let frame_ring = FrameRing::open(Path::new(&path))?;
+        let frame_len = self.server_info.frame_len()?;
+        if frame_ring.frame_len() != frame_len {
+            return Err(ProtocolError::Malformed {
+                message_type: message_type::FRAMES_MAPPED,
+                reason: format!("ring frames are {} bytes, the Welcome said {}", frame_ring.frame_len(), frame_len),
+            });
+        }
+        self.frame_ring = Some(frame_ring);
+        Ok(())
+    }
+
+    // Frames are the hot path, read the payload straight into frame_data (or copy it out of the ring)
+    fn read_frame(&mut self, frame_data: &mut [u8]) -> Result<(), ProtocolError> {
+        let (message_type, payload_len) = read_message_header(&mut self.stream)?;
+        if message_type == message_type::FRAME && self.frame_decoder.encoding() != FrameEncoding::Raw {
+            self.payload.resize(payload_len, 0);
+            self.stream.read_exact(&mut self.payload)?;
+            return self
+                .frame_decoder
+                .decode(&self.payload, frame_data)
+                .map_err(|reason| ProtocolError::Malformed { message_type, reason });
+        }
+        if message_type == message_type::FRAME {
+            if payload_len != frame_data.len() {
+                // still take it off the stream, so the next request lines up with its response
+                io::copy(&mut (&mut self.stream).take(payload_len as u64), &mut io::sink())?;
+                return Err(ProtocolError::Malformed {
+                    message_type,
+                    reason: format!("frame is {} bytes, expected {}", payload_len, frame_data.len()),
+                });
+            }
+            self.stream.read_exact(frame_data)?;
+            return Ok(());
+        }
+        let mut payload = vec![0_u8; payload_len];
+        self.stream.read_exact(&mut payload)?;
+        match (Response::decode(message_type, &payload)?, &self.frame_ring) {
+            (Response::FrameReady { slot, seq }, Some(frame_ring)) => Ok(frame_ring.read(slot, seq, frame_data)?),
+            (response, _) => Err(response.unexpected("Frame", message_type)),
+        }
+    }
+
+    /// Run one frame with keycode held down, the new frame is written into frame_data
+    pub fn step(&mut self, keycode: u16, frame_data: &mut [u8]) -> Result<(), ProtocolError> {
+        Request::Step { keycode }.write_to(&mut self.stream)?;
+        self.read_frame(frame_data)
+    }
+
+    /// Put the server's core back to how it was right after initialization
+    pub fn reset(&mut self, frame_data: &mut [u8]) -> Result<(), ProtocolError> {
+        Request::Reset.write_to(&mut self.stream)?;
+        self.read_frame(frame_data)
+    }
+
+    pub fn snapshot(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        self.require(CAP_SNAPSHOT)?;
+        Request::Snapshot.write_to(&mut self.stream)?;
+        match Response::read_from(&mut self.stream)? {
+            Response::SnapshotData(state) => Ok(state),
+            response => Err(response.unexpected("SnapshotData", message_type::SNAPSHOT_DATA)),
+        }
+    }
+
+    pub fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, ProtocolError> {
+        self.require(CAP_READ_MEMORY)?;
+        Request::ReadMemory { address, len }.write_to(&mut self.stream)?;
+        match Response::read_from(&mut self.stream)? {
+            Response::MemoryData(data) if data.len() == len as usize => Ok(data),
+            Response::MemoryData(data) => Err(ProtocolError::Malformed {
+                message_type: message_type::MEMORY_DATA,
+                reason: format!("asked for {} bytes, got {}", len, data.len()),
+            }),
+            response => Err(response.unexpected("MemoryData", message_type::MEMORY_DATA)),
+        }
+    }
+
+    /// The server core's memory blocks, what {"block":..} game variable locations resolve against
+    pub fn list_memory_blocks(&mut self) -> Result<Vec<MemoryBlock>, ProtocolError> {
+        self.require(CAP_MEMORY_BLOCKS)?;
+        Request::ListMemoryBlocks.write_to(&mut self.stream)?;
+        match Response::read_from(&mut self.stream)? {
+            Response::MemoryBlocks(memory_blocks) => Ok(memory_blocks),
+            response => Err(response.unexpected("MemoryBlocks", message_type::MEMORY_BLOCKS)),
+        }
+    }
+
+    /// Tell the server to stop listening, the connection is done after this
+    pub fn shutdown(&mut self) -> Result<(), ProtocolError> {
+        Request::Shutdown.write_to(&mut self.stream)?;
+        match Response::read_from(&mut self.stream)? {
+            Response::Goodbye => Ok(()),
+            response => Err(response.unexpected("Goodbye", message_type::GOODBYE)),
+        }
+    }
+}
+
+// Same calls as above, the frame lives in the Client instead of the caller's buffer
+impl EmulatorBackend for Client {
+    fn video_size(&self) -> (u32, u32, u32) {
+        (self.server_info.width, self.server_info.height, self.server_info.bpp)
+    }
+
+    fn set_keys(&mut self, keycode: u16) -> Result<(), BackendError> {
+        self.held_keys = keycode;
+        Ok(())
+    }
+
+    fn run_frame(&mut self) -> Result<(), BackendError> {
+        let mut frame_data = std::mem::take(&mut self.frame_data);
+        let result = self.step(self.held_keys, &mut frame_data);
+        self.frame_data = frame_data;
+        result.map_err(BackendError::Session)
+    }
+
+    fn framebuffer(&self) -> &[u8] {
+        &self.frame_data
+    }
+
+    fn restart(&mut self) -> Result<(), BackendError> {
+        let mut frame_data = std::mem::take(&mut self.frame_data);
+        let result = self.reset(&mut frame_data);
+        self.frame_data = frame_data;
+        result.map_err(BackendError::Session)
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<u8>, BackendError> {
+        Client::snapshot(self).map_err(BackendError::Session)
+    }
+
+    fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, BackendError> {
+        Client::read_memory(self, address, len).map_err(BackendError::Session)
+    }
+
+    fn memory_blocks(&self) -> &[MemoryBlock] {
+        &self.memory_blocks
+    }
+
+    fn seed_rng(&mut self, _rng_seed: &super::RngSeed, _seed: u32) -> Result<(), BackendError> {
+        // the server only seeds with the game_config_data we connected with
+        Err(BackendError::Unsupported("reseed over sockets yet"))
+    }
+
+    fn shutdown(&mut self) -> Result<(), BackendError> {
+        Client::shutdown(self).map_err(BackendError::Session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::frame_encoding::FrameEncoder;
+    use crate::agent_stuff::frame_ring;
+    use crate::agent_stuff::game_definition::{GameVariable, VariableLocation};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn messages_round_trip() {
+        let requests = [
+            Request::Hello { version: PROTOCOL_VERSION, capabilities: ALL_CAPABILITIES, session_request: Box::default() },
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: CAP_SNAPSHOT,
+                session_request: Box::new(SessionRequest {
+                    game_config_data: Some(GameConfigData {
+                        rom_path: "roms/game.gba".to_string(),
+                        save_state_path: Some("states/start.ss1".to_string()),
+                        save_state_flags: 8,
+                        rng_seed: None,
+                        upload_files: true,
+                        libmgba_path: None,
+                        mgba_log: Default::default(),
+                    }),
+                    rom_data: Some(vec![0x2E; 32]),
+                    save_state_data: Some(vec![]),
+                    frame_encoding: FrameEncoding::Downscale { factor: 2 },
+                }),
+            },
+            Request::Step { keycode: 0x0123 },
+            Request::Reset,
+            Request::Snapshot,
+            Request::ReadMemory { address: 0x0200_0000, len: 16 },
+            Request::Shutdown,
+            Request::MapFrames { slots: 2 },
+            Request::ListMemoryBlocks,
+        ];
+        let mut wire = vec![];
+        for request in &requests {
+            request.write_to(&mut wire).unwrap();
+        }
+        let mut wire = &wire[..];
+        for request in &requests {
+            assert_eq!(Request::read_from(&mut wire).unwrap(), *request);
+        }
+
+        let responses = [
+            Response::Welcome(ServerInfo {
+                version: PROTOCOL_VERSION,
+                capabilities: CAP_SNAPSHOT,
+                frame_encoding: FrameEncoding::DeltaRle,
+                width: 240,
+                height: 160,
+                bpp: 4,
+            }),
+            Response::Frame(vec![1, 2, 3]),
+            Response::SnapshotData(vec![]),
+            Response::MemoryData(vec![9; 4]),
+            Response::Goodbye,
+            Response::FramesMapped { path: "/dev/shm/mgba_frames_1_0".to_string() },
+            Response::FrameReady { slot: 1, seq: 0x0102_0304_0506 },
+            Response::MemoryBlocks(vec![]),
+            Response::MemoryBlocks(vec![fake_wram(), MemoryBlock { id: 9, internal_name: String::new(), ..fake_wram() }]),
+            Response::Error { code: ErrorCode::Memory, message: "nope".to_string() },
+        ];
+        let mut wire = vec![];
+        for response in &responses {
+            response.write_to(&mut wire).unwrap();
+        }
+        let mut wire = &wire[..];
+        for response in &responses {
+            assert_eq!(Response::read_from(&mut wire).unwrap(), *response);
+        }
+        assert!(matches!(Response::read_from(&mut wire), Err(ProtocolError::Closed)));
+    }
+
+    #[test]
+    fn bad_messages_are_errors() {
+        // truncated Step payload
+        let mut wire = vec![];
+        write_message(&mut wire, message_type::STEP, &[1]).unwrap();
+        assert!(matches!(Request::read_from(&mut &wire[..]), Err(ProtocolError::Malformed { .. })));
+        // unknown type
+        let mut wire = vec![];
+        write_message(&mut wire, 0x42, &[]).unwrap();
+        assert!(matches!(Request::read_from(&mut &wire[..]), Err(ProtocolError::UnknownMessageType(0x42))));
+        // length over the limit, without having to send that much
+        let wire = [(MAX_MESSAGE_LEN + 1).to_be_bytes().as_slice(), &[message_type::FRAME]].concat();
+        assert!(matches!(Response::read_from(&mut &wire[..]), Err(ProtocolError::MessageTooLarge { .. })));
+        // a Hello from something that isn't us
+        let mut wire = vec![];
+        write_message(&mut wire, message_type::HELLO, b"HTTP\x00\x01\x00\x00\x00\x00").unwrap();
+        assert!(matches!(Request::read_from(&mut &wire[..]), Err(ProtocolError::BadMagic(_))));
+    }
+
+    // Stand-in server: frames are filled with the low byte of the last keycode
+    fn fake_wram() -> MemoryBlock {
+        MemoryBlock {
+            id: 1,
+            internal_name: "wram".to_string(),
+            short_name: "WRAM".to_string(),
+            start: 0,
+            end: 16,
+            size: 16,
+            flags: 3,
+        }
+    }
+
+    fn serve_fake_session(mut stream: Stream, server_capabilities: u32) {
+        let (capabilities, session_request) = read_hello(&mut stream).unwrap();
+        assert_eq!(session_request.rom_data.as_deref(), Some(&b"rom"[..]));
+        let frame_encoding = session_request.frame_encoding;
+        let mut frame_encoder = FrameEncoder::new(frame_encoding, 2, 2, 4).unwrap();
+        let (width, height, bpp) = frame_encoding.decoded_format(2, 2, 4).unwrap();
+        Response::Welcome(ServerInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities & server_capabilities,
+            frame_encoding,
+            width,
+            height,
+            bpp,
+        })
+        .write_to(&mut stream)
+        .unwrap();
+        let mut frame_ring: Option<FrameRing> = None;
+        let mut frame = |frame_data: Vec<u8>, frame_ring: &mut Option<FrameRing>| match frame_ring {
+            Some(frame_ring) => {
+                let (slot, seq) = frame_ring.publish(&frame_data);
+                Response::FrameReady { slot, seq }
+            },
+            None => Response::Frame(frame_encoder.encode(&frame_data).to_vec()),
+        };
+        loop {
+            match Request::read_from(&mut stream).unwrap() {
+                Request::Step { keycode } => frame(vec![keycode as u8; 16], &mut frame_ring),
+                Request::Reset => frame(vec![0; 16], &mut frame_ring),
+                Request::MapFrames { slots } => {
+                    let path = frame_ring::default_dir().join(format!("mgba_frames_test_{}", std::process::id()));
+                    let mapped_ring = FrameRing::create(&path, slots, 16).unwrap();
+                    frame_ring = Some(mapped_ring);
+                    Response::FramesMapped { path: path.to_string_lossy().into_owned() }
+                },
+                Request::ReadMemory { address: 0, len } => Response::MemoryData(vec![7; len as usize]),
+                Request::ReadMemory { .. } => Response::Error { code: ErrorCode::Memory, message: "unmapped".to_string() },
+                Request::ListMemoryBlocks => Response::MemoryBlocks(vec![fake_wram()]),
+                Request::Shutdown => {
+                    Response::Goodbye.write_to(&mut stream).unwrap();
+                    return;
+                },
+                request => panic!("Fake server didn't expect {:?}", request),
+            }
+            .write_to(&mut stream)
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn bogus_frame_formats_are_malformed() {
+        let info = |width, height, bpp| ServerInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            frame_encoding: FrameEncoding::Raw,
+            width,
+            height,
+            bpp,
+        };
+        assert_eq!(info(240, 160, 4).frame_len().unwrap(), 240 * 160 * 4);
+        for bogus in [info(240, 160, 0), info(240, 160, 5), info(0, 160, 4), info(240, 0, 4), info(0x10000, 0x10000, 4), info(8192, 8192, 1)] {
+            assert!(matches!(
+                bogus.frame_len(),
+                Err(ProtocolError::Malformed { message_type: message_type::WELCOME, .. })
+            ), "{:?}", bogus);
+        }
+    }
+
+    #[test]
+    fn client_talks_to_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_session(Stream::Tcp(stream), CAP_READ_MEMORY)
+        });
+
+        let session_request = SessionRequest { rom_data: Some(b"rom".to_vec()), ..SessionRequest::default() };
+        let mut client = Client::connect_with(&Endpoint::localhost(port), session_request, &ConnectOptions::default()).unwrap();
+        assert_eq!(client.server_info().width, 2);
+        let mut frame_data = vec![0_u8; 16];
+        client.step(0x0105, &mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 5));
+        client.reset(&mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 0));
+        assert_eq!(client.read_memory(0, 3).unwrap(), vec![7; 3]);
+        assert!(matches!(client.read_memory(4, 3), Err(ProtocolError::Remote { code: ErrorCode::Memory, .. })));
+        // the fake server didn't agree to snapshots, so the client doesn't even ask
+        assert!(matches!(client.snapshot(), Err(ProtocolError::MissingCapability(CAP_SNAPSHOT))));
+        // a wrongly sized frame is an error rather than a short read, and the connection stays usable
+        let mut short_frame = vec![0_u8; 8];
+        assert!(matches!(client.step(1, &mut short_frame), Err(ProtocolError::Malformed { .. })));
+        client.step(2, &mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 2));
+        assert!(matches!(client.map_frames(2), Err(ProtocolError::MissingCapability(CAP_SHARED_FRAMES))));
+        assert!(matches!(client.list_memory_blocks(), Err(ProtocolError::MissingCapability(CAP_MEMORY_BLOCKS))));
+        client.shutdown().unwrap();
+        server_handle.join().unwrap();
+    }
+
+    #[test]
+    fn client_works_as_an_emulator_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_session(Stream::Tcp(stream), CAP_READ_MEMORY | CAP_MEMORY_BLOCKS)
+        });
+
+        let session_request = SessionRequest { rom_data: Some(b"rom".to_vec()), ..SessionRequest::default() };
+        let mut client = Client::connect_with(&Endpoint::localhost(port), session_request, &ConnectOptions::default()).unwrap();
+        let backend: &mut dyn EmulatorBackend = &mut client;
+        assert_eq!(backend.video_size(), (2, 2, 4));
+        assert_eq!(backend.framebuffer(), [0; 16]);
+        // keys only go out with the next frame
+        backend.set_keys(3).unwrap();
+        assert_eq!(backend.framebuffer(), [0; 16]);
+        backend.run_frame().unwrap();
+        assert_eq!(backend.framebuffer(), [3; 16]);
+        backend.run_frame().unwrap();
+        assert_eq!(backend.framebuffer(), [3; 16]);
+        backend.restart().unwrap();
+        assert_eq!(backend.framebuffer(), [0; 16]);
+        assert_eq!(backend.read_memory(0, 2).unwrap(), vec![7; 2]);
+        assert!(matches!(backend.snapshot(), Err(BackendError::Session(ProtocolError::MissingCapability(_)))));
+        // the block list came along with the handshake, so game variables can use block locations
+        assert_eq!(backend.memory_blocks(), [fake_wram()]);
+        assert_eq!(backend.block_address("WRAM", 4, 4).unwrap(), 4);
+        assert!(matches!(backend.block_address("wram", 14, 4), Err(BackendError::Memory(_))));
+        assert!(matches!(backend.block_address("vram", 0, 1), Err(BackendError::Memory(_))));
+        let score = GameVariable {
+            name: "score".to_string(),
+            location: VariableLocation::Block { block: "wram".to_string(), offset: 0 },
+            width: 2,
+            signed: false,
+        };
+        assert_eq!(score.read(backend).unwrap(), 0x0707 as f64);
+        backend.shutdown().unwrap();
+        server_handle.join().unwrap();
+    }
+
+    #[test]
+    fn encoded_frames_reach_the_client_bit_exact() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_handle = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                serve_fake_session(Stream::Tcp(stream), ALL_CAPABILITIES);
+            }
+        });
+
+        let session_request = SessionRequest {
+            rom_data: Some(b"rom".to_vec()),
+            frame_encoding: FrameEncoding::DeltaRle,
+            ..SessionRequest::default()
+        };
+        let mut client = Client::connect_with(&Endpoint::localhost(port), session_request, &ConnectOptions::default()).unwrap();
+        assert_eq!(client.server_info().frame_encoding, FrameEncoding::DeltaRle);
+        let mut frame_data = vec![0_u8; 16];
+        for keycode in [1, 1, 2, 0x0300] {
+            client.step(keycode, &mut frame_data).unwrap();
+            assert!(frame_data.iter().all(|byte| *byte == keycode as u8));
+        }
+        // a frame the caller sized wrong is an error, but the delta after it still lines up
+        assert!(matches!(client.step(9, &mut [0_u8; 8]), Err(ProtocolError::Malformed { .. })));
+        client.step(9, &mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 9));
+        client.shutdown().unwrap();
+
+        // Grayscale frames come back as gray ABGR8888 pixels
+        let session_request = SessionRequest {
+            rom_data: Some(b"rom".to_vec()),
+            frame_encoding: FrameEncoding::Grayscale,
+            ..SessionRequest::default()
+        };
+        let mut client = Client::connect_with(&Endpoint::localhost(port), session_request, &ConnectOptions::default()).unwrap();
+        client.step(0xFF, &mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 0xFF));
+        client.shutdown().unwrap();
+        server_handle.join().unwrap();
+    }
+
+    #[test]
+    fn connect_retries_then_gives_up_and_reads_time_out() {
+        // a free port nobody listens on yet
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let connect_options = ConnectOptions {
+            read_timeout: Some(Duration::from_millis(100)),
+            connect_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            ..ConnectOptions::default()
+        };
+        let connect_error = Client::connect_with_retry(&Endpoint::localhost(port), &SessionRequest::default(), &connect_options)
+            .err()
+            .unwrap();
+        assert_eq!(connect_error.attempts, 3);
+        assert!(matches!(connect_error.error, ProtocolError::Io(_)));
+
+        // a server that takes the connection and never answers the Hello
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let server_handle = thread::spawn(move || listener.accept().unwrap());
+        let result = Client::connect_with(&Endpoint::localhost(port), SessionRequest::default(), &connect_options);
+        assert!(matches!(result, Err(ProtocolError::TimedOut)));
+        server_handle.join().unwrap();
+    }
+
+    #[test]
+    fn shared_frames_over_a_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!("mgba_protocol_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_session(Stream::Unix(stream), ALL_CAPABILITIES)
+        });
+
+        let session_request = SessionRequest { rom_data: Some(b"rom".to_vec()), ..SessionRequest::default() };
+        let mut client = Client::connect_with(&Endpoint::Unix(socket_path.clone()), session_request, &ConnectOptions::default()).unwrap();
+        let mut frame_data = vec![0_u8; 16];
+        // plain frames over the socket first
+        client.step(3, &mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 3));
+        client.map_frames(2).unwrap();
+        // more steps than slots, so the ring wraps around
+        for keycode in 4..9 {
+            client.step(keycode, &mut frame_data).unwrap();
+            assert!(frame_data.iter().all(|byte| *byte == keycode as u8));
+        }
+        client.reset(&mut frame_data).unwrap();
+        assert!(frame_data.iter().all(|byte| *byte == 0));
+        client.shutdown().unwrap();
+        server_handle.join().unwrap();
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn version_mismatch_is_reported_to_the_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_hello(&mut stream)
+        });
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        // a version 1 Hello, without the session request
+        write_message(&mut stream, message_type::HELLO, &[&PROTOCOL_MAGIC[..], &1_u16.to_be_bytes(), &0_u32.to_be_bytes()].concat()).unwrap();
+        assert!(matches!(
+            Response::read_from(&mut stream).unwrap(),
+            Response::Error { code: ErrorCode::UnsupportedVersion, .. }
+        ));
+        assert!(matches!(server_handle.join().unwrap(), Err(ProtocolError::VersionMismatch { .. })));
+    }
+}
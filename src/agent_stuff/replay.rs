@@ -0,0 +1,237 @@
+// Recorded per-frame keycodes that AgentControl::Replay feeds back into the emulator
+// Three sources:
+// 1) An album directory written by ObservationSet::save_album ({:0>4}/img.data json files)
+// 2) A trajectory file written by trajectory::TrajectoryWriter
+// 3) A compact input log: one big-endian u16 keycode per frame, same encoding as the socket protocol
+// A replay that can't be read (or would go out of sync) is an Err with the reason, never a partial replay
+
+use std::fs::{self, File};
+use std::io::{prelude::*, BufWriter};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::dataset::{self, DatasetFrames};
+use super::trajectory;
+
+// File name of an album's input log, every keycode of the episode (the album's frames are only the tail end)
+pub const INPUT_LOG_FILE_NAME: &str = "input_log.keys";
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    pub replay_path: String, // album directory, trajectory file or compact input log file
+    pub loop_replay: bool,   // start over at the end of the log instead of stopping
+}
+
+pub struct InputLog {
+    keycodes: Vec<u16>,
+    cursor: usize,
+    loop_replay: bool,
+}
+
+impl InputLog {
+    pub fn new(keycodes: Vec<u16>, loop_replay: bool) -> Self {
+        Self {
+            keycodes,
+            cursor: 0,
+            loop_replay,
+        }
+    }
+
+    pub fn from_config(replay_config: &ReplayConfig) -> Result<Self, String> {
+        let replay_path = Path::new(&replay_config.replay_path);
+        let keycodes = if replay_path.is_dir() {
+            // prefer the whole episode's log if the album has one, the img.data frames are only the tail end
+            let input_log_path = replay_path.join(INPUT_LOG_FILE_NAME);
+            if input_log_path.exists() {
+                read_input_log(&input_log_path)?
+            } else {
+                read_album_keycodes(replay_path)?
+            }
+        } else if trajectory::is_trajectory_file(replay_path) {
+            read_trajectory_keycodes(replay_path)?
+        } else {
+            read_input_log(replay_path)?
+        };
+        println!(
+            "Loaded replay of {} frames from {}",
+            keycodes.len(),
+            replay_path.display()
+        );
+        Ok(Self::new(keycodes, replay_config.loop_replay))
+    }
+
+    /// Next frame's keycode, None once the log is exhausted (and we aren't looping)
+    #[inline(always)]
+    pub fn next_keycode(&mut self) -> Option<u16> {
+        if self.cursor >= self.keycodes.len() {
+            if !self.loop_replay || self.keycodes.is_empty() {
+                return None;
+            }
+            self.cursor = 0;
+        }
+        let keycode = self.keycodes[self.cursor];
+        self.cursor += 1;
+        Some(keycode)
+    }
+}
+
+/// Input log written a keycode at a time, so a long episode never has to sit in memory
+pub struct InputLogWriter {
+    file_writer: BufWriter<File>,
+}
+
+impl InputLogWriter {
+    pub fn create(file_path: &Path) -> std::io::Result<Self> {
+        Ok(Self { file_writer: BufWriter::new(File::create(file_path)?) })
+    }
+
+    #[inline(always)]
+    pub fn push(&mut self, keycode: u16) -> std::io::Result<()> {
+        self.file_writer.write_all(&keycode.to_be_bytes())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file_writer.flush()
+    }
+}
+
+pub fn read_input_log(file_path: &Path) -> Result<Vec<u16>, String> {
+    let raw_data = fs::read(file_path)
+        .map_err(|e| format!("Failed to read input log {}: {}", file_path.display(), e))?;
+    let chunked_keycodes = raw_data.chunks_exact(2);
+    if !chunked_keycodes.remainder().is_empty() {
+        println!(
+            "Input log {} has a trailing byte, ignoring it",
+            file_path.display()
+        );
+    }
+    Ok(chunked_keycodes
+        .map(|keycode| u16::from_be_bytes([keycode[0], keycode[1]]))
+        .collect())
+}
+
+// Only pull the keycode out of each img.data, serde skips over the frame data
+#[derive(Deserialize)]
+struct AlbumFrameKeycode {
+    keycode_data: u16,
+}
+
+#[derive(Deserialize)]
+struct AlbumFrame {
+    observation_data: AlbumFrameKeycode,
+}
+
+fn read_album_keycodes(album_dir: &Path) -> Result<Vec<u16>, String> {
+    let frame_dirs = dataset::album_frame_dirs(album_dir)
+        .map_err(|e| format!("Failed to read album: {}", e))?;
+
+    frame_dirs
+        .iter()
+        .map(|frame_dir| {
+            let image_data = frame_dir.join("img.data");
+            let file_read = fs::read_to_string(&image_data)
+                .map_err(|e| format!("Failed to read {}: {}", image_data.display(), e))?;
+            serde_json::from_str::<AlbumFrame>(&file_read)
+                .map(|album_frame| album_frame.observation_data.keycode_data)
+                .map_err(|e| format!("Trouble parsing {}: {}", image_data.display(), e))
+        })
+        .collect()
+}
+
+// A replay can't skip frames without going out of sync, so any corrupt chunk is fatal here
+fn read_trajectory_keycodes(file_path: &Path) -> Result<Vec<u16>, String> {
+    DatasetFrames::open(file_path)
+        .map_err(|e| format!("Failed to open trajectory {}: {}", file_path.display(), e))?
+        .map(|frame_result| match frame_result {
+            Ok(frame) => Ok(frame.observation_data.keycode_data),
+            Err(corrupt_frame) => Err(format!("Can't replay {}: {}", file_path.display(), corrupt_frame)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::observation::{pixel_format_for_bpp, FrameBuffer, ObservationData, ObservationSet};
+    use crate::agent_stuff::trajectory::{TrajectoryHeader, TrajectoryWriter};
+
+    fn observation(keycode: u16) -> ObservationData {
+        ObservationData {
+            frame_buffer: FrameBuffer::new(4, 2, 2, pixel_format_for_bpp(2)),
+            keycode_data: keycode,
+        }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("replay_{}_{}", name, std::process::id()))
+    }
+
+    fn replay(replay_path: &Path, loop_replay: bool) -> Result<InputLog, String> {
+        InputLog::from_config(&ReplayConfig { replay_path: replay_path.display().to_string(), loop_replay })
+    }
+
+    fn drain(input_log: &mut InputLog, max_frames: usize) -> Vec<u16> {
+        std::iter::from_fn(|| input_log.next_keycode()).take(max_frames).collect()
+    }
+
+    #[test]
+    fn input_logs_stop_or_loop_at_the_end() {
+        let file_path = temp_path("input_log.keys");
+        let mut input_log_writer = InputLogWriter::create(&file_path).unwrap();
+        for keycode in [1, 0x200, 16] {
+            input_log_writer.push(keycode).unwrap();
+        }
+        input_log_writer.flush().unwrap();
+        // big endian, like the socket protocol
+        assert_eq!(fs::read(&file_path).unwrap(), [0, 1, 2, 0, 0, 16]);
+
+        assert_eq!(drain(&mut replay(&file_path, false).unwrap(), 10), [1, 0x200, 16]);
+        assert_eq!(drain(&mut replay(&file_path, true).unwrap(), 7), [1, 0x200, 16, 1, 0x200, 16, 1]);
+        // looping nothing is still nothing
+        assert_eq!(InputLog::new(vec![], true).next_keycode(), None);
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn albums_and_trajectories_replay_their_keycodes() {
+        // album without an input log falls back to the img.data keycodes
+        let album_dir = temp_path("album");
+        let mut observation_set = ObservationSet::new(&observation(0), 3);
+        for keycode in [4, 5, 6] {
+            observation_set.push(&observation(keycode));
+        }
+        observation_set.save_album(&album_dir);
+        assert_eq!(drain(&mut replay(&album_dir, false).unwrap(), 10), [4, 5, 6]);
+        // with one, the input log wins
+        let mut input_log_writer = InputLogWriter::create(&album_dir.join(INPUT_LOG_FILE_NAME)).unwrap();
+        for keycode in [1, 2, 3, 4, 5, 6] {
+            input_log_writer.push(keycode).unwrap();
+        }
+        drop(input_log_writer);
+        assert_eq!(drain(&mut replay(&album_dir, false).unwrap(), 10), [1, 2, 3, 4, 5, 6]);
+
+        let trajectory_path = temp_path("trajectory.traj");
+        {
+            let mut trajectory_writer =
+                TrajectoryWriter::create(&trajectory_path, TrajectoryHeader::from_observation(&observation(0), false), 2).unwrap();
+            for keycode in [7, 8, 9] {
+                trajectory_writer.append_observation(&observation(keycode)).unwrap();
+                trajectory_writer.add_step_outcome(0.0, false);
+            }
+        }
+        assert_eq!(drain(&mut replay(&trajectory_path, false).unwrap(), 10), [7, 8, 9]);
+        fs::remove_dir_all(&album_dir).unwrap();
+        fs::remove_file(&trajectory_path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_replays_are_errors() {
+        assert!(replay(&temp_path("missing.keys"), false).err().unwrap().starts_with("Failed to read input log"));
+        let album_dir = temp_path("bad_album");
+        fs::create_dir_all(album_dir.join("0000")).unwrap();
+        fs::write(album_dir.join("0000/img.data"), "{").unwrap();
+        assert!(replay(&album_dir, false).err().unwrap().starts_with("Trouble parsing"));
+        fs::remove_dir_all(&album_dir).unwrap();
+    }
+}
@@ -103,8 +103,15 @@ impl SdlWindow {
         self.window_texture.with_mut(|fields| {
             fields
                 .texture
-                .with_lock(None, |wonly_tex_frame_buffer: &mut [u8], _pitch| {
-                    wonly_tex_frame_buffer.copy_from_slice(self.observation_data.frame_buffer.get_buffer());
+                .with_lock(None, |wonly_tex_frame_buffer: &mut [u8], pitch| {
+                    // texture is created with the frame's own pixel format (RGB565 or ABGR8888), so no conversion,
+                    // but SDL can pad its rows, copy one row at a time
+                    let frame_buffer = &mut self.observation_data.frame_buffer;
+                    let row_len = (frame_buffer.width * frame_buffer.bpp) as usize;
+                    wonly_tex_frame_buffer
+                        .chunks_mut(pitch)
+                        .zip(frame_buffer.get_buffer().chunks_exact(row_len))
+                        .for_each(|(texture_row, frame_row)| texture_row[..row_len].copy_from_slice(frame_row));
                 })
                 .unwrap();
         });
@@ -0,0 +1,523 @@
+// The Rust mgba server: hosts emulator sessions for Agents running AgentDriver::Sockets/UnixSocket/SharedMemory
+// Listens on a TCP port or a unix domain socket path (protocol::Endpoint), a SharedMemory agent uses the socket too
+// Every connection gets its own thread and its own core (mgba_ffi::Core), up to max_sessions at once
+// The client picks the game in its Hello (SessionRequest), the server's GameConfigData is only the fallback
+// A session ends when its client hangs up, the core is dropped with the thread
+// A Shutdown from any client (or the stop flag) stops the whole server, the other sessions get hung up on
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Shutdown, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::frame_encoding::{FrameEncoder, FrameEncoding};
+use super::frame_ring::{self, FrameRing};
+use super::backend::EmulatorBackend;
+use super::mgba_ffi::Core;
+use super::protocol::{
+    self, message_type, Endpoint, ErrorCode, ProtocolError, Request, Response, ServerInfo, SessionRequest, Stream,
+};
+use super::GameConfigData;
+
+// how often the accept loop looks at the stop flags while nobody is connecting
+const ACCEPT_POLL_PERIOD: Duration = Duration::from_millis(50);
+// a turned away client gets this long to send its Hello before we hang up anyway
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub endpoint: Endpoint,
+    pub max_sessions: usize,
+}
+
+// Either kind of listening socket, accept hands out the matching Stream
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf), // the socket file is ours to remove again
+}
+
+impl Listener {
+    fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            // 0.0.0.0 (or the container's address) for agents on other machines, 127.0.0.1 keeps it local
+            Endpoint::Tcp(address) => Ok(Self::Tcp(TcpListener::bind(address)?)),
+            Endpoint::Unix(socket_path) => {
+                // a server that didn't get to clean up leaves its socket file behind, binding over it fails
+                if socket_path.exists() {
+                    fs::remove_file(socket_path)?;
+                }
+                Ok(Self::Unix(UnixListener::bind(socket_path)?, socket_path.clone()))
+            },
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Self::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    // The stream and who's on the other end, for the logs
+    fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), peer_addr.to_string()))
+            },
+            Self::Unix(listener, socket_path) => {
+                let (stream, _) = listener.accept()?;
+                // unix clients are usually unnamed, the socket they came in on says more
+                Ok((Stream::Unix(stream), socket_path.display().to_string()))
+            },
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, socket_path) = self {
+            let _ = fs::remove_file(socket_path);
+        }
+    }
+}
+
+enum SessionEnd {
+    Disconnected,
+    Shutdown,
+    Refused(String), // couldn't set up the game the client asked for, it got an Error saying why
+}
+
+// Live sessions by id, the streams are clones so the server can hang up on them when it stops
+#[derive(Default)]
+struct Sessions {
+    next_id: u64,
+    streams: HashMap<u64, Stream>,
+}
+
+// Frees the session's slot when its thread is done, even if the session panicked
+struct SessionGuard {
+    session_id: u64,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        // a poisoned lock still has the map in it, a panicking session shouldn't keep its slot
+        let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sessions.streams.remove(&self.session_id);
+    }
+}
+
+/// Runs until a client sends Shutdown or stop_flag is raised, returns the error if the listener itself fails
+/// game_config_data is what sessions run when their client doesn't send one
+pub fn spawn_server(
+    server_config: &ServerConfig,
+    game_config_data: &GameConfigData,
+    stop_flag: Option<Arc<AtomicBool>>,
+) -> Result<(), ProtocolError> {
+    let listener = Listener::bind(&server_config.endpoint)?;
+    // non blocking so the loop gets to check the stop flags between connections
+    listener.set_nonblocking(true)?;
+    let stop_flag = stop_flag.unwrap_or_else(|| Arc::new(AtomicBool::new(false)));
+    // raised by a client's Shutdown, separate so we don't flip the caller's flag
+    let shutdown_flag = Arc::new(AtomicBool::new(false));
+    let sessions = Arc::new(Mutex::new(Sessions::default()));
+    let mut session_handles: Vec<(u64, JoinHandle<()>)> = vec![];
+
+    println!(
+        "Waitin for connections at {}, up to {} sessions",
+        server_config.endpoint, server_config.max_sessions
+    );
+    while !stop_flag.load(Ordering::Relaxed) && !shutdown_flag.load(Ordering::Relaxed) {
+        let (stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                reap_sessions(&mut session_handles);
+                thread::sleep(ACCEPT_POLL_PERIOD);
+                continue;
+            },
+            Err(e) => {
+                println!("Failed to accept a connection: {}", e);
+                continue;
+            },
+        };
+        // some platforms hand out accepted streams with the listener's non blocking flag
+        stream.set_nonblocking(false)?;
+
+        let session_id = {
+            let mut sessions = sessions.lock().unwrap();
+            if sessions.streams.len() >= server_config.max_sessions {
+                None
+            } else {
+                let session_id = sessions.next_id;
+                sessions.next_id += 1;
+                sessions.streams.insert(session_id, stream.try_clone()?);
+                Some(session_id)
+            }
+        };
+        let Some(session_id) = session_id else {
+            println!("Turning away {}, all {} sessions are taken", peer_addr, server_config.max_sessions);
+            let max_sessions = server_config.max_sessions;
+            thread::spawn(move || reject_session(stream, max_sessions));
+            continue;
+        };
+
+        println!("Session #{} connected from {}", session_id, peer_addr);
+        let session_guard = SessionGuard { session_id, sessions: Arc::clone(&sessions) };
+        let game_config_data = game_config_data.clone();
+        let shutdown_flag = Arc::clone(&shutdown_flag);
+        let session_handle = thread::Builder::new()
+            .name(format!("mgba-session-{}", session_id))
+            .spawn(move || {
+                let _session_guard = session_guard;
+                match serve_session(session_id, stream, &game_config_data) {
+                    Ok(SessionEnd::Disconnected) => println!("Session #{}: client disconnected", session_id),
+                    Ok(SessionEnd::Shutdown) => {
+                        println!("Session #{}: client asked the server to shut down", session_id);
+                        shutdown_flag.store(true, Ordering::Relaxed);
+                    },
+                    Ok(SessionEnd::Refused(reason)) => println!("Session #{} refused: {}", session_id, reason),
+                    Err(e) => println!("Session #{} ended: {}", session_id, e),
+                }
+            })?;
+        session_handles.push((session_id, session_handle));
+    }
+
+    // hang up on everyone still connected, their blocked reads return and the threads wind down
+    for stream in sessions.lock().unwrap().streams.values() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    for (session_id, session_handle) in session_handles {
+        if session_handle.join().is_err() {
+            println!("Session #{} panicked", session_id);
+        }
+    }
+    println!("Server on {} stopped", server_config.endpoint);
+    Ok(())
+}
+
+// Join the sessions that already finished, so the handle list doesn't grow forever
+fn reap_sessions(session_handles: &mut Vec<(u64, JoinHandle<()>)>) {
+    let (finished, running): (Vec<_>, Vec<_>) = session_handles
+        .drain(..)
+        .partition(|(_, session_handle)| session_handle.is_finished());
+    *session_handles = running;
+    for (session_id, session_handle) in finished {
+        if session_handle.join().is_err() {
+            println!("Session #{} panicked", session_id);
+        }
+    }
+}
+
+// Answer the Hello with ServerFull so the client gets a real error instead of a dropped connection
+fn reject_session(mut stream: Stream, max_sessions: usize) {
+    let _ = stream.set_read_timeout(Some(REJECT_TIMEOUT));
+    if protocol::read_hello(&mut stream).is_ok() {
+        let _ = Response::Error {
+            code: ErrorCode::ServerFull,
+            message: format!("All {} sessions are taken", max_sessions),
+        }.write_to(&mut stream);
+    }
+}
+
+// The game a session runs, uploaded files live in a temp dir that goes away with the session
+struct SessionGame {
+    game_config_data: GameConfigData,
+    upload_dir: Option<PathBuf>,
+}
+
+impl SessionGame {
+    fn prepare(
+        session_id: u64,
+        session_request: SessionRequest,
+        default_game_config_data: &GameConfigData,
+    ) -> Result<Self, String> {
+        let mut session_game = Self {
+            game_config_data: session_request.game_config_data.unwrap_or_else(|| default_game_config_data.clone()),
+            upload_dir: None,
+        };
+        // the client's libmgba_path is for the client's machine, the server loads its own
+        session_game.game_config_data.libmgba_path = default_game_config_data.libmgba_path.clone();
+        if session_request.rom_data.is_some() || session_request.save_state_data.is_some() {
+            let upload_dir = std::env::temp_dir().join(format!("mgba_session_{}_{}", std::process::id(), session_id));
+            fs::create_dir_all(&upload_dir)
+                .map_err(|e| format!("Failed to create {}: {}", upload_dir.display(), e))?;
+            session_game.upload_dir = Some(upload_dir);
+        }
+        if let Some(rom_data) = &session_request.rom_data {
+            // keep the file name, the extension decides the platform (and Core::new's .sav name)
+            let rom_path = session_game.write_upload(&session_game.game_config_data.rom_path, "rom.gba", rom_data)?;
+            session_game.game_config_data.rom_path = rom_path;
+        }
+        if let Some(save_state_data) = &session_request.save_state_data {
+            let save_state_name = session_game.game_config_data.save_state_path.clone().unwrap_or_default();
+            let save_state_path = session_game.write_upload(&save_state_name, "save_state.ss1", save_state_data)?;
+            session_game.game_config_data.save_state_path = Some(save_state_path);
+        }
+
+        // a missing file would only show up as a panic deep inside Core::new
+        if !Path::new(&session_game.game_config_data.rom_path).is_file() {
+            return Err(format!("ROM {} doesn't exist on the server", session_game.game_config_data.rom_path));
+        }
+        if let Some(save_state_path) = &session_game.game_config_data.save_state_path {
+            if !Path::new(save_state_path).is_file() {
+                return Err(format!("Save state {} doesn't exist on the server", save_state_path));
+            }
+        }
+        Ok(session_game)
+    }
+
+    fn write_upload(&self, client_path: &str, fallback_name: &str, data: &[u8]) -> Result<String, String> {
+        let file_name = Path::new(client_path)
+            .file_name()
+            .map_or_else(|| fallback_name.into(), |file_name| file_name.to_os_string());
+        let upload_path = self.upload_dir.as_ref().unwrap().join(file_name);
+        fs::write(&upload_path, data).map_err(|e| format!("Failed to write {}: {}", upload_path.display(), e))?;
+        Ok(upload_path.to_string_lossy().into_owned())
+    }
+}
+
+impl Drop for SessionGame {
+    fn drop(&mut self) {
+        if let Some(upload_dir) = &self.upload_dir {
+            if let Err(e) = fs::remove_dir_all(upload_dir) {
+                println!("Failed to clean up {}: {}", upload_dir.display(), e);
+            }
+        }
+    }
+}
+
+fn serve_session(
+    session_id: u64,
+    mut stream: Stream,
+    default_game_config_data: &GameConfigData,
+) -> Result<SessionEnd, ProtocolError> {
+    let (client_capabilities, session_request) = protocol::read_hello(&mut stream)?;
+    let requested_frame_encoding = session_request.frame_encoding;
+
+    let session_game = match SessionGame::prepare(session_id, session_request, default_game_config_data) {
+        Ok(session_game) => session_game,
+        Err(reason) => {
+            Response::Error { code: ErrorCode::GameConfig, message: reason.clone() }.write_to(&mut stream)?;
+            return Ok(SessionEnd::Refused(reason));
+        },
+    };
+    println!("Session #{}: running {}", session_id, session_game.game_config_data.rom_path);
+
+    // the core lives and dies with this session, one that doesn't come up shouldn't leave the client hanging
+    let mut core = match Core::new(&session_game.game_config_data) {
+        Ok(core) => core,
+        Err(e) => {
+            let reason = format!("Failed to initialize a core for {}: {}", session_game.game_config_data.rom_path, e);
+            Response::Error { code: ErrorCode::Emulator, message: reason.clone() }.write_to(&mut stream)?;
+            return Ok(SessionEnd::Refused(reason));
+        },
+    };
+    // set up by a MapFrames, frames go through here instead of the socket after that
+    let mut frame_ring: Option<FrameRing> = None;
+
+    let (width, height, bpp) = core.video_size();
+    // an encoding that can't do this core's frames falls back to Raw, the Welcome tells the client
+    let mut frame_encoder = FrameEncoder::new(requested_frame_encoding, width, height, bpp).unwrap_or_else(|reason| {
+        println!("Session #{}: sending Raw frames, {}", session_id, reason);
+        FrameEncoder::new(FrameEncoding::Raw, width, height, bpp).unwrap()
+    });
+    let frame_encoding = frame_encoder.encoding();
+    let (decoded_width, decoded_height, decoded_bpp) = frame_encoding.decoded_format(width, height, bpp).unwrap();
+
+    Response::Welcome(ServerInfo {
+        version: protocol::PROTOCOL_VERSION,
+        capabilities: client_capabilities & protocol::ALL_CAPABILITIES,
+        frame_encoding,
+        width: decoded_width,
+        height: decoded_height,
+        bpp: decoded_bpp,
+    }).write_to(&mut stream)?;
+
+    // ##########################################################################################
+    // mgba STEADY STATE phase
+    // ##########################################################################################
+    loop {
+        let request = match Request::read_from(&mut stream) {
+            Ok(request) => request,
+            // Client hanging up between requests is a normal end of the session
+            Err(ProtocolError::Closed) => return Ok(SessionEnd::Disconnected),
+            Err(e @ (ProtocolError::Io(_) | ProtocolError::MessageTooLarge { .. })) => return Err(e),
+            // the stream is still in sync after these, let the client know what it did wrong
+            Err(e) => {
+                Response::Error { code: ErrorCode::Malformed, message: e.to_string() }.write_to(&mut stream)?;
+                continue;
+            },
+        };
+        match request {
+            Request::Step { keycode } => match core.set_keys(keycode).and_then(|_| core.run_frame()) {
+                Ok(()) => send_frame(&mut stream, &mut frame_ring, &mut frame_encoder, core.framebuffer())?,
+                Err(e) => Response::Error { code: ErrorCode::Emulator, message: e.to_string() }.write_to(&mut stream)?,
+            },
+            // same as the Native Agent's reset, back to the snapshot the core took when it started
+            Request::Reset => match core.restart() {
+                Ok(()) => send_frame(&mut stream, &mut frame_ring, &mut frame_encoder, core.framebuffer())?,
+                Err(e) => Response::Error {
+                    code: ErrorCode::Emulator,
+                    message: format!("Failed to restore the core to its initial state: {}", e),
+                }.write_to(&mut stream)?,
+            },
+            Request::Snapshot => match core.snapshot() {
+                Ok(state) => Response::SnapshotData(state).write_to(&mut stream)?,
+                Err(e) => Response::Error { code: ErrorCode::Emulator, message: e.to_string() }.write_to(&mut stream)?,
+            },
+            Request::ReadMemory { address, len } => match core.read_bytes(address, len) {
+                Ok(data) => Response::MemoryData(data).write_to(&mut stream)?,
+                Err(e) => Response::Error { code: ErrorCode::Memory, message: e.to_string() }.write_to(&mut stream)?,
+            },
+            Request::ListMemoryBlocks => Response::MemoryBlocks(core.memory_blocks().to_vec()).write_to(&mut stream)?,
+            Request::Shutdown => {
+                Response::Goodbye.write_to(&mut stream)?;
+                return Ok(SessionEnd::Shutdown);
+            },
+            // the ring holds the core's frames as they are, the client couldn't decode those into a Grayscale/Downscale frame
+            Request::MapFrames { .. } if !matches!(frame_encoding, FrameEncoding::Raw | FrameEncoding::DeltaRle) => {
+                Response::Error {
+                    code: ErrorCode::SharedMemory,
+                    message: format!("Shared frames are always raw, this session sends {:?}", frame_encoding),
+                }.write_to(&mut stream)?
+            },
+            Request::MapFrames { slots } => {
+                // dropping a previous ring removes its file, the client maps the new one
+                frame_ring = None;
+                let ring_path = frame_ring::default_dir()
+                    .join(format!("mgba_frames_{}_{}", std::process::id(), session_id));
+                match FrameRing::create(&ring_path, slots, core.framebuffer().len()) {
+                    Ok(mapped_ring) => {
+                        frame_ring = Some(mapped_ring);
+                        Response::FramesMapped { path: ring_path.to_string_lossy().into_owned() }.write_to(&mut stream)?;
+                    },
+                    Err(e) => Response::Error {
+                        code: ErrorCode::SharedMemory,
+                        message: format!("Failed to create frame ring {}: {}", ring_path.display(), e),
+                    }.write_to(&mut stream)?,
+                }
+            },
+            Request::Hello { .. } => Response::Error {
+                code: ErrorCode::UnexpectedMessage,
+                message: "Already shook hands".to_string(),
+            }.write_to(&mut stream)?,
+        }
+    }
+}
+
+// Step/Reset answer: the encoded frame over the socket, or just where to find it in the ring
+fn send_frame(
+    stream: &mut Stream,
+    frame_ring: &mut Option<FrameRing>,
+    frame_encoder: &mut FrameEncoder,
+    frame_data: &[u8],
+) -> Result<(), ProtocolError> {
+    match frame_ring {
+        Some(frame_ring) => {
+            let (slot, seq) = frame_ring.publish(frame_data);
+            Response::FrameReady { slot, seq }.write_to(stream)
+        },
+        None => protocol::write_message(stream, message_type::FRAME, frame_encoder.encode(frame_data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::protocol::{Client, ConnectOptions};
+
+    #[test]
+    fn full_server_turns_clients_away_and_stops_on_the_flag() {
+        // no session ever starts, so this never needs libmgba or a ROM
+        let socket_path = std::env::temp_dir().join(format!("mgba_server_test_{}.sock", std::process::id()));
+        let endpoint = Endpoint::Unix(socket_path.clone());
+        let server_config = ServerConfig { endpoint: endpoint.clone(), max_sessions: 0 };
+        let game_config_data = GameConfigData {
+            rom_path: "no_rom.gba".to_string(),
+            save_state_path: None,
+            save_state_flags: 0,
+            rng_seed: None,
+            upload_files: false,
+            libmgba_path: None,
+            mgba_log: Default::default(),
+        };
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let server_stop_flag = Arc::clone(&stop_flag);
+        let server_handle = thread::spawn(move || spawn_server(&server_config, &game_config_data, Some(server_stop_flag)));
+
+        // the listener might not be up yet
+        let mut client_result = Client::connect_with(&endpoint, SessionRequest::default(), &ConnectOptions::default());
+        for _ in 0..50 {
+            if !matches!(client_result, Err(ProtocolError::Io(_))) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            client_result = Client::connect_with(&endpoint, SessionRequest::default(), &ConnectOptions::default());
+        }
+        match client_result {
+            Err(ProtocolError::Remote { code, .. }) => assert_eq!(code, ErrorCode::ServerFull),
+            Err(e) => panic!("Expected ServerFull, got {}", e),
+            Ok(client) => panic!("Expected ServerFull, got a session with version {}", client.server_info().version),
+        }
+
+        stop_flag.store(true, Ordering::Relaxed);
+        server_handle.join().unwrap().unwrap();
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn bad_session_requests_get_errors_and_leave_nothing_behind() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server_config = ServerConfig { endpoint: Endpoint::localhost(port), max_sessions: 1 };
+        let game_config_data = GameConfigData {
+            rom_path: "no_rom.gba".to_string(),
+            save_state_path: None,
+            save_state_flags: 0,
+            rng_seed: None,
+            upload_files: false,
+            libmgba_path: None,
+            mgba_log: Default::default(),
+        };
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let server_stop_flag = Arc::clone(&stop_flag);
+        let server_game_config_data = game_config_data.clone();
+        let server_handle = thread::spawn(move || spawn_server(&server_config, &server_game_config_data, Some(server_stop_flag)));
+        let connect = |session_request: SessionRequest| {
+            for _ in 0..50 {
+                match Client::connect_with(&Endpoint::localhost(port), session_request.clone(), &ConnectOptions::default()) {
+                    Err(ProtocolError::Io(_)) => thread::sleep(Duration::from_millis(20)),
+                    Err(ProtocolError::Remote { code, .. }) => return code,
+                    Err(e) => panic!("Expected a server error, got {}", e),
+                    Ok(_) => panic!("Expected a server error, got a session"),
+                }
+            }
+            panic!("Server never came up");
+        };
+
+        // the server's own ROM doesn't exist either
+        assert_eq!(connect(SessionRequest::default()), ErrorCode::GameConfig);
+        // the slot was freed again, so this isn't ServerFull
+        assert_eq!(connect(SessionRequest { game_config_data: Some(game_config_data.clone()), ..SessionRequest::default() }), ErrorCode::GameConfig);
+        // an uploaded ROM exists, but isn't one (or there's no libmgba to load it with)
+        let code = connect(SessionRequest { rom_data: Some(vec![0; 64]), ..SessionRequest::default() });
+        assert_eq!(code, ErrorCode::Emulator);
+
+        // joining the server joins the sessions, so their cleanup is done after this
+        stop_flag.store(true, Ordering::Relaxed);
+        server_handle.join().unwrap().unwrap();
+        let upload_dir_prefix = format!("mgba_session_{}_", std::process::id());
+        let leftover_uploads = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&upload_dir_prefix))
+            .count();
+        assert_eq!(leftover_uploads, 0);
+    }
+}
@@ -0,0 +1,601 @@
+// Compact binary trajectory files, the alternative to save_album's json + png per frame
+// Append only, everything little endian:
+//
+//   header: magic "MGBATRJ\0", version u16, flags u16,
+//           width u32, height u32, bpp u32 (raw frame, pixel format from observation::pixel_format_for_bpp)
+//           processed shape: frames u32, height u32, width u32, channels u32, element type u32 (0 = u8, 1 = f32)
+//   chunks: magic "CHNK", num_records u32, checksum u32 (of the record bytes), then num_records records
+//   record: keycode u16, reward f32, done u8, processed_data, frame_data (only with FLAG_FRAME_DATA)
+//
+// Records are all the same size, so the reader only has to index the chunk headers to seek to any frame
+// A crash mid chunk leaves a truncated last chunk, which the reader skips (and reports)
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::observation::ObservationData;
+use super::preprocessing::{ElementType, Shape};
+
+const FILE_MAGIC: &[u8; 8] = b"MGBATRJ\0";
+const CHUNK_MAGIC: &[u8; 4] = b"CHNK";
+pub const TRAJECTORY_VERSION: u16 = 1;
+pub const FLAG_FRAME_DATA: u16 = 1 << 0;
+const HEADER_LEN: u64 = 8 + 2 + 2 + 3 * 4 + 5 * 4;
+const CHUNK_HEADER_LEN: u64 = 4 + 4 + 4;
+const RECORD_PREFIX_LEN: usize = 2 + 4 + 1; // keycode, reward, done
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrajectoryConfig {
+    pub path: String,
+    // also store the raw frame_data of every frame, a lot bigger but replayable bit for bit
+    #[serde(default)]
+    pub include_frame_data: bool,
+    // frames buffered in memory before they hit the disk
+    #[serde(default = "default_chunk_frames")]
+    pub chunk_frames: u32,
+}
+
+fn default_chunk_frames() -> u32 {
+    // a second at 60 FPS
+    60
+}
+
+#[derive(Debug)]
+pub enum TrajectoryError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    BadHeader(String),
+    BadChunk { offset: u64 },
+    ChecksumMismatch { chunk_idx: usize, first_frame: usize, num_frames: usize },
+    FrameOutOfRange { frame_idx: usize, len: usize },
+    ChunkOutOfRange { chunk_idx: usize, len: usize },
+}
+
+impl fmt::Display for TrajectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Trajectory file IO error: {}", e),
+            Self::BadMagic => write!(f, "Not a trajectory file"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Trajectory version {} isn't supported (expected {})", version, TRAJECTORY_VERSION)
+            },
+            Self::BadHeader(reason) => write!(f, "Invalid trajectory header: {}", reason),
+            Self::BadChunk { offset } => write!(f, "Invalid chunk header at byte {}", offset),
+            Self::ChecksumMismatch { chunk_idx, first_frame, num_frames } => write!(
+                f,
+                "Chunk {} (frames {}..{}) doesn't match its checksum",
+                chunk_idx, first_frame, first_frame + num_frames
+            ),
+            Self::FrameOutOfRange { frame_idx, len } => {
+                write!(f, "Frame {} is out of range, trajectory has {} frames", frame_idx, len)
+            },
+            Self::ChunkOutOfRange { chunk_idx, len } => {
+                write!(f, "Chunk {} is out of range, trajectory has {} chunks", chunk_idx, len)
+            },
+        }
+    }
+}
+
+impl std::error::Error for TrajectoryError {}
+
+impl From<io::Error> for TrajectoryError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrajectoryHeader {
+    pub flags: u16,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u32,
+    pub processed_shape: Shape,
+}
+
+impl TrajectoryHeader {
+    /// Header matching the observations an Agent produces
+    pub fn from_observation(observation_data: &ObservationData, include_frame_data: bool) -> Self {
+        let frame_buffer = &observation_data.frame_buffer;
+        Self {
+            flags: if include_frame_data { FLAG_FRAME_DATA } else { 0 },
+            width: frame_buffer.width,
+            height: frame_buffer.height,
+            bpp: frame_buffer.bpp,
+            processed_shape: frame_buffer.processed_shape,
+        }
+    }
+
+    pub fn has_frame_data(&self) -> bool {
+        (self.flags & FLAG_FRAME_DATA) != 0
+    }
+
+    pub fn frame_data_len(&self) -> usize {
+        match self.has_frame_data() {
+            true => (self.width * self.height * self.bpp) as usize,
+            false => 0,
+        }
+    }
+
+    pub fn record_len(&self) -> usize {
+        RECORD_PREFIX_LEN + self.processed_shape.num_bytes() + self.frame_data_len()
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let shape = &self.processed_shape;
+        let element_type: u32 = match shape.element_type {
+            ElementType::U8 => 0,
+            ElementType::F32 => 1,
+        };
+        writer.write_all(FILE_MAGIC)?;
+        writer.write_all(&TRAJECTORY_VERSION.to_le_bytes())?;
+        writer.write_all(&self.flags.to_le_bytes())?;
+        for value in [self.width, self.height, self.bpp, shape.frames, shape.height, shape.width, shape.channels, element_type] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from(reader: &mut impl Read) -> Result<Self, TrajectoryError> {
+        let mut magic = [0_u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != FILE_MAGIC {
+            return Err(TrajectoryError::BadMagic);
+        }
+        let version = read_u16(reader)?;
+        if version != TRAJECTORY_VERSION {
+            return Err(TrajectoryError::UnsupportedVersion(version));
+        }
+        let flags = read_u16(reader)?;
+        let mut values = [0_u32; 8];
+        for value in values.iter_mut() {
+            *value = read_u32(reader)?;
+        }
+        let [width, height, bpp, frames, processed_height, processed_width, channels, element_type] = values;
+        if ![2, 4].contains(&bpp) {
+            return Err(TrajectoryError::BadHeader(format!("{} bytes per pixel", bpp)));
+        }
+        let element_type = match element_type {
+            0 => ElementType::U8,
+            1 => ElementType::F32,
+            _ => return Err(TrajectoryError::BadHeader(format!("element type {}", element_type))),
+        };
+        // record_len multiplies these as u32, a corrupt header shouldn't get to overflow them
+        let fits_u32 = |values: &[u32]| values.iter().try_fold(1_u32, |product, value| product.checked_mul(*value)).is_some();
+        if !fits_u32(&[width, height, bpp]) {
+            return Err(TrajectoryError::BadHeader(format!("{}x{} frames at {} bytes per pixel", width, height, bpp)));
+        }
+        if !fits_u32(&[frames, processed_height, processed_width, channels]) {
+            return Err(TrajectoryError::BadHeader(format!(
+                "processed shape {}x{}x{}x{}",
+                frames, processed_height, processed_width, channels
+            )));
+        }
+        Ok(Self {
+            flags,
+            width,
+            height,
+            bpp,
+            processed_shape: Shape {
+                frames,
+                height: processed_height,
+                width: processed_width,
+                channels,
+                element_type,
+            },
+        })
+    }
+}
+
+/// Cheap check for the magic, to tell trajectories apart from other recordings
+pub fn is_trajectory_file(file_path: &Path) -> bool {
+    let mut magic = [0_u8; 8];
+    File::open(file_path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == FILE_MAGIC)
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0_u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0_u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// 2's complement byte sum, like the albums' pixel_checksum
+pub fn chunk_checksum(records: &[u8]) -> u32 {
+    records
+        .iter()
+        .fold(0_u32, |sum, byte| sum.wrapping_add(*byte as u32))
+        .wrapping_neg()
+}
+
+// ################################################################################
+// Writing
+// ################################################################################
+
+/// Streams observations into a trajectory file, cheap enough for run_client's hot loop:
+/// records are copied into a preallocated chunk buffer and only written out once it's full
+pub struct TrajectoryWriter {
+    header: TrajectoryHeader,
+    file: BufWriter<File>,
+    chunk: Vec<u8>,
+    chunk_frames: usize,
+    chunk_len: usize,      // records in chunk
+    frames_written: usize, // records in the file + chunk
+    // outcomes that came in while the chunk was empty (after a flush, before the first record),
+    // they go into the next record
+    pending_reward: f32,
+    pending_done: bool,
+}
+
+impl TrajectoryWriter {
+    pub fn create(file_path: &Path, header: TrajectoryHeader, chunk_frames: u32) -> Result<Self, TrajectoryError> {
+        let chunk_frames = chunk_frames.max(1) as usize;
+        if let Some(parent_dir) = file_path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+        let mut file = BufWriter::new(File::create(file_path)?);
+        header.write_to(&mut file)?;
+        Ok(Self {
+            header,
+            file,
+            chunk: Vec::with_capacity(chunk_frames * header.record_len()),
+            chunk_frames,
+            chunk_len: 0,
+            frames_written: 0,
+            pending_reward: 0.0,
+            pending_done: false,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames_written
+    }
+
+    /// New record for the observation the keycode was picked from, reward 0 and not done
+    /// until add_step_outcome says otherwise (plus any outcome that came in while there was no record to add it to)
+    pub fn append_observation(&mut self, observation_data: &ObservationData) -> Result<(), TrajectoryError> {
+        if self.chunk_len == self.chunk_frames {
+            self.write_chunk()?;
+        }
+        let frame_buffer = &observation_data.frame_buffer;
+        self.chunk.extend_from_slice(&observation_data.keycode_data.to_le_bytes());
+        self.chunk.extend_from_slice(&std::mem::take(&mut self.pending_reward).to_le_bytes());
+        self.chunk.push(std::mem::take(&mut self.pending_done) as u8);
+        self.chunk.extend_from_slice(&frame_buffer.processed_data);
+        if self.header.has_frame_data() {
+            self.chunk.extend_from_slice(&frame_buffer.frame_data);
+        }
+        self.chunk_len += 1;
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    /// Reward/done of the emulator frames run since the last append_observation get added to that record
+    /// (with CycleIgnore several frames can run per stored observation)
+    pub fn add_step_outcome(&mut self, reward: f32, done: bool) {
+        if self.chunk_len == 0 {
+            // nothing appended yet, or the last record already went out with a flush at the end of an episode:
+            // these frames ran before the next observation, so they count towards the next record
+            self.pending_reward += reward;
+            self.pending_done |= done;
+            return;
+        }
+        let record_start = (self.chunk_len - 1) * self.header.record_len();
+        let reward_bytes = &mut self.chunk[record_start + 2..record_start + 6];
+        let total_reward = f32::from_le_bytes([reward_bytes[0], reward_bytes[1], reward_bytes[2], reward_bytes[3]]) + reward;
+        reward_bytes.copy_from_slice(&total_reward.to_le_bytes());
+        self.chunk[record_start + 6] |= done as u8;
+    }
+
+    fn write_chunk(&mut self) -> Result<(), TrajectoryError> {
+        if self.chunk_len == 0 {
+            return Ok(());
+        }
+        self.file.write_all(CHUNK_MAGIC)?;
+        self.file.write_all(&(self.chunk_len as u32).to_le_bytes())?;
+        self.file.write_all(&chunk_checksum(&self.chunk).to_le_bytes())?;
+        self.file.write_all(&self.chunk)?;
+        self.chunk.clear();
+        self.chunk_len = 0;
+        Ok(())
+    }
+
+    /// Write out the partial chunk too, e.g. at the end of an episode
+    pub fn flush(&mut self) -> Result<(), TrajectoryError> {
+        self.write_chunk()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for TrajectoryWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Failed to flush trajectory: {}", e);
+        }
+    }
+}
+
+// ################################################################################
+// Reading
+// ################################################################################
+
+#[derive(Clone, Debug)]
+pub struct TrajectoryFrame {
+    pub keycode: u16,
+    pub reward: f32,
+    pub done: bool,
+    pub processed_data: Vec<u8>,
+    pub frame_data: Option<Vec<u8>>, // only with FLAG_FRAME_DATA
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkIndexEntry {
+    pub first_frame: usize,
+    pub num_frames: usize,
+    pub offset: u64, // of the first record
+    pub checksum: u32,
+}
+
+/// Random access by frame index, only the chunk headers are read when opening
+pub struct TrajectoryReader {
+    header: TrajectoryHeader,
+    file: BufReader<File>,
+    chunks: Vec<ChunkIndexEntry>,
+    len: usize,
+    truncated_bytes: u64, // trailing bytes of an incomplete chunk, not part of any frame
+}
+
+impl TrajectoryReader {
+    pub fn open(file_path: &Path) -> Result<Self, TrajectoryError> {
+        let mut file = BufReader::new(File::open(file_path)?);
+        let file_len = file.get_ref().metadata()?.len();
+        let header = TrajectoryHeader::read_from(&mut file)?;
+        let record_len = header.record_len() as u64;
+
+        let mut chunks = vec![];
+        let mut len = 0_usize;
+        let mut offset = HEADER_LEN;
+        while offset < file_len {
+            if file_len - offset < CHUNK_HEADER_LEN {
+                break;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            let mut magic = [0_u8; 4];
+            file.read_exact(&mut magic)?;
+            if &magic != CHUNK_MAGIC {
+                return Err(TrajectoryError::BadChunk { offset });
+            }
+            let num_frames = read_u32(&mut file)? as usize;
+            let checksum = read_u32(&mut file)?;
+            let records_offset = offset + CHUNK_HEADER_LEN;
+            let chunk_end = records_offset + num_frames as u64 * record_len;
+            if num_frames == 0 {
+                return Err(TrajectoryError::BadChunk { offset });
+            }
+            if chunk_end > file_len {
+                break;
+            }
+            chunks.push(ChunkIndexEntry {
+                first_frame: len,
+                num_frames,
+                offset: records_offset,
+                checksum,
+            });
+            len += num_frames;
+            offset = chunk_end;
+        }
+
+        Ok(Self {
+            header,
+            file,
+            chunks,
+            len,
+            truncated_bytes: file_len - offset,
+        })
+    }
+
+    pub fn header(&self) -> &TrajectoryHeader {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn chunks(&self) -> &[ChunkIndexEntry] {
+        &self.chunks
+    }
+
+    pub fn truncated_bytes(&self) -> u64 {
+        self.truncated_bytes
+    }
+
+    fn chunk_for_frame(&self, frame_idx: usize) -> Result<&ChunkIndexEntry, TrajectoryError> {
+        if frame_idx >= self.len {
+            return Err(TrajectoryError::FrameOutOfRange { frame_idx, len: self.len });
+        }
+        let chunk_idx = self.chunks.partition_point(|chunk| chunk.first_frame + chunk.num_frames <= frame_idx);
+        Ok(&self.chunks[chunk_idx])
+    }
+
+    pub fn read_frame(&mut self, frame_idx: usize) -> Result<TrajectoryFrame, TrajectoryError> {
+        let chunk = *self.chunk_for_frame(frame_idx)?;
+        let record_len = self.header.record_len();
+        let offset = chunk.offset + ((frame_idx - chunk.first_frame) * record_len) as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut record = vec![0_u8; record_len];
+        self.file.read_exact(&mut record)?;
+        Ok(self.parse_record(&record))
+    }
+
+    /// Every frame of a chunk in one read, checked against the chunk's checksum
+    pub fn read_chunk(&mut self, chunk_idx: usize) -> Result<Vec<TrajectoryFrame>, TrajectoryError> {
+        let chunk = *self
+            .chunks
+            .get(chunk_idx)
+            .ok_or(TrajectoryError::ChunkOutOfRange { chunk_idx, len: self.chunks.len() })?;
+        let record_len = self.header.record_len();
+        self.file.seek(SeekFrom::Start(chunk.offset))?;
+        let mut records = vec![0_u8; chunk.num_frames * record_len];
+        self.file.read_exact(&mut records)?;
+        if chunk_checksum(&records) != chunk.checksum {
+            return Err(TrajectoryError::ChecksumMismatch {
+                chunk_idx,
+                first_frame: chunk.first_frame,
+                num_frames: chunk.num_frames,
+            });
+        }
+        Ok(records.chunks_exact(record_len).map(|record| self.parse_record(record)).collect())
+    }
+
+    fn parse_record(&self, record: &[u8]) -> TrajectoryFrame {
+        let processed_end = RECORD_PREFIX_LEN + self.header.processed_shape.num_bytes();
+        TrajectoryFrame {
+            keycode: u16::from_le_bytes([record[0], record[1]]),
+            reward: f32::from_le_bytes([record[2], record[3], record[4], record[5]]),
+            done: record[6] != 0,
+            processed_data: record[RECORD_PREFIX_LEN..processed_end].to_vec(),
+            frame_data: self.header.has_frame_data().then(|| record[processed_end..].to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_stuff::observation::{pixel_format_for_bpp, FrameBuffer};
+
+    fn observation(keycode: u16, fill: u8) -> ObservationData {
+        let mut frame_buffer = FrameBuffer::new(4, 2, 4, pixel_format_for_bpp(4));
+        frame_buffer.frame_data.fill(fill);
+        frame_buffer.processed_data.fill(fill.wrapping_add(1));
+        ObservationData {
+            frame_buffer,
+            keycode_data: keycode,
+        }
+    }
+
+    #[test]
+    fn round_trip_with_partial_chunks() {
+        let file_path = std::env::temp_dir().join(format!("trajectory_round_trip_{}.traj", std::process::id()));
+        let header = TrajectoryHeader::from_observation(&observation(0, 0), true);
+        {
+            // 3 frame chunks + a flush in the middle, so there are full and partial chunks
+            let mut writer = TrajectoryWriter::create(&file_path, header, 3).unwrap();
+            for frame_idx in 0..8_u16 {
+                writer.append_observation(&observation(frame_idx, frame_idx as u8)).unwrap();
+                writer.add_step_outcome(0.5, false);
+                writer.add_step_outcome(0.25, frame_idx == 4);
+                if frame_idx == 4 {
+                    writer.flush().unwrap();
+                }
+            }
+        }
+        let mut reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(*reader.header(), header);
+        assert_eq!(reader.len(), 8);
+        assert_eq!(reader.truncated_bytes(), 0);
+        // read out of order on purpose
+        for frame_idx in [7, 0, 4, 3, 5] {
+            let frame = reader.read_frame(frame_idx).unwrap();
+            assert_eq!(frame.keycode, frame_idx as u16);
+            assert_eq!(frame.reward, 0.75);
+            assert_eq!(frame.done, frame_idx == 4);
+            assert!(frame.processed_data.iter().all(|byte| *byte == frame_idx as u8 + 1));
+            assert!(frame.frame_data.unwrap().iter().all(|byte| *byte == frame_idx as u8));
+        }
+        assert!(matches!(reader.read_frame(8), Err(TrajectoryError::FrameOutOfRange { .. })));
+
+        // a crash halfway through the last chunk only loses that chunk
+        let file_len = std::fs::metadata(&file_path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&file_path).unwrap().set_len(file_len - 5).unwrap();
+        let reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(reader.len(), 5); // chunks are frames 0-2, 3-4 (flushed early) and 5-7
+        assert!(reader.truncated_bytes() > 0);
+        std::fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn outcomes_without_a_record_go_into_the_next_one() {
+        let file_path = std::env::temp_dir().join(format!("trajectory_pending_{}.traj", std::process::id()));
+        let header = TrajectoryHeader::from_observation(&observation(0, 0), false);
+        {
+            let mut writer = TrajectoryWriter::create(&file_path, header, 4).unwrap();
+            // CycleIgnore frames before the very first observation
+            writer.add_step_outcome(0.5, false);
+            writer.append_observation(&observation(1, 1)).unwrap();
+            writer.add_step_outcome(1.0, true);
+            // episode end flushes the record, then the next episode runs a few frames before its first observation
+            writer.flush().unwrap();
+            writer.add_step_outcome(0.25, false);
+            writer.add_step_outcome(0.25, false);
+            writer.append_observation(&observation(2, 2)).unwrap();
+            writer.add_step_outcome(2.0, false);
+            writer.append_observation(&observation(3, 3)).unwrap();
+        }
+        let mut reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(reader.len(), 3);
+        let outcomes: Vec<(u16, f32, bool)> = (0..3)
+            .map(|frame_idx| reader.read_frame(frame_idx).unwrap())
+            .map(|frame| (frame.keycode, frame.reward, frame.done))
+            .collect();
+        assert_eq!(outcomes, [(1, 1.5, true), (2, 2.5, false), (3, 0.0, false)]);
+        std::fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn damaged_files_are_errors() {
+        let file_path = std::env::temp_dir().join(format!("trajectory_damaged_{}.traj", std::process::id()));
+        let header = TrajectoryHeader::from_observation(&observation(0, 0), false);
+        {
+            let mut writer = TrajectoryWriter::create(&file_path, header, 2).unwrap();
+            for frame_idx in 0..4_u16 {
+                writer.append_observation(&observation(frame_idx, frame_idx as u8)).unwrap();
+            }
+        }
+        let mut reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(reader.chunks().len(), 2);
+        assert_eq!(reader.read_chunk(1).unwrap().len(), 2);
+        assert!(matches!(reader.read_chunk(2), Err(TrajectoryError::ChunkOutOfRange { chunk_idx: 2, len: 2 })));
+
+        // a flipped bit in the second chunk's records
+        let mut file_bytes = std::fs::read(&file_path).unwrap();
+        let last = file_bytes.len() - 1;
+        file_bytes[last] ^= 0x10;
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        let mut reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(reader.read_chunk(0).unwrap().len(), 2);
+        assert!(matches!(reader.read_chunk(1), Err(TrajectoryError::ChecksumMismatch { chunk_idx: 1, first_frame: 2, num_frames: 2 })));
+
+        // the file shrinking after it was opened
+        std::fs::OpenOptions::new().write(true).open(&file_path).unwrap().set_len(HEADER_LEN + 4).unwrap();
+        assert!(matches!(reader.read_chunk(1), Err(TrajectoryError::Io(_))));
+        assert!(matches!(reader.read_frame(3), Err(TrajectoryError::Io(_))));
+
+        // a chunk header claiming more records than the file holds is a truncated chunk, not a huge read
+        file_bytes[HEADER_LEN as usize + 4..HEADER_LEN as usize + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        let reader = TrajectoryReader::open(&file_path).unwrap();
+        assert_eq!(reader.len(), 0);
+
+        // frame sizes that would overflow record_len
+        file_bytes[12..16].copy_from_slice(&0x0001_0000_u32.to_le_bytes());
+        file_bytes[16..20].copy_from_slice(&0x0001_0000_u32.to_le_bytes());
+        std::fs::write(&file_path, &file_bytes).unwrap();
+        assert!(matches!(TrajectoryReader::open(&file_path), Err(TrajectoryError::BadHeader(_))));
+        std::fs::remove_file(&file_path).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
-use argparse::{ArgumentParser, List, Store};
+use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
 use std::path::Path;
 use std::process::Command;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::fs;
 
 pub mod c_land {
@@ -115,8 +115,18 @@ pub mod c_land {
 // mod observation;
 mod agent_stuff;
 use agent_stuff::{
-    Agent, AgentConfiguration,
+    Agent, AgentConfiguration, AgentControl,
     AgentDriver, GameConfigData,
+    environment::Environment,
+    episode,
+    trajectory,
+    dataset,
+    npy::{FrameSource, NpyBatch},
+    observation::ObservationSet,
+    replay,
+    frame_encoding::FrameEncoding,
+    protocol::{self, Endpoint},
+    server::{self, ServerConfig},
 };
 // mod mgba_ffi; // need this line to invoke compiler on that module, good for testing
 
@@ -126,6 +136,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 fn main() -> process::ExitCode {
+    // mgba's log messages come through the log crate (target "mgba"), RUST_LOG picks what gets printed
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     // here is our agent_configuration_file
     let agent_config_file = Path::new("./agent_config.json");
     // assumes a configuration file
@@ -146,6 +158,8 @@ fn main() -> process::ExitCode {
         TestRun, // all of our development to this point
         // instead of just forking, going to use Management to do it better
         Management, // this will be either boss or worker
+        Dataset, // look at recorded trajectories
+        Serve, // standalone rust mgba server for a pool of socket agents
     }
     use std::str::FromStr;
     impl FromStr for ProgramAction {
@@ -156,6 +170,8 @@ fn main() -> process::ExitCode {
                 "Clean" => Ok(ProgramAction::Clean),
                 "TestRun" => Ok(ProgramAction::TestRun),
                 "Management" => Ok(ProgramAction::Management),
+                "Dataset" => Ok(ProgramAction::Dataset),
+                "Serve" => Ok(ProgramAction::Serve),
                 _ => Err(()),
             };
         }
@@ -175,7 +191,7 @@ fn main() -> process::ExitCode {
         ap.refer(&mut action).add_argument(
             "action",
             Store,
-            "Specify an action to take: Clean, TestRun, or Management",
+            "Specify an action to take: Clean, TestRun, Management, Dataset, or Serve",
         );
         ap.refer(&mut sub_args).add_argument(
             "sub arguments",
@@ -210,6 +226,9 @@ fn main() -> process::ExitCode {
                 RustHybrid,    // rust client, c server
                 TotalRust,     // rust client, rust server
                 RustNoSockets, // no client-server, only rust Agent directly using mgba library/core
+                RustEnvironment, // drive the Agent frame by frame through the step/reset Environment
+                RustDeterminism, // run the same seed + inputs twice and compare frame checksums
+                TransportBenchmark, // time the same steps over TCP, a unix socket and shared memory frames, and each frame encoding
             }
             impl FromStr for MgbaTestScenario {
                 type Err = ();
@@ -220,6 +239,9 @@ fn main() -> process::ExitCode {
                         "RustHybrid" => Ok(MgbaTestScenario::RustHybrid),
                         "TotalRust" => Ok(MgbaTestScenario::TotalRust),
                         "RustNoSockets" => Ok(MgbaTestScenario::RustNoSockets),
+                        "RustEnvironment" => Ok(MgbaTestScenario::RustEnvironment),
+                        "RustDeterminism" => Ok(MgbaTestScenario::RustDeterminism),
+                        "TransportBenchmark" => Ok(MgbaTestScenario::TransportBenchmark),
                         _ => Err(()),
                     };
                 }
@@ -233,7 +255,7 @@ fn main() -> process::ExitCode {
                         "which test",
                         Store,
                         // r#"Output sink to play to"#
-                        "which test scenario we want to run: OriginalC, RustHybrid, TotalRust, RustNoSockets",
+                        "which test scenario we want to run: OriginalC, RustHybrid, TotalRust, RustNoSockets, RustEnvironment, RustDeterminism, TransportBenchmark",
                     );
                 use std::io::{stderr, stdout};
                 match ap.parse(sub_args, &mut stdout(), &mut stderr()) {
@@ -279,14 +301,19 @@ fn main() -> process::ExitCode {
                         agent_driver: AgentDriver::Sockets(13721_u16),
                         ..agent_config.clone()
                     };
-                    let mut mgba_agent = Agent::new(rusthybrid_agent_config,None);
-                    let observation_data_set = mgba_agent.run_client();
+                    let mut mgba_agent = match Agent::new(rusthybrid_agent_config,None) {
+                        Ok(mgba_agent) => mgba_agent,
+                        Err(e) => {
+                            println!("{}", e);
+                            server_handle.kill().expect("Duhh we had trouble moyduhing the soyvuh boss");
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let episode_results = mgba_agent.run_client();
                     server_handle
                         .kill()
                         .expect("Duhh we had trouble moyduhing the soyvuh boss");
-                    println!("Observation data: {:?}", observation_data_set.clone().unwrap().len());
-                    // observation_data_set.to_file(Path::new("foobar.json"));
-                    observation_data_set.unwrap().save_album(Path::new("imageData/"));
+                    episode::print_episode_summary(&episode_results);
                 }
                 MgbaTestScenario::TotalRust => {
                     println!("Running the TotalRust test scenario!");
@@ -299,24 +326,293 @@ fn main() -> process::ExitCode {
                     };
                     
                     // spawn the server with a thread
+                    let server_config = ServerConfig { endpoint: Endpoint::localhost(mgba_port), max_sessions: 1 };
                     let server_handle = thread::spawn(move || {
-                        agent_stuff::mgba_ffi::spawn_server(mgba_port,&agent_config.game_config_data);
+                        if let Err(e) = server::spawn_server(&server_config,&agent_config.game_config_data,None) {
+                            println!("Rust server failed: {}", e);
+                        }
                     });
                     
-                    let mut mgba_agent = Agent::new(totalrust_agent_config,Some(atomic_bool_rc));
-                    let observation_data_set = mgba_agent.run_client();
+                    let mut mgba_agent = match Agent::new(totalrust_agent_config,Some(atomic_bool_rc)) {
+                        Ok(mgba_agent) => mgba_agent,
+                        // the server thread keeps waiting for us, exiting takes it down too
+                        Err(e) => {
+                            println!("{}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let episode_results = mgba_agent.run_client();
+                    // otherwise the server just waits for the next client and the join never returns
+                    mgba_agent.shutdown_server();
                     println!("Finished running our rust client-rust server impl!");
-                    observation_data_set.unwrap().save_album(Path::new("imageData/"));
+                    episode::print_episode_summary(&episode_results);
                     server_handle.join().expect("Couldn't join our rust server ;-;");
                 }
                 MgbaTestScenario::RustNoSockets => {
                     println!("Running the RustNoSockets test scenario!");
                     // println!("No socket connection all rust!!");
 
-                    let mut mgba_agent = Agent::new(agent_config,Some(atomic_bool_rc));
-                    let observation_data_set = mgba_agent.run_client();
+                    let mut mgba_agent = match Agent::new(agent_config,Some(atomic_bool_rc)) {
+                        Ok(mgba_agent) => mgba_agent,
+                        Err(e) => {
+                            println!("{}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let episode_results = mgba_agent.run_client();
                     println!("Finished running our rust core impl!");
-                    observation_data_set.unwrap().save_album(Path::new("imageData/"));
+                    episode::print_episode_summary(&episode_results);
+                }
+                MgbaTestScenario::RustEnvironment => {
+                    println!("Running the RustEnvironment test scenario!");
+                    // Same as RustNoSockets/TotalRust depending on agent_driver, but we own the loop
+                    // Use the configured policy if there is one, otherwise just hold nothing down
+                    let mut policy = match &agent_config.agent_control {
+                        AgentControl::Intelligent(policy_config) => match agent_stuff::policy::build_policy(policy_config) {
+                            Ok(policy) => Some(policy),
+                            Err(e) => {
+                                println!("{}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        },
+                        _ => None,
+                    };
+                    let mut environment = match Environment::new(agent_config,Some(atomic_bool_rc)) {
+                        Ok(environment) => environment,
+                        Err(e) => {
+                            println!("{}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let mut observation = match environment.reset() {
+                        Ok(observation) => observation,
+                        Err(e) => {
+                            println!("Failed to reset the environment: {}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let mut total_reward = 0_f32;
+                    loop {
+                        let action = match policy.as_mut() {
+                            Some(policy) => policy.act(&observation),
+                            None => 0_u16,
+                        };
+                        let step_result = match environment.step(action) {
+                            Ok(step_result) => step_result,
+                            Err(e) => {
+                                println!("Failed to step the environment: {}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        };
+                        total_reward += step_result.reward;
+                        for error in &step_result.info.emulator_errors {
+                            println!("Emulator error at frame {}, the episode is invalid: {}", step_result.info.frame_counter, error);
+                        }
+                        if step_result.done {
+                            let done_reason = if let Some(terminated_by) = &step_result.info.terminated_by {
+                                terminated_by.as_str()
+                            } else if step_result.info.truncated {
+                                "max frames"
+                            } else if step_result.info.stop_requested {
+                                "stop requested"
+                            } else if step_result.info.window_closed {
+                                "window closed"
+                            } else {
+                                "episode over"
+                            };
+                            println!(
+                                "Episode done ({}) after {} frames, total reward {}, last frame {}x{}",
+                                done_reason,
+                                step_result.info.frame_counter,
+                                total_reward,
+                                step_result.observation.frame_buffer.width,
+                                step_result.observation.frame_buffer.height,
+                            );
+                            break;
+                        }
+                        observation = step_result.observation;
+                    }
+                }
+                MgbaTestScenario::RustDeterminism => {
+                    println!("Running the RustDeterminism test scenario!");
+                    // seed from the config, falls back to 0 if it doesn't have one (RTC/RNG won't be pinned then)
+                    let seed = agent_config.game_config_data.rng_seed.as_ref().map_or(0, |rng_seed| rng_seed.seed);
+                    let policy_config = match &agent_config.agent_control {
+                        AgentControl::Intelligent(policy_config) => policy_config.clone(),
+                        _ => agent_stuff::policy::PolicyConfig {
+                            name: "Random".to_string(),
+                            params: serde_json::Value::Null,
+                        },
+                    };
+                    const DETERMINISM_FRAMES: u32 = 60 * 60;
+                    // the seeded RNG state, read back to check the seeding step actually landed
+                    let rng_location = agent_config.game_config_data.rng_seed.as_ref().and_then(|rng_seed| {
+                        rng_seed.rng_address.as_ref().map(|rng_address| (rng_address.clone(), rng_seed.rng_width))
+                    });
+                    let mut environment = match Environment::new(agent_config,Some(atomic_bool_rc)) {
+                        Ok(environment) => environment,
+                        Err(e) => {
+                            println!("{}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let mut run_checksums: Vec<Vec<u32>> = vec![];
+                    let mut run_snapshots: Vec<Vec<u8>> = vec![];
+                    for run_idx in 0..2 {
+                        // fresh policy each run, so the inputs are identical
+                        let mut policy = match agent_stuff::policy::build_policy(&policy_config) {
+                            Ok(policy) => policy,
+                            Err(e) => {
+                                println!("{}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        };
+                        let mut observation = match environment.reset_with_seed(seed) {
+                            Ok(observation) => observation,
+                            Err(e) => {
+                                println!("Failed to reset the environment: {}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        };
+                        if let Some((rng_address, rng_width)) = &rng_location {
+                            let rng_state = rng_address
+                                .value()
+                                .and_then(|address| environment.read_memory(address, *rng_width));
+                            println!("Run #{}: RNG state after seeding {:02x?}", run_idx, rng_state);
+                        }
+                        let mut checksums = vec![observation.frame_buffer.checksum()];
+                        for _ in 0..DETERMINISM_FRAMES {
+                            let step_result = match environment.step(policy.act(&observation)) {
+                                Ok(step_result) => step_result,
+                                Err(e) => {
+                                    println!("Run #{} failed: {}", run_idx, e);
+                                    return process::ExitCode::from(1);
+                                }
+                            };
+                            checksums.push(step_result.info.frame_checksum);
+                            if step_result.done {
+                                break;
+                            }
+                            observation = step_result.observation;
+                        }
+                        println!("Run #{}: {} frames", run_idx, checksums.len());
+                        run_checksums.push(checksums);
+                        match environment.snapshot() {
+                            Ok(snapshot) => run_snapshots.push(snapshot),
+                            Err(e) => {
+                                println!("Failed to snapshot the emulator: {}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        }
+                    }
+                    match run_checksums[0].iter().zip(&run_checksums[1]).position(|(a, b)| a != b) {
+                        Some(frame_idx) => {
+                            println!("Runs diverged at frame {}, seeding isn't deterministic!", frame_idx);
+                            return process::ExitCode::from(1);
+                        },
+                        None => println!("Runs matched on every frame with seed {}", seed),
+                    }
+                    // frames can match while hidden state (RNG, timers) already drifted apart
+                    if run_snapshots[0] != run_snapshots[1] {
+                        println!("Emulator state differs after the last frame, the next frames might diverge");
+                    }
+                }
+                MgbaTestScenario::TransportBenchmark => {
+                    println!("Running the TransportBenchmark test scenario!");
+                    // Same game, same steps, only how the frames get to us changes
+                    // Every transport pays for the emulation too, so compare the differences rather than the totals
+                    const BENCHMARK_FRAMES: u32 = 60 * 60;
+                    let socket_path = std::env::temp_dir().join(format!("mgba_benchmark_{}.sock", process::id()));
+                    let tcp_endpoint = Endpoint::localhost(10103_u16);
+                    let unix_endpoint = Endpoint::Unix(socket_path);
+                    // the servers stop when we're done, or on Ctrl-C
+                    let server_handles: Vec<_> = [&tcp_endpoint, &unix_endpoint]
+                        .into_iter()
+                        .map(|endpoint| {
+                            let server_config = ServerConfig { endpoint: endpoint.clone(), max_sessions: 1 };
+                            let game_config_data = agent_config.game_config_data.clone();
+                            let stop_flag = atomic_bool_rc.clone();
+                            thread::spawn(move || {
+                                if let Err(e) = server::spawn_server(&server_config, &game_config_data, Some(stop_flag)) {
+                                    println!("Rust server on {} failed: {}", server_config.endpoint, e);
+                                }
+                            })
+                        })
+                        .collect();
+                    let transports = [
+                        ("TCP", &tcp_endpoint, false, FrameEncoding::Raw),
+                        ("TCP, delta frames", &tcp_endpoint, false, FrameEncoding::DeltaRle),
+                        ("TCP, grayscale frames", &tcp_endpoint, false, FrameEncoding::Grayscale),
+                        ("TCP, downscaled frames", &tcp_endpoint, false, FrameEncoding::Downscale { factor: 2 }),
+                        ("Unix socket", &unix_endpoint, false, FrameEncoding::Raw),
+                        ("Unix socket, delta frames", &unix_endpoint, false, FrameEncoding::DeltaRle),
+                        ("Shared memory", &unix_endpoint, true, FrameEncoding::Raw),
+                    ];
+                    let mut benchmark_failed = false;
+                    for (transport_name, endpoint, shared_frames, frame_encoding) in transports {
+                        let session_request = protocol::SessionRequest {
+                            game_config_data: Some(agent_config.game_config_data.clone()),
+                            frame_encoding,
+                            ..protocol::SessionRequest::default()
+                        };
+                        // the servers might still be binding
+                        let connect_options = protocol::ConnectOptions::default();
+                        let mut connection = match protocol::Client::connect_with_retry(endpoint, &session_request, &connect_options) {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("{}: failed to connect to {}: {}", transport_name, endpoint, e);
+                                benchmark_failed = true;
+                                continue;
+                            },
+                        };
+                        if shared_frames {
+                            if let Err(e) = connection.map_frames(2) {
+                                println!("{}: failed to map frames: {}", transport_name, e);
+                                benchmark_failed = true;
+                                continue;
+                            }
+                        }
+                        let mut frame_data = match connection.server_info().frame_len() {
+                            Ok(frame_len) => vec![0_u8; frame_len],
+                            Err(e) => {
+                                println!("{}: {}", transport_name, e);
+                                benchmark_failed = true;
+                                continue;
+                            },
+                        };
+                        let started = Instant::now();
+                        let mut step_result = connection.reset(&mut frame_data);
+                        for frame_idx in 0..BENCHMARK_FRAMES {
+                            if step_result.is_err() || atomic_bool_rc.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            // walk through a few buttons so the game has something to draw
+                            step_result = connection.step(1 << (frame_idx / 60 % 8), &mut frame_data);
+                        }
+                        let elapsed = started.elapsed();
+                        if let Err(e) = step_result {
+                            println!("{}: failed mid benchmark: {}", transport_name, e);
+                            benchmark_failed = true;
+                            continue;
+                        }
+                        println!(
+                            "{}: {} frames of {} bytes in {:.3}s, {:.1} us/frame, {:.0} frames/s",
+                            transport_name,
+                            BENCHMARK_FRAMES,
+                            frame_data.len(),
+                            elapsed.as_secs_f64(),
+                            elapsed.as_secs_f64() * 1e6 / BENCHMARK_FRAMES as f64,
+                            BENCHMARK_FRAMES as f64 / elapsed.as_secs_f64(),
+                        );
+                        // hanging up only ends this session, the next transport gets a fresh core
+                    }
+                    atomic_bool_rc.store(true, Ordering::Relaxed);
+                    for server_handle in server_handles {
+                        server_handle.join().expect("Couldn't join a benchmark server");
+                    }
+                    if benchmark_failed {
+                        return process::ExitCode::from(1);
+                    }
                 }
             }
         }
@@ -482,7 +778,15 @@ fn main() -> process::ExitCode {
                                 game_config_data: GameConfigData{
                                     rom_path: dest_rom_path,
                                     save_state_path: dest_sav_path,
+                                    ..agent_config.game_config_data.clone()
                                 },
+                                // workers can't all write the same trajectory file
+                                trajectory: agent_config.trajectory.clone().map(|trajectory_config| {
+                                    trajectory::TrajectoryConfig {
+                                        path: format!("{}.worker{}", trajectory_config.path, spawn_worker_idx),
+                                        ..trajectory_config
+                                    }
+                                }),
                                 ..agent_config.clone()
                             }
                         }; // new agent_configuration
@@ -563,6 +867,9 @@ fn main() -> process::ExitCode {
                         worker_manager.unix_socket.write_all(&serialized_agent_config.clone().into_bytes())
                             .expect("Boss: Failure to write to unix socket 1");
                         println!("Boss: Data sent to Worker!");
+                        // that's all the configurations, the Worker exits once it's done with them
+                        worker_manager.unix_socket.shutdown(std::net::Shutdown::Write)
+                            .expect("Boss: Failure to shut down unix socket");
                     } // initialize configuration loop
 
                     // Overwrite the signal handler here for this Boss!
@@ -596,42 +903,347 @@ fn main() -> process::ExitCode {
                         .expect("Couldn't connect to Boss' socket, DOY!!");
 
                     // Read some sockets data for Agent configuration
+                    // The Boss can send more than one, every configuration after the first only swaps the game
+                    // (Agent::load_game) so the library, core and everything else get reused
+                    // None once the Boss hangs up
+                    let mut read_configuration = || -> Option<AgentConfiguration> {
+                        // read size first
+                        let mut buf = [0_u8; 4];
+                        println!("Worker: About to read message length from Boss");
+                        match stream.read_exact(&mut buf) {
+                            Ok(()) => (),
+                            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+                            Err(e) => panic!("Worker: Failure to read message length: {}", e),
+                        }
+                        let message_len = u32::from_be_bytes(buf);
+                        println!("Worker: About to read in Agent configuration");
+                        // Agent configuration comes from parent process
+                        let mut incoming_configuration = vec![0_u8; message_len as usize];
+                        stream.read_exact(&mut incoming_configuration[..]).expect("Worker: Failure to read Agent configuration");
+                        println!("Worker: Received configuration data (size {} bytes)",message_len);
+                        println!("Worker: Raw data: {}",std::str::from_utf8(&incoming_configuration[..]).expect(""));
+                        let worker_agent_config: AgentConfiguration =
+                            serde_json::from_slice(&incoming_configuration)
+                                .expect("Trouble parsing data");
+                        println!("Worker: Successfully parsed worker_agent_config");
+                        Some(worker_agent_config)
+                    };
 
-                    // Read in a serialized version of our Agent configuration
-                    // read size first
-
-                    // let mut buf = [0_u8; 1];
-                    let mut buf = [0_u8; 4];
-                    println!("Worker: About to read message length from Boss");
-                    stream.read_exact(&mut buf).expect("Worker: Failure to read message length");
-                    let message_len = u32::from_be_bytes(buf);
-                    println!("Worker: About to read in Agent configuration");
-                    // Agent configuration comes from parent process
-                    let mut incoming_configuration = vec![0_u8; message_len as usize];
-                    stream.read_exact(&mut incoming_configuration[..]).expect("Worker: Failure to read Agent configuration");
-                    println!("Worker: Received configuration data (size {} bytes)",message_len);
-                    println!("Worker: Raw data: {}",std::str::from_utf8(&incoming_configuration[..]).expect(""));
-                    let worker_agent_config: AgentConfiguration =
-                        serde_json::from_slice(&incoming_configuration)
-                            .expect("Trouble parsing data");
-                    println!("Worker: Successfully parsed worker_agent_config");
-                    // println!(
-                    //     "Worker: Finished reading in configuration:\n{:?}",
-                    //     worker_agent_config
-                    // );
-
-                    // run_client is running indefinitely
-                    // but somehow we need to SIGNAL it to stop -- batch size? Part of configuration?
-                    // Should also be able to just signal it to stop and do last activities
-                    let mut mgba_agent = Agent::new(worker_agent_config,Some(atomic_bool_rc));
-                    let _observation_data_set = mgba_agent.run_client();
-                    // any final steps for this run, do we need to save some data?
-                    // Store info about a neural network? --> This gets referenced via worker_agent_config
+                    let worker_agent_config = match read_configuration() {
+                        Some(worker_agent_config) => worker_agent_config,
+                        None => {
+                            println!("Worker: Boss hung up before sending a configuration");
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    // run_client returns after episode_config.num_episodes (the batch size)
+                    // or when the stop flag gets raised, whichever comes first
+                    let mut mgba_agent = match Agent::new(worker_agent_config,Some(atomic_bool_rc)) {
+                        Ok(mgba_agent) => mgba_agent,
+                        Err(e) => {
+                            println!("Worker: {}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    loop {
+                        let episode_results = mgba_agent.run_client();
+                        episode::print_episode_summary(&episode_results);
+                        // any final steps for this run, do we need to save some data?
+                        // Store info about a neural network? --> This gets referenced via worker_agent_config
+                        if mgba_agent.stop_requested() {
+                            break;
+                        }
+                        let next_agent_config = match read_configuration() {
+                            Some(next_agent_config) => next_agent_config,
+                            None => break,
+                        };
+                        if let Err(e) = mgba_agent.load_game(next_agent_config.game_config_data) {
+                            println!("Worker: Failed to switch games: {}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    }
                     println!("Finished running our rust core impl!");
                     // observation_data_set.save_album(Path::new("imageData/"));
                 }
             }
         }
+        ProgramAction::Dataset => {
+            println!("Dataset argument detected!");
+            #[derive(Debug)]
+            enum DatasetAction {
+                Info, // header, frame/chunk counts and episodes of a trajectory file
+                Verify, // check every frame of an album or trajectory, report the corrupt ones
+                ToAlbum, // load an album or trajectory and save it (again) as an album of pngs
+                Export, // numpy arrays for the training side, .npz or .npy files
+            }
+            impl FromStr for DatasetAction {
+                type Err = ();
+                fn from_str(src: &str) -> Result<DatasetAction, ()> {
+                    match src {
+                        "Info" => Ok(DatasetAction::Info),
+                        "Verify" => Ok(DatasetAction::Verify),
+                        "ToAlbum" => Ok(DatasetAction::ToAlbum),
+                        "Export" => Ok(DatasetAction::Export),
+                        _ => Err(()),
+                    }
+                }
+            }
+            let mut dataset_action = DatasetAction::Info;
+            let mut dataset_path = "".to_string();
+            let mut frame_idx: Option<usize> = None;
+            let mut out_path = "".to_string();
+            let mut raw_frames = false;
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Inspect recorded trajectories");
+                ap.refer(&mut dataset_action).add_argument(
+                    "dataset action",
+                    Store,
+                    "What to do with the dataset: Info, Verify, ToAlbum, Export",
+                );
+                ap.refer(&mut dataset_path).add_argument(
+                    "path",
+                    Store,
+                    "Trajectory file written by an Agent with a trajectory config, or an album directory",
+                ).required();
+                ap.refer(&mut frame_idx).add_option(
+                    &["--frame"],
+                    StoreOption,
+                    "Also print this frame's record",
+                );
+                ap.refer(&mut out_path).add_option(
+                    &["--out"],
+                    Store,
+                    "Album directory to write for ToAlbum, .npz (or .npy) file for Export",
+                );
+                ap.refer(&mut raw_frames).add_option(
+                    &["--raw"],
+                    StoreTrue,
+                    "Export the raw frames as RGB instead of the processed data",
+                );
+                use std::io::{stderr, stdout};
+                match ap.parse(sub_args, &mut stdout(), &mut stderr()) {
+                    Ok(()) => {}
+                    Err(x) => {
+                        std::process::exit(x);
+                    }
+                }
+            }
+            match dataset_action {
+                DatasetAction::Info => {
+                    let mut reader = match trajectory::TrajectoryReader::open(Path::new(&dataset_path)) {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            println!("Couldn't open {}: {}", dataset_path, e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    let header = *reader.header();
+                    println!(
+                        "{}: {}x{} frames at {} bpp, processed {:?}, raw frame data {}",
+                        dataset_path, header.width, header.height, header.bpp, header.processed_shape, header.has_frame_data(),
+                    );
+                    println!(
+                        "{} frames in {} chunks, {} trailing bytes of an unfinished chunk",
+                        reader.len(), reader.chunks().len(), reader.truncated_bytes(),
+                    );
+                    let mut episodes = 0_u32;
+                    let mut total_reward = 0_f32;
+                    for idx in 0..reader.len() {
+                        let frame = reader.read_frame(idx).expect("Failed to read trajectory frame");
+                        total_reward += frame.reward;
+                        episodes += frame.done as u32;
+                    }
+                    println!("{} finished episodes, total reward {}", episodes, total_reward);
+                    if let Some(frame_idx) = frame_idx {
+                        match reader.read_frame(frame_idx) {
+                            Ok(frame) => println!(
+                                "Frame {}: keycode {:#06x}, reward {}, done {}, {} processed bytes, {} raw frame bytes",
+                                frame_idx, frame.keycode, frame.reward, frame.done, frame.processed_data.len(),
+                                frame.frame_data.as_ref().map_or(0, |frame_data| frame_data.len()),
+                            ),
+                            Err(e) => {
+                                println!("{}", e);
+                                return process::ExitCode::from(1);
+                            }
+                        }
+                    }
+                }
+                DatasetAction::Verify => {
+                    let dataset_frames = match dataset::DatasetFrames::open(Path::new(&dataset_path)) {
+                        Ok(dataset_frames) => dataset_frames,
+                        Err(e) => {
+                            println!("Couldn't open {}: {}", dataset_path, e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    println!("Verifying {} frames of {}", dataset_frames.len(), dataset_frames.path().display());
+                    let mut valid_frames = 0_usize;
+                    let mut corrupt_frames = 0_usize;
+                    let mut last_frame_idx = None;
+                    let mut episodes = 0_u32;
+                    let mut total_reward = 0_f32;
+                    for frame_result in dataset_frames {
+                        match frame_result {
+                            Ok(frame) => {
+                                valid_frames += 1;
+                                last_frame_idx = Some(frame.frame_idx);
+                                total_reward += frame.reward.unwrap_or(0.0);
+                                episodes += frame.done.unwrap_or(false) as u32;
+                            }
+                            Err(corrupt_frame) => {
+                                corrupt_frames += 1;
+                                println!("Corrupt: {}", corrupt_frame);
+                            }
+                        }
+                    }
+                    println!(
+                        "{} valid frames (last one #{:?}), {} corrupt, {} finished episodes, total reward {}",
+                        valid_frames, last_frame_idx, corrupt_frames, episodes, total_reward,
+                    );
+                    if corrupt_frames > 0 {
+                        return process::ExitCode::from(1);
+                    }
+                }
+                DatasetAction::ToAlbum => {
+                    if out_path.is_empty() {
+                        println!("ToAlbum needs --out <album directory>");
+                        return process::ExitCode::from(1);
+                    }
+                    let (observation_set, corrupt_frames) = match ObservationSet::load(Path::new(&dataset_path)) {
+                        Ok(loaded) => loaded,
+                        Err(e) => {
+                            println!("Couldn't load {}: {}", dataset_path, e);
+                            return process::ExitCode::from(1);
+                        }
+                    };
+                    for corrupt_frame in &corrupt_frames {
+                        println!("Skipping corrupt {}", corrupt_frame);
+                    }
+                    observation_set.save_album(Path::new(&out_path));
+                    // the input log has every keycode of the episode, not only the frames that were kept
+                    let input_log_path = Path::new(&dataset_path).join(replay::INPUT_LOG_FILE_NAME);
+                    if input_log_path.is_file() {
+                        if let Err(e) = fs::copy(&input_log_path, Path::new(&out_path).join(replay::INPUT_LOG_FILE_NAME)) {
+                            println!("Couldn't copy the input log: {}", e);
+                            return process::ExitCode::from(1);
+                        }
+                    }
+                }
+                DatasetAction::Export => {
+                    if out_path.is_empty() {
+                        println!("Export needs --out <file.npz or file.npy>");
+                        return process::ExitCode::from(1);
+                    }
+                    let frame_source = if raw_frames { FrameSource::Raw } else { FrameSource::Processed };
+                    let export_result = if Path::new(&dataset_path).is_dir() {
+                        // albums don't record rewards, the ObservationSet has everything there is
+                        let (observation_set, corrupt_frames) = match ObservationSet::load(Path::new(&dataset_path)) {
+                            Ok(loaded) => loaded,
+                            Err(e) => {
+                                println!("Couldn't load {}: {}", dataset_path, e);
+                                return process::ExitCode::from(1);
+                            }
+                        };
+                        for corrupt_frame in &corrupt_frames {
+                            println!("Skipping corrupt {}", corrupt_frame);
+                        }
+                        println!("Exporting {} frames", observation_set.len());
+                        observation_set.export_npy(Path::new(&out_path), frame_source)
+                    } else {
+                        // stream the trajectory so rewards and dones come along
+                        let dataset_frames = match dataset::DatasetFrames::open(Path::new(&dataset_path)) {
+                            Ok(dataset_frames) => dataset_frames,
+                            Err(e) => {
+                                println!("Couldn't open {}: {}", dataset_path, e);
+                                return process::ExitCode::from(1);
+                            }
+                        };
+                        // without FLAG_FRAME_DATA the raw frames would all come out black
+                        let has_frame_data = trajectory::TrajectoryReader::open(Path::new(&dataset_path))
+                            .is_ok_and(|reader| reader.header().has_frame_data());
+                        if raw_frames && !has_frame_data {
+                            println!("{} has no raw frame data, export the processed frames instead", dataset_path);
+                            return process::ExitCode::from(1);
+                        }
+                        let mut npy_batch = NpyBatch::new(frame_source);
+                        for frame_result in dataset_frames {
+                            match frame_result {
+                                Ok(frame) => {
+                                    if let Err(e) = npy_batch.push(&frame.observation_data, frame.reward, frame.done) {
+                                        println!("Skipping frame {}: {}", frame.frame_idx, e);
+                                    }
+                                }
+                                Err(corrupt_frame) => println!("Skipping corrupt {}", corrupt_frame),
+                            }
+                        }
+                        println!("Exporting {} frames", npy_batch.len());
+                        npy_batch.write(Path::new(&out_path))
+                    };
+                    match export_result {
+                        Ok(array_names) => println!("Wrote {:?} to {}", array_names, out_path),
+                        Err(e) => {
+                            println!("Failed to export to {}: {}", out_path, e);
+                            return process::ExitCode::from(1);
+                        }
+                    }
+                }
+            }
+        }
+        ProgramAction::Serve => {
+            println!("Serve argument detected!");
+            let mut host = "127.0.0.1".to_string();
+            let mut port = 10103_u16;
+            let mut socket_path: Option<String> = None;
+            let mut max_sessions = 8_usize;
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Hosts emulator sessions (one core per connection) for socket agents, ROM from agent_config.json");
+                ap.refer(&mut host).add_option(
+                    &["--host"],
+                    Store,
+                    "Address to listen on, 0.0.0.0 takes AgentDriver::Remote agents from other machines/containers",
+                );
+                ap.refer(&mut port).add_option(
+                    &["--port"],
+                    Store,
+                    "TCP port to listen on, for AgentDriver::Sockets/Remote",
+                );
+                ap.refer(&mut socket_path).add_option(
+                    &["--socket"],
+                    StoreOption,
+                    "Unix domain socket path to listen on instead, for AgentDriver::UnixSocket/SharedMemory",
+                );
+                ap.refer(&mut max_sessions).add_option(
+                    &["--max-sessions"],
+                    Store,
+                    "Sessions to run at once, clients past that get turned away",
+                );
+                use std::io::{stderr, stdout};
+                match ap.parse(sub_args, &mut stdout(), &mut stderr()) {
+                    Ok(()) => {}
+                    Err(x) => {
+                        std::process::exit(x);
+                    }
+                }
+            }
+            let server_config = ServerConfig {
+                endpoint: match socket_path {
+                    Some(socket_path) => Endpoint::Unix(socket_path.into()),
+                    None => Endpoint::Tcp(format!("{}:{}", host, port)),
+                },
+                max_sessions,
+            };
+            // Ctrl-C stops the server, hanging up on every session
+            if let Err(e) = server::spawn_server(
+                &server_config,
+                &agent_config.game_config_data,
+                Some(atomic_bool_rc),
+            ) {
+                println!("Server failed: {}", e);
+                return process::ExitCode::from(1);
+            }
+        }
     };
 
     process::ExitCode::from(0)